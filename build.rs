@@ -27,6 +27,8 @@ fn main() {
     println!("cargo:rerun-if-changed=Cargo.lock");
     println!("cargo:rerun-if-changed=interstellar_os");
     println!("cargo:rerun-if-changed=initrd-files");
+    println!("cargo:rerun-if-changed=interstellar_user");
+    println!("cargo:rerun-if-changed=user-programs");
     println!("cargo:rerun-if-changed=test_runner");
     println!("cargo:rerun-if-changed=rust-toolchain");
 
@@ -37,7 +39,8 @@ fn main() {
         PathBuf::from(std::env::var_os("CARGO_BIN_FILE_INTERSTELLAR_OS_interstellar_os").unwrap());
 
     // Create initrd
-    let _ = create_initrd();
+    build_user_programs(Path::new("./user-programs"), &out_dir);
+    let _ = create_initrd(&out_dir);
 
     let mut boot_config = BootConfig::default();
 
@@ -105,6 +108,11 @@ fn main() {
 //Data:           // Contains all the raw data
 //Data End:
 
+// The example programs of interstellar_user, they are packed into the initrd under their own names
+const USER_PROGRAMS: [&str; 3] = ["hello_rust", "args", "primes"];
+
+include!("user-programs/build_programs.rs");
+
 // Struct to represent a file entry
 struct FileEntry {
     name: String,
@@ -112,7 +120,7 @@ struct FileEntry {
     offset: usize,
 }
 
-fn create_initrd() -> io::Result<()> {
+fn create_initrd(out_dir: &Path) -> io::Result<()> {
     let files = fs::read_dir("./initrd-files")?;
     let mut file_entries: Vec<FileEntry> = Vec::new();
     let mut total_file_size = 1;
@@ -135,12 +143,31 @@ fn create_initrd() -> io::Result<()> {
         }
     }
 
+    let built_programs = BUILT_PROGRAMS
+        .iter()
+        .map(|name| (name.to_string(), out_dir.join(name)));
+
+    let user_programs = USER_PROGRAMS.iter().map(|name| {
+        // set by cargo's artifact dependency feature
+        let path = std::env::var_os(format!("CARGO_BIN_FILE_INTERSTELLAR_USER_{name}"))
+            .unwrap_or_else(|| panic!("user program {name} was not built"));
+        (name.to_string(), PathBuf::from(path))
+    });
+
+    for (name, path) in built_programs.chain(user_programs) {
+        let data = fs::read(path)?;
+        let offset = total_file_size;
+        total_file_size += data.len();
+
+        file_entries.push(FileEntry { name, data, offset });
+    }
+
     let total_files = file_entries.len();
 
     let mut file = File::create("./target/initrd")?;
     file.write_all("Metadata:\n".as_bytes())?;
-    file.write_all(format!("Number Of Files: {}\n", total_files).as_bytes())?;
-    file.write_all(format!("Total Files Size: {}\n", total_file_size).as_bytes())?;
+    file.write_all(format!("Number Of Files: {total_files}\n").as_bytes())?;
+    file.write_all(format!("Total Files Size: {total_file_size}\n").as_bytes())?;
     file.write_all("Metadata End:\n".as_bytes())?;
 
     for file_entry in &file_entries {
@@ -1,28 +1,35 @@
-//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
-//Copyright (C) 2023  contributors of the interstellar OS project
-//
-//This program is free software: you can redistribute it and/or modify
-//it under the terms of the GNU General Public License as published by
-//the Free Software Foundation, either version 3 of the License, or
-//(at your option) any later version.
-//
-//This program is distributed in the hope that it will be useful,
-//but WITHOUT ANY WARRANTY; without even the implied warranty of
-//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
-//GNU General Public License for more details.
-//
-//You should have received a copy of the GNU General Public License
-//along with this program.  If not, see <https://www.gnu.org/licenses/>.
-
-use std::env;
-
-fn main() {
-    let cwd_path = env::current_dir().expect("can't get current directory");
-    let cwd = cwd_path.display();
-    println!("cargo:rerun-if-changed={cwd}/src");
-    println!("cargo:rerun-if-changed={cwd}/tests");
-    println!("cargo:rerun-if-changed={cwd}/build.rs");
-    println!("cargo:rerun-if-changed={cwd}/Cargo.toml");
-    println!("cargo:rerun-if-changed={cwd}/linker.ld");
-    println!("cargo:rerun-if-changed={cwd}/rust-toolchain.toml");
-}
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::env;
+use std::path::PathBuf;
+
+fn main() {
+    let cwd_path = env::current_dir().expect("can't get current directory");
+    let cwd = cwd_path.display();
+    println!("cargo:rerun-if-changed={cwd}/src");
+    println!("cargo:rerun-if-changed={cwd}/tests");
+    println!("cargo:rerun-if-changed={cwd}/build.rs");
+    println!("cargo:rerun-if-changed={cwd}/Cargo.toml");
+    println!("cargo:rerun-if-changed={cwd}/linker.ld");
+    println!("cargo:rerun-if-changed={cwd}/rust-toolchain.toml");
+
+    // The tests load the programs from user-programs out of OUT_DIR
+    let out_dir = PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    build_user_programs(&cwd_path.join("../user-programs"), &out_dir);
+}
+
+include!("../user-programs/build_programs.rs");
@@ -36,6 +36,8 @@ pub static ACPI_INFO: OnceCell<Spinlock<AcpiInfo>> = OnceCell::uninit();
 pub struct AcpiInfo {
     pub platform_info: Result<PlatformInfo, AcpiError>,
     pub hpet_info: Result<HpetInfo, AcpiError>,
+    /// CMOS register the RTC keeps the century in, 0 if the firmware does not provide one
+    pub century_register: u8,
 }
 
 #[derive(Clone)]
@@ -120,12 +122,20 @@ pub fn init(rsdp_address: PhysAddr) -> AcpiTables<AcpiHandlerImpl> {
 
             let hpet_info = HpetInfo::new(&acpi_tables);
 
+            let century_register =
+                unsafe { acpi_tables.get_sdt::<acpi::fadt::Fadt>(acpi::sdt::Signature::FADT) }
+                    .ok()
+                    .flatten()
+                    .map(|fadt| fadt.century)
+                    .unwrap_or(0);
+
             //let _context = parse_aml_tables(aml_tables);
 
             ACPI_INFO.init_once(|| {
                 Spinlock::new(AcpiInfo {
                     platform_info,
                     hpet_info,
+                    century_register,
                 })
             });
 
@@ -137,6 +147,7 @@ pub fn init(rsdp_address: PhysAddr) -> AcpiTables<AcpiHandlerImpl> {
     }
 }
 
+#[allow(dead_code)] // Only used by _parse_aml_tables which is not called yet
 struct OsAmlHandler;
 
 impl aml::Handler for OsAmlHandler {
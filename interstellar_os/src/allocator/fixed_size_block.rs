@@ -17,6 +17,7 @@
 use super::Locked;
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::{mem, ptr, ptr::NonNull};
+use x86_64::instructions::interrupts;
 
 /// A node in the linked list used by `FixedSizeBlockAllocator`.
 struct ListNode {
@@ -39,29 +40,33 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     ///
     /// This function is unsafe because it performs low-level memory allocation operations.
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        crate::memory::MEMORY.get().unwrap().force_unlock();
-        crate::memory::MEMORY
-            .get()
-            .unwrap()
-            .lock()
-            .add_to_used_mem(layout.size().try_into().unwrap());
-        let mut allocator = self.lock();
+        // A thread preempted while holding the heap lock would deadlock anything that allocates with interrupts disabled,
+        // so interrupts stay disabled while it is held
+        interrupts::without_interrupts(|| {
+            crate::memory::MEMORY.get().unwrap().force_unlock();
+            crate::memory::MEMORY
+                .get()
+                .unwrap()
+                .lock()
+                .add_to_used_mem(layout.size().try_into().unwrap());
+            let mut allocator = self.lock();
 
-        match FixedSizeBlockAllocator::list_index(&layout) {
-            Some(index) => match allocator.list_heads[index].take() {
-                Some(node) => {
-                    allocator.list_heads[index] = node.next.take();
-                    node as *mut ListNode as *mut u8
-                }
-                None => {
-                    let block_size = BLOCK_SIZES[index];
-                    let block_align = block_size;
-                    let layout = Layout::from_size_align(block_size, block_align).unwrap();
-                    allocator.fallback_alloc(layout)
-                }
-            },
-            None => allocator.fallback_alloc(layout),
-        }
+            match FixedSizeBlockAllocator::list_index(&layout) {
+                Some(index) => match allocator.list_heads[index].take() {
+                    Some(node) => {
+                        allocator.list_heads[index] = node.next.take();
+                        node as *mut ListNode as *mut u8
+                    }
+                    None => {
+                        let block_size = BLOCK_SIZES[index];
+                        let block_align = block_size;
+                        let layout = Layout::from_size_align(block_size, block_align).unwrap();
+                        allocator.fallback_alloc(layout)
+                    }
+                },
+                None => allocator.fallback_alloc(layout),
+            }
+        })
     }
 
     /// Deallocates the memory block pointed to by `ptr` with the given layout.
@@ -70,30 +75,38 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllocator> {
     ///
     /// This function is unsafe because it performs low-level memory deallocation operations.
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        crate::memory::MEMORY.get().unwrap().force_unlock();
-        crate::memory::MEMORY
-            .get()
-            .unwrap()
-            .lock()
-            .takeaway_from_used_mem(layout.size().try_into().unwrap());
-        let mut allocator = self.lock();
+        interrupts::without_interrupts(|| {
+            crate::memory::MEMORY.get().unwrap().force_unlock();
+            crate::memory::MEMORY
+                .get()
+                .unwrap()
+                .lock()
+                .takeaway_from_used_mem(layout.size().try_into().unwrap());
+            let mut allocator = self.lock();
 
-        match FixedSizeBlockAllocator::list_index(&layout) {
-            Some(index) => {
-                let new_node = ListNode {
-                    next: allocator.list_heads[index].take(),
-                };
-                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
-                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
-                let new_node_ptr = ptr as *mut ListNode;
-                new_node_ptr.write(new_node);
-                allocator.list_heads[index] = Some(&mut *new_node_ptr);
-            }
-            None => {
-                let ptr = NonNull::new(ptr).unwrap();
-                allocator.fallback_allocator.deallocate(ptr, layout);
+            match FixedSizeBlockAllocator::list_index(&layout) {
+                Some(index) => {
+                    let new_node = ListNode {
+                        next: allocator.list_heads[index].take(),
+                    };
+                    assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                    assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                    let new_node_ptr = ptr as *mut ListNode;
+                    new_node_ptr.write(new_node);
+                    allocator.list_heads[index] = Some(&mut *new_node_ptr);
+                }
+                None => {
+                    let ptr = NonNull::new(ptr).unwrap();
+                    allocator.fallback_allocator.deallocate(ptr, layout);
+                }
             }
-        }
+        })
+    }
+}
+
+impl Default for FixedSizeBlockAllocator {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -0,0 +1,270 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Per-CPU data
+//!
+//! Each CPU gets a [PerCpu] block when it starts and its index goes in IA32_TSC_AUX, so [id] is a single
+//! `rdpid` (or `rdtscp`) and user programs can read it but never change it.
+//! The GS base belongs to user programs, they can load any GS selector, so the kernel never reads through GS.
+//! Only the system call entry uses the block through GS: the kernel GS base points at it and the entry `swapgs`es
+//! around the few instructions that switch to the kernel stack.
+//! Only the boot CPU is started for now, application processors will call [init_current] as they are brought up.
+//!
+//! [enable_protections] turns on the CPU features that keep the kernel and user programs apart.
+
+use alloc::boxed::Box;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{KernelGsBase, Msr};
+use x86_64::VirtAddr;
+
+use crate::other::log::LOGGER;
+
+/// Most CPUs the kernel will start
+pub const MAX_CPUS: usize = 64;
+
+/// Number of CPUs that have called [init_current]
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Set once the boot CPU has a [PerCpu] block, before then [id] is always 0
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// MSR holding the CPU index, read by `rdpid` and `rdtscp`
+const IA32_TSC_AUX: u32 = 0xC000_0103;
+
+/// How [id] reads IA32_TSC_AUX
+static RDPID: AtomicBool = AtomicBool::new(false);
+static RDTSCP: AtomicBool = AtomicBool::new(false);
+
+/// The [PerCpu] block of each CPU by index
+static PER_CPU: [AtomicPtr<PerCpu>; MAX_CPUS] =
+    [const { AtomicPtr::new(core::ptr::null_mut()) }; MAX_CPUS];
+
+/// Data belonging to one CPU
+#[repr(C)]
+pub struct PerCpu {
+    /// Index of the CPU from 0, the boot CPU is 0
+    index: usize,
+    /// Points at the TSS stack for interrupts from user mode, the system call entry switches to it.
+    /// The entry reads this at offset 8
+    #[allow(dead_code)] // Only used by the system call entry
+    syscall_stack: *const u64,
+    /// Where the system call entry keeps the user stack pointer while it switches stacks, offset 16
+    #[allow(dead_code)] // Only used by the system call entry
+    user_stack: u64,
+    /// Local APIC ID of the CPU
+    apic_id: u32,
+    /// Index of the executor worker running on this CPU, usize::MAX if there is none
+    pub(crate) current_worker: AtomicUsize,
+}
+
+// Each block is only written by the CPU it belongs to
+unsafe impl Sync for PerCpu {}
+
+impl PerCpu {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+}
+
+/// Sets up the boot CPU
+pub fn init() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Initializing per-CPU data", file!(), line!());
+
+    let cpuid = raw_cpuid::CpuId::new();
+    RDPID.store(
+        cpuid
+            .get_extended_feature_info()
+            .is_some_and(|info| info.has_rdpid()),
+        Ordering::SeqCst,
+    );
+    RDTSCP.store(
+        cpuid
+            .get_extended_processor_and_feature_identifiers()
+            .is_some_and(|info| info.has_rdtscp()),
+        Ordering::SeqCst,
+    );
+
+    init_current();
+
+    INITIALIZED.store(true, Ordering::SeqCst);
+}
+
+/// Gives the running CPU the next index and a [PerCpu] block
+///
+/// # Panics
+///
+/// If more than [MAX_CPUS] CPUs are started, or a second CPU is started without IA32_TSC_AUX to tell them apart
+pub fn init_current() -> usize {
+    let index = CPU_COUNT.fetch_add(1, Ordering::SeqCst);
+    assert!(index < MAX_CPUS, "too many CPUs");
+
+    let has_tsc_aux = RDPID.load(Ordering::SeqCst) || RDTSCP.load(Ordering::SeqCst);
+    assert!(
+        index == 0 || has_tsc_aux,
+        "more than one CPU needs rdpid or rdtscp"
+    );
+
+    let apic_id = raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map_or(0, |info| info.initial_local_apic_id() as u32);
+
+    let per_cpu = Box::leak(Box::new(PerCpu {
+        index,
+        syscall_stack: crate::gdt::kernel_stack_slot(),
+        user_stack: 0,
+        apic_id,
+        current_worker: AtomicUsize::new(usize::MAX),
+    }));
+    PER_CPU[index].store(per_cpu, Ordering::SeqCst);
+
+    if has_tsc_aux {
+        unsafe { Msr::new(IA32_TSC_AUX).write(index as u64) };
+    }
+
+    // Only the system call entry swaps this in, the GS base itself is left for user programs
+    KernelGsBase::write(VirtAddr::from_ptr(per_cpu as *const PerCpu));
+
+    index
+}
+
+/// Index of the running CPU
+pub fn id() -> usize {
+    if !INITIALIZED.load(Ordering::Relaxed) {
+        return 0;
+    }
+
+    if RDPID.load(Ordering::Relaxed) {
+        let index: usize;
+        unsafe {
+            asm!(
+                "rdpid {}",
+                out(reg) index,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        index
+    } else if RDTSCP.load(Ordering::Relaxed) {
+        let index: u32;
+        unsafe {
+            asm!(
+                "rdtscp",
+                out("eax") _,
+                out("ecx") index,
+                out("edx") _,
+                options(nomem, nostack, preserves_flags),
+            );
+        }
+        index as usize
+    } else {
+        // init_current only starts one CPU without IA32_TSC_AUX
+        0
+    }
+}
+
+/// The running CPU's [PerCpu] block
+///
+/// # Panics
+///
+/// If [init] has not been called
+pub fn current() -> &'static PerCpu {
+    assert!(
+        INITIALIZED.load(Ordering::Relaxed),
+        "cpu::current called before cpu::init"
+    );
+
+    // Blocks are leaked so they live forever
+    unsafe { &*PER_CPU[id()].load(Ordering::Relaxed) }
+}
+
+/// Number of CPUs that have been started
+pub fn count() -> usize {
+    CPU_COUNT.load(Ordering::SeqCst).max(1)
+}
+
+//########################################
+// Protection Features
+//########################################
+
+static SMEP: AtomicBool = AtomicBool::new(false);
+static SMAP: AtomicBool = AtomicBool::new(false);
+static UMIP: AtomicBool = AtomicBool::new(false);
+
+/// Which protections [enable_protections] turned on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protections {
+    /// Supervisor mode execution prevention, the kernel faults when it runs code from a user page
+    pub smep: bool,
+    /// Supervisor mode access prevention, the kernel faults when it touches a user page outside a `stac` section
+    pub smap: bool,
+    /// User mode instruction prevention, `sgdt`, `sidt`, `sldt`, `smsw` and `str` fault in user mode
+    pub umip: bool,
+}
+
+/// Turns on SMEP, SMAP and UMIP on the running CPU where CPUID says it has them
+pub fn enable_protections() -> Protections {
+    let features = raw_cpuid::CpuId::new().get_extended_feature_info();
+
+    let protections = Protections {
+        smep: features.as_ref().is_some_and(|f| f.has_smep()),
+        smap: features.as_ref().is_some_and(|f| f.has_smap()),
+        umip: features.as_ref().is_some_and(|f| f.has_umip()),
+    };
+
+    unsafe {
+        Cr4::update(|flags| {
+            flags.set(
+                Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION,
+                protections.smep,
+            );
+            flags.set(
+                Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION,
+                protections.smap,
+            );
+            flags.set(Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION, protections.umip);
+        });
+    }
+
+    SMEP.store(protections.smep, Ordering::SeqCst);
+    SMAP.store(protections.smap, Ordering::SeqCst);
+    UMIP.store(protections.umip, Ordering::SeqCst);
+
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .info(&alloc::format!("CPU protections: {protections:?}"));
+
+    protections
+}
+
+/// The protections turned on by [enable_protections]
+pub fn protections() -> Protections {
+    Protections {
+        smep: SMEP.load(Ordering::Relaxed),
+        smap: SMAP.load(Ordering::Relaxed),
+        umip: UMIP.load(Ordering::Relaxed),
+    }
+}
@@ -235,6 +235,20 @@ pub fn get_file_contents(file_name: &str) -> Option<&str> {
     None
 }
 
+/// The raw contents of a file, for files that are not text
+pub fn get_file_bytes(file_name: &str) -> Option<&'static [u8]> {
+    let initrddata = INITRDDATA.get()?.lock();
+
+    let data = extract_data_section(initrddata.data)?;
+    let lowercase_file_name = file_name.trim().to_lowercase();
+
+    initrddata
+        .file_entries
+        .iter()
+        .find(|file_entry| file_entry.name.trim().to_lowercase() == lowercase_file_name)
+        .and_then(|file_entry| data.get(file_entry.offset..file_entry.offset + file_entry.size))
+}
+
 pub fn number_of_files() -> Option<usize> {
     let initrddata = INITRDDATA.get();
     initrddata?;
@@ -18,6 +18,7 @@ use alloc::format;
 
 use crate::other::log::LOGGER;
 use core::arch::asm;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 const LETTER_LIST: [&str; 26] = [
     "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", "p", "q", "r", "s",
@@ -84,7 +85,7 @@ impl RandomNumberGenerator {
                 .get()
                 .unwrap()
                 .lock()
-                .warn(&format!("Min range: {} is Bigger than max: {}", min, max))
+                .warn(&format!("Min range: {min} is Bigger than max: {max}"))
         }
 
         if self.x.is_none() {
@@ -143,40 +144,100 @@ impl RandomNumberGenerator {
     }
 }
 
+/// Set once [rdseed] has warned that it fell back to a weaker source so it only warns once
+static WARNED: AtomicBool = AtomicBool::new(false);
+
+/// Times RDSEED and RDRAND are retried when they run out of entropy, Intel recommends 10
+const RETRIES: usize = 10;
+
 /// Generates a random seed for the random number generator
+///
+/// This uses RDSEED where the CPU has it, then RDRAND, then the time stamp counter
+/// which is only as random as the moment it is read. None if the CPU has none of them
 fn rdseed() -> Option<u128> {
     LOGGER
         .get()
         .unwrap()
         .lock()
         .trace("Generating Random Seed", file!(), line!());
-    if !check_random_support() {
-        // Maybe change this to just check once and save to variable instead of calling again
-        LOGGER.get().unwrap().lock().warn(
-            "RDSEED instruction is not supported for this CPU random numbers cannot be generated",
-        );
-        return None;
+
+    let support = check_random_support();
+
+    if support.rdseed {
+        if let Some(seed) = retry(rdseed_instruction) {
+            return Some(seed as u128);
+        }
+    }
+
+    if support.rdrand {
+        if let Some(seed) = retry(rdrand_instruction) {
+            return Some(seed as u128);
+        }
+    }
+
+    if support.tsc {
+        warn_once("RDSEED and RDRAND are not available, random numbers are seeded from the time stamp counter");
+        return Some(unsafe { core::arch::x86_64::_rdtsc() } as u128);
+    }
+
+    warn_once("The CPU has no source of random numbers, random numbers cannot be generated");
+    None
+}
+
+/// Runs `instruction` until it succeeds, at most [RETRIES] times
+fn retry(instruction: fn() -> Option<u64>) -> Option<u64> {
+    (0..RETRIES).find_map(|_| instruction())
+}
+
+/// Runs RDSEED, None if it had no entropy ready
+fn rdseed_instruction() -> Option<u64> {
+    let result: u64;
+    let ok: u8;
+    unsafe {
+        asm!("rdseed {0}", "setc {1}", out(reg) result, out(reg_byte) ok, options(nomem, nostack));
     }
-    let mut result: u64;
+    (ok != 0).then_some(result)
+}
+
+/// Runs RDRAND, None if it had no entropy ready
+fn rdrand_instruction() -> Option<u64> {
+    let result: u64;
+    let ok: u8;
     unsafe {
-        asm!("rdseed {0:r}", out(reg) result);
+        asm!("rdrand {0}", "setc {1}", out(reg) result, out(reg_byte) ok, options(nomem, nostack));
     }
-    Some(result as u128)
+    (ok != 0).then_some(result)
 }
 
-/// Checks CPUID for if the CPU supports RDSEED instruction
-fn check_random_support() -> bool {
+fn warn_once(message: &str) {
+    if !WARNED.swap(true, Ordering::SeqCst) {
+        LOGGER.get().unwrap().lock().warn(message);
+    }
+}
+
+/// The random number sources the CPU has
+struct RandomSupport {
+    rdseed: bool,
+    rdrand: bool,
+    tsc: bool,
+}
+
+/// Checks CPUID for the RDSEED and RDRAND instructions and the time stamp counter
+fn check_random_support() -> RandomSupport {
     LOGGER.get().unwrap().lock().trace(
         "Checking if CPU supports RDSEED instruction",
         file!(),
         line!(),
     );
     let cpuid = raw_cpuid::CpuId::new();
-
-    match cpuid.get_feature_info() {
-        Some(feat_info) => {
-            feat_info.has_rdrand() // Unsure if this also means it supports RDSEED but no other options for it
-        }
-        None => false,
+    let features = cpuid.get_feature_info();
+
+    RandomSupport {
+        // CPUID leaf 7 EBX bit 18, CPUs like Ivy Bridge and Haswell have RDRAND without it
+        rdseed: cpuid
+            .get_extended_feature_info()
+            .is_some_and(|info| info.has_rdseed()),
+        rdrand: features.as_ref().is_some_and(|info| info.has_rdrand()),
+        tsc: features.as_ref().is_some_and(|info| info.has_tsc()),
     }
 }
@@ -136,6 +136,9 @@ pub const LETTER_SPACING: usize = 0;
 /// Padding from the border. Prevent that font is too close to border.
 pub const BORDER_PADDING: usize = 1;
 
+/// Height of the underline text cursor in pixels
+pub const TEXT_CURSOR_HEIGHT: usize = 2;
+
 /// Constants for the usage of the [`noto_sans_mono_bitmap`] crate.
 pub mod font_constants {
     use super::*;
@@ -173,6 +176,8 @@ pub struct FrameBufferWriter {
     pub info: FrameBufferInfo,
     x_pos: usize,
     y_pos: usize,
+    /// The text cursor is drawn at `x_pos`, `y_pos`
+    text_cursor_visible: bool,
     pub text_col: Color,
     pub background_col: Color,
 }
@@ -188,6 +193,7 @@ impl FrameBufferWriter {
             info,
             x_pos: 0,
             y_pos: 0,
+            text_cursor_visible: false,
             text_col: Color::White,
             background_col: Color::MidnightBlue,
         };
@@ -197,16 +203,19 @@ impl FrameBufferWriter {
     /// Sets The Y Pos To + 1 Char Height + Additional Line Spacing
     /// Then Calls self.carriage_return()
     pub fn newline(&mut self) {
+        self.set_text_cursor_visible(false);
         self.y_pos += font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
         self.carriage_return()
     }
     /// Resets The X Pos To Border Padding
     pub fn carriage_return(&mut self) {
+        self.set_text_cursor_visible(false);
         self.x_pos = BORDER_PADDING;
     }
 
     /// Erases all text on the screen. Resets `self.x_pos` and `self.y_pos`.
     pub fn clear(&mut self) {
+        self.text_cursor_visible = false;
         self.x_pos = BORDER_PADDING;
         self.y_pos = BORDER_PADDING;
 
@@ -236,6 +245,18 @@ impl FrameBufferWriter {
         (self.info.width, self.info.height)
     }
 
+    /// Returns how many (columns, rows) of characters fit on the screen.
+    pub fn text_size(&self) -> (usize, usize) {
+        let char_width = font_constants::CHAR_RASTER_WIDTH + LETTER_SPACING;
+        let line_height = font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
+        let usable = |size: usize| size.saturating_sub(BORDER_PADDING * 2);
+
+        (
+            usable(self.info.width) / char_width,
+            usable(self.info.height) / line_height,
+        )
+    }
+
     /// Returns the information about the framebuffer.
     pub fn buffer_info(&mut self) -> FrameBufferInfo {
         self.info
@@ -245,9 +266,33 @@ impl FrameBufferWriter {
         self.y_pos
     }
 
+    /// Draws or erases the underline text cursor where the next char will be written
+    pub fn set_text_cursor_visible(&mut self, visible: bool) {
+        if visible == self.text_cursor_visible {
+            return;
+        }
+        self.text_cursor_visible = visible;
+
+        let color = if visible {
+            Color::to_pixel(self.text_col, self.info)
+        } else {
+            Color::to_pixel(self.background_col, self.info)
+        };
+
+        self.draw_filled_rect(
+            self.x_pos,
+            self.y_pos + font_constants::CHAR_RASTER_HEIGHT.val() - TEXT_CURSOR_HEIGHT,
+            font_constants::CHAR_RASTER_WIDTH,
+            TEXT_CURSOR_HEIGHT,
+            color,
+        );
+    }
+
     /// Writes a single char to the framebuffer. Takes care of special control characters, such as
     /// newlines and carriage returns.
     pub fn write_char(&mut self, c: char, color: &[u8; 4]) {
+        self.set_text_cursor_visible(false);
+
         match c {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
@@ -302,6 +347,8 @@ impl FrameBufferWriter {
 
     /// Deletes the last character written to the framebuffer.
     pub fn delete_char(&mut self) {
+        self.set_text_cursor_visible(false);
+
         let char_width = font_constants::CHAR_RASTER_WIDTH + LETTER_SPACING;
         let line_height = font_constants::CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
 
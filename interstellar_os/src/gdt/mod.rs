@@ -15,6 +15,7 @@
 //along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use crate::other::log::LOGGER;
+use core::ptr::{addr_of, addr_of_mut};
 use lazy_static::lazy_static;
 use x86_64::instructions::segmentation::{Segment, CS, DS, SS};
 use x86_64::instructions::tables::load_tss;
@@ -34,33 +35,56 @@ pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 /// The index of the IST used for general protection faults.
 pub const GENERAL_PROTECTION_FAULT_IST_INDEX: u16 = 2;
 
+/// The index of the IST used for non-maskable interrupts.
+///
+/// NMI and machine checks can arrive while the system call entry is still on the user stack, so they always switch stacks.
+pub const NMI_IST_INDEX: u16 = 3;
+
+/// The index of the IST used for machine checks.
+pub const MACHINE_CHECK_IST_INDEX: u16 = 4;
+
+/// The task state segment, privilege level 0 of it is changed on every thread switch so it is not in the lazy static
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
 lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
+    static ref TSS_INIT: &'static TaskStateSegment = {
+        let tss = unsafe { &mut *addr_of_mut!(TSS) };
 
-        // Set the stack pointer for privilege level 0 (kernel stack).
+        // Set the stack pointer for privilege level 0 (kernel stack), used until a thread enters user mode.
         tss.privilege_stack_table[0] = {
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            VirtAddr::from_ptr(unsafe { &STACK }) + STACK_SIZE
+            VirtAddr::from_ptr(addr_of!(STACK)) + STACK_SIZE
         };
 
         // Set the stack pointer for double fault interrupts.
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
+            let stack_start = VirtAddr::from_ptr(addr_of!(STACK));
             stack_start + STACK_SIZE
         };
 
         // Set the stack pointer for page fault interrupts.
         tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            VirtAddr::from_ptr(unsafe { &STACK }) + STACK_SIZE
+            VirtAddr::from_ptr(addr_of!(STACK)) + STACK_SIZE
         };
 
         // Set the stack pointer for general protection fault interrupts.
         tss.interrupt_stack_table[GENERAL_PROTECTION_FAULT_IST_INDEX as usize] = {
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-            VirtAddr::from_ptr(unsafe { &STACK }) + STACK_SIZE
+            VirtAddr::from_ptr(addr_of!(STACK)) + STACK_SIZE
+        };
+
+        // Set the stack pointer for non-maskable interrupts.
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            VirtAddr::from_ptr(addr_of!(STACK)) + STACK_SIZE
+        };
+
+        // Set the stack pointer for machine checks.
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = {
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            VirtAddr::from_ptr(addr_of!(STACK)) + STACK_SIZE
         };
 
         tss
@@ -70,11 +94,12 @@ lazy_static! {
         let mut gdt = GlobalDescriptorTable::new();
 
         // Add entries to the Global Descriptor Table (GDT).
+        // SYSCALL and SYSRET need kernel data straight after kernel code and user code straight after user data
         let code = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss = gdt.add_entry(Descriptor::tss_segment(&TSS));
         let data = gdt.add_entry(Descriptor::kernel_data_segment());
-        let user_code = gdt.add_entry(Descriptor::user_code_segment());
         let user_data = gdt.add_entry(Descriptor::user_data_segment());
+        let user_code = gdt.add_entry(Descriptor::user_code_segment());
+        let tss = gdt.add_entry(Descriptor::tss_segment(*TSS_INIT));
 
         (
             gdt,
@@ -89,15 +114,30 @@ lazy_static! {
     };
 }
 
-#[allow(dead_code)]
-struct Selectors {
-    code: SegmentSelector,
-    tss: SegmentSelector,
-    data: SegmentSelector,
+/// The segment selectors in the GDT, user selectors have a requested privilege level of 3
+pub struct Selectors {
+    pub code: SegmentSelector,
+    pub tss: SegmentSelector,
+    pub data: SegmentSelector,
     pub user_code: SegmentSelector,
     pub user_data: SegmentSelector,
 }
 
+/// The segment selectors, [init] must have been called
+pub fn selectors() -> &'static Selectors {
+    &GDT.1
+}
+
+/// Sets the stack the CPU switches to when an interrupt or exception comes from user mode
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (*addr_of_mut!(TSS)).privilege_stack_table[0] = top };
+}
+
+/// Pointer to the stack [set_kernel_stack] sets, for assembly that sets it while entering user mode
+pub(crate) fn kernel_stack_slot() -> *mut u64 {
+    unsafe { addr_of_mut!((*addr_of_mut!(TSS)).privilege_stack_table[0]) as *mut u64 }
+}
+
 /// Initializes the Global Descriptor Table (GDT) and Task State Segment (TSS).
 pub fn init() {
     // Load the GDT.
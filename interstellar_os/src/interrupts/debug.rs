@@ -0,0 +1,159 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The debug and breakpoint exceptions
+//!
+//! `#DB` and `#BP` save every register the way a system call does, so a tracer sees and can change all of them when a
+//! traced program single steps or reaches a breakpoint, see [crate::process::trace]. Other user programs get SIGTRAP.
+//!
+//! From the kernel a breakpoint is only logged and a debug exception panics.
+
+use alloc::format;
+use core::arch::{asm, global_asm};
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use crate::cpu;
+use crate::other::log::LOGGER;
+use crate::process::signal::{self, UserReturn};
+use crate::process::trace;
+use crate::syscall::SyscallFrame;
+use crate::user::UserFault;
+
+global_asm!(
+    ".global debug_exception_entry",
+    "debug_exception_entry:",
+    "push rax",
+    "lea rax, [rip + debug_exception]",
+    "jmp debug_trap_entry",
+    "",
+    ".global breakpoint_exception_entry",
+    "breakpoint_exception_entry:",
+    "push rax",
+    "lea rax, [rip + breakpoint_exception]",
+    "jmp debug_trap_entry",
+    "",
+    // The rest of a SyscallFrame on top of the interrupt frame and rax, then the handler in rax is called with it
+    "debug_trap_entry:",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // The stack is 16 byte aligned here
+    "mov rdi, rsp",
+    "cld",
+    "call rax",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+);
+
+extern "C" {
+    fn debug_exception_entry();
+    fn breakpoint_exception_entry();
+}
+
+/// Address of the `#DB` handler for the IDT
+pub(crate) fn debug_entry() -> VirtAddr {
+    VirtAddr::new(debug_exception_entry as usize as u64)
+}
+
+/// Address of the `#BP` handler for the IDT
+pub(crate) fn breakpoint_entry() -> VirtAddr {
+    VirtAddr::new(breakpoint_exception_entry as usize as u64)
+}
+
+#[no_mangle]
+extern "C" fn debug_exception(frame: &mut SyscallFrame) {
+    if frame.cs & 3 != 3 {
+        panic!("EXCEPTION: DEBUG\n{:#x?}", frame);
+    }
+
+    from_user(frame, trace::single_step, UserFault::SingleStep);
+}
+
+#[no_mangle]
+extern "C" fn breakpoint_exception(frame: &mut SyscallFrame) {
+    if frame.cs & 3 != 3 {
+        unsafe { LOGGER.get().unwrap().force_unlock() };
+        LOGGER
+            .get()
+            .unwrap()
+            .lock()
+            .error(&format!("EXCEPTION: BREAKPOINT\n{frame:#x?}"));
+        return;
+    }
+
+    from_user(frame, trace::breakpoint, UserFault::Breakpoint);
+}
+
+/// Gives the exception to the tracer with `traced`, or sends the program SIGTRAP for `fault` if it is not traced
+///
+/// Pending signals are delivered on the way back like at the end of a system call
+fn from_user(frame: &mut SyscallFrame, traced: fn(&mut SyscallFrame) -> bool, fault: UserFault) {
+    // The program's alignment check flag is still set, with SMAP on that would let the kernel touch user pages
+    if cpu::protections().smap {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+
+    // Stopping blocks until the tracer resumes the thread
+    interrupts::enable();
+
+    let mut to = UserReturn {
+        rip: frame.rip,
+        rsp: frame.rsp,
+        rflags: frame.rflags,
+    };
+
+    if traced(frame) {
+        (to.rip, to.rsp, to.rflags) = (frame.rip, frame.rsp, frame.rflags);
+    } else {
+        LOGGER
+            .get()
+            .unwrap()
+            .lock()
+            .warn(&format!("User program got {:?} at {:#x}", fault, frame.rip));
+        signal::deliver_fault(&mut to, fault);
+    }
+
+    signal::deliver(&mut to);
+    (frame.rip, frame.rsp, frame.rflags) = (to.rip, to.rsp, to.rflags);
+
+    interrupts::disable();
+}
@@ -16,25 +16,69 @@
 
 use pic8259::ChainedPics;
 use x86_64::{
+    instructions::interrupts,
     instructions::port::{Port, PortReadOnly},
-    registers::control::Cr2,
+    registers::control::{Cr2, Cr3},
+    registers::rflags::RFlags,
     structures::idt::{InterruptStackFrame, PageFaultErrorCode},
+    structures::paging::PageTableFlags,
+    VirtAddr,
 };
 
 use crate::other::log::LOGGER;
+use crate::process::signal::UserReturn;
+use crate::user::UserFault;
 
 //###############################################
 //        Exception handlers
 //###############################################
 
-/// Handler for the divide by zero exception
-pub extern "x86-interrupt" fn divide_by_zero_fault_handler(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
+/// Sends the user program that caused an exception the signal for it, returns false if the exception came from the kernel
+///
+/// Returns true once the program's signal handler is set up to run, the exception handler has to return to it.
+/// Does not return if the program does not handle the signal
+fn signal_user_program(stack_frame: &mut InterruptStackFrame, fault: UserFault) -> bool {
+    if stack_frame.code_segment & 3 != 3 {
+        return false;
+    }
+
+    let mut to = UserReturn::from_interrupt(stack_frame);
+
+    with_interrupted_flags(stack_frame, || {
+        LOGGER.get().unwrap().lock().warn(&alloc::format!(
+            "User program got {:?} at {:#x}",
+            fault,
+            stack_frame.instruction_pointer.as_u64()
+        ));
+
+        crate::process::signal::deliver_fault(&mut to, fault);
+    });
+    to.apply(stack_frame);
+
+    true
 }
 
-/// Handler for the debug exception
-pub extern "x86-interrupt" fn debug_handler(stack_frame: InterruptStackFrame) {
-    panic!("EXCEPTION: DEBUG\n{:#?}", stack_frame);
+/// Runs `f` with interrupts enabled if the interrupted code had them enabled, like a system call does
+///
+/// `f` takes locks such as the logger and the process table, a thread that was preempted holding one
+/// could never run again to release it if interrupts stayed disabled
+fn with_interrupted_flags<R>(stack_frame: &InterruptStackFrame, f: impl FnOnce() -> R) -> R {
+    if stack_frame.cpu_flags & RFlags::INTERRUPT_FLAG.bits() == 0 {
+        return f();
+    }
+
+    interrupts::enable();
+    let result = f();
+    interrupts::disable();
+    result
+}
+
+/// Handler for the divide by zero exception
+pub extern "x86-interrupt" fn divide_by_zero_fault_handler(mut stack_frame: InterruptStackFrame) {
+    if signal_user_program(&mut stack_frame, UserFault::DivideError) {
+        return;
+    }
+    panic!("EXCEPTION: DIVIDE BY ZERO\n{:#?}", stack_frame);
 }
 
 /// Handler for the non-maskable interrupt exception
@@ -42,28 +86,27 @@ pub extern "x86-interrupt" fn non_masked_interrupt_handler(stack_frame: Interrup
     panic!("EXCEPTION: NON-MASKABLE INTERRUPT\n{:#?}", stack_frame);
 }
 
-/// Handler for the breakpoint exception
-pub extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    unsafe { LOGGER.get().unwrap().force_unlock() };
-    LOGGER
-        .get()
-        .unwrap()
-        .lock()
-        .error(&alloc::format!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame));
-}
-
 /// Handler for the overflow exception
-pub extern "x86-interrupt" fn overflow_handler(stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn overflow_handler(mut stack_frame: InterruptStackFrame) {
+    if signal_user_program(&mut stack_frame, UserFault::Overflow) {
+        return;
+    }
     panic!("EXCEPTION: OVERFLOW\n{:#?}", stack_frame);
 }
 
 /// Handler for the bound-range-exceeded exception
-pub extern "x86-interrupt" fn bound_range_exceeded_handler(stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn bound_range_exceeded_handler(mut stack_frame: InterruptStackFrame) {
+    if signal_user_program(&mut stack_frame, UserFault::BoundRange) {
+        return;
+    }
     panic!("EXCEPTION: BOUND_RANGE_EXCEEDED\n{:#?}", stack_frame);
 }
 
 /// Handler for the invalid opcode exception
-pub extern "x86-interrupt" fn invalid_opcode_fault_handler(stack_frame: InterruptStackFrame) {
+pub extern "x86-interrupt" fn invalid_opcode_fault_handler(mut stack_frame: InterruptStackFrame) {
+    if signal_user_program(&mut stack_frame, UserFault::InvalidOpcode) {
+        return;
+    }
     panic!("EXCEPTION: INVALID OPCODE\n{:#?}", stack_frame);
 }
 
@@ -104,17 +147,23 @@ pub extern "x86-interrupt" fn segment_not_present_handler(
 
 /// Handler for the stack-segment-fault exception
 pub extern "x86-interrupt" fn stack_segment_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) {
+    if signal_user_program(&mut stack_frame, UserFault::StackSegment) {
+        return;
+    }
     panic!("EXCEPTION: STACK-SEGMENT-FAULT\n{:#?}", stack_frame);
 }
 
 /// Handler for the general protection fault exception
 pub extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     stack_segment: u64,
 ) {
+    if signal_user_program(&mut stack_frame, UserFault::GeneralProtection) {
+        return;
+    }
     panic!(
         "EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}\nStack Segment: {}",
         stack_frame, stack_segment
@@ -123,10 +172,37 @@ pub extern "x86-interrupt" fn general_protection_fault_handler(
 
 /// Handler for the page-fault exception
 pub extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode,
 ) {
     let address = Cr2::read();
+
+    // A write to a page shared by fork, the writer gets its own copy and tries again
+    if error_code
+        .contains(PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE)
+        && crate::user::address_space::handle_cow_fault(address)
+    {
+        return;
+    }
+
+    // A system call copying to or from user memory that went away, the copy fails instead
+    if crate::user::access::fixup(&mut stack_frame, address) {
+        return;
+    }
+
+    if signal_user_program(&mut stack_frame, UserFault::PageFault(address.as_u64())) {
+        return;
+    }
+
+    if let Some(violation) = security_violation(error_code, address) {
+        panic!(
+            "SECURITY FAULT: {} violation at {:#x}\n{:#?}",
+            violation,
+            address.as_u64(),
+            stack_frame
+        );
+    }
+
     let protv = error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
     let write = error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE);
     let user = error_code.contains(PageFaultErrorCode::USER_MODE);
@@ -145,6 +221,27 @@ pub extern "x86-interrupt" fn page_fault_handler(
     );
 }
 
+/// Names the protection a page fault from ring 0 broke, if it was caused by SMEP or SMAP
+fn security_violation(error_code: PageFaultErrorCode, address: VirtAddr) -> Option<&'static str> {
+    if error_code.contains(PageFaultErrorCode::USER_MODE)
+        || !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION)
+    {
+        return None;
+    }
+
+    let (level_4, _) = Cr3::read();
+    let flags = crate::user::address_space::flags(level_4, address)?;
+    if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return None;
+    }
+
+    if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+        Some("SMEP")
+    } else {
+        Some("SMAP")
+    }
+}
+
 /// Handler for the x87-floating-point exception
 pub extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptStackFrame) {
     panic!("EXCEPTION: x87-FLOATING-POINT\n{:#?}", stack_frame);
@@ -152,20 +249,18 @@ pub extern "x86-interrupt" fn x87_floating_point_handler(stack_frame: InterruptS
 
 /// Handler for the alignment-check exception
 pub extern "x86-interrupt" fn alignment_check_handler(
-    stack_frame: InterruptStackFrame,
+    mut stack_frame: InterruptStackFrame,
     error_code: u64,
 ) {
+    if signal_user_program(&mut stack_frame, UserFault::AlignmentCheck) {
+        return;
+    }
     panic!(
         "EXCEPTION: ALIGNMENT-CHECK\nError Code: {:?}\n{:#?}",
         error_code, stack_frame
     );
 }
 
-/// Handler for the machine-check exception
-pub extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
-    panic!("EXCEPTION: MACHINE-CHECK\n{:#?}", stack_frame);
-}
-
 /// Handler for the simd-floating-point exception
 pub extern "x86-interrupt" fn simd_floating_point_handler(stack_frame: InterruptStackFrame) {
     panic!("EXCEPTION: SIMD-FLOATING-POINT\n{:#?}", stack_frame);
@@ -213,7 +308,7 @@ pub enum InterruptIndex {
     _LPT2,                                    // 5/38
     _FloppyDisk,                              // 6/39
     _LPT1,                                    // 7/40
-    _RTC,                                     // 8/41
+    Rtc,                                      // 8/41
     _Free1,                                   // 9/42
     _Free2,                                   // 10/43
     _Free3,                                   // 11/44
@@ -224,6 +319,7 @@ pub enum InterruptIndex {
     ApicError = LAPIC_INTERRUPT_INDEX_OFFSET, // 49
     Timer,                                    // 50
     Spurious,                                 // 51
+    Hpet,                                     // 52
 }
 
 impl InterruptIndex {
@@ -245,6 +341,7 @@ pub enum IoApicTableIndex {
     // These can be different depending on the UEFI software but most map them 1:1 the same as the PICS
     Pit = 0, // For some reason this is 2 instead of 0 for QEMUs IOAPIC but we use the interrupt source overide table entries to remap it
     Keyboard = 1,
+    Rtc = 8,
     Mouse = 12,
 }
 
@@ -275,7 +372,7 @@ pub extern "x86-interrupt" fn error_interrupt_handler(stack_frame: InterruptStac
         .get()
         .unwrap()
         .lock()
-        .error(&alloc::format!("APIC ERROR: {:#?}", stack_frame));
+        .error(&alloc::format!("APIC ERROR: {stack_frame:#?}"));
     unsafe {
         if let Some(mut apic) = super::LAPIC.get().unwrap().try_lock() {
             apic.end_of_interrupt();
@@ -286,8 +383,11 @@ pub extern "x86-interrupt" fn error_interrupt_handler(stack_frame: InterruptStac
     }
 }
 
-pub extern "x86-interrupt" fn apic_timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe { crate::time::APIC_COUNT.fetch_add(1, core::sync::atomic::Ordering::SeqCst) };
+pub extern "x86-interrupt" fn apic_timer_interrupt_handler(mut stack_frame: InterruptStackFrame) {
+    crate::time::APIC_COUNT.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
+
+    crate::time::timer::handle_interrupt();
+
     unsafe {
         if let Some(mut apic) = super::LAPIC.get().unwrap().try_lock() {
             apic.end_of_interrupt();
@@ -296,13 +396,24 @@ pub extern "x86-interrupt" fn apic_timer_interrupt_handler(_stack_frame: Interru
             super::LAPIC.get().unwrap().lock().end_of_interrupt();
         }
     }
+
+    // After the end of interrupt as the next thread may not return through this handler for a while
+    crate::thread::preempt();
+
+    // Signals reach a program that never makes system calls here, a killed program ends instead of going back
+    if stack_frame.code_segment & 3 == 3 {
+        let mut to = UserReturn::from_interrupt(&stack_frame);
+        with_interrupted_flags(&stack_frame, || crate::process::signal::deliver(&mut to));
+        to.apply(&mut stack_frame);
+    }
 }
 
 pub extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
-    LOGGER.get().unwrap().lock().error(&alloc::format!(
-        "SPURIOUS HARDWARE ERROR: {:#?}",
-        stack_frame
-    ));
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .error(&alloc::format!("SPURIOUS HARDWARE ERROR: {stack_frame:#?}"));
     unsafe {
         if let Some(mut apic) = super::LAPIC.get().unwrap().try_lock() {
             apic.end_of_interrupt();
@@ -317,9 +428,37 @@ pub extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptS
 //        IOAPIC interrupt handlers
 //###############################################
 
+/// Handler for the HPET comparator interrupts
+pub extern "x86-interrupt" fn hpet_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::time::hpet::handle_interrupt();
+
+    unsafe {
+        if let Some(mut apic) = super::LAPIC.get().unwrap().try_lock() {
+            apic.end_of_interrupt();
+        } else {
+            super::LAPIC.get().unwrap().force_unlock();
+            super::LAPIC.get().unwrap().lock().end_of_interrupt();
+        }
+    } // Tell It We Are Done
+}
+
+/// Handler for the RTC periodic interrupt
+pub extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::time::rtc::handle_interrupt();
+
+    unsafe {
+        if let Some(mut apic) = super::LAPIC.get().unwrap().try_lock() {
+            apic.end_of_interrupt();
+        } else {
+            super::LAPIC.get().unwrap().force_unlock();
+            super::LAPIC.get().unwrap().lock().end_of_interrupt();
+        }
+    } // Tell It We Are Done
+}
+
 /// Handler for the PIT interrupt
 pub extern "x86-interrupt" fn pit_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    unsafe { crate::time::PIT_COUNT.fetch_add(1, core::sync::atomic::Ordering::SeqCst) };
+    crate::time::PIT_COUNT.fetch_add(1, core::sync::atomic::Ordering::SeqCst);
 
     unsafe {
         if let Some(mut apic) = super::LAPIC.get().unwrap().try_lock() {
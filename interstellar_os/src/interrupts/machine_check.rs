@@ -0,0 +1,509 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Machine Check Architecture (MCA) support
+//!
+//! On boot every MCi_CTL bank is enabled and CR4.MCE is set so the CPU raises `#MC` for uncorrected errors.
+//!
+//! Corrected errors do not raise an exception, the CPU just leaves them in the banks,
+//! so [poll] is run from a kernel timer every [POLL_INTERVAL] to pick them up once [start_polling] has been called.
+//!
+//! To test this in QEMU open the monitor and inject an error for example:
+//!
+//! `mce 0 1 0x9000000000000000 0 0 0` - a corrected error in bank 1 that the poll will log
+//!
+//! `mce 0 1 0xbe00000000000000 0 0 0` - an uncorrected error in bank 1 that will raise `#MC`
+
+use alloc::format;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use uart_16550::SerialPort;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::{Cr4, Cr4Flags};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::idt::InterruptStackFrame;
+
+use crate::other::log::LOGGER;
+
+/// Machine check global capability register
+const IA32_MCG_CAP: u32 = 0x179;
+/// Machine check global status register
+const IA32_MCG_STATUS: u32 = 0x17A;
+/// Machine check global control register - only present if MCG_CAP.MCG_CTL_P is set
+const IA32_MCG_CTL: u32 = 0x17B;
+/// The MSR of MC0_CTL - each bank has 4 MSRs CTL, STATUS, ADDR, MISC
+const IA32_MC0_CTL: u32 = 0x400;
+
+/// MCG_CAP bits 0-7 - number of reporting banks
+const MCG_CAP_COUNT_MASK: u64 = 0xFF;
+/// MCG_CAP bit 8 - IA32_MCG_CTL is present
+const MCG_CAP_CTL_P: u64 = 1 << 8;
+/// MCG_CAP bit 24 - software error recovery, MCi_STATUS.S and AR classify uncorrected errors
+const MCG_CAP_SER_P: u64 = 1 << 24;
+
+/// MCG_STATUS bit 0 - restart IP valid, execution can continue from the pushed RIP
+const MCG_STATUS_RIPV: u64 = 1 << 0;
+/// MCG_STATUS bit 1 - error IP valid, the pushed RIP is directly associated with the error
+const MCG_STATUS_EIPV: u64 = 1 << 1;
+/// MCG_STATUS bit 2 - machine check in progress
+const MCG_STATUS_MCIP: u64 = 1 << 2;
+
+/// MCi_STATUS bit 63 - the bank contains a valid error
+const MCI_STATUS_VAL: u64 = 1 << 63;
+/// MCi_STATUS bit 62 - an error was lost because the bank already held one
+const MCI_STATUS_OVER: u64 = 1 << 62;
+/// MCi_STATUS bit 61 - the error was not corrected by the processor
+const MCI_STATUS_UC: u64 = 1 << 61;
+/// MCi_STATUS bit 60 - reporting of this error was enabled by MCi_CTL
+const MCI_STATUS_EN: u64 = 1 << 60;
+/// MCi_STATUS bit 59 - MCi_MISC holds additional information
+const MCI_STATUS_MISCV: u64 = 1 << 59;
+/// MCi_STATUS bit 58 - MCi_ADDR holds the address of the error
+const MCI_STATUS_ADDRV: u64 = 1 << 58;
+/// MCi_STATUS bit 57 - the processor context is corrupt and cannot be restarted
+const MCI_STATUS_PCC: u64 = 1 << 57;
+/// MCi_STATUS bit 56 - the error was signaled with #MC, only defined if MCG_CAP.SER_P is set
+const MCI_STATUS_S: u64 = 1 << 56;
+/// MCi_STATUS bit 55 - software has to act on the error before restarting, only defined if MCG_CAP.SER_P is set
+const MCI_STATUS_AR: u64 = 1 << 55;
+
+/// The first serial port, the `#MC` handler writes to it directly as the interrupted code may hold any lock
+const SERIAL_PORT: u16 = 0x3f8;
+
+/// Time between each poll of the banks for corrected errors
+pub const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Set once the banks have been enabled
+static MCE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Number of reporting banks read from MCG_CAP
+static BANK_COUNT: AtomicU8 = AtomicU8::new(0);
+
+/// Set if MCG_CAP.SER_P says uncorrected errors are classified for software recovery
+static SOFTWARE_RECOVERY: AtomicBool = AtomicBool::new(false);
+
+/// Number of corrected errors found by [poll]
+static CORRECTED_ERRORS: AtomicU64 = AtomicU64::new(0);
+
+/// Initialize machine check architecture
+///
+/// Enables every MCi_CTL bank, clears any errors left over from before boot and sets CR4.MCE
+pub fn init() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Initializing machine check architecture", file!(), line!());
+
+    let cpuid = raw_cpuid::CpuId::new();
+
+    let (has_mce, has_mca) = match cpuid.get_feature_info() {
+        Some(feat_info) => (feat_info.has_mce(), feat_info.has_mca()),
+        None => (false, false),
+    };
+
+    if !has_mce {
+        LOGGER
+            .get()
+            .unwrap()
+            .lock()
+            .warn("CPU does not support machine check exceptions");
+        return;
+    }
+
+    if has_mca {
+        let mcg_cap = unsafe { Msr::new(IA32_MCG_CAP).read() };
+        let bank_count = (mcg_cap & MCG_CAP_COUNT_MASK) as u8;
+
+        if mcg_cap & MCG_CAP_CTL_P != 0 {
+            unsafe { Msr::new(IA32_MCG_CTL).write(u64::MAX) };
+        }
+
+        for bank in 0..bank_count {
+            // Report every error type the bank supports
+            unsafe { Msr::new(mci_ctl(bank)).write(u64::MAX) };
+
+            // Log then clear anything left over from the firmware or a previous boot
+            let status = unsafe { Msr::new(mci_status(bank)).read() };
+            if status & MCI_STATUS_VAL != 0 {
+                let record = MachineCheckRecord::read(bank, 0);
+                LOGGER
+                    .get()
+                    .unwrap()
+                    .lock()
+                    .warn(&format!("Machine check from before boot: {record}"));
+            }
+            unsafe { Msr::new(mci_status(bank)).write(0) };
+        }
+
+        BANK_COUNT.store(bank_count, Ordering::SeqCst);
+        SOFTWARE_RECOVERY.store(mcg_cap & MCG_CAP_SER_P != 0, Ordering::SeqCst);
+
+        LOGGER
+            .get()
+            .unwrap()
+            .lock()
+            .info(&format!("Machine check banks enabled: {bank_count}"));
+    } else {
+        LOGGER
+            .get()
+            .unwrap()
+            .lock()
+            .warn("CPU does not support machine check architecture only #MC will be reported");
+    }
+
+    unsafe { Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION)) };
+
+    MCE_ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Returns true if machine check exceptions have been enabled
+pub fn enabled() -> bool {
+    MCE_ENABLED.load(Ordering::SeqCst)
+}
+
+/// Returns the number of machine check reporting banks
+pub fn bank_count() -> u8 {
+    BANK_COUNT.load(Ordering::SeqCst)
+}
+
+/// Returns true if the CPU classifies uncorrected errors for software recovery
+pub fn software_recovery() -> bool {
+    SOFTWARE_RECOVERY.load(Ordering::SeqCst)
+}
+
+/// Returns the number of corrected errors found by [poll] since boot
+pub fn corrected_errors() -> u64 {
+    CORRECTED_ERRORS.load(Ordering::SeqCst)
+}
+
+/// Starts polling the banks every [POLL_INTERVAL]
+///
+/// This needs the kernel timers so it is called after [crate::time::init]
+pub fn start_polling() {
+    if !enabled() {
+        return;
+    }
+
+    if crate::time::timer::add_periodic(POLL_INTERVAL, |_| poll(), 0).is_err() {
+        LOGGER
+            .get()
+            .unwrap()
+            .lock()
+            .warn("Could not add timer to poll machine check banks");
+    }
+}
+
+/// Polls every bank for corrected errors
+///
+/// This is called from the LAPIC timer interrupt so it must not block or allocate,
+/// any errors found are handed to [crate::task::machine_check] to be logged
+pub fn poll() {
+    if !enabled() {
+        return;
+    }
+
+    // The timer may poll in the middle of a poll from a task, reading, clearing and counting a bank
+    // with interrupts disabled makes sure each record is only counted once
+    interrupts::without_interrupts(|| {
+        for bank in 0..bank_count() {
+            let status = unsafe { Msr::new(mci_status(bank)).read() };
+
+            // Uncorrected errors are left for the #MC handler
+            if status & MCI_STATUS_VAL != 0 && status & MCI_STATUS_UC == 0 {
+                let record = MachineCheckRecord::read(bank, 0);
+                unsafe { Msr::new(mci_status(bank)).write(0) };
+                CORRECTED_ERRORS.fetch_add(1, Ordering::SeqCst);
+                crate::task::machine_check::write(record);
+            }
+        }
+    });
+}
+
+/// Handler for the machine-check exception
+///
+/// The IDT entry for `#MC` expects a diverging handler but an error that the processor marks as
+/// recoverable should return, so this is installed with `set_handler_addr`
+///
+/// `#MC` cannot be masked so the interrupted code may hold the heap, logger or serial lock,
+/// everything here is formatted on the stack and written straight to the serial port
+pub extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) {
+    let mcg_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+    let software_recovery = software_recovery();
+
+    let mut fatal = mcg_status & MCG_STATUS_RIPV == 0;
+    let mut found = false;
+
+    // One bit for each bank holding an error, they are only cleared once every bank has been read
+    let mut valid = [0u64; 4];
+
+    let mut serial = unsafe { SerialPort::new(SERIAL_PORT) };
+
+    let _ = writeln!(
+        serial,
+        "EXCEPTION: MACHINE-CHECK\nMCG_STATUS: {:#x} (RIPV: {}, EIPV: {}, MCIP: {})",
+        mcg_status,
+        mcg_status & MCG_STATUS_RIPV != 0,
+        mcg_status & MCG_STATUS_EIPV != 0,
+        mcg_status & MCG_STATUS_MCIP != 0,
+    );
+
+    for bank in 0..bank_count() {
+        let record = MachineCheckRecord::read(bank, mcg_status);
+
+        if !record.is_valid() {
+            continue;
+        }
+
+        found = true;
+        valid[bank as usize / 64] |= 1 << (bank % 64);
+
+        if !record.recoverable(software_recovery) {
+            fatal = true;
+        }
+
+        let _ = writeln!(serial, "{record}");
+    }
+
+    if !found {
+        // No banks or no valid bank, nothing tells us it is safe to carry on
+        fatal = true;
+    }
+
+    if fatal {
+        // The panic handler allocates so the report above is all that is written
+        let _ = writeln!(serial, "Fatal machine check\n{stack_frame:#?}");
+
+        #[cfg(feature = "test")]
+        crate::exit_qemu(crate::QemuExitCode::Failed);
+
+        #[cfg(not(feature = "test"))]
+        crate::other::assembly::hlt_loop();
+    }
+
+    for bank in 0..bank_count() {
+        if valid[bank as usize / 64] & (1 << (bank % 64)) != 0 {
+            unsafe { Msr::new(mci_status(bank)).write(0) };
+        }
+    }
+
+    // Clear MCIP otherwise another #MC will cause a shutdown
+    unsafe { Msr::new(IA32_MCG_STATUS).write(mcg_status & !MCG_STATUS_MCIP) };
+
+    let _ = writeln!(serial, "Recovered from machine check");
+}
+
+/// A decoded machine check error read from one bank
+#[derive(Debug, Clone, Copy)]
+pub struct MachineCheckRecord {
+    /// The bank the error was read from
+    pub bank: u8,
+    /// Raw MCi_STATUS
+    pub status: u64,
+    /// MCi_ADDR if MCi_STATUS.ADDRV is set
+    pub addr: Option<u64>,
+    /// MCi_MISC if MCi_STATUS.MISCV is set
+    pub misc: Option<u64>,
+    /// MCG_STATUS at the time of the error - 0 when polled
+    pub mcg_status: u64,
+}
+
+impl MachineCheckRecord {
+    /// Reads the status, address and misc registers of a bank
+    pub fn read(bank: u8, mcg_status: u64) -> MachineCheckRecord {
+        let status = unsafe { Msr::new(mci_status(bank)).read() };
+
+        let addr = if status & MCI_STATUS_VAL != 0 && status & MCI_STATUS_ADDRV != 0 {
+            Some(unsafe { Msr::new(mci_addr(bank)).read() })
+        } else {
+            None
+        };
+
+        let misc = if status & MCI_STATUS_VAL != 0 && status & MCI_STATUS_MISCV != 0 {
+            Some(unsafe { Msr::new(mci_misc(bank)).read() })
+        } else {
+            None
+        };
+
+        MachineCheckRecord {
+            bank,
+            status,
+            addr,
+            misc,
+            mcg_status,
+        }
+    }
+
+    /// The bank holds a valid error
+    pub fn is_valid(&self) -> bool {
+        self.status & MCI_STATUS_VAL != 0
+    }
+
+    /// Another error was lost while this one was in the bank
+    pub fn overflow(&self) -> bool {
+        self.status & MCI_STATUS_OVER != 0
+    }
+
+    /// The processor did not correct the error
+    pub fn uncorrected(&self) -> bool {
+        self.status & MCI_STATUS_UC != 0
+    }
+
+    /// Reporting of the error was enabled
+    pub fn enabled(&self) -> bool {
+        self.status & MCI_STATUS_EN != 0
+    }
+
+    /// The processor context is corrupt and execution cannot continue
+    pub fn processor_context_corrupt(&self) -> bool {
+        self.status & MCI_STATUS_PCC != 0
+    }
+
+    /// Execution can be restarted from the pushed RIP
+    pub fn restartable(&self) -> bool {
+        self.mcg_status & MCG_STATUS_RIPV != 0
+    }
+
+    /// The error was signaled with `#MC` - MCi_STATUS.S, only meaningful with software recovery
+    pub fn signaled(&self) -> bool {
+        self.status & MCI_STATUS_S != 0
+    }
+
+    /// Software has to act on the error before restarting - MCi_STATUS.AR, only meaningful with software recovery
+    pub fn action_required(&self) -> bool {
+        self.status & MCI_STATUS_AR != 0
+    }
+
+    /// Returns true if execution can carry on after this error
+    ///
+    /// Corrected errors always can, an uncorrected error only if `software_recovery` (MCG_CAP.SER_P) is set
+    /// and it is classified as UCNA (no action) or SRAO (action optional), see the Intel SDM Volume 3 section 15.6.3.
+    /// The kernel cannot take the action an SRAR error asks for so those are fatal too
+    pub fn recoverable(&self, software_recovery: bool) -> bool {
+        if self.processor_context_corrupt() {
+            return false;
+        }
+
+        if !self.uncorrected() {
+            return true;
+        }
+
+        software_recovery && self.restartable() && !self.action_required()
+    }
+
+    /// The MCA error code - bits 0-15 of MCi_STATUS
+    pub fn error_code(&self) -> u16 {
+        self.status as u16
+    }
+
+    /// The model specific error code - bits 16-31 of MCi_STATUS
+    pub fn model_specific_code(&self) -> u16 {
+        (self.status >> 16) as u16
+    }
+
+    /// Describes the MCA error code using the architectural error code classes
+    pub fn describe(&self) -> &'static str {
+        decode_error_code(self.error_code())
+    }
+}
+
+impl core::fmt::Display for MachineCheckRecord {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "bank {}: {} {} (error code: {:#06x}, model specific: {:#06x}, status: {:#018x})",
+            self.bank,
+            if self.uncorrected() {
+                "uncorrected"
+            } else {
+                "corrected"
+            },
+            self.describe(),
+            self.error_code(),
+            self.model_specific_code(),
+            self.status,
+        )?;
+
+        if self.overflow() {
+            write!(f, " overflow")?;
+        }
+        if self.processor_context_corrupt() {
+            write!(f, " processor-context-corrupt")?;
+        }
+        if !self.enabled() {
+            write!(f, " not-enabled")?;
+        }
+        if let Some(addr) = self.addr {
+            write!(f, " addr: {addr:#x}")?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, " misc: {misc:#x}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Decodes an MCA error code into the simple or compound error class it belongs to
+///
+/// See the Intel SDM Volume 3 section 15.9 "Interpreting the MCA Error Codes"
+pub fn decode_error_code(code: u16) -> &'static str {
+    // Simple error codes
+    match code {
+        0x0000 => return "no error",
+        0x0001 => return "unclassified error",
+        0x0002 => return "microcode ROM parity error",
+        0x0003 => return "external error",
+        0x0004 => return "FRC error",
+        0x0005 => return "internal parity error",
+        0x0006 => return "SMM handler code access violation",
+        0x0400 => return "internal timer error",
+        0x0401..=0x07FF => return "internal unclassified error",
+        _ => {}
+    }
+
+    // Compound error codes - bit 12 is the correction report filtering bit so it is ignored
+    if code & 0xE800 == 0x0800 {
+        "bus or interconnect error"
+    } else if code & 0xEF00 == 0x0100 {
+        "cache hierarchy error"
+    } else if code & 0xEF80 == 0x0080 {
+        "memory controller error"
+    } else if code & 0xEFF0 == 0x0010 {
+        "TLB error"
+    } else if code & 0xEFFC == 0x000C {
+        "generic cache hierarchy error"
+    } else {
+        "unknown error"
+    }
+}
+
+fn mci_ctl(bank: u8) -> u32 {
+    IA32_MC0_CTL + 4 * bank as u32
+}
+
+fn mci_status(bank: u8) -> u32 {
+    IA32_MC0_CTL + 4 * bank as u32 + 1
+}
+
+fn mci_addr(bank: u8) -> u32 {
+    IA32_MC0_CTL + 4 * bank as u32 + 2
+}
+
+fn mci_misc(bank: u8) -> u32 {
+    IA32_MC0_CTL + 4 * bank as u32 + 3
+}
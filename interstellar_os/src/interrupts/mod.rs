@@ -28,12 +28,13 @@
 use crate::{gdt, other::log::LOGGER};
 use acpi::platform::interrupt::Polarity;
 use alloc::format;
+use core::sync::atomic::AtomicU64;
 use handlers::*;
 use lazy_static::lazy_static;
 use x86_64::instructions::port::Port;
 use x86_64::structures::idt::InterruptDescriptorTable;
 
-use x86_64::PhysAddr;
+use x86_64::{PhysAddr, PrivilegeLevel, VirtAddr};
 
 use spinning_top::Spinlock;
 
@@ -46,7 +47,10 @@ use x2apic::{
 
 use acpi::{platform::interrupt::Apic as ApicInfo, InterruptModel};
 
+mod debug;
 mod handlers;
+pub use handlers::InterruptIndex;
+pub mod machine_check;
 
 pub static LAPIC_BASE: OnceCell<u64> = OnceCell::uninit();
 
@@ -54,6 +58,12 @@ pub static LAPIC: OnceCell<Spinlock<LocalApic>> = OnceCell::uninit();
 
 pub static IOAPIC: OnceCell<Spinlock<IoApic>> = OnceCell::uninit();
 
+/// Vector of the `int 0x80` system call gate
+pub const SYSCALL_INTERRUPT_INDEX: usize = 0x80;
+
+/// LAPIC timer counts per second with the divider set in [init_lapic], measured against the PIT at boot
+pub static LAPIC_TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
 /*
 Vector |Exception/Interrupt |Mnemonic |Cause
 0 |Divide-by-Zero-Error |#DE |DIV, IDIV, AAM instructions
@@ -98,9 +108,6 @@ lazy_static! {
         // 0-31 = 32 total
 
         idt.divide_error.set_handler_fn(divide_by_zero_fault_handler);
-        idt.debug.set_handler_fn(debug_handler);
-        idt.non_maskable_interrupt.set_handler_fn(non_masked_interrupt_handler);
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.overflow.set_handler_fn(overflow_handler);
         idt.bound_range_exceeded.set_handler_fn(bound_range_exceeded_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_fault_handler);
@@ -115,7 +122,6 @@ lazy_static! {
         // Reserved
         idt.x87_floating_point.set_handler_fn(x87_floating_point_handler);
         idt.alignment_check.set_handler_fn(alignment_check_handler);
-        idt.machine_check.set_handler_fn(machine_check_handler);
         idt.simd_floating_point.set_handler_fn(simd_floating_point_handler);
         // Reserved
         // Control-Protection Exception
@@ -128,6 +134,21 @@ lazy_static! {
         unsafe {
             // Set the stack index for the double fault handler to switch the stack
             idt.double_fault.set_handler_fn(double_fault_handler).set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+
+            // NMI and machine checks can come while the system call entry is on the user stack so they get their own
+            idt.non_maskable_interrupt.set_handler_fn(non_masked_interrupt_handler).set_stack_index(gdt::NMI_IST_INDEX);
+
+            // The machine check handler returns for recoverable errors so it cannot use the diverging handler type
+            idt.machine_check
+                .set_handler_addr(VirtAddr::new(machine_check::machine_check_handler as usize as u64))
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+
+            // Debug exceptions save every register for debuggers, user programs may run int3 themselves
+            idt.debug.set_handler_addr(debug::debug_entry());
+            idt.breakpoint.set_handler_addr(debug::breakpoint_entry()).set_privilege_level(PrivilegeLevel::Ring3);
+
+            // System calls from user programs, the entry saves every register so it is not an x86-interrupt function
+            idt[SYSCALL_INTERRUPT_INDEX].set_handler_addr(crate::syscall::interrupt_entry()).set_privilege_level(PrivilegeLevel::Ring3);
         }
 
         //################################################
@@ -137,10 +158,12 @@ lazy_static! {
         idt[InterruptIndex::Pit.as_usize()].set_handler_fn(pit_interrupt_handler); // 33
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler); // 34
 
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler); // 41
         idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler); // 45
         idt[InterruptIndex::ApicError.as_usize()].set_handler_fn(error_interrupt_handler); // 46
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(apic_timer_interrupt_handler); // 47
         idt[InterruptIndex::Spurious.as_usize()].set_handler_fn(spurious_interrupt_handler); // 48
+        idt[InterruptIndex::Hpet.as_usize()].set_handler_fn(hpet_interrupt_handler); // 52
 
         idt
     };
@@ -175,6 +198,8 @@ pub fn init() {
 
     IDT.load();
 
+    machine_check::init();
+
     let acpi_info = crate::acpi::ACPI_INFO.get().unwrap().lock();
 
     let platform_info = acpi_info
@@ -266,8 +291,8 @@ fn init_lapic_timer() {
 
     unsafe { LAPIC.get().unwrap().lock().disable_timer() };
 
-    unsafe { crate::time::APIC_COUNT.store(0, core::sync::atomic::Ordering::SeqCst) };
-    unsafe { crate::time::PIT_COUNT.store(0, core::sync::atomic::Ordering::SeqCst) };
+    crate::time::APIC_COUNT.store(0, core::sync::atomic::Ordering::SeqCst);
+    crate::time::PIT_COUNT.store(0, core::sync::atomic::Ordering::SeqCst);
 
     unsafe { LAPIC.get().unwrap().lock().set_timer_initial(u32::MAX) };
 
@@ -276,7 +301,7 @@ fn init_lapic_timer() {
     // Wait for 10 ms using PIT
 
     loop {
-        if unsafe { crate::time::PIT_COUNT.load(core::sync::atomic::Ordering::SeqCst) } != 0 {
+        if crate::time::PIT_COUNT.load(core::sync::atomic::Ordering::SeqCst) != 0 {
             break;
         }
     }
@@ -291,7 +316,7 @@ fn init_lapic_timer() {
         .get()
         .unwrap()
         .lock()
-        .info(&format!("LAPIC count after 10ms: {} ", count));
+        .info(&format!("LAPIC count after 10ms: {count} "));
 
     let new_count = u32::MAX - count;
 
@@ -299,7 +324,9 @@ fn init_lapic_timer() {
         .get()
         .unwrap()
         .lock()
-        .info(&format!("New count: {}", new_count));
+        .info(&format!("New count: {new_count}"));
+
+    LAPIC_TIMER_FREQUENCY.store(new_count as u64 * 100, core::sync::atomic::Ordering::SeqCst);
 
     // set new timer settings
     unsafe {
@@ -359,6 +386,14 @@ fn init_ioapic(apic_info: &ApicInfo) {
                 IoApicTableIndex::Keyboard.into(),
             );
 
+            register_io_apic_entry(
+                &mut ioapic,
+                apic_info,
+                lapic.id() as u8,
+                InterruptIndex::Rtc.as_u8(),
+                IoApicTableIndex::Rtc.into(),
+            );
+
             register_io_apic_entry(
                 &mut ioapic,
                 apic_info,
@@ -397,6 +432,50 @@ fn init_ioapic(apic_info: &ApicInfo) {
     LOGGER.get().unwrap().lock().info("IOAPIC initialized");
 }
 
+/// Masks the PIT interrupt once the tickless timers and a better clocksource have taken over from it
+pub fn disable_pit_interrupt() {
+    let pit_irq: u8 = IoApicTableIndex::Pit.into();
+
+    let gsi = {
+        let acpi_info = crate::acpi::ACPI_INFO.get().unwrap().lock();
+
+        match acpi_info
+            .platform_info
+            .as_ref()
+            .map(|info| &info.interrupt_model)
+        {
+            Ok(InterruptModel::Apic(apic_info)) => apic_info
+                .interrupt_source_overrides
+                .iter()
+                .find(|iso| iso.isa_source == pit_irq)
+                .map_or(pit_irq, |iso| iso.global_system_interrupt as u8),
+            _ => pit_irq,
+        }
+    };
+
+    unsafe { IOAPIC.get().unwrap().lock().disable_irq(gsi) };
+}
+
+/// Routes a global system interrupt that is not an ISA interrupt to `vector` on the boot CPU
+///
+/// The entry is edge triggered and active high which is what the HPET comparators use
+pub fn route_gsi(gsi: u8, vector: u8) {
+    let lapic_id = unsafe { LAPIC.get().unwrap().lock().id() } as u8;
+
+    let mut entry = RedirectionTableEntry::default();
+    entry.set_mode(x2apic::ioapic::IrqMode::Fixed);
+    entry.set_dest(lapic_id);
+    entry.set_vector(vector);
+    entry.set_flags(IrqFlags::MASKED);
+
+    let mut ioapic = IOAPIC.get().unwrap().lock();
+
+    unsafe {
+        ioapic.set_table_entry(gsi, entry);
+        ioapic.enable_irq(gsi);
+    }
+}
+
 fn register_io_apic_entry(
     ioapic: &mut IoApic,
     apic_info: &ApicInfo,
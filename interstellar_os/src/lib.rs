@@ -17,11 +17,9 @@
 #![no_std]
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
-#![feature(const_mut_refs)]
 #![feature(let_chains)]
 #![feature(allocator_api)]
 #![feature(slice_from_ptr_range)]
-#![cfg(not(feature = "std"))]
 
 use bootloader_api::BootInfo as BI;
 use drivers::fs::initrd;
@@ -52,6 +50,7 @@ pub mod drivers {
 
 pub mod acpi;
 pub mod allocator;
+pub mod cpu;
 pub mod interrupts;
 pub mod memory;
 pub mod time;
@@ -65,8 +64,11 @@ pub mod other {
 }
 
 pub mod gdt;
+pub mod process;
 pub mod syscall;
 pub mod task;
+pub mod thread;
+pub mod user;
 
 extern crate alloc;
 
@@ -101,7 +103,7 @@ pub fn init(boot_info: &'static mut BI) {
 
     let mut ramdisk_address = None; // Will Be Required In Future For Drivers Ect
     let mut recursive_index = None; // Never Needed And Never Will Be Used
-    let mut tls_template = None; // Dont Know If We Need This
+    let mut tls_template = None; // The kernel has no thread locals, user programs get templates like it from PT_TLS
 
     // Needed to write to screen
     if let Some(fb) = boot_info.framebuffer.as_mut() {
@@ -162,7 +164,7 @@ pub fn init(boot_info: &'static mut BI) {
             .trace("Recursive Index Not Found", file!(), line!());
     }
 
-    // Dont Need This Either
+    // The kernel's own TLS template, see user::tls for the user ones
     if let Some(tls_templ) = boot_info.tls_template.as_mut().cloned() {
         tls_template = Some(tls_templ);
     } else {
@@ -263,14 +265,30 @@ pub fn init(boot_info: &'static mut BI) {
     // Initialize The Global Descriptor Table
     gdt::init();
 
+    // Give The Boot CPU Its Per-CPU Data
+    cpu::init();
+
+    // Stop The Kernel Running Or Touching User Pages By Mistake
+    cpu::enable_protections();
+
+    // Enable The SYSCALL Instruction For User Programs
+    syscall::init();
+
     // Parse The ACPI Tables
     acpi::init(PhysAddr::new(boot_info.rsdp_addr.into_option().unwrap()));
 
     // Create IDT And APIC Structures And Enable Interrupts
     interrupts::init();
 
+    // Select A Clocksource And Start The Tickless Timers
     time::init();
 
+    // Start Preemptive Kernel Threads
+    thread::init();
+
+    // Poll For Corrected Hardware Errors
+    interrupts::machine_check::start_polling();
+
     // Enable The PS/2 Keyboard
     drivers::hid::keyboard::init();
 
@@ -311,7 +329,7 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
             .get()
             .unwrap()
             .lock()
-            .error(format!("{}", info).as_str().trim());
+            .error(format!("{info}").as_str().trim());
         LOGGER.get().unwrap().lock().show_trace();
     }
     hlt_loop();
@@ -350,6 +368,16 @@ pub fn exit_qemu(exit_code: QemuExitCode) -> ! {
     hlt_loop();
 }
 
+/// The test runner runs the rest of a serial line starting with this in the QEMU monitor
+pub const QEMU_MONITOR_PREFIX: &str = "qemu-monitor: ";
+
+/// Asks the test runner to run a command in the QEMU monitor, for example to inject a machine check
+///
+/// This returns straight away, the command runs some time after
+pub fn qemu_monitor(command: &str) {
+    serial_println!("\n{}{}", QEMU_MONITOR_PREFIX, command);
+}
+
 pub trait Testable {
     fn run(&self);
 }
@@ -79,7 +79,7 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     let version_patch = BOOT_INFO.get().unwrap().lock().api_version.version_patch();
 
     let interstellar_os_version = INFO.get().unwrap().lock().os_version;
-    let bootloader_api_version = format!("{}.{}.{}", version_major, version_minor, version_patch);
+    let bootloader_api_version = format!("{version_major}.{version_minor}.{version_patch}");
 
     println!("Interstellar OS Version {}", interstellar_os_version);
     println!("Bootloader Version: {}\n", bootloader_api_version.as_str());
@@ -95,17 +95,17 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         .get()
         .unwrap()
         .lock()
-        .info(format!("Number of initrd files: {}", number_of_files).as_str());
+        .info(format!("Number of initrd files: {number_of_files}").as_str());
     LOGGER
         .get()
         .unwrap()
         .lock()
-        .info(format!("Total files size: {}", total_files_size).as_str());
+        .info(format!("Total files size: {total_files_size}").as_str());
     LOGGER
         .get()
         .unwrap()
         .lock()
-        .info(format!("File Names: {:?}", file_names).as_str());
+        .info(format!("File Names: {file_names:?}").as_str());
 
     LOGGER.get().unwrap().lock().info("Creating Task Executor");
     LOGGER
@@ -114,13 +114,19 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
         .lock()
         .trace("Creating Task Executor", file!(), line!());
 
-    let spawner = Spawner::new(100);
+    let spawner = Spawner::new();
 
     let mut executor = Executor::new(spawner.clone());
 
-    spawner.add(console_start());
+    spawner.add_named("console", console_start());
 
-    spawner.add(lib::task::mouse::process());
+    spawner.add_named("cursor_blink", lib::task::console_handler::cursor_blink());
+
+    spawner.add_named("mouse", lib::task::mouse::process());
+
+    spawner.add_named("machine_check", lib::task::machine_check::process());
+
+    lib::user::start_init();
 
     executor.run();
 }
@@ -15,6 +15,7 @@
 //along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use core::ops::Range;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 use alloc::format;
 use bootloader_api::info::{MemoryRegion, MemoryRegionKind, MemoryRegions};
@@ -24,8 +25,8 @@ use spin::Mutex;
 use spinning_top::Spinlock;
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, page::PageRange, FrameAllocator, Mapper, OffsetPageTable, Page,
-        PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+        mapper::MapToError, page::PageRange, FrameAllocator, FrameDeallocator, Mapper,
+        OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
@@ -40,12 +41,27 @@ lazy_static! {
     pub static ref MEMORY: OnceCell<Spinlock<Memory>> = OnceCell::uninit();
 }
 
+/// Where all of physical memory is mapped, set by [init]
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Physical address of the level 4 table the kernel booted with, set by [init]
+static KERNEL_PAGE_TABLE: AtomicU64 = AtomicU64::new(0);
+
 /// Initialize the Frame allocator and Mapper
 ///
 /// # Safety
 ///
 /// This function is unsafe because the caller must guarantee that the passed `physical_memory_offset` is valid
 pub unsafe fn init(physical_memory_offset: u64, memory_regions: &'static mut MemoryRegions) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset, Ordering::SeqCst);
+    KERNEL_PAGE_TABLE.store(
+        x86_64::registers::control::Cr3::read()
+            .0
+            .start_address()
+            .as_u64(),
+        Ordering::SeqCst,
+    );
+
     let level_4_table = active_level_4_table(physical_memory_offset);
     let table = OffsetPageTable::new(level_4_table, VirtAddr::new(physical_memory_offset));
 
@@ -124,11 +140,18 @@ impl Memory {
 }
 
 /// A [FrameAllocator] that returns usable frames from the bootloadeer's memory map
+///
+/// Frames given back with [FrameDeallocator::deallocate_frame] are handed out again first
 pub struct BootInfoFrameAllocator {
     memory_regions: &'static mut [MemoryRegion],
     next: usize,
+    /// The last frame given back, each free frame holds the address of the one given back before it
+    free: Option<PhysFrame>,
 }
 
+/// Marks the end of the free frame list
+const NO_FRAME: u64 = u64::MAX;
+
 impl BootInfoFrameAllocator {
     /// Create a [FrameAllocator] from the passed memory map.
     ///
@@ -141,6 +164,7 @@ impl BootInfoFrameAllocator {
         BootInfoFrameAllocator {
             memory_regions,
             next: 0,
+            free: None,
         }
     }
     /// Returns an iterator of usable frames
@@ -160,12 +184,29 @@ impl BootInfoFrameAllocator {
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        if let Some(frame) = self.free {
+            let next = unsafe { *phys_to_virt(frame.start_address()).as_ptr::<u64>() };
+            self.free =
+                (next != NO_FRAME).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        let next = self
+            .free
+            .map_or(NO_FRAME, |free| free.start_address().as_u64());
+        *phys_to_virt(frame.start_address()).as_mut_ptr::<u64>() = next;
+        self.free = Some(frame);
+    }
+}
+
 /// # Safety
 ///
 /// You must provide a correct physical_memory_offset
@@ -182,6 +223,40 @@ pub unsafe fn active_level_4_table(physical_memory_offset: u64) -> &'static mut
     &mut *page_table_ptr
 }
 
+/// The virtual address a physical address can be read and written at
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst) + addr.as_u64())
+}
+
+/// The level 4 page table the kernel booted with, the kernel half of every address space comes from it
+///
+/// This does not lock anything so it can be used from interrupt handlers
+pub fn kernel_page_table() -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(KERNEL_PAGE_TABLE.load(Ordering::SeqCst)))
+}
+
+/// Takes an unused frame from the frame allocator
+///
+/// Interrupts are disabled while the allocator is locked so this can be used from the page fault handler
+pub fn allocate_frame() -> Option<PhysFrame> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_ALLOCATOR.lock().as_mut()?.allocate_frame()
+    })
+}
+
+/// Gives a frame back to the frame allocator
+///
+/// # Safety
+///
+/// Nothing may map or use the frame anymore
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            allocator.deallocate_frame(frame);
+        }
+    })
+}
+
 pub fn active_level_4_table_phys_addr() -> PhysAddr {
     let page_table = MAPPER.lock().as_mut().unwrap().level_4_table() as *const _ as u64;
 
@@ -233,25 +308,27 @@ pub fn map_pages_from(start: PhysAddr, object_size: usize, region: PageRange) ->
 
     let virt = search_free_addr_from(num_pages, region).expect("error searching for free addr");
 
-    let mut mapper = MAPPER.lock();
-    let mapper = mapper.as_mut().unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
 
-    let mut frame_allocator = FRAME_ALLOCATOR.lock();
-    let frame_allocator = frame_allocator.as_mut().unwrap();
+        let mut frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_mut().unwrap();
 
-    for i in 0..num_pages.as_usize() {
-        let page = Page::<Size4KiB>::containing_address(virt + Size4KiB::SIZE * i as u64);
-        let frame = PhysFrame::containing_address(start_frame_addr + Size4KiB::SIZE * i as u64);
-        let flag =
-            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        for i in 0..num_pages.as_usize() {
+            let page = Page::<Size4KiB>::containing_address(virt + Size4KiB::SIZE * i as u64);
+            let frame = PhysFrame::containing_address(start_frame_addr + Size4KiB::SIZE * i as u64);
+            let flag =
+                PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
 
-        unsafe {
-            mapper
-                .map_to(page, frame, flag, frame_allocator)
-                .unwrap()
-                .flush();
+            unsafe {
+                mapper
+                    .map_to(page, frame, flag, frame_allocator)
+                    .unwrap()
+                    .flush();
+            }
         }
-    }
+    });
 
     let page_offset = start.as_u64() % Size4KiB::SIZE;
 
@@ -277,21 +354,23 @@ pub fn identity_map(
         .get()
         .unwrap()
         .lock()
-        .serial_debug(&format!("identitiy mapping frame: {:x?}", frame));
+        .serial_debug(&format!("identitiy mapping frame: {frame:x?}"));
 
     let flags = flags.unwrap_or_else(|| {
         PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE
     });
-    let mut mapper = MAPPER.lock();
-    let mapper = mapper.as_mut().unwrap();
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut mapper = MAPPER.lock();
+        let mapper = mapper.as_mut().unwrap();
 
-    let mut allocator = FRAME_ALLOCATOR.lock();
-    let allocator = allocator.as_mut().unwrap();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().unwrap();
 
-    unsafe {
-        mapper.identity_map(frame, flags, allocator)?.flush();
-    }
-    Ok(())
+        unsafe {
+            mapper.identity_map(frame, flags, allocator)?.flush();
+        }
+        Ok(())
+    })
 }
 
 pub fn identity_map_address(
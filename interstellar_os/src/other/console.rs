@@ -20,8 +20,11 @@ use crate::{
     memory::MEMORY,
     print, println,
 };
+use alloc::collections::BTreeMap;
 use alloc::format;
 use alloc::vec::Vec;
+use core::time::Duration;
+use spinning_top::Spinlock;
 
 /// Handles the console input by executing the corresponding commands.
 ///
@@ -54,6 +57,13 @@ pub fn handle_console(line: &str) -> bool {
                 "power" => power_command(args),
                 "mem" => check_memory(),
                 "time" => time_command(args),
+                "date" => date_command(),
+                "uptime" => uptime_command(),
+                "top" => top_command(),
+                "tasks" => tasks_command(args),
+                "ps" => ps_command(),
+                "kill" => kill_command(args),
+                "debug" => debug_command(args),
                 "color" => change_color(args),
                 "bgcolor" => {
                     let clear = change_background_color(args);
@@ -80,12 +90,13 @@ pub fn handle_console(line: &str) -> bool {
                 "stack_overflow" => {
                     stack_overflow();
                 }
+                _ if crate::user::find_program(command).is_some() => run_program(command, args),
                 _ => {
                     LOGGER
                         .get()
                         .unwrap()
                         .lock()
-                        .error(&format!("UNKNOWN COMMAND - <{}>", command));
+                        .error(&format!("UNKNOWN COMMAND - <{command}>"));
                 }
             }
         }
@@ -305,7 +316,7 @@ fn time_command(args: &[&str]) {
             boot"
             );
         } else if arg == "boot" {
-            let boot_time = unsafe { crate::time::GLOBAL_TIMER.get().unwrap().lock().elapsed() };
+            let boot_time = crate::time::GLOBAL_TIMER.get().unwrap().lock().elapsed();
 
             if boot_time.as_secs() > 60 {
                 if (boot_time.as_secs() / 60) > 60 {
@@ -328,6 +339,469 @@ fn time_command(args: &[&str]) {
     }
 }
 
+/// Prints the current date and time from the RTC
+fn date_command() {
+    println!("{}", crate::time::SystemTime::now().date_time());
+}
+
+/// Prints how long the OS has been running and the clocksource it was measured with
+fn uptime_command() {
+    let uptime = crate::time::Instant::now().since_boot().as_secs();
+
+    println!(
+        "Up {} days {:02}:{:02}:{:02} (clocksource: {:?})",
+        uptime / 86400,
+        (uptime % 86400) / 3600,
+        (uptime % 3600) / 60,
+        uptime % 60,
+        crate::time::clocksource()
+    );
+}
+
+/// When `top` last ran and the CPU time each thread had used by then
+static TOP_SAMPLE: Spinlock<Option<(u64, BTreeMap<crate::thread::ThreadId, Duration>)>> =
+    Spinlock::new(None);
+
+/// Prints each thread's share of the CPU since `top` last ran, or since boot the first time
+fn top_command() {
+    let stats = crate::thread::stats();
+    let now = crate::time::monotonic_nanos();
+
+    let mut sample = TOP_SAMPLE.lock();
+    let (since, previous) = sample.take().unwrap_or_default();
+    let window = now.saturating_sub(since).max(1);
+
+    println!(
+        "{:>4} {:<16} {:<8} {:>5} {:>6} {:>10} {:>8}",
+        "ID", "NAME", "STATE", "CLASS", "CPU%", "TIME", "SWITCHES"
+    );
+
+    for thread in stats.iter() {
+        let used = thread.cpu_time
+            - previous
+                .get(&thread.id)
+                .copied()
+                .unwrap_or_default()
+                .min(thread.cpu_time);
+
+        println!(
+            "{:>4} {:<16} {:<8} {:>5} {:>5.1}% {:>9}ms {:>8}",
+            thread.id.as_u64(),
+            thread.name,
+            format!("{:?}", thread.state),
+            format!("{}", thread.class),
+            used.as_nanos() as f64 * 100.0 / window as f64,
+            thread.cpu_time.as_millis(),
+            thread.switches
+        );
+    }
+
+    *sample = Some((
+        now,
+        stats
+            .iter()
+            .map(|thread| (thread.id, thread.cpu_time))
+            .collect(),
+    ));
+}
+
+/// Lists the executor tasks, `tasks slow <ms|off>` sets when a slow poll is logged
+fn tasks_command(args: &[&str]) {
+    use crate::task::executor;
+
+    match args {
+        [] => {}
+        ["slow", "off"] => {
+            executor::set_slow_poll_warning(None);
+            println!("Slow poll warning off");
+            return;
+        }
+        ["slow", millis] => {
+            if let Ok(millis) = millis.parse::<u64>() {
+                executor::set_slow_poll_warning(Some(Duration::from_millis(millis)));
+                println!("Warning when a poll takes longer than {}ms", millis);
+            } else {
+                LOGGER
+                    .get()
+                    .unwrap()
+                    .lock()
+                    .error("Invalid arguments. Usage: tasks slow <ms|off>");
+            }
+            return;
+        }
+        _ => {
+            LOGGER
+                .get()
+                .unwrap()
+                .lock()
+                .error("Invalid arguments. Usage: tasks [slow <ms|off>]");
+            return;
+        }
+    }
+
+    let now = crate::time::Instant::now();
+
+    println!(
+        "{:>4} {:<16} {:>6} {:>8} {:>10} {:>8} {:>6} {:>8} {:>10}",
+        "ID", "NAME", "WORKER", "POLLS", "POLL TIME", "AVG", "SLOW", "WAKES", "LAST WAKE"
+    );
+
+    for task in executor::stats() {
+        let average = task.poll_time.as_micros() / (task.polls.max(1) as u128);
+
+        println!(
+            "{:>4} {:<16} {:>5}{} {:>8} {:>8}ms {:>6}us {:>6} {:>8} {:>10}",
+            task.id.as_u64(),
+            task.name.as_deref().unwrap_or("-"),
+            task.worker,
+            if task.pinned { "*" } else { " " },
+            task.polls,
+            task.poll_time.as_millis(),
+            average,
+            task.slow_polls,
+            task.wakes,
+            task.last_wake
+                .map_or(alloc::string::String::from("never"), |wake| {
+                    format!("{}ms ago", now.saturating_duration_since(wake).as_millis())
+                })
+        );
+    }
+
+    match executor::slow_poll_warning() {
+        Some(threshold) => println!(
+            "* pinned - polls over {}ms are logged",
+            threshold.as_millis()
+        ),
+        None => println!("* pinned - slow poll warning off"),
+    }
+}
+
+/// Lists the user processes
+fn ps_command() {
+    println!(
+        "{:>5} {:>5} {:<8} {:>6} {:>7} {:<16} {}",
+        "PID", "PPID", "STATE", "THREAD", "THREADS", "NAME", "EXIT"
+    );
+
+    for process in crate::process::list() {
+        println!(
+            "{:>5} {:>5} {:<8} {:>6} {:>7} {:<16} {}",
+            process.pid.as_u64(),
+            process.parent.map_or(0, |pid| pid.as_u64()),
+            format!("{:?}", process.state),
+            process
+                .thread
+                .map_or(alloc::string::String::from("-"), |id| format!("{id}")),
+            process.threads,
+            process.name,
+            process
+                .exit
+                .map_or(alloc::string::String::from("-"), |exit| format!("{exit:?}"))
+        );
+    }
+}
+
+/// Sends a signal to a user process, SIGKILL if no signal is given
+fn kill_command(args: &[&str]) {
+    let (signal, pid) = match args {
+        [pid] => (Some(crate::process::signal::Signal::SIGKILL), pid),
+        [signal, pid] => (
+            signal
+                .strip_prefix('-')
+                .and_then(|signal| signal.parse::<u64>().ok())
+                .and_then(crate::process::signal::Signal::new),
+            pid,
+        ),
+        _ => (None, &""),
+    };
+
+    let (Some(signal), Ok(pid)) = (signal, pid.parse::<u64>()) else {
+        LOGGER
+            .get()
+            .unwrap()
+            .lock()
+            .error("Invalid arguments. Usage: kill [-<signal>] <pid>");
+        return;
+    };
+
+    match crate::process::signal::send(crate::process::Pid::from_u64(pid), signal) {
+        Ok(()) => println!("Sent signal {} to process {}", signal, pid),
+        Err(_) => println!("No process with PID {}", pid),
+    }
+}
+
+/// The process the `debug` command traces
+static DEBUGGED: Spinlock<Option<crate::process::Pid>> = Spinlock::new(None);
+
+/// How long `debug` waits for the process to stop
+const DEBUG_WAIT: Duration = Duration::from_millis(500);
+
+/// Registers `debug regs` shows and `debug set` changes, in the order they are shown
+const DEBUG_REGISTERS: [&str; 18] = [
+    "rax", "rbx", "rcx", "rdx", "rsi", "rdi", "rbp", "rsp", "r8", "r9", "r10", "r11", "r12", "r13",
+    "r14", "r15", "rip", "rflags",
+];
+
+/// Debugs a user process, see [crate::process::trace]
+fn debug_command(args: &[&str]) {
+    use crate::process::trace::{self, Resume, Tracer};
+
+    let usage = || {
+        LOGGER.get().unwrap().lock().error(
+            "Invalid arguments. Usage: debug <run <program> [args]|attach <pid>|step [count]|continue|regs|set <register> <value>|peek <addr> [len]|poke <addr> <byte>...|break [addr]|delete <addr>|detach>",
+        );
+    };
+
+    let (Some(&subcommand), rest) = (args.first(), args.get(1..).unwrap_or(&[])) else {
+        usage();
+        return;
+    };
+
+    match (subcommand, rest) {
+        ("run", [path, program_args @ ..]) => {
+            let Some(image) = crate::user::find_program(path) else {
+                println!("No program {} in the initrd", path);
+                return;
+            };
+            let name = crate::user::program_name(path);
+
+            let mut argv = Vec::with_capacity(program_args.len() + 1);
+            argv.push(name);
+            argv.extend_from_slice(program_args);
+
+            match crate::user::spawn_traced(name, image, &argv, &[], Tracer::Kernel) {
+                Ok(child) => {
+                    *DEBUGGED.lock() = Some(child.pid());
+                    show_stop(child.pid());
+                }
+                Err(err) => println!("Cannot run {}: {:?}", path, err),
+            }
+            return;
+        }
+        ("attach", [pid]) => {
+            let Some(pid) = parse_number(pid).map(crate::process::Pid::from_u64) else {
+                usage();
+                return;
+            };
+
+            match trace::attach(pid, Tracer::Kernel) {
+                Ok(()) => {
+                    *DEBUGGED.lock() = Some(pid);
+                    show_stop(pid);
+                }
+                Err(err) => println!("Cannot attach to process {}: {:?}", pid, err),
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    let Some(pid) = *DEBUGGED.lock() else {
+        println!("No process is being debugged, start one with debug run or debug attach");
+        return;
+    };
+
+    let result = match (subcommand, rest) {
+        ("step", [] | [_]) => {
+            let Some(count) = rest.first().map_or(Some(1), |count| parse_number(count)) else {
+                usage();
+                return;
+            };
+
+            (0..count)
+                .try_for_each(|_| {
+                    trace::resume(pid, Tracer::Kernel, Resume::Step)?;
+                    trace::wait(pid, Tracer::Kernel, Some(DEBUG_WAIT)).map(|_| ())
+                })
+                .map(|()| show_stop(pid))
+        }
+        ("continue", []) => trace::resume(pid, Tracer::Kernel, Resume::Continue)
+            .map(|()| println!("Process {} continues", pid)),
+        ("regs", []) => trace::registers(pid, Tracer::Kernel).map(|mut registers| {
+            for (index, name) in DEBUG_REGISTERS.iter().enumerate() {
+                print!(
+                    "{:<6} {:#018x}{}",
+                    name,
+                    *register(&mut registers, name).unwrap(),
+                    if index % 3 == 2 { "\n" } else { "   " }
+                );
+            }
+            println!();
+        }),
+        ("set", [name, value]) => {
+            let Some(value) = parse_number(value) else {
+                usage();
+                return;
+            };
+
+            trace::registers(pid, Tracer::Kernel).and_then(|mut registers| {
+                let Some(slot) = register(&mut registers, name) else {
+                    println!("No register {}", name);
+                    return Ok(());
+                };
+                *slot = value;
+                trace::set_registers(pid, Tracer::Kernel, registers)
+                    .map(|()| println!("{} = {:#x}", name, value))
+            })
+        }
+        ("peek", [addr] | [addr, _]) => {
+            let (Some(addr), Some(len)) = (
+                parse_number(addr),
+                rest.get(1).map_or(Some(16), |len| parse_number(len)),
+            ) else {
+                usage();
+                return;
+            };
+
+            let mut buffer = alloc::vec![0; len.min(256) as usize];
+            trace::read_memory(pid, Tracer::Kernel, addr, &mut buffer).map(|()| {
+                for (line, bytes) in buffer.chunks(16).enumerate() {
+                    print!("{:#014x}:", addr + line as u64 * 16);
+                    for byte in bytes {
+                        print!(" {:02x}", byte);
+                    }
+                    println!();
+                }
+            })
+        }
+        ("poke", [addr, bytes @ ..]) if !bytes.is_empty() => {
+            let bytes: Option<Vec<u8>> = bytes
+                .iter()
+                .map(|byte| u8::from_str_radix(byte.trim_start_matches("0x"), 16).ok())
+                .collect();
+            let (Some(addr), Some(bytes)) = (parse_number(addr), bytes) else {
+                usage();
+                return;
+            };
+
+            trace::write_memory(pid, Tracer::Kernel, addr, &bytes)
+                .map(|()| println!("Wrote {} bytes at {:#x}", bytes.len(), addr))
+        }
+        ("break", []) => trace::breakpoints(pid, Tracer::Kernel).map(|breakpoints| {
+            for addr in breakpoints {
+                println!("Breakpoint at {:#x}", addr);
+            }
+        }),
+        ("break", [addr]) => {
+            let Some(addr) = parse_number(addr) else {
+                usage();
+                return;
+            };
+
+            trace::set_breakpoint(pid, Tracer::Kernel, addr)
+                .map(|()| println!("Breakpoint at {:#x}", addr))
+        }
+        ("delete", [addr]) => {
+            let Some(addr) = parse_number(addr) else {
+                usage();
+                return;
+            };
+
+            trace::remove_breakpoint(pid, Tracer::Kernel, addr)
+                .map(|()| println!("Removed the breakpoint at {:#x}", addr))
+        }
+        ("detach", []) => trace::detach(pid, Tracer::Kernel).map(|()| {
+            *DEBUGGED.lock() = None;
+            println!("Detached from process {}", pid);
+        }),
+        _ => {
+            usage();
+            return;
+        }
+    };
+
+    match result {
+        Ok(()) => {}
+        Err(trace::TraceError::NoSuchProcess | trace::TraceError::NotTraced) => {
+            *DEBUGGED.lock() = None;
+            println!("Process {} has ended", pid);
+        }
+        Err(trace::TraceError::NotStopped) => {
+            println!("Process {} is running, it has not stopped yet", pid)
+        }
+        Err(err) => println!("Process {}: {:?}", pid, err),
+    }
+}
+
+/// Waits a moment for the debugged process to stop and shows where it is
+fn show_stop(pid: crate::process::Pid) {
+    use crate::process::trace::{self, Tracer};
+
+    let stop = match trace::wait(pid, Tracer::Kernel, Some(DEBUG_WAIT)) {
+        Ok(stop) => stop,
+        Err(trace::TraceError::TimedOut) => {
+            println!("Process {} will stop the next time it runs", pid);
+            return;
+        }
+        Err(err) => {
+            println!("Process {}: {:?}", pid, err);
+            return;
+        }
+    };
+
+    let mut code = [0; 8];
+    let code = match trace::read_memory(pid, Tracer::Kernel, stop.registers.rip, &mut code) {
+        Ok(()) => format!("{code:02x?}"),
+        Err(_) => alloc::string::String::from("not mapped"),
+    };
+
+    println!(
+        "Process {} thread {} stopped ({:?}) at {:#x}: {}",
+        pid, stop.thread, stop.reason, stop.registers.rip, code
+    );
+}
+
+/// A register of `registers` by name
+fn register<'a>(
+    registers: &'a mut crate::syscall::SyscallFrame,
+    name: &str,
+) -> Option<&'a mut u64> {
+    Some(match name {
+        "rax" => &mut registers.rax,
+        "rbx" => &mut registers.rbx,
+        "rcx" => &mut registers.rcx,
+        "rdx" => &mut registers.rdx,
+        "rsi" => &mut registers.rsi,
+        "rdi" => &mut registers.rdi,
+        "rbp" => &mut registers.rbp,
+        "rsp" => &mut registers.rsp,
+        "r8" => &mut registers.r8,
+        "r9" => &mut registers.r9,
+        "r10" => &mut registers.r10,
+        "r11" => &mut registers.r11,
+        "r12" => &mut registers.r12,
+        "r13" => &mut registers.r13,
+        "r14" => &mut registers.r14,
+        "r15" => &mut registers.r15,
+        "rip" => &mut registers.rip,
+        "rflags" => &mut registers.rflags,
+        _ => return None,
+    })
+}
+
+/// A decimal number, or hexadecimal with a `0x` prefix
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+/// Starts a program from the initrd in the background, it writes to the screen like the console does
+fn run_program(path: &str, args: &[&str]) {
+    let image = crate::user::find_program(path).unwrap();
+    let name = crate::user::program_name(path);
+
+    let mut argv = Vec::with_capacity(args.len() + 1);
+    argv.push(name);
+    argv.extend_from_slice(args);
+
+    match crate::user::spawn(name, image, &argv, &[]) {
+        Ok(child) => println!("Started {} as process {}", name, child.pid()),
+        Err(err) => println!("Cannot run {}: {:?}", path, err),
+    }
+}
+
 /// Executes the "color" command.
 ///
 /// # Arguments
@@ -402,8 +876,7 @@ fn change_color(args: &[&str]) {
             "aqua" => Color::Aqua,
             _ => {
                 LOGGER.get().unwrap().lock().error(&format!(
-                    "Invalid Color: {} - Tip Use color help or color /?",
-                    arg
+                    "Invalid Color: {arg} - Tip Use color help or color /?"
                 ));
                 return;
             }
@@ -486,8 +959,7 @@ fn change_background_color(args: &[&str]) -> bool {
             "aqua" => Color::Aqua,
             _ => {
                 LOGGER.get().unwrap().lock().error(&format!(
-                    "Invalid color: {} - Tip Use bgcolor help or bgcolor /?",
-                    arg
+                    "Invalid color: {arg} - Tip Use bgcolor help or bgcolor /?"
                 ));
                 return false;
             }
@@ -528,6 +1000,15 @@ fn help_command() {
     println!("echo <text>");
     println!("test");
     println!("mem");
+    println!("time <boot>");
+    println!("date");
+    println!("uptime");
+    println!("top");
+    println!("tasks [slow <ms|off>]");
+    println!("ps");
+    println!("kill [-<signal>] <pid>");
+    println!("debug <run|attach|step|continue|regs|set|peek|poke|break|delete|detach> [args]");
+    println!("<program in the initrd> [args]");
     println!("stack_overflow");
     println!("help");
 }
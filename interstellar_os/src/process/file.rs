@@ -0,0 +1,93 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Files a process has open
+//!
+//! Files come from the initrd and can only be read, each open file keeps its own offset.
+
+use alloc::string::String;
+use spinning_top::Spinlock;
+
+use crate::drivers::fs::initrd;
+
+/// A file opened from the initrd
+#[derive(Debug)]
+pub struct OpenFile {
+    name: String,
+    data: &'static [u8],
+    offset: Spinlock<usize>,
+}
+
+/// Where [OpenFile::seek] counts from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(i64),
+    Current(i64),
+    End(i64),
+}
+
+impl OpenFile {
+    /// Opens the file at `path` in the initrd with the offset at the start, a leading `/` is ignored
+    pub fn open(path: &str) -> Option<OpenFile> {
+        let name = path.trim_start_matches('/');
+        if name.is_empty() {
+            return None;
+        }
+
+        Some(OpenFile {
+            name: name.into(),
+            data: initrd::get_file_bytes(name)?,
+            offset: Spinlock::new(0),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The whole file
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+
+    /// Copies from the offset into `buffer` and moves the offset past what was copied, returns how many bytes
+    /// were copied which is 0 at the end of the file
+    pub fn read(&self, buffer: &mut [u8]) -> usize {
+        let mut offset = self.offset.lock();
+        let start = (*offset).min(self.data.len());
+        let len = buffer.len().min(self.data.len() - start);
+
+        buffer[..len].copy_from_slice(&self.data[start..start + len]);
+        *offset = start + len;
+        len
+    }
+
+    /// Moves the offset and returns where it is now, None if it would be before the start.
+    /// It can go past the end, reads there find nothing
+    pub fn seek(&self, from: SeekFrom) -> Option<usize> {
+        let mut offset = self.offset.lock();
+
+        let (base, change) = match from {
+            SeekFrom::Start(change) => (0, change),
+            SeekFrom::Current(change) => (*offset as i64, change),
+            SeekFrom::End(change) => (self.data.len() as i64, change),
+        };
+
+        let new = usize::try_from(base.checked_add(change)?).ok()?;
+        *offset = new;
+        Some(new)
+    }
+}
@@ -0,0 +1,593 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! User processes
+//!
+//! A process is a user program with its own [AddressSpace], a table of open [Handle]s and a place in the process tree.
+//! A process is started from an executable by [crate::user::spawn] or copied from a running one by [fork],
+//! and `exec` swaps its program for another. Processes are sent [signal]s to interrupt or stop them and parents
+//! get SIGCHLD when a child ends.
+//!
+//! The program can run on several threads sharing the address space, started with [crate::user::clone].
+//! A thread can end on its own, anything else that ends a thread ends the whole process with [exit_group].
+//! Once the last thread is gone the process becomes a zombie that keeps its exit status until the parent collects it
+//! with [wait], processes started by the kernel are removed straight away.
+//!
+//! A debugger can [trace] a process to stop it, step through its program and change its registers and memory.
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spinning_top::Spinlock;
+use x86_64::structures::paging::PhysFrame;
+
+use crate::other::log::LOGGER;
+use crate::thread::{self, Thread, ThreadState, WaitQueue};
+use crate::user::tls::{self, Tls};
+use crate::user::{self, Abi, AddressSpace, Exit, LoadedProgram, MapError};
+
+pub mod file;
+pub mod signal;
+pub mod trace;
+
+use file::OpenFile;
+
+use signal::{Signal, Signals};
+use trace::Tracing;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    pub fn from_u64(pid: u64) -> Self {
+        Pid(pid)
+    }
+}
+
+impl core::fmt::Display for Pid {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessState {
+    Running,
+    /// Blocked in a system call
+    Sleeping,
+    /// Stopped by a signal until it gets SIGCONT, or held by its tracer
+    Stopped,
+    /// Ended and waiting for its parent to collect the exit status
+    Zombie,
+}
+
+/// Something a process has open, its index in the handle table is the file descriptor
+///
+/// Copies of a handle made by `fork` share the file offset
+#[derive(Debug, Clone)]
+pub enum Handle {
+    Console,
+    File(Arc<OpenFile>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    /// There is no process with that PID
+    NoSuchProcess,
+    /// The process has no children to wait for, or the PID is not one of them
+    NoChildren,
+    /// The running thread does not belong to a process
+    NotAProcess,
+    /// A signal arrived while waiting
+    Interrupted,
+}
+
+/// A process control block
+pub struct Process {
+    pid: Pid,
+    /// Name of the program, changed by `exec`
+    name: Spinlock<String>,
+    /// PID of the parent, 0 if the kernel started the process or the parent has ended
+    parent: AtomicU64,
+    /// The threads running the program in the order they started
+    threads: Spinlock<Vec<Arc<Thread>>>,
+    /// Set by [kill] and [exit_group], every thread ends the next time it would return to user mode
+    killed: AtomicBool,
+    signals: Signals,
+    /// Woken when the process ends
+    ended: WaitQueue,
+    tracing: Tracing,
+    inner: Spinlock<Inner>,
+}
+
+struct Inner {
+    children: Vec<Pid>,
+    /// Taken away when the program ends
+    address_space: Option<AddressSpace>,
+    /// The program's TLS template, new threads get a copy
+    tls: Option<Tls>,
+    /// The system calls the program makes
+    abi: Abi,
+    handles: Vec<Option<Handle>>,
+    /// Threads started for the program that have not ended, counted before they start running
+    thread_count: usize,
+    /// How the process ends once its last thread has, set by [exit_group]
+    ending: Option<Exit>,
+    exit: Option<Exit>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> String {
+        self.name.lock().clone()
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        match self.parent.load(Ordering::SeqCst) {
+            0 => None,
+            pid => Some(Pid(pid)),
+        }
+    }
+
+    pub fn state(&self) -> ProcessState {
+        if self.inner.lock().exit.is_some() {
+            return ProcessState::Zombie;
+        }
+
+        if self.signals.is_stopped() || self.tracing.is_stopped() {
+            return ProcessState::Stopped;
+        }
+
+        let threads = self.threads.lock();
+        if !threads.is_empty()
+            && threads
+                .iter()
+                .all(|thread| thread.state() == ThreadState::Blocked)
+        {
+            ProcessState::Sleeping
+        } else {
+            ProcessState::Running
+        }
+    }
+
+    /// How the program ended, None while it runs
+    pub fn exit_status(&self) -> Option<Exit> {
+        self.inner.lock().exit
+    }
+
+    pub fn is_killed(&self) -> bool {
+        self.killed.load(Ordering::SeqCst)
+    }
+
+    /// IDs of the threads running the program
+    pub fn threads(&self) -> Vec<thread::ThreadId> {
+        self.threads
+            .lock()
+            .iter()
+            .map(|thread| thread.id())
+            .collect()
+    }
+
+    /// How many threads the program has, including ones that are starting
+    pub fn thread_count(&self) -> usize {
+        self.inner.lock().thread_count
+    }
+
+    /// The level 4 page table of the process, None once it has ended
+    pub fn page_table(&self) -> Option<PhysFrame> {
+        self.inner
+            .lock()
+            .address_space
+            .as_ref()
+            .map(|space| space.page_table())
+    }
+
+    /// The handle open as file descriptor `fd`
+    pub fn handle(&self, fd: u64) -> Option<Handle> {
+        let inner = self.inner.lock();
+        inner.handles.get(fd as usize).cloned().flatten()
+    }
+
+    /// Opens `handle` as the lowest free file descriptor and returns it
+    pub(crate) fn open_handle(&self, handle: Handle) -> u64 {
+        let mut inner = self.inner.lock();
+
+        match inner.handles.iter().position(Option::is_none) {
+            Some(fd) => {
+                inner.handles[fd] = Some(handle);
+                fd as u64
+            }
+            None => {
+                inner.handles.push(Some(handle));
+                inner.handles.len() as u64 - 1
+            }
+        }
+    }
+
+    /// Closes file descriptor `fd`, returns false if it was not open
+    pub(crate) fn close_handle(&self, fd: u64) -> bool {
+        let mut inner = self.inner.lock();
+        inner
+            .handles
+            .get_mut(fd as usize)
+            .and_then(Option::take)
+            .is_some()
+    }
+
+    /// The system calls the program makes
+    pub fn abi(&self) -> Abi {
+        self.inner.lock().abi
+    }
+
+    /// Swaps the address space for a newly loaded program's, the old one is returned
+    /// so it can be dropped once it is no longer in use
+    pub(crate) fn replace_image(
+        &self,
+        name: &str,
+        address_space: AddressSpace,
+        program: &LoadedProgram,
+    ) -> Option<AddressSpace> {
+        *self.name.lock() = name.to_string();
+        self.signals.reset_handlers();
+        self.tracing.forget_breakpoints();
+        self.set_program(program);
+
+        self.inner.lock().address_space.replace(address_space)
+    }
+
+    /// Sets the TLS template and ABI of the program
+    pub(crate) fn set_program(&self, program: &LoadedProgram) {
+        let mut inner = self.inner.lock();
+        inner.tls = program.tls;
+        inner.abi = program.abi;
+    }
+
+    /// Maps a new copy of the TLS template and returns its thread pointer, 0 if the program has no template
+    pub(crate) fn new_thread_pointer(&self) -> Result<u64, MapError> {
+        let mut inner = self.inner.lock();
+        let Some(tls) = inner.tls else {
+            return Ok(0);
+        };
+
+        let address_space = inner.address_space.as_mut().ok_or(MapError::NotMapped)?;
+        Ok(tls::allocate(address_space, &tls)?.as_u64())
+    }
+
+    /// Runs `f` on the address space of the process, None once it has ended
+    pub(crate) fn with_address_space<R>(
+        &self,
+        f: impl FnOnce(&mut AddressSpace) -> R,
+    ) -> Option<R> {
+        self.inner.lock().address_space.as_mut().map(f)
+    }
+
+    /// Counts a thread that is about to start running the program, returns false if the process is ending
+    pub(crate) fn add_thread(&self) -> bool {
+        let mut inner = self.inner.lock();
+        if inner.ending.is_some() || inner.exit.is_some() || self.is_killed() {
+            return false;
+        }
+
+        inner.thread_count += 1;
+        true
+    }
+
+    /// Makes the running thread one of the threads of this process, it must have been counted by [Process::add_thread]
+    pub(crate) fn attach_current_thread(&self) {
+        let thread = thread::current();
+        thread.set_process(self.pid.0);
+        self.threads.lock().push(thread);
+    }
+
+    /// Wakes every thread of the process so those blocked in system calls look at what changed
+    pub(crate) fn wake_threads(&self) {
+        for thread in self.threads.lock().iter() {
+            thread::wake(thread.id());
+        }
+    }
+
+    /// Blocks until the process has ended and returns how it ended
+    pub fn wait_for_exit(&self) -> Exit {
+        loop {
+            if let Some(exit) = self.exit_status() {
+                return exit;
+            }
+
+            self.ended
+                .wait_if(|| self.exit_status().is_none(), None, || false);
+        }
+    }
+}
+
+/// Every process that has not been collected yet
+static PROCESSES: Spinlock<BTreeMap<Pid, Arc<Process>>> = Spinlock::new(BTreeMap::new());
+
+/// Adds a process with the console open as its standard input, output and error
+///
+/// Its program is started by attaching a thread with [Process::attach_current_thread]
+pub fn create(name: &str, parent: Option<Pid>, address_space: AddressSpace) -> Arc<Process> {
+    insert(
+        name,
+        parent,
+        address_space,
+        None,
+        Abi::Interstellar,
+        vec![Some(Handle::Console); 3],
+        Signals::new(),
+    )
+}
+
+/// Adds a child of `parent` with a copy-on-write copy of its address space, the same handles open
+/// and the same signal actions
+///
+/// Only the thread that forks is copied, its program is started by attaching a thread with
+/// [Process::attach_current_thread]
+pub fn fork(parent: &Process) -> Result<Arc<Process>, MapError> {
+    let (address_space, tls, abi, handles) = {
+        let mut inner = parent.inner.lock();
+        let address_space = inner
+            .address_space
+            .as_mut()
+            .ok_or(MapError::NotMapped)?
+            .fork()?;
+        (address_space, inner.tls, inner.abi, inner.handles.clone())
+    };
+
+    Ok(insert(
+        &parent.name(),
+        Some(parent.pid),
+        address_space,
+        tls,
+        abi,
+        handles,
+        parent.signals.fork(),
+    ))
+}
+
+fn insert(
+    name: &str,
+    parent: Option<Pid>,
+    address_space: AddressSpace,
+    tls: Option<Tls>,
+    abi: Abi,
+    handles: Vec<Option<Handle>>,
+    signals: Signals,
+) -> Arc<Process> {
+    let process = Arc::new(Process {
+        pid: Pid::new(),
+        name: Spinlock::new(name.to_string()),
+        parent: AtomicU64::new(parent.map_or(0, |pid| pid.0)),
+        threads: Spinlock::new(Vec::new()),
+        killed: AtomicBool::new(false),
+        signals,
+        ended: WaitQueue::new(),
+        tracing: Tracing::new(),
+        inner: Spinlock::new(Inner {
+            children: Vec::new(),
+            address_space: Some(address_space),
+            tls,
+            abi,
+            handles,
+            thread_count: 0,
+            ending: None,
+            exit: None,
+        }),
+    });
+
+    if let Some(parent) = parent.and_then(get) {
+        parent.inner.lock().children.push(process.pid);
+    }
+
+    PROCESSES.lock().insert(process.pid, process.clone());
+
+    process
+}
+
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// The process of the running thread, None for kernel threads
+pub fn current() -> Option<Arc<Process>> {
+    match thread::current().process() {
+        0 => None,
+        pid => get(Pid(pid)),
+    }
+}
+
+/// Ends a process the next time it would run in user mode, a process blocked in a system call is woken for it
+///
+/// This is SIGKILL, see [signal::send] for the others
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    signal::send(pid, Signal::SIGKILL)
+}
+
+/// Ends the running program with [Exit::Killed] if its process has been killed
+///
+/// Called on the way back to user mode
+pub(crate) fn check_killed() {
+    if current().is_some_and(|process| process.is_killed()) {
+        user::exit_current(Exit::Killed);
+    }
+}
+
+/// Ends every thread of the process, the process ends with `exit` unless an earlier call decided otherwise
+///
+/// The other threads end the next time they would return to user mode, threads blocked in system calls are woken for it
+pub(crate) fn exit_group(process: &Process, exit: Exit) {
+    process.inner.lock().ending.get_or_insert(exit);
+    process.killed.store(true, Ordering::SeqCst);
+    process.wake_threads();
+}
+
+/// Takes the running thread out of its process once the program has stopped running on it
+///
+/// [Exit::Code] only ends the thread, anything else ends the whole process with [exit_group].
+/// The process ends when its last thread does, then this returns how it ended.
+pub(crate) fn exit_thread(process: &Process, exit: Exit) -> Option<Exit> {
+    let id = thread::current_id();
+    process.threads.lock().retain(|thread| thread.id() != id);
+
+    if !matches!(exit, Exit::Code(_)) {
+        exit_group(process, exit);
+    }
+
+    let ended = {
+        let mut inner = process.inner.lock();
+        inner.thread_count -= 1;
+        (inner.thread_count == 0).then(|| inner.ending.unwrap_or(exit))
+    };
+
+    if let Some(exit) = ended {
+        self::exit(process, exit);
+    }
+
+    ended
+}
+
+/// Makes a process a zombie once its last thread has ended and tells the parent
+fn exit(process: &Process, exit: Exit) {
+    LOGGER.get().unwrap().lock().info(&format!(
+        "Process {} ({}) ended: {:?}",
+        process.pid,
+        process.name(),
+        exit
+    ));
+
+    let children = {
+        let mut inner = process.inner.lock();
+        inner.exit = Some(exit);
+        inner.address_space = None;
+        inner.handles.clear();
+        core::mem::take(&mut inner.children)
+    };
+
+    // Orphans have nobody to wait for them
+    for child in children.into_iter().filter_map(get) {
+        child.parent.store(0, Ordering::SeqCst);
+        if child.exit_status().is_some() {
+            PROCESSES.lock().remove(&child.pid);
+        }
+    }
+
+    process.ended.wake_all();
+    trace::ended(process);
+    trace::tracer_ended(process.pid);
+
+    match process.parent().and_then(get) {
+        Some(parent) => {
+            signal::send_to(&parent, Signal::SIGCHLD);
+            parent.wake_threads();
+        }
+        None => {
+            PROCESSES.lock().remove(&process.pid);
+        }
+    }
+}
+
+/// Collects an ended child of the running process, any child if `pid` is None
+///
+/// Blocks until a child ends unless `block` is false, then it returns None if none has.
+pub fn wait(pid: Option<Pid>, block: bool) -> Result<Option<(Pid, Exit)>, ProcessError> {
+    let me = current().ok_or(ProcessError::NotAProcess)?;
+
+    loop {
+        {
+            let mut inner = me.inner.lock();
+
+            let mut waiting_for = inner
+                .children
+                .iter()
+                .copied()
+                .filter(|&child| pid.is_none_or(|pid| pid == child))
+                .peekable();
+
+            if waiting_for.peek().is_none() {
+                return Err(ProcessError::NoChildren);
+            }
+
+            let ended = waiting_for.find_map(|child| {
+                get(child)
+                    .and_then(|child| child.exit_status())
+                    .map(|exit| (child, exit))
+            });
+
+            if let Some((child, exit)) = ended {
+                inner.children.retain(|&other| other != child);
+                PROCESSES.lock().remove(&child);
+                return Ok(Some((child, exit)));
+            }
+        }
+
+        if !block {
+            return Ok(None);
+        }
+
+        check_killed();
+        if me.has_deliverable_signal() {
+            return Err(ProcessError::Interrupted);
+        }
+
+        thread::block();
+    }
+}
+
+/// What `ps` shows about a process
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub state: ProcessState,
+    /// The first of its threads that is still running
+    pub thread: Option<thread::ThreadId>,
+    pub threads: usize,
+    pub exit: Option<Exit>,
+}
+
+/// Every process in PID order
+pub fn list() -> Vec<ProcessInfo> {
+    let processes: Vec<Arc<Process>> = PROCESSES.lock().values().cloned().collect();
+
+    processes
+        .iter()
+        .map(|process| ProcessInfo {
+            pid: process.pid,
+            parent: process.parent(),
+            name: process.name(),
+            state: process.state(),
+            thread: process.threads.lock().first().map(|thread| thread.id()),
+            threads: process.thread_count(),
+            exit: process.exit_status(),
+        })
+        .collect()
+}
@@ -0,0 +1,690 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! POSIX style signals
+//!
+//! A signal sent with [send] stays pending until its process next returns to user mode, at the end of a system call
+//! or an interrupt from user mode, and it is not blocked. Then its action runs: the default action ends, stops or
+//! continues the process or does nothing, and a handler set with [Process::set_signal_action] is called on the user
+//! stack.
+//!
+//! To call a handler the kernel puts a [SignalFrame] below the interrupted stack pointer and returns to the signal
+//! trampoline, a page mapped into every address space at [SIGNAL_TRAMPOLINE]. The trampoline saves the registers in
+//! the frame, calls the handler with the signal number, a [SigInfo] and the frame, then makes the `sigreturn` system
+//! call which goes back to the interrupted code with everything the frame saved.
+//!
+//! A fault in a user program sends it SIGSEGV, SIGFPE, SIGILL, SIGBUS or SIGTRAP straight away with [deliver_fault].
+//! These cannot wait, so the program ends with [Exit::Fault] if the signal is blocked or not handled.
+
+use core::arch::global_asm;
+use core::mem::{offset_of, size_of};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spinning_top::Spinlock;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+use super::{Pid, Process, ProcessError};
+use crate::memory;
+use crate::syscall::{SyscallFrame, SYS_SIGRETURN};
+use crate::thread;
+use crate::user::access::Plain;
+use crate::user::{self, BadAddress, Exit, UserFault, UserPtr, SIGNAL_TRAMPOLINE};
+
+/// Highest signal number, the masks have one bit for each signal
+pub const MAX_SIGNAL: u64 = 64;
+
+/// Handler value for the default action
+pub const SIG_DFL: u64 = 0;
+
+/// Handler value to ignore the signal
+pub const SIG_IGN: u64 = 1;
+
+/// The handler is called with a [SigInfo], it always is here
+pub const SA_SIGINFO: u64 = 0x4;
+
+/// The restorer field is set, the signal trampoline is used either way
+pub const SA_RESTORER: u64 = 0x0400_0000;
+
+/// The signal is not blocked while its handler runs
+pub const SA_NODEFER: u64 = 0x4000_0000;
+
+/// The action goes back to the default once the handler is called
+pub const SA_RESETHAND: u64 = 0x8000_0000;
+
+/// Space below the interrupted stack pointer that is left alone, the System V ABI lets functions use it
+const RED_ZONE: u64 = 128;
+
+/// [SigInfo] code for a signal sent with [send]
+pub const SI_USER: u64 = 0;
+
+/// [SigInfo] code for a signal caused by a fault
+pub const SI_FAULT: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Signal(u8);
+
+impl Signal {
+    pub const SIGHUP: Signal = Signal(1);
+    pub const SIGINT: Signal = Signal(2);
+    pub const SIGQUIT: Signal = Signal(3);
+    pub const SIGILL: Signal = Signal(4);
+    pub const SIGTRAP: Signal = Signal(5);
+    pub const SIGABRT: Signal = Signal(6);
+    pub const SIGBUS: Signal = Signal(7);
+    pub const SIGFPE: Signal = Signal(8);
+    pub const SIGKILL: Signal = Signal(9);
+    pub const SIGUSR1: Signal = Signal(10);
+    pub const SIGSEGV: Signal = Signal(11);
+    pub const SIGUSR2: Signal = Signal(12);
+    pub const SIGPIPE: Signal = Signal(13);
+    pub const SIGALRM: Signal = Signal(14);
+    pub const SIGTERM: Signal = Signal(15);
+    pub const SIGCHLD: Signal = Signal(17);
+    pub const SIGCONT: Signal = Signal(18);
+    pub const SIGSTOP: Signal = Signal(19);
+    pub const SIGTSTP: Signal = Signal(20);
+    pub const SIGTTIN: Signal = Signal(21);
+    pub const SIGTTOU: Signal = Signal(22);
+    pub const SIGURG: Signal = Signal(23);
+    pub const SIGXCPU: Signal = Signal(24);
+    pub const SIGXFSZ: Signal = Signal(25);
+    pub const SIGWINCH: Signal = Signal(28);
+    pub const SIGSYS: Signal = Signal(31);
+
+    /// None for 0 and numbers above [MAX_SIGNAL]
+    pub fn new(number: u64) -> Option<Signal> {
+        (1..=MAX_SIGNAL)
+            .contains(&number)
+            .then_some(Signal(number as u8))
+    }
+
+    pub fn number(&self) -> u64 {
+        self.0 as u64
+    }
+
+    /// The signal's bit in a mask
+    pub const fn bit(&self) -> u64 {
+        1 << (self.0 - 1)
+    }
+
+    /// SIGKILL and SIGSTOP cannot be handled, blocked or ignored
+    pub fn can_be_caught(&self) -> bool {
+        *self != Signal::SIGKILL && *self != Signal::SIGSTOP
+    }
+
+    pub fn default_action(&self) -> DefaultAction {
+        match *self {
+            Signal::SIGQUIT
+            | Signal::SIGILL
+            | Signal::SIGTRAP
+            | Signal::SIGABRT
+            | Signal::SIGBUS
+            | Signal::SIGFPE
+            | Signal::SIGSEGV
+            | Signal::SIGXCPU
+            | Signal::SIGXFSZ
+            | Signal::SIGSYS => DefaultAction::Core,
+            Signal::SIGCHLD | Signal::SIGURG | Signal::SIGWINCH => DefaultAction::Ignore,
+            Signal::SIGSTOP | Signal::SIGTSTP | Signal::SIGTTIN | Signal::SIGTTOU => {
+                DefaultAction::Stop
+            }
+            Signal::SIGCONT => DefaultAction::Continue,
+            _ => DefaultAction::Terminate,
+        }
+    }
+}
+
+impl core::fmt::Display for Signal {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// What a signal does when it has no handler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DefaultAction {
+    /// Ends the process
+    Terminate,
+    /// Ends the process, where a core dump would be written. No core dumps are written yet
+    Core,
+    Ignore,
+    /// Stops the process until it gets SIGCONT
+    Stop,
+    /// Continues a stopped process
+    Continue,
+}
+
+/// Signals that stop a process by default
+const STOP_SIGNALS: u64 =
+    Signal::SIGSTOP.bit() | Signal::SIGTSTP.bit() | Signal::SIGTTIN.bit() | Signal::SIGTTOU.bit();
+
+/// Signals that can never be blocked
+const UNBLOCKABLE: u64 = Signal::SIGKILL.bit() | Signal::SIGSTOP.bit();
+
+/// What a process does with a signal, laid out like the `sigaction` system call argument
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigAction {
+    /// [SIG_DFL], [SIG_IGN] or the address of the handler
+    pub handler: u64,
+    pub flags: u64,
+    /// Not used, handlers return through the signal trampoline
+    pub restorer: u64,
+    /// Signals blocked while the handler runs, on top of the ones already blocked
+    pub mask: u64,
+}
+
+impl SigAction {
+    pub const DEFAULT: SigAction = SigAction {
+        handler: SIG_DFL,
+        flags: 0,
+        restorer: 0,
+        mask: 0,
+    };
+
+    /// True if the signal is thrown away when it is sent
+    fn ignores(&self, signal: Signal) -> bool {
+        match self.handler {
+            SIG_IGN => true,
+            SIG_DFL => matches!(
+                signal.default_action(),
+                DefaultAction::Ignore | DefaultAction::Continue
+            ),
+            _ => false,
+        }
+    }
+}
+
+unsafe impl Plain for SigAction {}
+
+/// Why a signal was sent, the second argument of a handler
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SigInfo {
+    pub signal: u64,
+    /// [SI_USER] or [SI_FAULT]
+    pub code: u64,
+    /// The address that was accessed for a page fault
+    pub address: u64,
+}
+
+/// Put on the user stack when a handler is called, the third argument of the handler and what `sigreturn` restores
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SignalFrame {
+    /// The interrupted registers, the trampoline fills in the general purpose ones
+    pub registers: SyscallFrame,
+    /// Signals blocked before the handler was called
+    pub blocked: u64,
+    pub handler: u64,
+    pub info: SigInfo,
+    _reserved: u64,
+}
+
+unsafe impl Plain for SignalFrame {}
+
+// The stack is 16 byte aligned at the frame so it is still aligned for the handler after the call
+const _: () = assert!(size_of::<SignalFrame>() % 16 == 0);
+
+global_asm!(
+    ".pushsection .rodata",
+    ".global signal_trampoline_start",
+    "signal_trampoline_start:",
+    // The registers are the interrupted ones here, they are saved in SyscallFrame order
+    "mov [rsp + 0], r15",
+    "mov [rsp + 8], r14",
+    "mov [rsp + 16], r13",
+    "mov [rsp + 24], r12",
+    "mov [rsp + 32], r11",
+    "mov [rsp + 40], r10",
+    "mov [rsp + 48], r9",
+    "mov [rsp + 56], r8",
+    "mov [rsp + 64], rbp",
+    "mov [rsp + 72], rdi",
+    "mov [rsp + 80], rsi",
+    "mov [rsp + 88], rdx",
+    "mov [rsp + 96], rcx",
+    "mov [rsp + 104], rbx",
+    "mov [rsp + 112], rax",
+    "mov rdi, [rsp + {signal}]",
+    "lea rsi, [rsp + {info}]",
+    "mov rdx, rsp",
+    "call qword ptr [rsp + {handler}]",
+    // int 0x80 returns with every register restored, SYSRET would lose rcx and r11
+    "mov eax, {sigreturn}",
+    "int 0x80",
+    "ud2",
+    ".global signal_trampoline_end",
+    "signal_trampoline_end:",
+    ".popsection",
+    signal = const offset_of!(SignalFrame, info) + offset_of!(SigInfo, signal),
+    info = const offset_of!(SignalFrame, info),
+    handler = const offset_of!(SignalFrame, handler),
+    sigreturn = const SYS_SIGRETURN,
+);
+
+extern "C" {
+    static signal_trampoline_start: u8;
+    static signal_trampoline_end: u8;
+}
+
+// The trampoline saves the registers at fixed offsets
+const _: () = assert!(offset_of!(SignalFrame, registers) == 0);
+const _: () = assert!(offset_of!(SyscallFrame, rax) == 112);
+
+/// The frame holding the signal trampoline, the same frame is mapped read-only into every address space
+pub(crate) fn trampoline_frame() -> Option<PhysFrame> {
+    static FRAME: spin::Once<Option<PhysFrame>> = spin::Once::new();
+
+    *FRAME.call_once(|| {
+        let frame = memory::allocate_frame()?;
+        let page = memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+
+        unsafe {
+            let start = core::ptr::addr_of!(signal_trampoline_start);
+            let len = core::ptr::addr_of!(signal_trampoline_end) as usize - start as usize;
+
+            core::ptr::write_bytes(page, 0, 4096);
+            core::ptr::copy_nonoverlapping(start, page, len);
+        }
+
+        Some(frame)
+    })
+}
+
+/// The signal state of a process
+pub(super) struct Signals {
+    /// Sent and not yet delivered
+    pending: AtomicU64,
+    /// Stopped by a stop signal until SIGCONT
+    stopped: AtomicBool,
+    inner: Spinlock<Inner>,
+}
+
+struct Inner {
+    /// Index 0 is signal 1
+    actions: [SigAction; MAX_SIGNAL as usize],
+    blocked: u64,
+}
+
+impl Signals {
+    pub(super) fn new() -> Self {
+        Signals {
+            pending: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+            inner: Spinlock::new(Inner {
+                actions: [SigAction::DEFAULT; MAX_SIGNAL as usize],
+                blocked: 0,
+            }),
+        }
+    }
+
+    /// A forked child gets the same actions and blocked signals but nothing pending
+    pub(super) fn fork(&self) -> Self {
+        let inner = self.inner.lock();
+
+        Signals {
+            pending: AtomicU64::new(0),
+            stopped: AtomicBool::new(false),
+            inner: Spinlock::new(Inner {
+                actions: inner.actions,
+                blocked: inner.blocked,
+            }),
+        }
+    }
+
+    /// Handlers are gone after exec, ignored signals stay ignored
+    pub(super) fn reset_handlers(&self) {
+        for action in self.inner.lock().actions.iter_mut() {
+            if action.handler != SIG_IGN {
+                *action = SigAction::DEFAULT;
+            }
+        }
+    }
+
+    pub(super) fn pending(&self) -> u64 {
+        self.pending.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn blocked(&self) -> u64 {
+        self.inner.lock().blocked
+    }
+
+    pub(super) fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    fn action(&self, signal: Signal) -> SigAction {
+        self.inner.lock().actions[signal.0 as usize - 1]
+    }
+}
+
+impl Process {
+    /// The action for `signal`, returns the old one or None if the signal cannot be caught or ignored
+    ///
+    /// Ignoring a signal throws away any of it that is pending
+    pub fn set_signal_action(&self, signal: Signal, action: SigAction) -> Option<SigAction> {
+        if !signal.can_be_caught() {
+            return None;
+        }
+
+        let old = core::mem::replace(
+            &mut self.signals.inner.lock().actions[signal.0 as usize - 1],
+            action,
+        );
+
+        if action.ignores(signal) {
+            self.signals
+                .pending
+                .fetch_and(!signal.bit(), Ordering::SeqCst);
+        }
+
+        Some(old)
+    }
+
+    pub fn signal_action(&self, signal: Signal) -> SigAction {
+        self.signals.action(signal)
+    }
+
+    /// Signals that are held pending instead of delivered
+    pub fn blocked_signals(&self) -> u64 {
+        self.signals.blocked()
+    }
+
+    /// Changes the blocked signals and returns the old mask, SIGKILL and SIGSTOP are never blocked
+    pub fn set_blocked_signals(&self, blocked: u64) -> u64 {
+        core::mem::replace(
+            &mut self.signals.inner.lock().blocked,
+            blocked & !UNBLOCKABLE,
+        )
+    }
+
+    pub fn pending_signals(&self) -> u64 {
+        self.signals.pending()
+    }
+
+    /// A pending signal that is not blocked, it interrupts blocking system calls
+    pub(crate) fn has_deliverable_signal(&self) -> bool {
+        self.signals.pending() & !self.signals.blocked() != 0
+    }
+}
+
+/// Sends `signal` to a process
+pub fn send(pid: Pid, signal: Signal) -> Result<(), ProcessError> {
+    let process = super::get(pid).ok_or(ProcessError::NoSuchProcess)?;
+    send_to(&process, signal);
+    Ok(())
+}
+
+pub(crate) fn send_to(process: &Process, signal: Signal) {
+    let signals = &process.signals;
+
+    match signal {
+        Signal::SIGKILL => {
+            process.killed.store(true, Ordering::SeqCst);
+            signals.stopped.store(false, Ordering::SeqCst);
+            wake(process);
+            return;
+        }
+        Signal::SIGCONT => {
+            signals.pending.fetch_and(!STOP_SIGNALS, Ordering::SeqCst);
+            if signals.stopped.swap(false, Ordering::SeqCst) {
+                wake(process);
+            }
+        }
+        _ if STOP_SIGNALS & signal.bit() != 0 => {
+            signals
+                .pending
+                .fetch_and(!Signal::SIGCONT.bit(), Ordering::SeqCst);
+        }
+        _ => {}
+    }
+
+    if signals.action(signal).ignores(signal) {
+        return;
+    }
+
+    signals.pending.fetch_or(signal.bit(), Ordering::SeqCst);
+
+    // A blocked system call stops waiting to let the signal in
+    if signal.bit() & !signals.blocked() != 0 {
+        wake(process);
+    }
+}
+
+fn wake(process: &Process) {
+    process.wake_threads();
+}
+
+/// Where the running thread goes back to in user mode, changed to enter a signal handler
+pub(crate) struct UserReturn {
+    pub rip: u64,
+    pub rsp: u64,
+    pub rflags: u64,
+}
+
+impl UserReturn {
+    pub(crate) fn from_interrupt(stack_frame: &InterruptStackFrame) -> Self {
+        UserReturn {
+            rip: stack_frame.instruction_pointer.as_u64(),
+            rsp: stack_frame.stack_pointer.as_u64(),
+            rflags: stack_frame.cpu_flags,
+        }
+    }
+
+    /// Writes the return back into an interrupt frame from user mode
+    pub(crate) fn apply(&self, stack_frame: &mut InterruptStackFrame) {
+        unsafe {
+            stack_frame.as_mut().update(|frame| {
+                frame.instruction_pointer = VirtAddr::new(self.rip);
+                frame.stack_pointer = VirtAddr::new(self.rsp);
+                frame.cpu_flags = self.rflags;
+            });
+        }
+    }
+}
+
+/// Runs the actions of pending signals on the way back to user mode
+///
+/// Returns after setting up at most one handler. Does not return if a signal ends the program
+pub(crate) fn deliver(to: &mut UserReturn) {
+    let Some(process) = super::current() else {
+        return;
+    };
+
+    // The debug exception after the next instruction stops the thread with every register for the tracer
+    if super::trace::stop_requested(&process) {
+        to.rflags |= RFlags::TRAP_FLAG.bits();
+    }
+
+    loop {
+        super::check_killed();
+
+        if process.signals.is_stopped() {
+            wait_while_stopped(&process);
+            continue;
+        }
+
+        let Some(signal) = take_pending(&process) else {
+            return;
+        };
+
+        let action = process.signals.action(signal);
+
+        match action.handler {
+            SIG_IGN => {}
+            SIG_DFL => match signal.default_action() {
+                DefaultAction::Ignore | DefaultAction::Continue => {}
+                DefaultAction::Stop => {
+                    process.signals.stopped.store(true, Ordering::SeqCst);
+                }
+                DefaultAction::Terminate | DefaultAction::Core => {
+                    // exit_current does not return so nothing else would drop the reference
+                    drop(process);
+                    user::exit_current(Exit::Signal(signal));
+                }
+            },
+            _ => {
+                let info = SigInfo {
+                    signal: signal.number(),
+                    code: SI_USER,
+                    address: 0,
+                };
+
+                if enter_handler(&process, to, signal, action, info).is_err() {
+                    drop(process);
+                    user::exit_current(Exit::Signal(Signal::SIGSEGV));
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Sends the signal for `fault` to the running program and sets up its handler
+///
+/// Does not return if the signal is blocked or not handled, the program ends with [Exit::Fault]
+pub(crate) fn deliver_fault(to: &mut UserReturn, fault: UserFault) {
+    super::check_killed();
+
+    let Some(process) = super::current() else {
+        user::exit_current(Exit::Fault(fault));
+    };
+
+    let signal = fault.signal();
+    let action = process.signals.action(signal);
+
+    if matches!(action.handler, SIG_DFL | SIG_IGN) || process.blocked_signals() & signal.bit() != 0
+    {
+        // exit_current does not return so nothing else would drop the reference
+        drop(process);
+        user::exit_current(Exit::Fault(fault));
+    }
+
+    let info = SigInfo {
+        signal: signal.number(),
+        code: SI_FAULT,
+        address: match fault {
+            UserFault::PageFault(address) => address,
+            _ => 0,
+        },
+    };
+
+    if enter_handler(&process, to, signal, action, info).is_err() {
+        drop(process);
+        user::exit_current(Exit::Fault(fault));
+    }
+}
+
+/// The lowest pending signal that is not blocked, taken off the pending mask
+fn take_pending(process: &Process) -> Option<Signal> {
+    let blocked = process.signals.blocked();
+
+    loop {
+        let pending = process.signals.pending();
+        let deliverable = pending & !blocked;
+        if deliverable == 0 {
+            return None;
+        }
+
+        let bit = deliverable & deliverable.wrapping_neg();
+        if process
+            .signals
+            .pending
+            .compare_exchange(pending, pending & !bit, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return Signal::new(bit.trailing_zeros() as u64 + 1);
+        }
+    }
+}
+
+/// Blocks until the process is continued or killed, the parent is woken so it can see the process stopped
+fn wait_while_stopped(process: &Process) {
+    if let Some(parent) = process.parent().and_then(super::get) {
+        wake(&parent);
+    }
+
+    while process.signals.is_stopped() && !process.is_killed() {
+        thread::block();
+    }
+}
+
+/// Puts a [SignalFrame] on the user stack and makes `to` return into the trampoline
+fn enter_handler(
+    process: &Process,
+    to: &mut UserReturn,
+    signal: Signal,
+    action: SigAction,
+    info: SigInfo,
+) -> Result<(), BadAddress> {
+    let blocked = process.blocked_signals();
+
+    let address = to
+        .rsp
+        .wrapping_sub(RED_ZONE + size_of::<SignalFrame>() as u64)
+        & !0xF;
+
+    UserPtr::new(address).write(SignalFrame {
+        registers: SyscallFrame {
+            rip: to.rip,
+            rsp: to.rsp,
+            rflags: to.rflags,
+            ..Default::default()
+        },
+        blocked,
+        handler: action.handler,
+        info,
+        _reserved: 0,
+    })?;
+
+    let mut handler_blocked = blocked | action.mask;
+    if action.flags & SA_NODEFER == 0 {
+        handler_blocked |= signal.bit();
+    }
+    process.set_blocked_signals(handler_blocked);
+
+    if action.flags & SA_RESETHAND != 0 {
+        process.set_signal_action(signal, SigAction::DEFAULT);
+    }
+
+    to.rip = SIGNAL_TRAMPOLINE;
+    to.rsp = address;
+    to.rflags &= !(RFlags::TRAP_FLAG | RFlags::DIRECTION_FLAG).bits();
+
+    Ok(())
+}
+
+/// Goes back to the code a handler interrupted, `frame` is the `sigreturn` system call from the trampoline
+///
+/// Returns the interrupted `rax` so the system call result does not overwrite it
+pub(crate) fn sigreturn(frame: &mut SyscallFrame) -> Result<u64, BadAddress> {
+    let process = super::current().ok_or(BadAddress(frame.rsp))?;
+
+    let saved: SignalFrame = UserPtr::new(frame.rsp).read()?;
+
+    process.set_blocked_signals(saved.blocked);
+
+    // The selectors stay the user ones, the flags are checked on the way out of the system call
+    let (cs, ss) = (frame.cs, frame.ss);
+    *frame = saved.registers;
+    frame.cs = cs;
+    frame.ss = ss;
+
+    Ok(frame.rax)
+}
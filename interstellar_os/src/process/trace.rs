@@ -0,0 +1,686 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Tracing processes for debuggers, like `ptrace`
+//!
+//! A tracer attaches to a running process with [attach], which stops it the next time it runs in user mode, or starts
+//! a program traced with [crate::user::spawn_traced], which stops it before its first instruction. A traced process
+//! also stops when one of its threads single steps or hits a breakpoint, and stays stopped until the tracer resumes
+//! it with [resume]. While it is stopped the tracer can read and change the registers of the stopped thread.
+//! Memory can be read and changed at any time, writes go to the process's own copy of shared pages.
+//!
+//! Single stepping sets the trap flag so the CPU raises `#DB` after one instruction. A breakpoint replaces the first
+//! byte of an instruction with `int3` and the `#BP` handler moves the instruction pointer back to it before the
+//! tracer sees it. Resuming from a breakpoint puts the instruction back, steps over it and puts the `int3` back again.
+//!
+//! The tracer is another process or the kernel, for the console's `debug` command. Only the thread that stopped is
+//! held, the other threads of the process keep running.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::time::Duration;
+use spinning_top::Spinlock;
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use super::{Pid, Process, PROCESSES};
+use crate::syscall::{SyscallFrame, USER_FLAGS};
+use crate::thread::{self, ThreadId, WaitQueue, WaitResult};
+use crate::time::Instant;
+use crate::user::USER_END;
+
+/// The breakpoint instruction
+const INT3: u8 = 0xCC;
+
+const TRAP_FLAG: u64 = RFlags::TRAP_FLAG.bits();
+
+/// Who traces a process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Tracer {
+    Kernel,
+    Process(Pid),
+}
+
+/// Why a traced process stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// A thread is about to run its first instruction
+    Start,
+    /// The tracer attached
+    Interrupted,
+    /// A thread ran one instruction
+    Step,
+    /// A thread reached the breakpoint at the address
+    Breakpoint(u64),
+}
+
+impl StopReason {
+    /// The number `ptrace` returns for the stop
+    pub fn number(&self) -> u64 {
+        match self {
+            StopReason::Start => 0,
+            StopReason::Interrupted => 1,
+            StopReason::Step => 2,
+            StopReason::Breakpoint(_) => 3,
+        }
+    }
+}
+
+/// How a stopped process carries on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resume {
+    /// Until the next breakpoint
+    Continue,
+    /// For one instruction
+    Step,
+}
+
+/// A thread of a traced process held for the tracer
+#[derive(Debug, Clone, Copy)]
+pub struct Stop {
+    pub thread: ThreadId,
+    pub reason: StopReason,
+    /// Restored when the thread carries on
+    pub registers: SyscallFrame,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceError {
+    /// There is no process with that PID, or it has ended
+    NoSuchProcess,
+    /// The process is not traced by this tracer
+    NotTraced,
+    /// The process is traced already or is the tracer itself
+    AlreadyTraced,
+    /// The process has to be stopped for this
+    NotStopped,
+    /// The memory is not mapped in the process, or the registers would leave user space
+    BadAddress,
+    /// There is no breakpoint at the address
+    NoBreakpoint,
+    /// The process did not stop in time
+    TimedOut,
+    /// A signal arrived for the tracer while it was waiting
+    Interrupted,
+}
+
+/// The tracing state of a process
+pub(super) struct Tracing {
+    inner: Spinlock<Option<Trace>>,
+    /// Tracers waiting for the process to stop
+    stopped: WaitQueue,
+    /// Threads waiting for the tracer to resume them
+    resumed: WaitQueue,
+}
+
+struct Trace {
+    /// None once the tracer has detached, the state goes away when no thread needs it anymore
+    tracer: Option<Tracer>,
+    /// The tracer wants the process to stop
+    interrupt: bool,
+    stop: Option<Stop>,
+    /// Set by the tracer for the stopped thread to pick up, with the registers it carries on with
+    resume: Option<(Resume, SyscallFrame)>,
+    /// The byte each breakpoint's `int3` replaced
+    breakpoints: BTreeMap<u64, u8>,
+    /// A breakpoint taken out while its instruction is stepped over, and how the thread carries on afterwards
+    step_over: Option<(u64, Resume)>,
+}
+
+impl Trace {
+    fn new(tracer: Tracer) -> Self {
+        Trace {
+            tracer: Some(tracer),
+            interrupt: true,
+            stop: None,
+            resume: None,
+            breakpoints: BTreeMap::new(),
+            step_over: None,
+        }
+    }
+
+    /// A thread is stopped or has not picked up its resume yet
+    fn is_holding(&self) -> bool {
+        self.stop.is_some() || self.resume.is_some()
+    }
+}
+
+impl Tracing {
+    pub(super) fn new() -> Self {
+        Tracing {
+            inner: Spinlock::new(None),
+            stopped: WaitQueue::new(),
+            resumed: WaitQueue::new(),
+        }
+    }
+
+    /// A thread is held for the tracer
+    pub(super) fn is_stopped(&self) -> bool {
+        self.inner
+            .lock()
+            .as_ref()
+            .is_some_and(|trace| trace.stop.is_some())
+    }
+
+    /// A new program has no breakpoints
+    pub(super) fn forget_breakpoints(&self) {
+        if let Some(trace) = self.inner.lock().as_mut() {
+            trace.breakpoints.clear();
+            trace.step_over = None;
+        }
+    }
+}
+
+//####################################
+//        Tracers
+//####################################
+
+/// Starts tracing a process, it stops the next time one of its threads runs in user mode
+pub fn attach(pid: Pid, tracer: Tracer) -> Result<(), TraceError> {
+    let process = super::get(pid)
+        .filter(|process| process.exit_status().is_none())
+        .ok_or(TraceError::NoSuchProcess)?;
+
+    if tracer == Tracer::Process(pid) {
+        return Err(TraceError::AlreadyTraced);
+    }
+
+    let mut inner = process.tracing.inner.lock();
+    if inner.is_some() {
+        return Err(TraceError::AlreadyTraced);
+    }
+    *inner = Some(Trace::new(tracer));
+
+    Ok(())
+}
+
+/// Traces a process that has not started running yet, its first thread stops before its first instruction
+pub(crate) fn attach_new(process: &Process, tracer: Tracer) {
+    *process.tracing.inner.lock() = Some(Trace::new(tracer));
+}
+
+/// Stops tracing a process, its breakpoints are taken out and a stopped thread carries on
+pub fn detach(pid: Pid, tracer: Tracer) -> Result<(), TraceError> {
+    let process = traced(pid, tracer)?;
+
+    {
+        let mut inner = process.tracing.inner.lock();
+        let trace = inner.as_mut().ok_or(TraceError::NotTraced)?;
+
+        for (&addr, &original) in trace.breakpoints.iter() {
+            let _ = write(&process, addr, &[original]);
+        }
+        trace.breakpoints.clear();
+        trace.tracer = None;
+        trace.interrupt = false;
+
+        if let Some(stop) = trace.stop.take() {
+            trace.resume = Some((Resume::Continue, stop.registers));
+        } else if !trace.is_holding() && trace.step_over.is_none() {
+            *inner = None;
+        }
+    }
+
+    process.tracing.resumed.wake_all();
+    Ok(())
+}
+
+/// Blocks until the process is stopped and returns the stop, for at most `timeout` if it is not None
+///
+/// A process tracer stops waiting when it gets a signal
+pub fn wait(pid: Pid, tracer: Tracer, timeout: Option<Duration>) -> Result<Stop, TraceError> {
+    let process = traced(pid, tracer)?;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let interrupted = || match tracer {
+        Tracer::Kernel => false,
+        Tracer::Process(_) => {
+            super::current().is_some_and(|me| me.is_killed() || me.has_deliverable_signal())
+        }
+    };
+
+    loop {
+        let stop = {
+            let inner = process.tracing.inner.lock();
+            let trace = inner
+                .as_ref()
+                .filter(|trace| trace.tracer == Some(tracer))
+                .ok_or(TraceError::NotTraced)?;
+            trace.stop
+        };
+
+        if let Some(stop) = stop {
+            return Ok(stop);
+        }
+        if process.exit_status().is_some() {
+            return Err(TraceError::NoSuchProcess);
+        }
+
+        let result = process.tracing.stopped.wait_if(
+            || {
+                process.exit_status().is_none()
+                    && process
+                        .tracing
+                        .inner
+                        .lock()
+                        .as_ref()
+                        .is_some_and(|trace| trace.stop.is_none())
+            },
+            deadline,
+            interrupted,
+        );
+
+        match result {
+            WaitResult::TimedOut => return Err(TraceError::TimedOut),
+            WaitResult::Interrupted => return Err(TraceError::Interrupted),
+            _ => {}
+        }
+    }
+}
+
+/// Lets the stopped thread carry on
+pub fn resume(pid: Pid, tracer: Tracer, how: Resume) -> Result<(), TraceError> {
+    let process = traced(pid, tracer)?;
+
+    {
+        let mut inner = process.tracing.inner.lock();
+        let trace = inner.as_mut().ok_or(TraceError::NotTraced)?;
+        let stop = trace.stop.take().ok_or(TraceError::NotStopped)?;
+        trace.resume = Some((how, stop.registers));
+    }
+
+    process.tracing.resumed.wake_all();
+    Ok(())
+}
+
+/// The registers of the stopped thread
+pub fn registers(pid: Pid, tracer: Tracer) -> Result<SyscallFrame, TraceError> {
+    let process = traced(pid, tracer)?;
+
+    let inner = process.tracing.inner.lock();
+    let stop = inner.as_ref().and_then(|trace| trace.stop.as_ref());
+    stop.map(|stop| stop.registers)
+        .ok_or(TraceError::NotStopped)
+}
+
+/// Changes the registers the stopped thread carries on with
+///
+/// The selectors cannot be changed and only the flags a program may set are kept
+pub fn set_registers(pid: Pid, tracer: Tracer, registers: SyscallFrame) -> Result<(), TraceError> {
+    // Returning to a kernel or non-canonical address would fault in ring 0
+    if registers.rip >= USER_END || registers.rsp >= USER_END {
+        return Err(TraceError::BadAddress);
+    }
+
+    let process = traced(pid, tracer)?;
+
+    let mut inner = process.tracing.inner.lock();
+    let stop = inner
+        .as_mut()
+        .and_then(|trace| trace.stop.as_mut())
+        .ok_or(TraceError::NotStopped)?;
+
+    let (cs, ss, rflags) = (stop.registers.cs, stop.registers.ss, stop.registers.rflags);
+    stop.registers = registers;
+    stop.registers.cs = cs;
+    stop.registers.ss = ss;
+    stop.registers.rflags = (registers.rflags & USER_FLAGS) | (rflags & !USER_FLAGS);
+
+    Ok(())
+}
+
+/// Copies memory of the process at `addr` into `buffer`, breakpoints read as the bytes they replaced
+pub fn read_memory(
+    pid: Pid,
+    tracer: Tracer,
+    addr: u64,
+    buffer: &mut [u8],
+) -> Result<(), TraceError> {
+    let process = traced(pid, tracer)?;
+
+    let inner = process.tracing.inner.lock();
+    let trace = inner.as_ref().ok_or(TraceError::NotTraced)?;
+
+    let start = VirtAddr::try_new(addr).map_err(|_| TraceError::BadAddress)?;
+    process
+        .with_address_space(|space| space.read(start, buffer))
+        .ok_or(TraceError::NoSuchProcess)?
+        .map_err(|_| TraceError::BadAddress)?;
+
+    let end = addr.saturating_add(buffer.len() as u64);
+    for (&at, &original) in trace.breakpoints.range(addr..end) {
+        buffer[(at - addr) as usize] = original;
+    }
+
+    Ok(())
+}
+
+/// Copies `data` into the memory of the process at `addr`, breakpoints in the range stay set
+pub fn write_memory(pid: Pid, tracer: Tracer, addr: u64, data: &[u8]) -> Result<(), TraceError> {
+    let process = traced(pid, tracer)?;
+
+    let mut inner = process.tracing.inner.lock();
+    let trace = inner.as_mut().ok_or(TraceError::NotTraced)?;
+
+    let mut data = Vec::from(data);
+    let end = addr.saturating_add(data.len() as u64);
+    for (&at, original) in trace.breakpoints.range_mut(addr..end) {
+        let byte = &mut data[(at - addr) as usize];
+        *original = *byte;
+        *byte = INT3;
+    }
+
+    write(&process, addr, &data)
+}
+
+/// Puts a breakpoint on the instruction at `addr`
+pub fn set_breakpoint(pid: Pid, tracer: Tracer, addr: u64) -> Result<(), TraceError> {
+    let process = traced(pid, tracer)?;
+
+    let mut inner = process.tracing.inner.lock();
+    let trace = inner.as_mut().ok_or(TraceError::NotTraced)?;
+    if trace.breakpoints.contains_key(&addr) {
+        return Ok(());
+    }
+
+    let mut original = [0];
+    let start = VirtAddr::try_new(addr).map_err(|_| TraceError::BadAddress)?;
+    process
+        .with_address_space(|space| space.read(start, &mut original))
+        .ok_or(TraceError::NoSuchProcess)?
+        .map_err(|_| TraceError::BadAddress)?;
+
+    write(&process, addr, &[INT3])?;
+    trace.breakpoints.insert(addr, original[0]);
+
+    Ok(())
+}
+
+/// Takes out the breakpoint at `addr`
+pub fn remove_breakpoint(pid: Pid, tracer: Tracer, addr: u64) -> Result<(), TraceError> {
+    let process = traced(pid, tracer)?;
+
+    let mut inner = process.tracing.inner.lock();
+    let trace = inner.as_mut().ok_or(TraceError::NotTraced)?;
+    let original = trace
+        .breakpoints
+        .remove(&addr)
+        .ok_or(TraceError::NoBreakpoint)?;
+
+    // The instruction is back already while it is stepped over
+    if trace.step_over.is_none_or(|(at, _)| at != addr) {
+        write(&process, addr, &[original])?;
+    }
+
+    Ok(())
+}
+
+/// Addresses of the breakpoints in the process
+pub fn breakpoints(pid: Pid, tracer: Tracer) -> Result<Vec<u64>, TraceError> {
+    let process = traced(pid, tracer)?;
+
+    let inner = process.tracing.inner.lock();
+    let trace = inner.as_ref().ok_or(TraceError::NotTraced)?;
+    Ok(trace.breakpoints.keys().copied().collect())
+}
+
+/// Detaches from every process traced by a process that has ended
+pub(super) fn tracer_ended(pid: Pid) {
+    let processes: Vec<Arc<Process>> = PROCESSES.lock().values().cloned().collect();
+
+    for process in processes {
+        let _ = detach(process.pid, Tracer::Process(pid));
+    }
+}
+
+/// The process if `tracer` traces it
+fn traced(pid: Pid, tracer: Tracer) -> Result<Arc<Process>, TraceError> {
+    let process = super::get(pid).ok_or(TraceError::NoSuchProcess)?;
+
+    let traced = process
+        .tracing
+        .inner
+        .lock()
+        .as_ref()
+        .is_some_and(|trace| trace.tracer == Some(tracer));
+
+    if traced {
+        Ok(process)
+    } else {
+        Err(TraceError::NotTraced)
+    }
+}
+
+/// Changes the program's memory for the tracer
+fn write(process: &Process, addr: u64, data: &[u8]) -> Result<(), TraceError> {
+    let start = VirtAddr::try_new(addr).map_err(|_| TraceError::BadAddress)?;
+
+    process
+        .with_address_space(|space| space.write_private(start, data))
+        .ok_or(TraceError::NoSuchProcess)?
+        .map_err(|_| TraceError::BadAddress)
+}
+
+//####################################
+//        Traced threads
+//####################################
+
+/// True if the tracer wants the process to stop
+///
+/// Called on the way back to user mode, the thread single steps so the debug exception stops it with every register
+pub(crate) fn stop_requested(process: &Process) -> bool {
+    process
+        .tracing
+        .inner
+        .lock()
+        .as_ref()
+        .is_some_and(|trace| trace.interrupt)
+}
+
+/// Stops a new thread of a traced process before its first instruction, `registers` are the ones it starts with
+///
+/// Called by the thread itself, returns at once if the process is not traced or the tracer did not ask it to stop
+pub(crate) fn thread_start(process: &Process, registers: &mut SyscallFrame) {
+    if stop_requested(process) {
+        stop(process, registers, StopReason::Start);
+    }
+}
+
+/// Handles `#DB` from user mode, `frame` has every register of the thread that single stepped
+///
+/// Returns false if the process is not traced, the program gets SIGTRAP then
+pub(crate) fn single_step(frame: &mut SyscallFrame) -> bool {
+    let Some(process) = super::current() else {
+        return false;
+    };
+
+    let reason = {
+        let mut inner = process.tracing.inner.lock();
+        let Some(trace) = inner.as_mut() else {
+            return false;
+        };
+
+        let reason = match trace.step_over.take() {
+            Some((addr, resume)) => {
+                if trace.breakpoints.contains_key(&addr) {
+                    let _ = write(&process, addr, &[INT3]);
+                }
+                (resume == Resume::Step).then_some(StopReason::Step)
+            }
+            None => Some(StopReason::Step),
+        };
+
+        // Attaching asks for a stop after one instruction
+        let reason = if trace.interrupt {
+            Some(StopReason::Interrupted)
+        } else {
+            reason
+        };
+
+        // A detached tracer was waiting for the step over to finish
+        if trace.tracer.is_none() {
+            if !trace.is_holding() {
+                *inner = None;
+            }
+            frame.rflags &= !TRAP_FLAG;
+            return true;
+        }
+
+        reason
+    };
+
+    frame.rflags &= !TRAP_FLAG;
+
+    if let Some(reason) = reason {
+        stop(&process, frame, reason);
+    }
+
+    true
+}
+
+/// Handles `#BP` from user mode, `frame` has every register of the thread that ran `int3`
+///
+/// Returns false if the `int3` is not a breakpoint set by the tracer, the program gets SIGTRAP then
+pub(crate) fn breakpoint(frame: &mut SyscallFrame) -> bool {
+    let Some(process) = super::current() else {
+        return false;
+    };
+
+    // The instruction pointer is after the int3
+    let addr = frame.rip.wrapping_sub(1);
+
+    let ours = process
+        .tracing
+        .inner
+        .lock()
+        .as_ref()
+        .is_some_and(|trace| trace.breakpoints.contains_key(&addr));
+    if !ours {
+        return false;
+    }
+
+    frame.rip = addr;
+    stop(&process, frame, StopReason::Breakpoint(addr));
+
+    true
+}
+
+/// Holds the running thread until the tracer resumes it, then `frame` has the registers to carry on with
+///
+/// Returns early if the process is killed or the tracer detaches
+fn stop(process: &Process, frame: &mut SyscallFrame, reason: StopReason) {
+    let tracing = &process.tracing;
+    frame.rflags &= !TRAP_FLAG;
+
+    // One thread is held at a time, others stopping at the same time wait their turn
+    loop {
+        {
+            let mut inner = tracing.inner.lock();
+            let Some(trace) = inner.as_mut().filter(|trace| trace.tracer.is_some()) else {
+                return;
+            };
+
+            if !trace.is_holding() {
+                trace.interrupt = false;
+                trace.stop = Some(Stop {
+                    thread: thread::current_id(),
+                    reason,
+                    registers: *frame,
+                });
+                break;
+            }
+        }
+
+        if process.is_killed() {
+            return;
+        }
+
+        tracing.resumed.wait_if(
+            || {
+                tracing
+                    .inner
+                    .lock()
+                    .as_ref()
+                    .is_some_and(|trace| trace.is_holding())
+            },
+            None,
+            || process.is_killed(),
+        );
+    }
+
+    tracing.stopped.wake_all();
+
+    let mut step = false;
+
+    loop {
+        {
+            let mut inner = tracing.inner.lock();
+            let Some(trace) = inner.as_mut() else {
+                return;
+            };
+
+            if let Some((how, registers)) = trace.resume.take() {
+                *frame = registers;
+                step = how == Resume::Step;
+
+                // The instruction under a breakpoint runs with the int3 taken out for one step
+                if let Some(&original) = trace.breakpoints.get(&frame.rip) {
+                    if write(process, frame.rip, &[original]).is_ok() {
+                        trace.step_over = Some((frame.rip, how));
+                        step = true;
+                    }
+                }
+
+                if trace.tracer.is_none() && trace.step_over.is_none() {
+                    *inner = None;
+                }
+                break;
+            }
+
+            if process.is_killed() {
+                trace.stop = None;
+                break;
+            }
+        }
+
+        tracing.resumed.wait_if(
+            || {
+                tracing
+                    .inner
+                    .lock()
+                    .as_ref()
+                    .is_some_and(|trace| trace.resume.is_none())
+            },
+            None,
+            || process.is_killed(),
+        );
+    }
+
+    // Let the next thread waiting to stop have its turn
+    tracing.resumed.wake_all();
+
+    if step {
+        frame.rflags |= TRAP_FLAG;
+    } else {
+        frame.rflags &= !TRAP_FLAG;
+    }
+}
+
+/// Wakes tracers waiting for a process that has ended
+pub(super) fn ended(process: &Process) {
+    process.tracing.stopped.wake_all();
+}
@@ -0,0 +1,497 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Linux x86_64 system calls for programs built for Linux
+//!
+//! Statically linked Linux programs, like ones built with musl, run unchanged with the system calls a C library
+//! needs to start, allocate, read files from the initrd and write to the console. The registers are the same as
+//! for the interstellar system calls but the numbers are Linux's, and a failed call returns a negated Linux error
+//! number. System calls that are not in [LINUX_SYSCALLS] fail with ENOSYS.
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+use super::{
+    page_flags, sys_brk, sys_exit, sys_getpid, sys_gettid, sys_munmap, sys_thread_exit, sys_write,
+    write_console, Args, Syscall, SyscallError, SyscallFrame, SyscallResult, PROT_EXEC, PROT_READ,
+    PROT_WRITE,
+};
+use crate::drivers::screen::framebuffer::FRAMEBUFFER;
+use crate::process::file::{OpenFile, SeekFrom};
+use crate::process::{self, Handle};
+use crate::thread;
+use crate::user::{read_c_string, MapError, UserPtr, UserSlice, USER_END};
+
+pub const SYS_READ: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_OPEN: u64 = 2;
+pub const SYS_CLOSE: u64 = 3;
+pub const SYS_LSEEK: u64 = 8;
+pub const SYS_MMAP: u64 = 9;
+pub const SYS_MUNMAP: u64 = 11;
+pub const SYS_BRK: u64 = 12;
+pub const SYS_IOCTL: u64 = 16;
+pub const SYS_WRITEV: u64 = 20;
+pub const SYS_GETPID: u64 = 39;
+pub const SYS_EXIT: u64 = 60;
+pub const SYS_ARCH_PRCTL: u64 = 158;
+pub const SYS_GETTID: u64 = 186;
+pub const SYS_SET_TID_ADDRESS: u64 = 218;
+pub const SYS_EXIT_GROUP: u64 = 231;
+pub const SYS_OPENAT: u64 = 257;
+
+/// Linux error numbers
+pub const ENOENT: u64 = 2;
+pub const ESRCH: u64 = 3;
+pub const EINTR: u64 = 4;
+pub const ENOEXEC: u64 = 8;
+pub const EBADF: u64 = 9;
+pub const ECHILD: u64 = 10;
+pub const EAGAIN: u64 = 11;
+pub const ENOMEM: u64 = 12;
+pub const EFAULT: u64 = 14;
+pub const EINVAL: u64 = 22;
+pub const ENOTTY: u64 = 25;
+pub const EROFS: u64 = 30;
+pub const ENOSYS: u64 = 38;
+pub const ETIMEDOUT: u64 = 110;
+
+/// `openat` directory that means the working directory, which is always the root
+pub const AT_FDCWD: i64 = -100;
+
+/// `open` flags
+pub const O_ACCMODE: u64 = 3;
+pub const O_RDONLY: u64 = 0;
+pub const O_CREAT: u64 = 0o100;
+pub const O_TRUNC: u64 = 0o1000;
+
+/// `lseek` whence values
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// `mmap` flags
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// `ioctl` request for the terminal size
+pub const TIOCGWINSZ: u64 = 0x5413;
+
+/// `arch_prctl` codes for the FS and GS base
+pub const ARCH_SET_GS: u64 = 0x1001;
+pub const ARCH_SET_FS: u64 = 0x1002;
+pub const ARCH_GET_FS: u64 = 0x1003;
+pub const ARCH_GET_GS: u64 = 0x1004;
+
+/// Most buffers `writev` takes, Linux's IOV_MAX
+pub const IOV_MAX: usize = 1024;
+
+/// Longest path `open` and `openat` accept, not counting the NUL
+pub const PATH_MAX: usize = 4096;
+
+/// The console size `TIOCGWINSZ` reports when there is no framebuffer
+const DEFAULT_WINDOW_SIZE: (usize, usize) = (80, 25);
+
+/// A Linux system call number with its handler
+pub struct LinuxSyscall {
+    pub number: u64,
+    pub syscall: Syscall,
+}
+
+/// The Linux system calls that are implemented
+pub static LINUX_SYSCALLS: [LinuxSyscall; 17] = [
+    LinuxSyscall {
+        number: SYS_READ,
+        syscall: Syscall {
+            name: "read",
+            handler: sys_read,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_WRITE,
+        syscall: Syscall {
+            name: "write",
+            handler: sys_write,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_OPEN,
+        syscall: Syscall {
+            name: "open",
+            handler: sys_open,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_CLOSE,
+        syscall: Syscall {
+            name: "close",
+            handler: sys_close,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_LSEEK,
+        syscall: Syscall {
+            name: "lseek",
+            handler: sys_lseek,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_MMAP,
+        syscall: Syscall {
+            name: "mmap",
+            handler: sys_mmap,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_MUNMAP,
+        syscall: Syscall {
+            name: "munmap",
+            handler: sys_munmap,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_BRK,
+        syscall: Syscall {
+            name: "brk",
+            handler: sys_brk,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_IOCTL,
+        syscall: Syscall {
+            name: "ioctl",
+            handler: sys_ioctl,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_WRITEV,
+        syscall: Syscall {
+            name: "writev",
+            handler: sys_writev,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_GETPID,
+        syscall: Syscall {
+            name: "getpid",
+            handler: sys_getpid,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_EXIT,
+        syscall: Syscall {
+            name: "exit",
+            handler: sys_thread_exit,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_ARCH_PRCTL,
+        syscall: Syscall {
+            name: "arch_prctl",
+            handler: sys_arch_prctl,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_GETTID,
+        syscall: Syscall {
+            name: "gettid",
+            handler: sys_gettid,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_SET_TID_ADDRESS,
+        syscall: Syscall {
+            name: "set_tid_address",
+            handler: sys_set_tid_address,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_EXIT_GROUP,
+        syscall: Syscall {
+            name: "exit_group",
+            handler: sys_exit,
+        },
+    },
+    LinuxSyscall {
+        number: SYS_OPENAT,
+        syscall: Syscall {
+            name: "openat",
+            handler: sys_openat,
+        },
+    },
+];
+
+fn find(number: u64) -> Option<&'static Syscall> {
+    LINUX_SYSCALLS
+        .iter()
+        .find(|entry| entry.number == number)
+        .map(|entry| &entry.syscall)
+}
+
+/// Name of a Linux system call for logs and tracing
+pub fn name(number: u64) -> Option<&'static str> {
+    find(number).map(|syscall| syscall.name)
+}
+
+/// Runs Linux system call `number`
+pub fn dispatch(frame: &mut SyscallFrame, number: u64, args: Args) -> SyscallResult {
+    match find(number) {
+        Some(syscall) => (syscall.handler)(frame, args),
+        None => Err(SyscallError::NoSuchSyscall),
+    }
+}
+
+/// The Linux error number for an error
+pub fn errno(err: SyscallError) -> u64 {
+    match err {
+        SyscallError::NoSuchSyscall => ENOSYS,
+        SyscallError::BadAddress => EFAULT,
+        SyscallError::BadFileDescriptor => EBADF,
+        SyscallError::InvalidArgument => EINVAL,
+        SyscallError::NoSuchProcess => ESRCH,
+        SyscallError::NoChildren => ECHILD,
+        SyscallError::NoSuchFile => ENOENT,
+        SyscallError::NotExecutable => ENOEXEC,
+        SyscallError::OutOfMemory => ENOMEM,
+        SyscallError::Interrupted => EINTR,
+        SyscallError::WouldBlock => EAGAIN,
+        SyscallError::TimedOut => ETIMEDOUT,
+        SyscallError::ReadOnly => EROFS,
+        SyscallError::NotATerminal => ENOTTY,
+    }
+}
+
+/// The value a failed Linux system call puts in `rax`
+pub fn to_return_value(err: SyscallError) -> u64 {
+    (-(errno(err) as i64)) as u64
+}
+
+fn current_handle(fd: u64) -> Result<Handle, SyscallError> {
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+    process.handle(fd).ok_or(SyscallError::BadFileDescriptor)
+}
+
+fn sys_read(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let buffer = args.slice(1);
+
+    match current_handle(args.u64(0))? {
+        // Programs cannot read the keyboard yet, the console is always at its end
+        Handle::Console => Ok(0),
+        Handle::File(file) => {
+            let mut data = vec![0; buffer.len().min(file.data().len())];
+            let len = file.read(&mut data);
+
+            if let Err(err) = buffer.write(&data[..len]) {
+                let _ = file.seek(SeekFrom::Current(-(len as i64)));
+                return Err(err.into());
+            }
+
+            Ok(len as u64)
+        }
+    }
+}
+
+/// Opens a file from the initrd for reading
+fn open(path: u64, flags: u64) -> SyscallResult {
+    let path = read_c_string(path, PATH_MAX)?.ok_or(SyscallError::InvalidArgument)?;
+    let path = String::from_utf8(path).map_err(|_| SyscallError::NoSuchFile)?;
+
+    // The initrd cannot be changed
+    if flags & O_ACCMODE != O_RDONLY || flags & (O_CREAT | O_TRUNC) != 0 {
+        return Err(SyscallError::ReadOnly);
+    }
+
+    let file = OpenFile::open(&path).ok_or(SyscallError::NoSuchFile)?;
+
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+    Ok(process.open_handle(Handle::File(Arc::new(file))))
+}
+
+fn sys_open(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    open(args.u64(0), args.u64(1))
+}
+
+fn sys_openat(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    // There are no directories to open yet so paths are always from the root
+    if args.i64(0) != AT_FDCWD {
+        return Err(SyscallError::BadFileDescriptor);
+    }
+
+    open(args.u64(1), args.u64(2))
+}
+
+fn sys_close(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+
+    match process.close_handle(args.u64(0)) {
+        true => Ok(0),
+        false => Err(SyscallError::BadFileDescriptor),
+    }
+}
+
+fn sys_lseek(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (offset, whence) = (args.i64(1), args.u64(2));
+
+    let Handle::File(file) = current_handle(args.u64(0))? else {
+        return Err(SyscallError::InvalidArgument);
+    };
+
+    let from = match whence {
+        SEEK_SET => SeekFrom::Start(offset),
+        SEEK_CUR => SeekFrom::Current(offset),
+        SEEK_END => SeekFrom::End(offset),
+        _ => return Err(SyscallError::InvalidArgument),
+    };
+
+    file.seek(from)
+        .map(|offset| offset as u64)
+        .ok_or(SyscallError::InvalidArgument)
+}
+
+fn sys_mmap(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (addr, len, prot, flags, fd, offset) = (
+        args.u64(0),
+        args.usize(1),
+        args.u64(2),
+        args.u64(3),
+        args.u64(4),
+        args.usize(5),
+    );
+
+    if len == 0 || offset % 4096 != 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    // Shared writable memory would be copied by fork, read-only mappings are the same either way
+    match flags & (MAP_SHARED | MAP_PRIVATE) {
+        MAP_PRIVATE => {}
+        MAP_SHARED if prot & PROT_WRITE == 0 => {}
+        _ => return Err(SyscallError::InvalidArgument),
+    }
+
+    let data = match flags & MAP_ANONYMOUS {
+        0 => match current_handle(fd)? {
+            Handle::File(file) => file.data().get(offset..).unwrap_or(&[]),
+            Handle::Console => return Err(SyscallError::InvalidArgument),
+        },
+        _ => &[],
+    };
+    let data = &data[..data.len().min(len)];
+
+    let fixed = flags & MAP_FIXED != 0;
+    if fixed && (addr % 4096 != 0 || addr >= USER_END) {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+    let start = process
+        .with_address_space(|space| {
+            let start = match fixed {
+                // Whatever was mapped there is replaced
+                true => {
+                    let start = VirtAddr::new(addr);
+                    space.unmap(start, len)?;
+                    space.map(start, len, page_flags(prot))?;
+                    start
+                }
+                false => space.map_anywhere(len, page_flags(prot))?,
+            };
+
+            // The new pages are not shared yet so they can be written even if the program cannot
+            space.write(start, data)?;
+            Ok::<VirtAddr, MapError>(start)
+        })
+        .ok_or(SyscallError::NoSuchProcess)??;
+
+    Ok(start.as_u64())
+}
+
+fn sys_ioctl(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (request, arg) = (args.u64(1), args.ptr::<[u16; 4]>(2));
+
+    match current_handle(args.u64(0))? {
+        Handle::Console if request == TIOCGWINSZ => {
+            let (columns, rows) = FRAMEBUFFER
+                .get()
+                .map_or(DEFAULT_WINDOW_SIZE, |framebuffer| {
+                    framebuffer.lock().text_size()
+                });
+
+            // struct winsize: rows, columns, then the size in pixels which is left at 0
+            arg.write([rows as u16, columns as u16, 0, 0])?;
+            Ok(0)
+        }
+        Handle::Console => Err(SyscallError::InvalidArgument),
+        Handle::File(_) => Err(SyscallError::NotATerminal),
+    }
+}
+
+fn sys_writev(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (iov, count) = (args.ptr::<[u64; 2]>(1), args.usize(2));
+    if count > IOV_MAX {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let Handle::Console = current_handle(args.u64(0))? else {
+        return Err(SyscallError::BadFileDescriptor);
+    };
+
+    // Gathered first so the buffers are printed together
+    let mut bytes = Vec::new();
+    for index in 0..count {
+        let [base, len] = iov.add(index)?.read()?;
+        if len != 0 {
+            bytes.extend(UserSlice::new(base, len as usize).read_to_vec()?);
+        }
+    }
+
+    write_console(&bytes);
+    Ok(bytes.len() as u64)
+}
+
+fn sys_arch_prctl(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (code, addr) = (args.u64(0), args.u64(1));
+
+    match code {
+        ARCH_SET_FS if addr < USER_END => {
+            thread::set_fs_base(addr);
+            Ok(0)
+        }
+        ARCH_GET_FS => {
+            UserPtr::<u64>::new(addr).write(thread::current().fs_base())?;
+            Ok(0)
+        }
+        ARCH_SET_GS if addr < USER_END => {
+            thread::set_gs_base(addr);
+            Ok(0)
+        }
+        ARCH_GET_GS => {
+            UserPtr::<u64>::new(addr).write(thread::gs_base())?;
+            Ok(0)
+        }
+        _ => Err(SyscallError::InvalidArgument),
+    }
+}
+
+/// Only returns the thread ID, threads are not started with Linux `clone` so nothing ever clears the address
+fn sys_set_tid_address(_frame: &mut SyscallFrame, _args: Args) -> SyscallResult {
+    Ok(thread::current_id().as_u64())
+}
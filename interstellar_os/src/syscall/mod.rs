@@ -14,102 +14,1032 @@
 //You should have received a copy of the GNU General Public License
 //along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+//! System calls from user programs
+//!
+//! User programs use the `syscall` instruction, `int 0x80` works the same way for code that cannot use it.
+//!
+//! | Register | On entry | On return |
+//! |----------|----------|-----------|
+//! | `rax` | system call number | result, or a negated [SyscallError] |
+//! | `rdi`, `rsi`, `rdx`, `r10`, `r8`, `r9` | arguments 1 to 6 | unchanged |
+//! | `rcx`, `r11` | | the return address and flags after `syscall`, unchanged after `int 0x80` |
+//!
+//! Every other register is preserved. The numbers index [SYSCALLS], programs built for Linux use the
+//! Linux numbers and error numbers from [linux] instead.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::time::Duration;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::cpu;
+use crate::gdt;
 use crate::other::log::LOGGER;
-use core::arch::asm;
-use x86_64::structures::idt::InterruptStackFrame;
+use crate::print;
+use crate::process::signal::{self, SigAction, Signal, UserReturn, SIG_DFL, SIG_IGN};
+use crate::process::trace::{self, Resume, TraceError, Tracer};
+use crate::process::{self, Handle, Pid, ProcessError};
+use crate::thread;
+use crate::user::access::Plain;
+use crate::user::futex::{self, FutexError};
+use crate::user::{
+    self, Abi, BadAddress, ElfError, Exit, MapError, UserFault, UserPtr, UserSlice, USER_END,
+};
+
+pub mod linux;
+
+/// Ends the program on every thread, the argument is the exit code
+pub const SYS_EXIT: u64 = 0;
+
+/// Writes a buffer to the console: file descriptor, pointer, length
+pub const SYS_WRITE: u64 = 1;
+
+/// Returns the PID of the calling process
+pub const SYS_GETPID: u64 = 2;
+
+/// Collects an ended child: PID or -1 for any child, pointer to the status or null, options.
+/// Returns the child's PID, or 0 with [WNOHANG] when no child has ended
+pub const SYS_WAITPID: u64 = 3;
+
+/// Sends a signal to a process: PID, signal. Signal 0 only checks that the process exists
+pub const SYS_KILL: u64 = 4;
+
+/// Copies the calling process, returns the child's PID in the parent and 0 in the child
+pub const SYS_FORK: u64 = 5;
+
+/// Replaces the program of the calling process: path, argument array, environment array.
+/// The arrays hold pointers to NUL terminated strings and end with a null pointer, either can be null.
+/// Does not return unless it fails, it fails while the process has other threads
+pub const SYS_EXEC: u64 = 6;
+
+/// Starts a program in a new child process: path, argument array, environment array like [SYS_EXEC].
+/// Returns the child's PID
+pub const SYS_SPAWN: u64 = 7;
+
+/// Changes what a signal does: signal, pointer to the new [SigAction] or null, pointer for the old one or null
+pub const SYS_SIGACTION: u64 = 8;
+
+/// Changes the blocked signals: [SIG_BLOCK], [SIG_UNBLOCK] or [SIG_SETMASK], pointer to the mask or null,
+/// pointer for the old mask or null
+pub const SYS_SIGPROCMASK: u64 = 9;
+
+/// Returns from a signal handler to the interrupted code, only made by the signal trampoline
+pub const SYS_SIGRETURN: u64 = 10;
+
+/// Starts a thread in the calling process that carries on from the system call: stack pointer, thread pointer
+/// or 0 for a new copy of the program's thread local storage, pointer to a `u32` set to 0 and woken as a futex
+/// when the thread ends or null. Returns the new thread's ID, and 0 in the new thread
+pub const SYS_CLONE: u64 = 11;
+
+/// Returns the ID of the calling thread
+pub const SYS_GETTID: u64 = 12;
+
+/// Ends the calling thread, the argument is the exit code of the process if it was the last thread
+pub const SYS_THREAD_EXIT: u64 = 13;
+
+/// Waits on or wakes a futex: address of an aligned `u32`, [FUTEX_WAIT] or [FUTEX_WAKE], value, timeout.
+/// Waiting blocks while the word holds the value for at most the timeout in nanoseconds, 0 waits for good.
+/// Waking wakes up to value threads and returns how many it woke
+pub const SYS_FUTEX: u64 = 14;
+
+/// Sets the FS base of the calling thread, where it keeps its thread pointer
+pub const SYS_SET_THREAD_POINTER: u64 = 15;
+
+/// Moves the end of the heap that starts after the program image: new end or 0 to only ask.
+/// Returns the end of the heap, which is left where it was if it could not be moved
+pub const SYS_BRK: u64 = 16;
+
+/// Maps zeroed pages where nothing is mapped yet: length, [PROT_WRITE] and [PROT_EXEC] flags.
+/// Returns where they start
+pub const SYS_MMAP: u64 = 17;
+
+/// Unmaps pages: page aligned address, length. Pages that are not mapped are skipped
+pub const SYS_MUNMAP: u64 = 18;
+
+/// Debugs another process: request, PID, address, data. See the `PTRACE_` requests,
+/// [process::trace] explains how tracing works
+pub const SYS_PTRACE: u64 = 19;
+
+/// `sigprocmask` adds the signals in the mask to the blocked ones
+pub const SIG_BLOCK: u64 = 0;
+
+/// `sigprocmask` takes the signals in the mask out of the blocked ones
+pub const SIG_UNBLOCK: u64 = 1;
+
+/// `sigprocmask` replaces the blocked signals with the mask
+pub const SIG_SETMASK: u64 = 2;
+
+/// `futex` operation that waits while the word holds the value
+pub const FUTEX_WAIT: u64 = 0;
+
+/// `futex` operation that wakes threads waiting on the word
+pub const FUTEX_WAKE: u64 = 1;
+
+/// `mmap` protection that lets the pages be read, they always can
+pub const PROT_READ: u64 = 1;
+
+/// `mmap` protection that lets the pages be written
+pub const PROT_WRITE: u64 = 2;
+
+/// `mmap` protection that lets the pages be run
+pub const PROT_EXEC: u64 = 4;
 
-type SyscallHandler = fn() -> i64;
-static SYSCALL_TABLE: [Option<SyscallHandler>; 1] = [Some(write_syscall_handler)];
+/// `ptrace` request to start tracing the process, it stops the next time it runs
+pub const PTRACE_ATTACH: u64 = 0;
 
-pub extern "x86-interrupt" fn syscall_handler(_stack_frame: InterruptStackFrame) {
+/// `ptrace` request to stop tracing the process, it carries on without its breakpoints
+pub const PTRACE_DETACH: u64 = 1;
+
+/// `ptrace` request to copy the word at the address in the process to the `u64` data points to
+pub const PTRACE_PEEK: u64 = 2;
+
+/// `ptrace` request to write data as a word at the address in the process
+pub const PTRACE_POKE: u64 = 3;
+
+/// `ptrace` request to copy the stopped thread's registers as a [SyscallFrame] to where data points
+pub const PTRACE_GETREGS: u64 = 4;
+
+/// `ptrace` request to set the stopped thread's registers from the [SyscallFrame] data points to
+pub const PTRACE_SETREGS: u64 = 5;
+
+/// `ptrace` request to let the stopped thread carry on until a breakpoint
+pub const PTRACE_CONT: u64 = 6;
+
+/// `ptrace` request to let the stopped thread run one instruction
+pub const PTRACE_SINGLESTEP: u64 = 7;
+
+/// `ptrace` request to put a breakpoint at the address
+pub const PTRACE_BREAKPOINT: u64 = 8;
+
+/// `ptrace` request to take out the breakpoint at the address
+pub const PTRACE_CLEAR_BREAKPOINT: u64 = 9;
+
+/// `ptrace` request that waits for the process to stop and returns why: 0 when a thread starts,
+/// 1 after attaching, 2 after a step and 3 at a breakpoint
+pub const PTRACE_WAIT: u64 = 10;
+
+/// Longest path or argument string [SYS_EXEC] and [SYS_SPAWN] accept, not counting the NUL
+pub const MAX_ARG_LENGTH: usize = 4096;
+
+/// Most arguments or environment strings [SYS_EXEC] and [SYS_SPAWN] accept
+pub const MAX_ARGS: usize = 256;
+
+/// `waitpid` option to return straight away when no child has ended
+pub const WNOHANG: u64 = 1;
+
+/// Returned negated in `rax` when a system call fails
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum SyscallError {
+    /// There is no system call with that number
+    NoSuchSyscall = 1,
+    /// A pointer argument is not mapped for the program
+    BadAddress = 2,
+    /// The file descriptor is not open
+    BadFileDescriptor = 3,
+    /// An argument is out of range
+    InvalidArgument = 4,
+    /// There is no process with that PID
+    NoSuchProcess = 5,
+    /// The process has no children to wait for
+    NoChildren = 6,
+    /// There is no file at the path
+    NoSuchFile = 7,
+    /// The file is not an executable this kernel can run
+    NotExecutable = 8,
+    /// There is not enough memory
+    OutOfMemory = 9,
+    /// A signal arrived while the system call was waiting
+    Interrupted = 10,
+    /// The futex did not hold the expected value
+    WouldBlock = 11,
+    /// The timeout passed
+    TimedOut = 12,
+    /// The file cannot be written
+    ReadOnly = 13,
+    /// The file descriptor is not a terminal
+    NotATerminal = 14,
+}
+
+impl SyscallError {
+    /// The value a failed system call puts in `rax`
+    pub fn to_return_value(self) -> u64 {
+        (-(self as i64)) as u64
+    }
+}
+
+impl From<BadAddress> for SyscallError {
+    fn from(_: BadAddress) -> Self {
+        SyscallError::BadAddress
+    }
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(err: ProcessError) -> Self {
+        match err {
+            ProcessError::NoSuchProcess | ProcessError::NotAProcess => SyscallError::NoSuchProcess,
+            ProcessError::NoChildren => SyscallError::NoChildren,
+            ProcessError::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+
+impl From<MapError> for SyscallError {
+    fn from(_: MapError) -> Self {
+        SyscallError::OutOfMemory
+    }
+}
+
+impl From<ElfError> for SyscallError {
+    fn from(err: ElfError) -> Self {
+        match err {
+            ElfError::Map(err) => err.into(),
+            ElfError::ArgumentsTooLong => SyscallError::InvalidArgument,
+            ElfError::MissingLibrary => SyscallError::NoSuchFile,
+            _ => SyscallError::NotExecutable,
+        }
+    }
+}
+
+impl From<FutexError> for SyscallError {
+    fn from(err: FutexError) -> Self {
+        match err {
+            FutexError::BadAddress => SyscallError::BadAddress,
+            FutexError::Unaligned => SyscallError::InvalidArgument,
+            FutexError::WouldBlock => SyscallError::WouldBlock,
+            FutexError::TimedOut => SyscallError::TimedOut,
+            FutexError::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+
+impl From<TraceError> for SyscallError {
+    fn from(err: TraceError) -> Self {
+        match err {
+            TraceError::NoSuchProcess => SyscallError::NoSuchProcess,
+            TraceError::BadAddress => SyscallError::BadAddress,
+            TraceError::TimedOut => SyscallError::TimedOut,
+            TraceError::Interrupted => SyscallError::Interrupted,
+            TraceError::NotTraced
+            | TraceError::AlreadyTraced
+            | TraceError::NotStopped
+            | TraceError::NoBreakpoint => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+pub type SyscallResult = Result<u64, SyscallError>;
+
+/// The argument registers of a system call in ABI order
+#[derive(Debug, Clone, Copy)]
+pub struct Args([u64; 6]);
+
+impl Args {
+    pub fn u64(&self, index: usize) -> u64 {
+        self.0[index]
+    }
+
+    pub fn i64(&self, index: usize) -> i64 {
+        self.0[index] as i64
+    }
+
+    pub fn usize(&self, index: usize) -> usize {
+        self.0[index] as usize
+    }
+
+    pub fn u32(&self, index: usize) -> Result<u32, SyscallError> {
+        u32::try_from(self.0[index]).map_err(|_| SyscallError::InvalidArgument)
+    }
+
+    pub fn ptr<T: Plain>(&self, index: usize) -> UserPtr<T> {
+        UserPtr::new(self.0[index])
+    }
+
+    /// A buffer given as a pointer argument followed by a length argument
+    pub fn slice(&self, index: usize) -> UserSlice {
+        UserSlice::new(self.0[index], self.0[index + 1] as usize)
+    }
+}
+
+/// An entry in the system call table, the handler can change the saved registers
+pub struct Syscall {
+    pub name: &'static str,
+    pub handler: fn(&mut SyscallFrame, Args) -> SyscallResult,
+}
+
+/// The system call table, indexed by system call number
+pub static SYSCALLS: [Syscall; 20] = [
+    Syscall {
+        name: "exit",
+        handler: sys_exit,
+    },
+    Syscall {
+        name: "write",
+        handler: sys_write,
+    },
+    Syscall {
+        name: "getpid",
+        handler: sys_getpid,
+    },
+    Syscall {
+        name: "waitpid",
+        handler: sys_waitpid,
+    },
+    Syscall {
+        name: "kill",
+        handler: sys_kill,
+    },
+    Syscall {
+        name: "fork",
+        handler: sys_fork,
+    },
+    Syscall {
+        name: "exec",
+        handler: sys_exec,
+    },
+    Syscall {
+        name: "spawn",
+        handler: sys_spawn,
+    },
+    Syscall {
+        name: "sigaction",
+        handler: sys_sigaction,
+    },
+    Syscall {
+        name: "sigprocmask",
+        handler: sys_sigprocmask,
+    },
+    Syscall {
+        name: "sigreturn",
+        handler: sys_sigreturn,
+    },
+    Syscall {
+        name: "clone",
+        handler: sys_clone,
+    },
+    Syscall {
+        name: "gettid",
+        handler: sys_gettid,
+    },
+    Syscall {
+        name: "thread_exit",
+        handler: sys_thread_exit,
+    },
+    Syscall {
+        name: "futex",
+        handler: sys_futex,
+    },
+    Syscall {
+        name: "set_thread_pointer",
+        handler: sys_set_thread_pointer,
+    },
+    Syscall {
+        name: "brk",
+        handler: sys_brk,
+    },
+    Syscall {
+        name: "mmap",
+        handler: sys_mmap,
+    },
+    Syscall {
+        name: "munmap",
+        handler: sys_munmap,
+    },
+    Syscall {
+        name: "ptrace",
+        handler: sys_ptrace,
+    },
+];
+
+/// Name of a system call for logs and tracing
+pub fn name(number: u64) -> Option<&'static str> {
+    SYSCALLS.get(number as usize).map(|syscall| syscall.name)
+}
+
+/// The registers of a user program saved by the system call entry, changes are restored when it returns
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    // Pushed by the CPU for `int 0x80`, built by the entry for `syscall`
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+unsafe impl Plain for SyscallFrame {}
+
+/// Flags a user program may set, anything else would change how the kernel runs it
+pub(crate) const USER_FLAGS: u64 = RFlags::CARRY_FLAG.bits()
+    | RFlags::PARITY_FLAG.bits()
+    | RFlags::AUXILIARY_CARRY_FLAG.bits()
+    | RFlags::ZERO_FLAG.bits()
+    | RFlags::SIGN_FLAG.bits()
+    | RFlags::TRAP_FLAG.bits()
+    | RFlags::DIRECTION_FLAG.bits()
+    | RFlags::OVERFLOW_FLAG.bits()
+    | RFlags::ALIGNMENT_CHECK.bits();
+
+/// User code and data selectors the `syscall` entry puts in the frame, [init] checks they match the GDT
+const USER_CODE_SELECTOR: u16 = 0x23;
+const USER_DATA_SELECTOR: u16 = 0x1b;
+
+global_asm!(
+    ".global syscall_interrupt_entry",
+    "syscall_interrupt_entry:",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    // The stack is 16 byte aligned here
+    "mov rdi, rsp",
+    "cld",
+    "call syscall_dispatch",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+    "",
+    // SYSCALL leaves the return address in rcx, the flags in r11 and interrupts masked by SFMASK.
+    // The stack pointer is still the user's until it is switched here.
+    // GS only points at the per-CPU block between the two swapgs, the kernel never reads the user's GS
+    ".global syscall_entry",
+    "syscall_entry:",
+    "swapgs",
+    "mov gs:[16], rsp",
+    "mov rsp, gs:[8]",
+    "mov rsp, [rsp]",
+    // The TSS stack is not always 16 byte aligned, the CPU aligns it itself for int 0x80
+    "and rsp, -16",
+    // Build the same frame int 0x80 gets from the CPU
+    "push 0x1b",
+    "push qword ptr gs:[16]",
+    "swapgs",
+    "push r11",
+    "push 0x23",
+    "push rcx",
+    "push rax",
+    "push rbx",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push rbp",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov rdi, rsp",
+    "cld",
+    "call syscall_dispatch",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    // Interrupts are off again so nothing runs on the user stack before SYSRET
+    "mov rcx, [rsp]",
+    "mov r11, [rsp + 16]",
+    "mov rsp, [rsp + 24]",
+    "sysretq",
+);
+
+extern "C" {
+    fn syscall_interrupt_entry();
+    fn syscall_entry();
+}
+
+/// Address of the `int 0x80` handler for the IDT
+pub(crate) fn interrupt_entry() -> VirtAddr {
+    VirtAddr::new(syscall_interrupt_entry as usize as u64)
+}
+
+/// Enables the `syscall` instruction on the running CPU, the GDT and per-CPU data must be set up
+pub fn init() {
     LOGGER
         .get()
         .unwrap()
         .lock()
-        .trace("Handling system call", file!(), line!());
-
-    let mut syscall_number: i32;
-    unsafe {
-        asm!("mov {0:r}, rax", out(reg) syscall_number);
-        if syscall_number != 1 {
-        } else if let Some(syscall_handler_fn) = SYSCALL_TABLE[syscall_number as usize] {
-            let result = syscall_handler_fn();
-            asm!("mov rax, {}", in(reg) result)
-        }
-    }
+        .trace("Enabling the syscall instruction", file!(), line!());
+
+    let selectors = gdt::selectors();
+    assert_eq!(selectors.user_code.0, USER_CODE_SELECTOR);
+    assert_eq!(selectors.user_data.0, USER_DATA_SELECTOR);
+
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.code,
+        selectors.data,
+    )
+    .expect("the GDT does not have the layout SYSCALL needs");
+
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+
+    // Handlers start with interrupts off, forward string operations and no single stepping
+    SFMask::write(
+        RFlags::INTERRUPT_FLAG
+            | RFlags::DIRECTION_FLAG
+            | RFlags::TRAP_FLAG
+            | RFlags::ALIGNMENT_CHECK
+            | RFlags::NESTED_TASK,
+    );
+
+    unsafe { Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS)) };
 }
 
-fn write_syscall_handler() -> i64 {
-    let text_pointer: *const u8;
-    let _text_length: u64;
-    unsafe {
-        asm!(
-            "mov rsi, {}", out(reg) text_pointer
-        );
-        asm!(
-            "mov rdx, {}", out(reg) _text_length
-        );
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    // Only user programs make system calls
+    if frame.cs & 3 != 3 {
+        frame.rax = SyscallError::NoSuchSyscall.to_return_value();
+        return;
+    }
+
+    // int 0x80 keeps the program's alignment check flag, with SMAP on that would let the kernel touch user pages
+    if cpu::protections().smap {
+        unsafe { asm!("clac", options(nostack)) };
+    }
+
+    // System calls can take a while so other threads can run in the meantime
+    interrupts::enable();
+
+    let args = Args([
+        frame.rdi, frame.rsi, frame.rdx, frame.r10, frame.r8, frame.r9,
+    ]);
+
+    let abi = process::current().map_or(Abi::Interstellar, |process| process.abi());
+
+    frame.rax = match abi {
+        Abi::Interstellar => match dispatch(frame, frame.rax, args) {
+            Ok(result) => result,
+            Err(err) => err.to_return_value(),
+        },
+        Abi::Linux => match linux::dispatch(frame, frame.rax, args) {
+            Ok(result) => result,
+            Err(err) => linux::to_return_value(err),
+        },
     };
-    unsafe {
-        let _text: u8 = *text_pointer;
+
+    // A handler may have changed where the program returns to, SYSRET to a kernel address would fault in ring 0
+    if frame.rip >= USER_END {
+        user::exit_current(Exit::Fault(UserFault::GeneralProtection));
+    }
+    frame.rflags = (frame.rflags & USER_FLAGS) | RFlags::INTERRUPT_FLAG.bits();
+
+    // Pending signals are delivered on the way out, the handler starts when the system call returns
+    let mut to = UserReturn {
+        rip: frame.rip,
+        rsp: frame.rsp,
+        rflags: frame.rflags,
     };
+    signal::deliver(&mut to);
+    (frame.rip, frame.rsp, frame.rflags) = (to.rip, to.rsp, to.rflags);
 
-    0 // Return value of type i64
+    interrupts::disable();
+}
+
+/// Runs system call `number` from the table
+pub fn dispatch(frame: &mut SyscallFrame, number: u64, args: Args) -> SyscallResult {
+    match SYSCALLS.get(number as usize) {
+        Some(syscall) => (syscall.handler)(frame, args),
+        None => Err(SyscallError::NoSuchSyscall),
+    }
 }
 
-pub fn test_syscall_handler_serial() {
-    unsafe {
-        let text = "Hello, World!\n";
-        let pointer = text.as_ptr();
-        let _ret: i32;
+/// A copy of what programs write to the console, kept while it is being captured
+static CONSOLE_CAPTURE: Spinlock<Option<Vec<u8>>> = Spinlock::new(None);
 
-        asm!("lock mov rax, {0:r}", in(reg) 0);
-        asm!("lock mov rdi, {0:r}", in(reg) 1);
-        asm!("lock mov rsi, {}", in(reg) pointer);
-        asm!("lock mov rdx, {}", in(reg) text.len());
-        asm!("INT $0x80");
-        asm!("lock mov {}, rcx", out(reg) _);
-        asm!("lock mov {}, r11", out(reg) _);
-        asm!("lock mov {0:r}, rax", lateout(reg) _ret);
+/// Prints what a program wrote to the console
+fn write_console(bytes: &[u8]) {
+    print!("{}", String::from_utf8_lossy(bytes));
+
+    interrupts::without_interrupts(|| {
+        if let Some(capture) = CONSOLE_CAPTURE.lock().as_mut() {
+            capture.extend_from_slice(bytes);
+        }
+    });
+}
+
+/// Starts keeping a copy of what programs write to the console, dropping any copy kept so far
+pub fn capture_console() {
+    interrupts::without_interrupts(|| *CONSOLE_CAPTURE.lock() = Some(Vec::new()));
+}
+
+/// Stops capturing the console and returns what was written since [capture_console]
+pub fn take_console_capture() -> Vec<u8> {
+    interrupts::without_interrupts(|| CONSOLE_CAPTURE.lock().take().unwrap_or_default())
+}
+
+fn sys_exit(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let exit = Exit::Code(args.i64(0));
+
+    if let Some(process) = process::current() {
+        process::exit_group(&process, exit);
+    }
+
+    user::exit_current(exit)
+}
+
+fn sys_write(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (fd, buffer) = (args.u64(0), args.slice(1));
+
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+
+    match process.handle(fd) {
+        Some(Handle::Console) => {
+            let bytes = buffer.read_to_vec()?;
+            write_console(&bytes);
+
+            Ok(bytes.len() as u64)
+        }
+        // Files from the initrd are only opened for reading
+        Some(Handle::File(_)) | None => Err(SyscallError::BadFileDescriptor),
+    }
+}
+
+fn sys_getpid(_frame: &mut SyscallFrame, _args: Args) -> SyscallResult {
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+    Ok(process.pid().as_u64())
+}
+
+fn sys_waitpid(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (pid, status, options) = (args.i64(0), args.ptr::<u64>(1), args.u64(2));
+
+    if options & !WNOHANG != 0 || pid == 0 || pid < -1 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let pid = (pid > 0).then(|| Pid::from_u64(pid as u64));
+
+    match process::wait(pid, options & WNOHANG == 0)? {
+        Some((child, exit)) => {
+            if !status.is_null() {
+                status.write(exit.wait_status())?;
+            }
+            Ok(child.as_u64())
+        }
+        None => Ok(0),
+    }
+}
+
+fn sys_kill(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (pid, signal) = (Pid::from_u64(args.u64(0)), args.u64(1));
+
+    if signal == 0 {
+        return process::get(pid)
+            .map(|_| 0)
+            .ok_or(SyscallError::NoSuchProcess);
+    }
+
+    let signal = Signal::new(signal).ok_or(SyscallError::InvalidArgument)?;
+    signal::send(pid, signal)?;
+    Ok(0)
+}
+
+fn sys_fork(frame: &mut SyscallFrame, _args: Args) -> SyscallResult {
+    process::current().ok_or(SyscallError::NoSuchProcess)?;
+
+    let child = user::fork(frame)?;
+    Ok(child.pid().as_u64())
+}
+
+fn sys_exec(frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+
+    // The other threads would carry on in the new program
+    if process.thread_count() > 1 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let program = Program::read(args)?;
+    let (args, env) = (program.args(), program.env());
+
+    user::exec(frame, &program.path, program.image, &args, &env)?;
+
+    // The new program starts with every register cleared
+    Ok(0)
+}
+
+fn sys_spawn(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    process::current().ok_or(SyscallError::NoSuchProcess)?;
+
+    let program = Program::read(args)?;
+    let (args, env) = (program.args(), program.env());
+    let child = user::spawn(
+        user::program_name(&program.path),
+        program.image,
+        &args,
+        &env,
+    )?;
+    Ok(child.pid().as_u64())
+}
+
+/// The arguments of [SYS_EXEC] and [SYS_SPAWN] copied into the kernel
+struct Program {
+    path: String,
+    image: &'static [u8],
+    args: Vec<String>,
+    env: Vec<String>,
+}
+
+impl Program {
+    fn read(args: Args) -> Result<Program, SyscallError> {
+        let path = read_string(args.u64(0))?;
+        let image = user::find_program(&path).ok_or(SyscallError::NoSuchFile)?;
+
+        Ok(Program {
+            path,
+            image,
+            args: read_string_array(args.ptr(1))?,
+            env: read_string_array(args.ptr(2))?,
+        })
+    }
+
+    fn args(&self) -> Vec<&str> {
+        self.args.iter().map(String::as_str).collect()
+    }
+
+    fn env(&self) -> Vec<&str> {
+        self.env.iter().map(String::as_str).collect()
+    }
+}
+
+/// A NUL terminated UTF-8 string from the program
+fn read_string(addr: u64) -> Result<String, SyscallError> {
+    let bytes = user::read_c_string(addr, MAX_ARG_LENGTH)?.ok_or(SyscallError::InvalidArgument)?;
+    String::from_utf8(bytes).map_err(|_| SyscallError::InvalidArgument)
+}
+
+/// The strings in a null terminated array of string pointers, a null array is empty
+fn read_string_array(array: UserPtr<u64>) -> Result<Vec<String>, SyscallError> {
+    let mut strings = Vec::new();
+    if array.is_null() {
+        return Ok(strings);
     }
+
+    loop {
+        let string = array.add(strings.len())?.read()?;
+        if string == 0 {
+            return Ok(strings);
+        }
+        if strings.len() == MAX_ARGS {
+            return Err(SyscallError::InvalidArgument);
+        }
+
+        strings.push(read_string(string)?);
+    }
+}
+
+fn sys_sigaction(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let signal = Signal::new(args.u64(0)).ok_or(SyscallError::InvalidArgument)?;
+    let (new, old) = (args.ptr::<SigAction>(1), args.ptr::<SigAction>(2));
+
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+    let current = process.signal_action(signal);
+
+    if !new.is_null() {
+        let action = new.read()?;
+        if !matches!(action.handler, SIG_DFL | SIG_IGN) && action.handler >= USER_END {
+            return Err(SyscallError::InvalidArgument);
+        }
+
+        process
+            .set_signal_action(signal, action)
+            .ok_or(SyscallError::InvalidArgument)?;
+    }
+
+    if !old.is_null() {
+        old.write(current)?;
+    }
+
+    Ok(0)
+}
+
+fn sys_sigprocmask(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (how, set, old) = (args.u64(0), args.ptr::<u64>(1), args.ptr::<u64>(2));
+
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+    let blocked = process.blocked_signals();
+
+    if !set.is_null() {
+        let set = set.read()?;
+        let new = match how {
+            SIG_BLOCK => blocked | set,
+            SIG_UNBLOCK => blocked & !set,
+            SIG_SETMASK => set,
+            _ => return Err(SyscallError::InvalidArgument),
+        };
+        process.set_blocked_signals(new);
+    }
+
+    if !old.is_null() {
+        old.write(blocked)?;
+    }
+
+    Ok(0)
+}
+
+fn sys_sigreturn(frame: &mut SyscallFrame, _args: Args) -> SyscallResult {
+    // A broken signal frame leaves nothing sensible to go back to
+    match signal::sigreturn(frame) {
+        Ok(rax) => Ok(rax),
+        Err(_) => user::exit_current(Exit::Signal(Signal::SIGSEGV)),
+    }
+}
+
+fn sys_clone(frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (stack, thread_pointer, clear_tid) = (args.u64(0), args.u64(1), args.ptr::<u32>(2));
+
+    process::current().ok_or(SyscallError::NoSuchProcess)?;
+
+    // Two threads on one stack would overwrite each other
+    if stack == 0 || stack >= USER_END || thread_pointer >= USER_END {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let thread_pointer = (thread_pointer != 0).then(|| VirtAddr::new(thread_pointer));
+    let thread = user::clone(frame, VirtAddr::new(stack), thread_pointer, clear_tid)?;
+    Ok(thread.as_u64())
+}
+
+fn sys_gettid(_frame: &mut SyscallFrame, _args: Args) -> SyscallResult {
+    Ok(thread::current_id().as_u64())
+}
+
+fn sys_thread_exit(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    user::exit_current(Exit::Code(args.i64(0)))
+}
+
+fn sys_futex(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (addr, op, value, timeout) = (args.u64(0), args.u64(1), args.u32(2)?, args.u64(3));
+
+    match op {
+        FUTEX_WAIT => {
+            let timeout = (timeout != 0).then(|| Duration::from_nanos(timeout));
+            futex::wait(addr, value, timeout)?;
+            Ok(0)
+        }
+        FUTEX_WAKE => Ok(futex::wake(addr, value as usize)? as u64),
+        _ => Err(SyscallError::InvalidArgument),
+    }
+}
+
+fn sys_set_thread_pointer(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let thread_pointer = args.u64(0);
+    if thread_pointer >= USER_END {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    thread::set_fs_base(thread_pointer);
+    Ok(0)
 }
 
-pub fn test_syscall_handler() {
-    unsafe {
-        let text = "Hello, World!\n";
-        let pointer = text.as_ptr();
-        let _ret: i32;
-        static mut SYSCALL_NUMBER: i32 = 1;
+fn sys_brk(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let end = args.u64(0);
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
 
-        //println!("inside of test_syscall_handler");
-        asm!("mov rax, {0:r}", in(reg) SYSCALL_NUMBER);
-        asm!("mov rdi, {0:r}", in(reg) 1);
-        asm!("mov rsi, {}", in(reg) pointer);
-        asm!("mov rdx, {}", in(reg) text.len());
-        //println!("SYSCALL: Sending INT 0x80");
+    let program_break = process
+        .with_address_space(|space| {
+            // Only a valid address can move the heap, failing leaves it where it was
+            if end != 0 && end < USER_END {
+                let _ = space.set_break(VirtAddr::new(end));
+            }
+            space.program_break()
+        })
+        .ok_or(SyscallError::NoSuchProcess)?;
 
-        asm!("INT $0x80");
+    Ok(program_break.as_u64())
+}
+
+fn sys_mmap(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (len, prot) = (args.usize(0), args.u64(1));
+    if len == 0 || prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+
+    let flags = page_flags(prot);
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+    let start = process
+        .with_address_space(|space| space.map_anywhere(len, flags))
+        .ok_or(SyscallError::NoSuchProcess)??;
 
-        //println!("SYSCALL: After Sending");
-        asm!("mov {}, rcx", out(reg) _);
-        asm!("mov {}, r11", out(reg) _);
-        asm!("mov {0:r}, rax", lateout(reg) _ret);
-        //println!("{}", ret);
+    Ok(start.as_u64())
+}
+
+/// The page flags for `mmap` protection flags
+fn page_flags(prot: u64) -> PageTableFlags {
+    let mut flags = PageTableFlags::empty();
+    if prot & PROT_WRITE != 0 {
+        flags.insert(PageTableFlags::WRITABLE);
     }
+    if prot & PROT_EXEC == 0 {
+        flags.insert(PageTableFlags::NO_EXECUTE);
+    }
+    flags
 }
 
-pub fn new_test_syscall_handler() {
-    let buf = "Hello From Asm!\n";
-    let _ret: i32;
-    unsafe {
-        asm!("int 0x80",
-            in("rax") 1,
-            in("rdi") 1,
-            in("rsi") buf.as_ptr(),
-            in("rdx") buf.len(),
-            out("rcx") _,
-            out("r11") _,
-            lateout("rax") _ret,
-        );
+fn sys_munmap(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (addr, len) = (args.u64(0), args.usize(1));
+    if addr % 4096 != 0 || addr >= USER_END {
+        return Err(SyscallError::InvalidArgument);
     }
+
+    let process = process::current().ok_or(SyscallError::NoSuchProcess)?;
+    process
+        .with_address_space(|space| space.unmap(VirtAddr::new(addr), len))
+        .ok_or(SyscallError::NoSuchProcess)?
+        .map_err(|_| SyscallError::InvalidArgument)?;
+
+    Ok(0)
+}
+
+fn sys_ptrace(_frame: &mut SyscallFrame, args: Args) -> SyscallResult {
+    let (request, pid, addr, data) = (
+        args.u64(0),
+        Pid::from_u64(args.u64(1)),
+        args.u64(2),
+        args.u64(3),
+    );
+    let tracer = Tracer::Process(process::current().ok_or(SyscallError::NoSuchProcess)?.pid());
+
+    match request {
+        PTRACE_ATTACH => trace::attach(pid, tracer)?,
+        PTRACE_DETACH => trace::detach(pid, tracer)?,
+        PTRACE_PEEK => {
+            let mut word = [0; 8];
+            trace::read_memory(pid, tracer, addr, &mut word)?;
+            UserPtr::new(data).write(u64::from_le_bytes(word))?;
+        }
+        PTRACE_POKE => trace::write_memory(pid, tracer, addr, &data.to_le_bytes())?,
+        PTRACE_GETREGS => UserPtr::new(data).write(trace::registers(pid, tracer)?)?,
+        PTRACE_SETREGS => trace::set_registers(pid, tracer, UserPtr::new(data).read()?)?,
+        PTRACE_CONT => trace::resume(pid, tracer, Resume::Continue)?,
+        PTRACE_SINGLESTEP => trace::resume(pid, tracer, Resume::Step)?,
+        PTRACE_BREAKPOINT => trace::set_breakpoint(pid, tracer, addr)?,
+        PTRACE_CLEAR_BREAKPOINT => trace::remove_breakpoint(pid, tracer, addr)?,
+        PTRACE_WAIT => return Ok(trace::wait(pid, tracer, None)?.reason.number()),
+        _ => return Err(SyscallError::InvalidArgument),
+    }
+
+    Ok(0)
 }
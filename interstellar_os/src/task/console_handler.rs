@@ -34,6 +34,9 @@ use super::keyboard::ScancodeStream;
 
 const CONSOLE_PRELINE: &str = "root@interstellar:~$ ";
 
+/// How long the text cursor stays on and off
+const CURSOR_BLINK_INTERVAL: crate::time::Duration = crate::time::Duration::from_millis(500);
+
 /// Prints to framebuffer
 #[macro_export]
 macro_rules! print {
@@ -48,7 +51,7 @@ macro_rules! print {
 /// Prints to framebuffer, appending a newline.
 #[macro_export]
 macro_rules! println {
-    () => ($crate::task::console_handler::print!("\n"));
+    () => ($crate::print!("\n"));
     ($fmt:expr) => {
         $crate::print!(concat!($fmt, "\n"))
     };
@@ -91,9 +94,9 @@ pub fn _print(args: core::fmt::Arguments, color: Option<Color>) {
     let binding = lines.clone();
     let chars_with_new_lines = binding.chars().enumerate().map(|(i, c)| {
         if i % max_chars_in_line == 0 && i != lines.len() - 1 {
-            format!("{}\n", c)
+            format!("{c}\n")
         } else {
-            format!("{}", c)
+            format!("{c}")
         }
     });
 
@@ -354,7 +357,7 @@ pub fn _print(args: core::fmt::Arguments, color: Option<Color>) {
     if let Some(col) = color {
         interrupts::without_interrupts(|| {
             if let Some(fb) = FRAMEBUFFER.get() {
-                let formatted = format!("{}", args);
+                let formatted = format!("{args}");
 
                 for c in formatted.as_str().chars() {
                     fb.lock().write_char(
@@ -454,6 +457,24 @@ impl ConsoleInfo {
     }
 }
 
+/// Blinks the text cursor where the next char will be printed
+pub async fn cursor_blink() {
+    let mut interval = crate::time::interval(CURSOR_BLINK_INTERVAL);
+    let mut visible = false;
+
+    loop {
+        interval.tick().await;
+
+        visible = !visible;
+
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            if let Some(fb) = FRAMEBUFFER.get() {
+                fb.lock().set_text_cursor_visible(visible);
+            }
+        });
+    }
+}
+
 pub async fn console_start() {
     print!("{}", CONSOLE_PRELINE);
     let mut scancode_stream = ScancodeStream::new();
@@ -14,119 +14,709 @@
 //You should have received a copy of the GNU General Public License
 //along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+//! Runs async tasks
+//!
+//! Each [Executor] owns a worker queue, normally one per CPU. Woken tasks go back on the queue of the worker
+//! they last ran on, and a worker with nothing to do steals half of the ready tasks from another worker.
+//! Tasks spawned with [spawn_local], [spawn_on] or a [Spawner] are pinned and never move to another worker.
+//!
+//! Every task records how often it is polled and woken, [stats] lists them.
+//! A poll that takes longer than the [slow_poll_warning] threshold is logged as it means the future is blocking.
+
+use super::join_handle::{JoinHandle, JoinState};
 use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+use crate::cpu;
+use crate::other::log::LOGGER;
+use crate::thread::{self, ThreadId};
+use crate::time::{Duration, Instant};
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    format,
+    string::{String, ToString},
+    sync::Arc,
+    task::Wake,
+    vec::Vec,
+};
 use core::future::Future;
-use core::task::{Context, Poll, Waker};
-use crossbeam_queue::ArrayQueue;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU64, AtomicUsize, Ordering};
+use core::task::{Context, Waker};
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+/// Most workers that can be made, each [Spawner::new] makes one
+pub const MAX_WORKERS: usize = 64;
+
+/// Every worker that has been made, workers are never freed so a waker can always find its task's worker
+static WORKERS: [AtomicPtr<Worker>; MAX_WORKERS] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; MAX_WORKERS];
+
+static WORKER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Every task that has not finished, used by [stats]. Only locked with interrupts disabled
+static TASKS: Spinlock<BTreeMap<TaskId, Arc<TaskHeader>>> = Spinlock::new(BTreeMap::new());
+
+/// Polls taking longer than this many nanoseconds are logged, 0 turns the warning off
+static SLOW_POLL_NANOS: AtomicU64 = AtomicU64::new(10_000_000);
+
+/// Statistics for one task
+#[derive(Debug, Clone)]
+pub struct TaskStats {
+    pub id: TaskId,
+    pub name: Option<String>,
+    /// Index of the worker the task belongs to
+    pub worker: usize,
+    /// The task never moves to another worker
+    pub pinned: bool,
+    /// Number of times the task has been polled
+    pub polls: u64,
+    /// Time spent polling the task
+    pub poll_time: Duration,
+    /// Number of polls that took longer than the [slow_poll_warning] threshold
+    pub slow_polls: u64,
+    /// Number of times the task has been woken
+    pub wakes: u64,
+    /// When the task was last woken
+    pub last_wake: Option<Instant>,
+}
+
+/// Statistics for every task that has not finished, ordered by ID
+pub fn stats() -> Vec<TaskStats> {
+    let tasks: Vec<Arc<TaskHeader>> =
+        interrupts::without_interrupts(|| TASKS.lock().values().cloned().collect());
+
+    tasks
+        .iter()
+        .map(|header| {
+            let last_wake = header.last_wake.load(Ordering::Relaxed);
+
+            TaskStats {
+                id: header.id,
+                name: header.name.clone(),
+                worker: header.worker.load(Ordering::Relaxed),
+                pinned: header.pinned,
+                polls: header.polls.load(Ordering::Relaxed),
+                poll_time: Duration::from_nanos(header.poll_nanos.load(Ordering::Relaxed)),
+                slow_polls: header.slow_polls.load(Ordering::Relaxed),
+                wakes: header.wakes.load(Ordering::Relaxed),
+                last_wake: (last_wake != 0).then_some(Instant::from_nanos(last_wake)),
+            }
+        })
+        .collect()
+}
+
+/// How long a single poll can take before a warning is logged, None if the warning is off
+pub fn slow_poll_warning() -> Option<Duration> {
+    match SLOW_POLL_NANOS.load(Ordering::Relaxed) {
+        0 => None,
+        nanos => Some(Duration::from_nanos(nanos)),
+    }
+}
+
+/// Sets how long a single poll can take before a warning is logged, None turns the warning off
+pub fn set_slow_poll_warning(threshold: Option<Duration>) {
+    let nanos = threshold.map_or(0, |threshold| (threshold.as_nanos() as u64).max(1));
+    SLOW_POLL_NANOS.store(nanos, Ordering::Relaxed);
+}
+
+/// Spawns a task that any executor can run and returns a [JoinHandle] for its output
+///
+/// The task starts on the executor running on this CPU, or the first one made on this CPU.
+/// This can be called from inside a running task
+///
+/// # Panics
+///
+/// If no [Executor] has been made on this CPU
+pub fn spawn<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    // The future is Send so it may move to another worker
+    current_worker().spawn(None, future, false)
+}
+
+/// Like [spawn] but the task is given a name that shows up in [stats]
+pub fn spawn_named<F>(name: &str, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    current_worker().spawn(Some(name.to_string()), future, false)
+}
+
+/// Spawns a task that only ever runs on the executor it was spawned on, so it does not have to be Send
+///
+/// # Panics
+///
+/// If no [Executor] has been made on this CPU, or if it is called from another thread than the one running it
+pub fn spawn_local<F>(future: F) -> JoinHandle<F::Output>
+where
+    F: Future + 'static,
+    F::Output: 'static,
+{
+    current_worker().spawn_local(future)
+}
+
+/// Spawns a task that only ever runs on the first executor made on `cpu`
+///
+/// # Panics
+///
+/// If no [Executor] has been made on that CPU
+pub fn spawn_on<F>(cpu: usize, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    worker_for_cpu(cpu)
+        .expect("spawn_on called for a CPU without an executor")
+        .spawn(None, future, true)
+}
+
+fn worker(index: usize) -> Option<&'static Worker> {
+    let worker = WORKERS.get(index)?.load(Ordering::Acquire);
+    unsafe { worker.as_ref() }
+}
+
+/// The first worker made on `cpu`
+fn worker_for_cpu(cpu: usize) -> Option<&'static Worker> {
+    (0..WORKER_COUNT.load(Ordering::Acquire))
+        .filter_map(worker)
+        .find(|worker| worker.cpu == cpu)
+}
+
+/// The worker running on this CPU, or the first one made on it
+fn current_worker() -> &'static Worker {
+    worker(cpu::current().current_worker.load(Ordering::SeqCst))
+        .or_else(|| worker_for_cpu(cpu::id()))
+        .expect("spawn called before an executor was created")
+}
+
+/// A task with the scheduling state shared by the executors, its wakers and its [JoinHandle]
+pub(super) struct TaskHeader {
+    id: TaskId,
+    name: Option<String>,
+    /// The task is in a run queue, stops it being queued twice
+    scheduled: AtomicBool,
+    /// Set by [JoinHandle::abort], the task is dropped the next time it is scheduled
+    aborted: AtomicBool,
+    /// The next task in the run queue
+    next: AtomicPtr<TaskHeader>,
+    /// Index of the worker the task belongs to, it is woken onto this worker's queue
+    worker: AtomicUsize,
+    /// Pinned tasks are never stolen by another worker
+    pinned: bool,
+    /// None once the task has finished or been aborted
+    task: Spinlock<Option<Task>>,
+    polls: AtomicU64,
+    /// Total time spent in poll
+    poll_nanos: AtomicU64,
+    slow_polls: AtomicU64,
+    wakes: AtomicU64,
+    /// Monotonic nanoseconds of the last wake, 0 if never woken
+    last_wake: AtomicU64,
+}
+
+// Only one worker polls a task at a time and pinned tasks, which may not be Send, never leave their worker
+unsafe impl Send for TaskHeader {}
+unsafe impl Sync for TaskHeader {}
+
+impl TaskHeader {
+    pub(super) fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub(super) fn abort(&self) {
+        self.aborted.store(true, Ordering::SeqCst);
+    }
+
+    /// Puts the task on its worker's queue unless it is already queued
+    ///
+    /// This only takes a reference count so it can be called from interrupt handlers on any CPU
+    fn schedule(self: &Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::SeqCst) {
+            let worker = worker(self.worker.load(Ordering::SeqCst)).unwrap();
+            worker.run_queue.push(self.clone());
+            worker.wake_owner();
+        }
+    }
+
+    fn record_wake(&self) {
+        self.wakes.fetch_add(1, Ordering::Relaxed);
+        self.last_wake
+            .store(crate::time::monotonic_nanos().max(1), Ordering::Relaxed);
+    }
+
+    /// Adds a poll to the task's statistics, logging it if it took too long
+    fn record_poll(&self, time: Duration) {
+        let nanos = time.as_nanos() as u64;
+
+        self.polls.fetch_add(1, Ordering::Relaxed);
+        self.poll_nanos.fetch_add(nanos, Ordering::Relaxed);
+
+        let threshold = SLOW_POLL_NANOS.load(Ordering::Relaxed);
+        if threshold != 0 && nanos > threshold {
+            self.slow_polls.fetch_add(1, Ordering::Relaxed);
+
+            LOGGER.get().unwrap().lock().warn(&format!(
+                "Task {} ({}) took {}us to poll, the future may be blocking",
+                self.id.as_u64(),
+                self.name.as_deref().unwrap_or("unnamed"),
+                time.as_micros()
+            ));
+        }
+    }
+}
+
+impl Wake for TaskHeader {
+    fn wake(self: Arc<Self>) {
+        self.record_wake();
+        self.schedule();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.record_wake();
+        self.schedule();
+    }
+}
+
+/// Tasks that have been woken
+///
+/// This is a lock-free stack linked through the task headers,
+/// pushing only takes a reference count so wakers can be called from interrupt handlers
+/// and there is no fixed number of tasks it can hold
+struct RunQueue {
+    head: AtomicPtr<TaskHeader>,
+}
+
+impl RunQueue {
+    fn new() -> RunQueue {
+        RunQueue {
+            head: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    fn push(&self, header: Arc<TaskHeader>) {
+        let node = Arc::into_raw(header) as *mut TaskHeader;
+
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            unsafe { (*node).next.store(head, Ordering::Relaxed) };
+
+            match self
+                .head
+                .compare_exchange_weak(head, node, Ordering::AcqRel, Ordering::Acquire)
+            {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.load(Ordering::Acquire).is_null()
+    }
+
+    /// Takes every queued task in the order they were woken
+    fn take_all(&self) -> VecDeque<Arc<TaskHeader>> {
+        let mut node = self.head.swap(ptr::null_mut(), Ordering::AcqRel);
+        let mut tasks = VecDeque::new();
+
+        while !node.is_null() {
+            // Every node was put in the queue with Arc::into_raw
+            let header = unsafe { Arc::from_raw(node) };
+            node = header.next.swap(ptr::null_mut(), Ordering::Relaxed);
+
+            // The stack is newest first
+            tasks.push_front(header);
+        }
+
+        tasks
+    }
+}
+
+impl Drop for RunQueue {
+    fn drop(&mut self) {
+        self.take_all();
+    }
+}
+
+/// The queues of one executor
+struct Worker {
+    index: usize,
+    /// CPU the worker was made on
+    cpu: usize,
+    /// Tasks woken since the executor last looked
+    run_queue: RunQueue,
+    /// Ready tasks the executor has taken from the run queue, other workers steal from here.
+    /// Only locked with interrupts disabled
+    local: Spinlock<VecDeque<Arc<TaskHeader>>>,
+    /// Tasks belonging to this worker that have not finished
+    tasks: AtomicUsize,
+    /// ID of the thread running the executor, 0 if threads are not running
+    owner: AtomicU64,
+}
+
+impl Worker {
+    /// Makes a worker for the running CPU
+    ///
+    /// # Panics
+    ///
+    /// If [MAX_WORKERS] workers have already been made
+    fn register() -> &'static Worker {
+        let index = WORKER_COUNT.fetch_add(1, Ordering::SeqCst);
+        assert!(index < MAX_WORKERS, "too many executor workers");
+
+        let worker = Box::leak(Box::new(Worker {
+            index,
+            cpu: cpu::id(),
+            run_queue: RunQueue::new(),
+            local: Spinlock::new(VecDeque::new()),
+            tasks: AtomicUsize::new(0),
+            owner: AtomicU64::new(0),
+        }));
+
+        WORKERS[index].store(worker, Ordering::Release);
+
+        worker
+    }
 
-const MAX_TASKS: usize = 100;
+    /// Adds a task to this worker, other workers may steal it unless it is pinned
+    fn spawn<F>(
+        &'static self,
+        name: Option<String>,
+        future: F,
+        pinned: bool,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.spawn_unchecked(name, future, pinned)
+    }
+
+    /// Adds a pinned task to this worker, the future is made and run on the thread running this worker
+    ///
+    /// # Panics
+    ///
+    /// If the running thread is not the one running this worker
+    fn spawn_local<F>(&'static self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let owner = self.owner.load(Ordering::SeqCst);
+        assert!(
+            owner == 0 || owner == thread::current_id().as_u64(),
+            "spawn_local called from another thread than the executor's"
+        );
+
+        self.spawn_unchecked(None, future, true)
+    }
+
+    /// Adds a task to this worker without checking the future can go where the task may run,
+    /// unpinned futures have to be Send as another worker may steal them
+    fn spawn_unchecked<F>(
+        &'static self,
+        name: Option<String>,
+        future: F,
+        pinned: bool,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+        F::Output: 'static,
+    {
+        let state = Arc::new(JoinState::new());
+        let task_state = state.clone();
+
+        let header = self.push(
+            name,
+            Task::new(async move {
+                let output = future.await;
+                task_state.finish(output);
+            }),
+            pinned,
+        );
+
+        JoinHandle::new(header.clone(), Waker::from(header), state)
+    }
+
+    fn push(&'static self, name: Option<String>, task: Task, pinned: bool) -> Arc<TaskHeader> {
+        let header = Arc::new(TaskHeader {
+            id: task.id,
+            name,
+            scheduled: AtomicBool::new(false),
+            aborted: AtomicBool::new(false),
+            next: AtomicPtr::new(ptr::null_mut()),
+            worker: AtomicUsize::new(self.index),
+            pinned,
+            task: Spinlock::new(Some(task)),
+            polls: AtomicU64::new(0),
+            poll_nanos: AtomicU64::new(0),
+            slow_polls: AtomicU64::new(0),
+            wakes: AtomicU64::new(0),
+            last_wake: AtomicU64::new(0),
+        });
+
+        self.tasks.fetch_add(1, Ordering::SeqCst);
+        interrupts::without_interrupts(|| TASKS.lock().insert(header.id, header.clone()));
+        header.schedule();
+
+        header
+    }
+
+    /// Wakes the executor's thread if it is blocked waiting for tasks
+    fn wake_owner(&self) {
+        let owner = self.owner.load(Ordering::SeqCst);
+        if owner != 0 {
+            thread::wake(ThreadId::from_u64(owner));
+        }
+    }
+
+    fn has_work(&self) -> bool {
+        !self.run_queue.is_empty()
+            || interrupts::without_interrupts(|| !self.local.lock().is_empty())
+    }
+
+    /// Moves woken tasks to the local queue
+    fn collect_woken(&self) {
+        let woken = self.run_queue.take_all();
+        if !woken.is_empty() {
+            interrupts::without_interrupts(|| self.local.lock().extend(woken));
+        }
+    }
+
+    fn pop_local(&self) -> Option<Arc<TaskHeader>> {
+        interrupts::without_interrupts(|| self.local.lock().pop_front())
+    }
+
+    /// Takes half of the ready tasks that are not pinned
+    ///
+    /// Only [Worker::spawn] makes tasks that are not pinned and it needs their futures to be Send
+    fn steal_half(&self) -> VecDeque<Arc<TaskHeader>> {
+        // Tasks woken since the owner last looked can be stolen too
+        self.collect_woken();
 
+        interrupts::without_interrupts(|| {
+            let mut local = self.local.lock();
+
+            let unpinned = local.iter().filter(|header| !header.pinned).count();
+            let mut wanted = unpinned.div_ceil(2);
+            let mut stolen = VecDeque::new();
+
+            local.retain(|header| {
+                if wanted > 0 && !header.pinned {
+                    stolen.push_back(header.clone());
+                    wanted -= 1;
+                    false
+                } else {
+                    true
+                }
+            });
+
+            stolen
+        })
+    }
+}
+
+/// Adds tasks to an [Executor], it can be cloned and used from inside running tasks
+///
+/// Tasks added with a spawner are pinned to its executor. A spawner can be sent to other threads so the futures have to be
+/// Send, use [spawn_local] for ones that are not. Spawning allocates so it must not be done from interrupt handlers
 #[derive(Clone)]
-#[repr(transparent)]
-pub struct Spawner(Arc<ArrayQueue<Task>>);
+pub struct Spawner(&'static Worker);
+
+impl Default for Spawner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Spawner {
-    pub fn new(capacity: usize) -> Self {
-        #[allow(clippy::arc_with_non_send_sync)]
-        Self(Arc::new(ArrayQueue::new(capacity)))
+    /// Makes a new worker queue on the running CPU
+    pub fn new() -> Self {
+        Self(Worker::register())
     }
-    pub fn add(&self, future: impl Future<Output = ()> + 'static) {
-        let _ = self.0.push(Task::new(future));
+
+    /// CPU the spawner's executor runs on
+    pub fn cpu(&self) -> usize {
+        self.0.cpu
+    }
+
+    /// Adds a task without a [JoinHandle]
+    pub fn add(&self, future: impl Future<Output = ()> + Send + 'static) {
+        self.0.push(None, Task::new(future), true);
+    }
+
+    /// Adds a task without a [JoinHandle], the name shows up in [stats]
+    pub fn add_named(&self, name: &str, future: impl Future<Output = ()> + Send + 'static) {
+        self.0.push(Some(name.to_string()), Task::new(future), true);
+    }
+
+    /// Adds a task and returns a [JoinHandle] to await its output or abort it
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.0.spawn(None, future, true)
     }
 }
 
 pub struct Executor {
-    tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    spawner: Spawner,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    worker: &'static Worker,
 }
 
 impl Executor {
+    /// Makes an executor that runs the tasks added to `spawner`
+    ///
+    /// If threads are running the executor's thread is woken when its tasks are
     pub fn new(spawner: Spawner) -> Self {
-        Self {
-            tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(MAX_TASKS)),
-            spawner,
-            waker_cache: BTreeMap::new(),
+        if thread::is_initialized() {
+            spawner
+                .0
+                .owner
+                .store(thread::current_id().as_u64(), Ordering::SeqCst);
         }
+
+        Self { worker: spawner.0 }
     }
-    fn spawn(&mut self, task: Task) {
-        let task_id = task.id;
-        if self.tasks.insert(task.id, task).is_some() {
-            panic!("Task with same ID already in task queue!!");
-        }
-        self.task_queue.push(task_id).expect("Task queue full!!");
+
+    /// Number of tasks belonging to this executor that have not finished
+    pub fn task_count(&self) -> usize {
+        self.worker.tasks.load(Ordering::SeqCst)
     }
+
     fn sleep_if_idle(&self) {
         use x86_64::instructions::interrupts::{self, enable_and_hlt};
+
+        // Other threads run while we wait, a wake that comes in before we block makes block return straight away
+        if self.worker.owner.load(Ordering::SeqCst) != 0 {
+            if !self.worker.has_work() {
+                thread::block();
+            }
+            return;
+        }
+
         interrupts::disable();
-        if self.task_queue.is_empty() {
+        if !self.worker.has_work() {
             enable_and_hlt();
         } else {
             interrupts::enable();
         }
     }
+
     pub fn run(&mut self) -> ! {
-        while let Some(e) = self.spawner.0.pop() {
-            self.spawn(e);
-        }
+        self.enter();
+
         loop {
             self.run_ready_tasks();
             self.sleep_if_idle();
         }
     }
 
-    fn run_ready_tasks(&mut self) {
-        let Self {
-            tasks,
-            task_queue,
-            waker_cache,
-            ..
-        } = self;
-
-        while let Some(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                None => continue, // task no longer exists
-            };
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-            match task.poll(&mut context) {
-                Poll::Ready(()) => {
-                    // task done -> remove it
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                Poll::Pending => {}
+    /// Runs tasks until none are ready here or on other workers and returns the number of tasks still waiting
+    pub fn run_until_idle(&mut self) -> usize {
+        let previous = self.enter();
+
+        while self.run_ready_tasks() {}
+
+        cpu::current()
+            .current_worker
+            .store(previous, Ordering::SeqCst);
+
+        self.task_count()
+    }
+
+    /// Makes this the executor [spawn] adds tasks to on this CPU, returning the one that was before
+    fn enter(&self) -> usize {
+        cpu::current()
+            .current_worker
+            .swap(self.worker.index, Ordering::SeqCst)
+    }
+
+    /// Runs the tasks that are ready, stealing some if there are none
+    ///
+    /// Returns false if there was nothing to run
+    fn run_ready_tasks(&mut self) -> bool {
+        self.worker.collect_woken();
+
+        if !self.worker.has_work() {
+            let stolen = self.steal();
+            if stolen.is_empty() {
+                return false;
             }
+            interrupts::without_interrupts(|| self.worker.local.lock().extend(stolen));
         }
-    }
-}
 
-struct TaskWaker {
-    task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-}
-impl Wake for TaskWaker {
-    fn wake(self: Arc<Self>) {
-        self.wake_task();
+        // Tasks woken while these run go on the run queue and wait for the next call
+        while let Some(header) = self.worker.pop_local() {
+            self.poll_task(header);
+        }
+
+        true
     }
-    fn wake_by_ref(self: &Arc<Self>) {
-        self.wake_task();
+
+    /// Takes tasks from the first worker that has some to spare
+    fn steal(&self) -> VecDeque<Arc<TaskHeader>> {
+        let count = WORKER_COUNT.load(Ordering::Acquire);
+
+        for offset in 1..count {
+            if let Some(victim) = worker((self.worker.index + offset) % count) {
+                let stolen = victim.steal_half();
+                if !stolen.is_empty() {
+                    return stolen;
+                }
+            }
+        }
+
+        VecDeque::new()
     }
-}
-impl TaskWaker {
-    fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
+
+    fn poll_task(&mut self, header: Arc<TaskHeader>) {
+        // Cleared before polling so a wake during the poll queues the task again
+        header.scheduled.store(false, Ordering::SeqCst);
+
+        // A stolen task belongs to us from now on
+        let home = header.worker.swap(self.worker.index, Ordering::SeqCst);
+        if home != self.worker.index {
+            self.worker.tasks.fetch_add(1, Ordering::SeqCst);
+            if let Some(home) = worker(home) {
+                home.tasks.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+
+        let mut slot = header.task.lock();
+
+        if header.aborted.load(Ordering::SeqCst) {
+            if slot.take().is_some() {
+                self.retire(&header);
+            }
+            return;
+        }
+
+        let Some(task) = slot.as_mut() else {
+            return; // task already finished
+        };
+
+        let waker = Waker::from(header.clone());
+        let mut context = Context::from_waker(&waker);
+
+        let start = Instant::now();
+        let ready = task.poll(&mut context).is_ready();
+        header.record_poll(start.elapsed());
+
+        if ready {
+            // task done -> drop it
+            *slot = None;
+            self.retire(&header);
+        }
     }
-    #[allow(clippy::new_ret_no_self)]
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+
+    /// Forgets a task that has finished or been aborted
+    fn retire(&self, header: &TaskHeader) {
+        self.worker.tasks.fetch_sub(1, Ordering::SeqCst);
+        interrupts::without_interrupts(|| TASKS.lock().remove(&header.id));
     }
 }
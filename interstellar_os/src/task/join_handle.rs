@@ -0,0 +1,151 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use super::executor::TaskHeader;
+use super::TaskId;
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use spinning_top::Spinlock;
+
+/// Returned by a [JoinHandle] when the task did not finish
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was stopped with [JoinHandle::abort]
+    Cancelled,
+}
+
+impl core::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "task was cancelled"),
+        }
+    }
+}
+
+/// The result of a task shared between the task and its [JoinHandle]
+pub(super) struct JoinState<T> {
+    inner: Spinlock<JoinInner<T>>,
+}
+
+struct JoinInner<T> {
+    result: Option<Result<T, JoinError>>,
+    /// The task waiting on the [JoinHandle]
+    waker: Option<Waker>,
+    /// Set once the result is known, it stays set after the result is taken
+    done: bool,
+}
+
+impl<T> JoinState<T> {
+    pub(super) fn new() -> JoinState<T> {
+        JoinState {
+            inner: Spinlock::new(JoinInner {
+                result: None,
+                waker: None,
+                done: false,
+            }),
+        }
+    }
+
+    /// Called by the task when its future completes
+    pub(super) fn finish(&self, output: T) {
+        self.complete(Ok(output));
+    }
+
+    /// Stores the result unless there already is one and wakes the waiting task
+    fn complete(&self, result: Result<T, JoinError>) {
+        let waker = {
+            let mut inner = self.inner.lock();
+            if inner.done {
+                return;
+            }
+            inner.done = true;
+            inner.result = Some(result);
+            inner.waker.take()
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Awaits the output of a spawned task
+///
+/// Dropping the handle detaches the task, it keeps running but its output is thrown away
+#[must_use = "dropping a JoinHandle detaches the task"]
+pub struct JoinHandle<T> {
+    header: Arc<TaskHeader>,
+    /// Wakes the task so the executor notices it has been aborted
+    task_waker: Waker,
+    state: Arc<JoinState<T>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub(super) fn new(
+        header: Arc<TaskHeader>,
+        task_waker: Waker,
+        state: Arc<JoinState<T>>,
+    ) -> JoinHandle<T> {
+        JoinHandle {
+            header,
+            task_waker,
+            state,
+        }
+    }
+
+    /// The ID of the task
+    pub fn id(&self) -> TaskId {
+        self.header.id()
+    }
+
+    /// Stops the task, it is dropped without being polled again
+    ///
+    /// Awaiting the handle gives [JoinError::Cancelled] unless the task had already finished
+    pub fn abort(&self) {
+        self.header.abort();
+        self.task_waker.wake_by_ref();
+        self.state.complete(Err(JoinError::Cancelled));
+    }
+
+    /// The task has finished or been aborted
+    pub fn is_finished(&self) -> bool {
+        self.state.inner.lock().done
+    }
+
+    /// Lets the task run on without a handle, the same as dropping it
+    pub fn detach(self) {}
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.state.inner.lock();
+
+        if let Some(result) = inner.result.take() {
+            return Poll::Ready(result);
+        }
+
+        if inner.done {
+            panic!("JoinHandle polled after completion");
+        }
+
+        inner.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
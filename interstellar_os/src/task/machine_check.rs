@@ -0,0 +1,31 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use crate::interrupts::machine_check::MachineCheckRecord;
+
+super::stream_processor_task!(MachineCheckRecord, 64);
+
+/// Logs corrected machine check errors found by [crate::interrupts::machine_check::poll]
+pub async fn process() {
+    let mut stream = TaskStream::new();
+    while let Some(record) = stream.next().await {
+        crate::other::log::LOGGER
+            .get()
+            .unwrap()
+            .lock()
+            .warn(&alloc::format!("Corrected machine check: {record}"));
+    }
+}
@@ -16,8 +16,14 @@
 
 pub mod console_handler;
 pub mod executor;
+pub mod join_handle;
 pub mod keyboard;
+pub mod machine_check;
 pub mod mouse;
+pub mod sync;
+pub use executor::{spawn, spawn_local, spawn_named, spawn_on};
+pub use join_handle::{JoinError, JoinHandle};
+
 use alloc::boxed::Box;
 use core::sync::atomic::{AtomicU64, Ordering};
 use core::{
@@ -40,6 +46,10 @@ impl TaskId {
         static NEXT_ID: AtomicU64 = AtomicU64::new(0);
         TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
     }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
 }
 
 pub struct Task {
@@ -0,0 +1,183 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Async synchronization primitives for kernel tasks
+//!
+//! These wait by returning [core::task::Poll::Pending] and registering the task's waker instead of spinning,
+//! so a task waiting for a lock or a message lets the executor run other tasks.
+//!
+//! The state of each primitive is protected by a spinlock that is only held with interrupts disabled,
+//! so an interrupt handler can never spin on a lock held by the task it interrupted.
+//! The following can be called from interrupt handlers as they never allocate:
+//!
+//! * [Notify::notify_one] and [Notify::notify_waiters]
+//! * [Semaphore::add_permits]
+//! * [mpsc::Sender::try_send] - bounded channels allocate their buffer up front
+//! * [oneshot::Sender::send]
+
+use alloc::collections::VecDeque;
+use core::task::Waker;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+pub mod mpsc;
+mod mutex;
+mod notify;
+pub mod oneshot;
+mod rwlock;
+mod semaphore;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::{Notified, Notify};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::{Acquire, Semaphore, SemaphorePermit, TryAcquireError};
+
+/// A spinlock that keeps interrupts disabled while it is held
+pub(crate) struct IrqLock<T> {
+    inner: Spinlock<T>,
+}
+
+impl<T> IrqLock<T> {
+    pub(crate) const fn new(value: T) -> IrqLock<T> {
+        IrqLock {
+            inner: Spinlock::new(value),
+        }
+    }
+
+    /// Runs `f` with the lock held
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        interrupts::without_interrupts(|| f(&mut self.inner.lock()))
+    }
+}
+
+/// A task waiting on a primitive
+struct Waiter {
+    id: u64,
+    waker: Option<Waker>,
+    /// Set when the waiter has been woken and should complete the next time it is polled
+    notified: bool,
+    /// Meaning depends on the primitive, for example the number of permits a [Semaphore] waiter wants
+    data: usize,
+}
+
+/// First in first out list of tasks waiting on a primitive
+///
+/// Adding a waiter allocates but waking and removing them does not
+struct WaiterList {
+    waiters: VecDeque<Waiter>,
+    next_id: u64,
+}
+
+impl WaiterList {
+    const fn new() -> WaiterList {
+        WaiterList {
+            waiters: VecDeque::new(),
+            next_id: 0,
+        }
+    }
+
+    /// Adds a waiter to the back of the list and returns its ID
+    fn push(&mut self, waker: &Waker, data: usize) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.waiters.push_back(Waiter {
+            id,
+            waker: Some(waker.clone()),
+            notified: false,
+            data,
+        });
+
+        id
+    }
+
+    /// Replaces the waker of a waiter if the task polling it changed
+    fn update(&mut self, id: u64, waker: &Waker) {
+        if let Some(waiter) = self.waiters.iter_mut().find(|waiter| waiter.id == id) {
+            if !waiter
+                .waker
+                .as_ref()
+                .is_some_and(|old| old.will_wake(waker))
+            {
+                waiter.waker = Some(waker.clone());
+            }
+        }
+    }
+
+    /// Removes a waiter returning it
+    fn remove(&mut self, id: u64) -> Option<Waiter> {
+        let index = self.waiters.iter().position(|waiter| waiter.id == id)?;
+        self.waiters.remove(index)
+    }
+
+    /// Removes a waiter if it has been notified
+    fn take_notified(&mut self, id: u64) -> Option<Waiter> {
+        let index = self
+            .waiters
+            .iter()
+            .position(|waiter| waiter.id == id && waiter.notified)?;
+        self.waiters.remove(index)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+
+    /// Wakes the first waiter that has not been notified yet and sets its data to `data`
+    ///
+    /// Returns false if there was nobody to wake
+    fn notify_one(&mut self, data: usize) -> bool {
+        match self.waiters.iter_mut().find(|waiter| !waiter.notified) {
+            Some(waiter) => {
+                waiter.notified = true;
+                waiter.data = data;
+                if let Some(waker) = waiter.waker.take() {
+                    waker.wake();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiter that has not been notified yet setting their data to `data`
+    fn notify_all(&mut self, data: usize) {
+        for waiter in self.waiters.iter_mut().filter(|waiter| !waiter.notified) {
+            waiter.notified = true;
+            waiter.data = data;
+            if let Some(waker) = waiter.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Hands out `available` to waiters in order until the next one wants more than is left
+    ///
+    /// A waiter's data is the amount it wants
+    fn grant(&mut self, available: &mut usize) {
+        for waiter in self.waiters.iter_mut().filter(|waiter| !waiter.notified) {
+            if waiter.data > *available {
+                break;
+            }
+
+            *available -= waiter.data;
+            waiter.notified = true;
+            if let Some(waker) = waiter.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
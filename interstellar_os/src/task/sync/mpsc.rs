@@ -0,0 +1,377 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Multi producer single consumer channels
+//!
+//! A bounded [channel] makes senders wait while it is full so a fast producer cannot use up the heap,
+//! an [unbounded_channel] never makes senders wait
+
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+use futures_util::stream::Stream;
+
+use super::{IrqLock, WaiterList};
+
+/// Returned when sending on a channel whose [Receiver] has been dropped or closed, holds the value that was not sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> core::fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "the channel is closed")
+    }
+}
+
+/// Returned by [Sender::try_send], holds the value that was not sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is full or other tasks are already waiting to send
+    Full(T),
+    /// The receiver has been dropped or closed
+    Closed(T),
+}
+
+impl<T> core::fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "the channel is full"),
+            TrySendError::Closed(_) => write!(f, "the channel is closed"),
+        }
+    }
+}
+
+/// Returned by [Receiver::try_recv]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The channel is empty but senders still exist
+    Empty,
+    /// The channel is empty and every sender has been dropped
+    Disconnected,
+}
+
+struct Chan<T> {
+    queue: VecDeque<T>,
+    /// None for unbounded channels
+    capacity: Option<usize>,
+    senders: usize,
+    rx_closed: bool,
+    rx_waker: Option<Waker>,
+    /// Tasks waiting for space in a bounded channel
+    send_waiters: WaiterList,
+}
+
+impl<T> Chan<T> {
+    fn has_space(&self) -> bool {
+        self.capacity
+            .is_none_or(|capacity| self.queue.len() < capacity)
+    }
+
+    /// Queues a value waking the receiver, the caller checks there is space
+    fn push(&mut self, value: T) {
+        self.queue.push_back(value);
+        if let Some(waker) = self.rx_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+type Shared<T> = Arc<IrqLock<Chan<T>>>;
+
+fn new_shared<T>(capacity: Option<usize>) -> Shared<T> {
+    Arc::new(IrqLock::new(Chan {
+        queue: VecDeque::with_capacity(capacity.unwrap_or(0)),
+        capacity,
+        senders: 1,
+        rx_closed: false,
+        rx_waker: None,
+        send_waiters: WaiterList::new(),
+    }))
+}
+
+/// Creates a channel that holds at most `capacity` values
+///
+/// The buffer is allocated here so [Sender::try_send] never allocates
+///
+/// # Panics
+///
+/// If `capacity` is 0
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity != 0, "mpsc channel capacity must not be 0");
+
+    let shared = new_shared(Some(capacity));
+
+    (
+        Sender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Creates a channel with no limit on the number of values it holds
+pub fn unbounded_channel<T>() -> (UnboundedSender<T>, Receiver<T>) {
+    let shared = new_shared(None);
+
+    (
+        UnboundedSender {
+            shared: shared.clone(),
+        },
+        Receiver { shared },
+    )
+}
+
+/// Sends values on a bounded [channel], clone it to get more senders
+pub struct Sender<T> {
+    shared: Shared<T>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value`, waiting while the channel is full
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        SendFuture {
+            shared: &self.shared,
+            value: Some(value),
+            id: None,
+        }
+        .await
+    }
+
+    /// Sends `value` if there is space straight away
+    ///
+    /// This does not allocate so it can be used from interrupt handlers
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        self.shared.with(|chan| {
+            if chan.rx_closed {
+                Err(TrySendError::Closed(value))
+            } else if !chan.send_waiters.is_empty() || !chan.has_space() {
+                Err(TrySendError::Full(value))
+            } else {
+                chan.push(value);
+                Ok(())
+            }
+        })
+    }
+
+    /// True if the receiver has been dropped or closed
+    pub fn is_closed(&self) -> bool {
+        self.shared.with(|chan| chan.rx_closed)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Sender<T> {
+        self.shared.with(|chan| chan.senders += 1);
+
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        drop_sender(&self.shared);
+    }
+}
+
+/// Sends values on an [unbounded_channel], clone it to get more senders
+pub struct UnboundedSender<T> {
+    shared: Shared<T>,
+}
+
+impl<T> UnboundedSender<T> {
+    /// Sends `value`, this never waits but may allocate
+    pub fn send(&self, value: T) -> Result<(), SendError<T>> {
+        self.shared.with(|chan| {
+            if chan.rx_closed {
+                Err(SendError(value))
+            } else {
+                chan.push(value);
+                Ok(())
+            }
+        })
+    }
+
+    /// True if the receiver has been dropped or closed
+    pub fn is_closed(&self) -> bool {
+        self.shared.with(|chan| chan.rx_closed)
+    }
+}
+
+impl<T> Clone for UnboundedSender<T> {
+    fn clone(&self) -> UnboundedSender<T> {
+        self.shared.with(|chan| chan.senders += 1);
+
+        UnboundedSender {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for UnboundedSender<T> {
+    fn drop(&mut self) {
+        drop_sender(&self.shared);
+    }
+}
+
+/// Wakes the receiver when the last sender is dropped so it sees the channel is disconnected
+fn drop_sender<T>(shared: &Shared<T>) {
+    shared.with(|chan| {
+        chan.senders -= 1;
+        if chan.senders == 0 {
+            if let Some(waker) = chan.rx_waker.take() {
+                waker.wake();
+            }
+        }
+    });
+}
+
+/// Waits for space in a bounded channel then sends the value
+struct SendFuture<'a, T> {
+    shared: &'a Shared<T>,
+    value: Option<T>,
+    /// Set once this is in the channel's send waiter list
+    id: Option<u64>,
+}
+
+// The value is only ever moved out, never pinned
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), SendError<T>>> {
+        let this = &mut *self;
+
+        this.shared.with(|chan| {
+            if chan.rx_closed {
+                if let Some(id) = this.id.take() {
+                    chan.send_waiters.remove(id);
+                }
+                return Poll::Ready(Err(SendError(this.value.take().unwrap())));
+            }
+
+            let our_turn = match this.id {
+                Some(id) => chan.send_waiters.take_notified(id).is_some(),
+                None => chan.send_waiters.is_empty(),
+            };
+
+            if our_turn {
+                this.id = None;
+
+                if chan.has_space() {
+                    chan.push(this.value.take().unwrap());
+                    return Poll::Ready(Ok(()));
+                }
+            }
+
+            match this.id {
+                Some(id) => chan.send_waiters.update(id, cx.waker()),
+                // A try_send took the space we were woken for so we go back in line
+                None => this.id = Some(chan.send_waiters.push(cx.waker(), 0)),
+            }
+
+            Poll::Pending
+        })
+    }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.shared.with(|chan| {
+                // Space we were woken for but never used goes to the next sender
+                if chan
+                    .send_waiters
+                    .remove(id)
+                    .is_some_and(|waiter| waiter.notified)
+                {
+                    chan.send_waiters.notify_one(0);
+                }
+            });
+        }
+    }
+}
+
+/// Receives values from a [channel] or [unbounded_channel]
+pub struct Receiver<T> {
+    shared: Shared<T>,
+}
+
+impl<T> Receiver<T> {
+    /// Waits for the next value, returns None once the channel is empty and every sender has been dropped
+    pub async fn recv(&mut self) -> Option<T> {
+        poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    /// Takes the next value if there is one
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.shared.with(|chan| match chan.queue.pop_front() {
+            Some(value) => {
+                chan.send_waiters.notify_one(0);
+                Ok(value)
+            }
+            None if chan.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        })
+    }
+
+    /// Polls for the next value, registering the task's waker if there is none
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.shared.with(|chan| match chan.queue.pop_front() {
+            Some(value) => {
+                chan.send_waiters.notify_one(0);
+                Poll::Ready(Some(value))
+            }
+            None if chan.senders == 0 => Poll::Ready(None),
+            None => {
+                if !chan
+                    .rx_waker
+                    .as_ref()
+                    .is_some_and(|old| old.will_wake(cx.waker()))
+                {
+                    chan.rx_waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        })
+    }
+
+    /// Stops any more values being sent, values already in the channel can still be received
+    pub fn close(&mut self) {
+        self.shared.with(|chan| {
+            chan.rx_closed = true;
+            chan.send_waiters.notify_all(0);
+        });
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.get_mut().poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
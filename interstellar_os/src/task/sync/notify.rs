@@ -0,0 +1,142 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use super::{IrqLock, WaiterList};
+
+/// Waiter data for a waiter woken by [Notify::notify_one]
+const NOTIFY_ONE: usize = 1;
+/// Waiter data for a waiter woken by [Notify::notify_waiters]
+const NOTIFY_WAITERS: usize = 2;
+
+/// Wakes tasks waiting for an event
+///
+/// If [Notify::notify_one] is called while no task is waiting a permit is stored
+/// and the next call to [Notify::notified] completes straight away
+pub struct Notify {
+    inner: IrqLock<NotifyInner>,
+}
+
+struct NotifyInner {
+    permit: bool,
+    waiters: WaiterList,
+}
+
+impl Notify {
+    pub const fn new() -> Notify {
+        Notify {
+            inner: IrqLock::new(NotifyInner {
+                permit: false,
+                waiters: WaiterList::new(),
+            }),
+        }
+    }
+
+    /// Waits for a notification
+    ///
+    /// The returned future only starts waiting when it is first polled
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            id: None,
+        }
+    }
+
+    /// Wakes the task that has been waiting longest, or stores a permit if nobody is waiting
+    ///
+    /// This does not allocate so it can be used from interrupt handlers
+    pub fn notify_one(&self) {
+        self.inner.with(|inner| {
+            if !inner.waiters.notify_one(NOTIFY_ONE) {
+                inner.permit = true;
+            }
+        });
+    }
+
+    /// Wakes every task that is waiting, no permit is stored
+    ///
+    /// This does not allocate so it can be used from interrupt handlers
+    pub fn notify_waiters(&self) {
+        self.inner
+            .with(|inner| inner.waiters.notify_all(NOTIFY_WAITERS));
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Notify {
+        Notify::new()
+    }
+}
+
+/// Future returned by [Notify::notified]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    /// Set once this is in the notify's waiter list
+    id: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let notify = self.notify;
+
+        let notified = notify.inner.with(|inner| match self.id {
+            Some(id) if inner.waiters.take_notified(id).is_some() => true,
+            Some(id) => {
+                inner.waiters.update(id, cx.waker());
+                false
+            }
+            None if inner.permit => {
+                inner.permit = false;
+                true
+            }
+            None => {
+                self.id = Some(inner.waiters.push(cx.waker(), 0));
+                false
+            }
+        });
+
+        if notified {
+            self.id = None;
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            self.notify.inner.with(|inner| {
+                // A notify_one we were given but never saw goes to the next waiter so it is not lost
+                let forward = inner
+                    .waiters
+                    .remove(id)
+                    .is_some_and(|waiter| waiter.notified && waiter.data == NOTIFY_ONE);
+
+                if forward && !inner.waiters.notify_one(NOTIFY_ONE) {
+                    inner.permit = true;
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A channel for sending a single value between tasks
+
+use alloc::sync::Arc;
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use super::IrqLock;
+
+/// Returned by the [Receiver] when the [Sender] was dropped without sending
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl core::fmt::Display for RecvError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "the sender was dropped without sending")
+    }
+}
+
+/// Returned by [Receiver::try_recv]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// Nothing has been sent yet
+    Empty,
+    /// The sender was dropped without sending
+    Closed,
+}
+
+struct Inner<T> {
+    value: Option<T>,
+    rx_waker: Option<Waker>,
+    tx_closed: bool,
+    rx_closed: bool,
+}
+
+/// Creates a oneshot channel
+///
+/// This is the only part that allocates, so the [Sender] can be moved into an interrupt handler
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(IrqLock::new(Inner {
+        value: None,
+        rx_waker: None,
+        tx_closed: false,
+        rx_closed: false,
+    }));
+
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+/// Sends a single value to the [Receiver]
+pub struct Sender<T> {
+    inner: Arc<IrqLock<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// Sends `value` waking the receiver, gives `value` back if the receiver was dropped
+    ///
+    /// This does not allocate so it can be used from interrupt handlers
+    pub fn send(self, value: T) -> Result<(), T> {
+        self.inner.with(|inner| {
+            if inner.rx_closed {
+                return Err(value);
+            }
+
+            inner.value = Some(value);
+            if let Some(waker) = inner.rx_waker.take() {
+                waker.wake();
+            }
+            Ok(())
+        })
+    }
+
+    /// True if the receiver has been dropped
+    pub fn is_closed(&self) -> bool {
+        self.inner.with(|inner| inner.rx_closed)
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        self.inner.with(|inner| {
+            inner.tx_closed = true;
+            if let Some(waker) = inner.rx_waker.take() {
+                waker.wake();
+            }
+        });
+    }
+}
+
+/// Receives the value sent by the [Sender], await this to wait for it
+pub struct Receiver<T> {
+    inner: Arc<IrqLock<Inner<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// Takes the value if it has been sent
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        self.inner.with(|inner| match inner.value.take() {
+            Some(value) => Ok(value),
+            None if inner.tx_closed => Err(TryRecvError::Closed),
+            None => Err(TryRecvError::Empty),
+        })
+    }
+
+    /// Stops the sender sending, a value that was already sent can still be received
+    pub fn close(&mut self) {
+        self.inner.with(|inner| inner.rx_closed = true);
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<T, RecvError>> {
+        self.inner.with(|inner| match inner.value.take() {
+            Some(value) => Poll::Ready(Ok(value)),
+            None if inner.tx_closed => Poll::Ready(Err(RecvError)),
+            None => {
+                if !inner
+                    .rx_waker
+                    .as_ref()
+                    .is_some_and(|old| old.will_wake(cx.waker()))
+                {
+                    inner.rx_waker = Some(cx.waker().clone());
+                }
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.with(|inner| inner.rx_closed = true);
+    }
+}
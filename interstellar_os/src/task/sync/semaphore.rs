@@ -0,0 +1,191 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use super::{IrqLock, WaiterList};
+
+/// Returned by [Semaphore::try_acquire] when the permits cannot be taken straight away
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryAcquireError {
+    /// There are not enough permits or other tasks are already waiting for them
+    NoPermits,
+}
+
+/// A counting semaphore
+///
+/// Waiting tasks are given permits in the order they started waiting,
+/// a task waiting for many permits stops later tasks taking the few that are free
+pub struct Semaphore {
+    inner: IrqLock<SemaphoreInner>,
+}
+
+struct SemaphoreInner {
+    permits: usize,
+    waiters: WaiterList,
+}
+
+impl Semaphore {
+    /// Creates a semaphore with `permits` free permits
+    pub const fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            inner: IrqLock::new(SemaphoreInner {
+                permits,
+                waiters: WaiterList::new(),
+            }),
+        }
+    }
+
+    /// Number of permits that are free
+    pub fn available_permits(&self) -> usize {
+        self.inner.with(|inner| inner.permits)
+    }
+
+    /// Waits for a permit
+    pub fn acquire(&self) -> Acquire<'_> {
+        self.acquire_many(1)
+    }
+
+    /// Waits for `permits` permits
+    pub fn acquire_many(&self, permits: usize) -> Acquire<'_> {
+        Acquire {
+            semaphore: self,
+            permits,
+            id: None,
+        }
+    }
+
+    /// Takes a permit if one is free and nobody is waiting
+    pub fn try_acquire(&self) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.try_acquire_many(1)
+    }
+
+    /// Takes `permits` permits if they are free and nobody is waiting
+    pub fn try_acquire_many(&self, permits: usize) -> Result<SemaphorePermit<'_>, TryAcquireError> {
+        self.inner.with(|inner| {
+            if inner.waiters.is_empty() && inner.permits >= permits {
+                inner.permits -= permits;
+                Ok(SemaphorePermit {
+                    semaphore: self,
+                    permits,
+                })
+            } else {
+                Err(TryAcquireError::NoPermits)
+            }
+        })
+    }
+
+    /// Adds permits waking any tasks that can now take them
+    ///
+    /// This does not allocate so it can be used from interrupt handlers
+    pub fn add_permits(&self, permits: usize) {
+        self.inner.with(|inner| {
+            inner.permits += permits;
+
+            let SemaphoreInner { permits, waiters } = inner;
+            waiters.grant(permits);
+        });
+    }
+}
+
+/// Permits taken from a [Semaphore], they are given back when this is dropped
+#[must_use = "the permits are released straight away if this is dropped"]
+pub struct SemaphorePermit<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+}
+
+impl SemaphorePermit<'_> {
+    /// Number of permits held
+    pub fn permits(&self) -> usize {
+        self.permits
+    }
+
+    /// Keeps the permits taken from the semaphore forever
+    pub fn forget(mut self) {
+        self.permits = 0;
+    }
+}
+
+impl Drop for SemaphorePermit<'_> {
+    fn drop(&mut self) {
+        if self.permits != 0 {
+            self.semaphore.add_permits(self.permits);
+        }
+    }
+}
+
+/// Future returned by [Semaphore::acquire] and [Semaphore::acquire_many]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+    permits: usize,
+    /// Set once this is in the semaphore's waiter list
+    id: Option<u64>,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = SemaphorePermit<'a>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<SemaphorePermit<'a>> {
+        let semaphore = self.semaphore;
+        let permits = self.permits;
+
+        let acquired = semaphore.inner.with(|inner| match self.id {
+            // The permits were taken from the semaphore for us when we were notified
+            Some(id) if inner.waiters.take_notified(id).is_some() => true,
+            Some(id) => {
+                inner.waiters.update(id, cx.waker());
+                false
+            }
+            None if inner.waiters.is_empty() && inner.permits >= permits => {
+                inner.permits -= permits;
+                true
+            }
+            None => {
+                self.id = Some(inner.waiters.push(cx.waker(), permits));
+                false
+            }
+        });
+
+        if acquired {
+            self.id = None;
+            Poll::Ready(SemaphorePermit { semaphore, permits })
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Acquire<'_> {
+    fn drop(&mut self) {
+        if let Some(id) = self.id {
+            let granted = self.semaphore.inner.with(|inner| {
+                inner
+                    .waiters
+                    .remove(id)
+                    .is_some_and(|waiter| waiter.notified)
+            });
+
+            // Permits handed to us that we never used go to the next waiter,
+            // and if we were at the front the waiters behind us may be able to go now
+            self.semaphore
+                .add_permits(if granted { self.permits } else { 0 });
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Saving and restoring the registers of a thread
+//!
+//! The kernel is built without SSE so only the general purpose registers need saving.
+//! A switch is an ordinary function call, so only the callee saved registers are pushed onto the old stack
+//! before the stack pointer is swapped, the compiler has already saved everything else.
+//! When a thread is preempted the switch happens inside the timer interrupt handler,
+//! which saves the rest of the registers of the interrupted code on the thread's own stack.
+
+use core::arch::global_asm;
+
+global_asm!(
+    ".global thread_switch_context",
+    "thread_switch_context:",
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    /// Pushes the callee saved registers, stores the stack pointer in `old` then loads `new` and pops them
+    fn thread_switch_context(old: *mut u64, new: u64);
+}
+
+/// Number of registers [thread_switch_context] pushes
+const SAVED_REGISTERS: usize = 6;
+
+/// Switches from the thread whose stack pointer is saved at `old` to the thread whose saved stack pointer is `new`
+///
+/// Returns when something switches back to the old thread
+///
+/// # Safety
+///
+/// Interrupts must be disabled and `new` must be a stack pointer saved by this function or made by [initial_stack]
+pub(super) unsafe fn switch(old: *mut u64, new: u64) {
+    thread_switch_context(old, new);
+}
+
+/// Sets up a new stack so switching to it starts running `entry`, returning the stack pointer to switch to
+///
+/// # Safety
+///
+/// `top` must be the 16 byte aligned top of a stack with room for a few words
+pub(super) unsafe fn initial_stack(top: u64, entry: extern "C" fn() -> !) -> u64 {
+    let mut stack = top as *mut u64;
+
+    // `entry` never returns, but a fake return address keeps the stack aligned as if it had been called
+    stack = stack.sub(1);
+    stack.write(0);
+
+    stack = stack.sub(1);
+    stack.write(entry as usize as u64);
+
+    // Zeroed registers for the switch to pop
+    for _ in 0..SAVED_REGISTERS {
+        stack = stack.sub(1);
+        stack.write(0);
+    }
+
+    stack as u64
+}
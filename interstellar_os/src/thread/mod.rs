@@ -0,0 +1,585 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Preemptive kernel threads
+//!
+//! Each thread has its own stack and is switched to and from with [context::switch].
+//! A thread runs until it yields, sleeps, blocks or exits, or until its time slice runs out
+//! while other threads are ready, in which case the LAPIC timer interrupt switches to the next one.
+//!
+//! Which thread runs next is decided by its [SchedClass], see [run_queue] for how.
+//!
+//! The code that calls [init] becomes the `kernel` thread, in the kernel this runs the async executor
+//! so all the async tasks share one thread. When no thread is ready the `idle` thread halts the CPU.
+//!
+//! Threads that wait for each other use a [WaitQueue].
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::registers::model_specific::{FsBase, GsBase};
+use x86_64::structures::paging::PhysFrame;
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::other::log::LOGGER;
+use crate::time::{timer, Instant};
+
+mod context;
+pub mod run_queue;
+mod scheduler;
+mod stack;
+mod wait_queue;
+
+pub use run_queue::SchedClass;
+pub use scheduler::{stats, ThreadStats};
+pub use wait_queue::{WaitQueue, WaitResult};
+
+use run_queue::SchedInfo;
+use stack::Stack;
+
+/// Stack size of threads made with [spawn]
+pub const DEFAULT_STACK_SIZE: usize = 64 * 1024;
+
+/// The idle thread only halts so it needs very little stack
+const IDLE_STACK_SIZE: usize = 16 * 1024;
+
+/// How long a thread runs before it is preempted if other threads are ready
+pub const TIME_SLICE: Duration = Duration::from_millis(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+
+    pub(crate) fn from_u64(id: u64) -> Self {
+        ThreadId(id)
+    }
+}
+
+impl core::fmt::Display for ThreadId {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ThreadState {
+    /// Waiting in the run queue
+    Ready,
+    Running,
+    /// Waiting for [wake]
+    Blocked,
+    /// Finished, the stack is freed once nothing refers to the thread
+    Dead,
+}
+
+impl ThreadState {
+    fn from_u8(value: u8) -> ThreadState {
+        match value {
+            0 => ThreadState::Ready,
+            1 => ThreadState::Running,
+            2 => ThreadState::Blocked,
+            _ => ThreadState::Dead,
+        }
+    }
+}
+
+/// A kernel thread
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: AtomicU8,
+    /// Set by [wake] when the thread is not blocked so its next [block] returns straight away
+    wake_pending: AtomicBool,
+    /// Saved stack pointer while the thread is not running
+    stack_pointer: UnsafeCell<u64>,
+    /// None for the kernel thread which uses the stack it was booted on
+    stack: Option<Stack>,
+    /// Taken and run when the thread first starts
+    entry: Spinlock<Option<Box<dyn FnOnce() + Send>>>,
+    /// Thread waiting in [JoinHandle::join], 0 if there is none
+    joiner: AtomicU64,
+    /// Only used with the scheduler locked
+    sched: UnsafeCell<SchedInfo>,
+    /// Physical address of the level 4 page table loaded while the thread runs, 0 for the kernel's
+    page_table: AtomicU64,
+    /// Pointer to the thread's user mode context while it is in user mode, 0 otherwise.
+    /// Its first field is the kernel stack pointer interrupts from user mode start at
+    user_context: AtomicU64,
+    /// PID of the process the thread runs, 0 for kernel threads
+    process: AtomicU64,
+    /// FS base for user mode, it holds the thread pointer of user thread local storage
+    fs_base: AtomicU64,
+    /// GS base for user mode, saved when the thread is switched out as the program can load GS itself
+    gs_base: AtomicU64,
+}
+
+// The stack pointer is only touched by the scheduler with interrupts disabled
+unsafe impl Send for Thread {}
+unsafe impl Sync for Thread {}
+
+impl Thread {
+    /// Makes a thread with its own stack that runs `entry` when it is first switched to
+    fn new(
+        name: &str,
+        class: SchedClass,
+        stack_size: usize,
+        entry: Box<dyn FnOnce() + Send>,
+    ) -> Arc<Thread> {
+        let stack = Stack::new(stack_size);
+        let stack_pointer = unsafe { context::initial_stack(stack.top(), thread_start) };
+
+        Arc::new(Thread {
+            id: ThreadId::new(),
+            name: name.to_string(),
+            state: AtomicU8::new(ThreadState::Ready as u8),
+            wake_pending: AtomicBool::new(false),
+            stack_pointer: UnsafeCell::new(stack_pointer),
+            stack: Some(stack),
+            entry: Spinlock::new(Some(entry)),
+            joiner: AtomicU64::new(0),
+            sched: UnsafeCell::new(SchedInfo::new(class)),
+            page_table: AtomicU64::new(0),
+            user_context: AtomicU64::new(0),
+            process: AtomicU64::new(0),
+            fs_base: AtomicU64::new(0),
+            gs_base: AtomicU64::new(0),
+        })
+    }
+
+    /// Wraps the code that is already running in a thread
+    fn adopt_current(name: &str) -> Arc<Thread> {
+        Arc::new(Thread {
+            id: ThreadId::new(),
+            name: name.to_string(),
+            state: AtomicU8::new(ThreadState::Running as u8),
+            wake_pending: AtomicBool::new(false),
+            stack_pointer: UnsafeCell::new(0),
+            stack: None,
+            entry: Spinlock::new(None),
+            joiner: AtomicU64::new(0),
+            sched: UnsafeCell::new(SchedInfo::new(SchedClass::Normal(0))),
+            page_table: AtomicU64::new(0),
+            user_context: AtomicU64::new(0),
+            process: AtomicU64::new(0),
+            fs_base: AtomicU64::new(0),
+            gs_base: AtomicU64::new(0),
+        })
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn state(&self) -> ThreadState {
+        ThreadState::from_u8(self.state.load(Ordering::SeqCst))
+    }
+
+    fn set_state(&self, state: ThreadState) {
+        self.state.store(state as u8, Ordering::SeqCst);
+    }
+
+    pub fn class(&self) -> SchedClass {
+        scheduler::class(self)
+    }
+
+    /// Usable stack size in bytes, 0 for the kernel thread
+    pub fn stack_size(&self) -> usize {
+        self.stack.as_ref().map_or(0, |stack| stack.size())
+    }
+
+    /// The level 4 page table the thread runs with
+    pub fn page_table(&self) -> PhysFrame {
+        match self.page_table.load(Ordering::SeqCst) {
+            0 => crate::memory::kernel_page_table(),
+            page_table => PhysFrame::containing_address(PhysAddr::new(page_table)),
+        }
+    }
+
+    /// PID of the process the thread belongs to, 0 for kernel threads
+    pub fn process(&self) -> u64 {
+        self.process.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn set_process(&self, pid: u64) {
+        self.process.store(pid, Ordering::SeqCst);
+    }
+
+    /// The FS base the thread runs user code with
+    pub fn fs_base(&self) -> u64 {
+        self.fs_base.load(Ordering::SeqCst)
+    }
+
+    /// The thread is running user code or handling an interrupt or system call from it
+    pub fn in_user_mode(&self) -> bool {
+        self.user_context.load(Ordering::SeqCst) != 0
+    }
+
+    /// Saves the GS base before the thread is switched out
+    ///
+    /// Called by the scheduler with interrupts disabled
+    fn deactivate(&self) {
+        self.gs_base
+            .store(GsBase::read().as_u64(), Ordering::SeqCst);
+    }
+
+    /// Loads the thread's page table, FS and GS base and kernel stack for user mode before it is switched to
+    ///
+    /// Called by the scheduler with interrupts disabled
+    fn activate(&self) {
+        let user_context = self.user_context.load(Ordering::SeqCst);
+        if user_context != 0 {
+            crate::gdt::set_kernel_stack(VirtAddr::new(unsafe { *(user_context as *const u64) }));
+        }
+
+        // The kernel does not use FS or GS so they can always hold the user values
+        FsBase::write(VirtAddr::new(self.fs_base.load(Ordering::SeqCst)));
+        GsBase::write(VirtAddr::new(self.gs_base.load(Ordering::SeqCst)));
+
+        let page_table = self.page_table();
+        let (active, flags) = Cr3::read();
+        if active != page_table {
+            unsafe { Cr3::write(page_table, flags) };
+        }
+    }
+}
+
+/// Owned permission to wait for a thread to finish and take its result
+pub struct JoinHandle<T> {
+    thread: Arc<Thread>,
+    result: Arc<Spinlock<Option<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    pub fn thread(&self) -> &Thread {
+        &self.thread
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.thread.state() == ThreadState::Dead
+    }
+
+    /// Blocks the calling thread until the thread finishes and returns what it returned
+    ///
+    /// # Panics
+    ///
+    /// If a thread joins itself
+    pub fn join(self) -> T {
+        let me = current_id();
+        assert!(me != self.thread.id, "a thread cannot join itself");
+
+        self.thread.joiner.store(me.0, Ordering::SeqCst);
+
+        // The thread is marked dead before it looks for a joiner so this cannot miss the wake
+        while self.thread.state() != ThreadState::Dead {
+            block();
+        }
+
+        self.result
+            .lock()
+            .take()
+            .expect("thread finished without a result")
+    }
+}
+
+/// Makes the running code the `kernel` thread and starts the idle thread
+pub fn init() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Initializing threads", file!(), line!());
+
+    let kernel = Thread::adopt_current("kernel");
+    let idle = Thread::new(
+        "idle",
+        SchedClass::Idle,
+        IDLE_STACK_SIZE,
+        Box::new(|| idle_loop()),
+    );
+
+    scheduler::init(kernel, idle);
+
+    LOGGER.get().unwrap().lock().info("Threads initialized");
+}
+
+/// True once [init] has run
+pub fn is_initialized() -> bool {
+    scheduler::is_initialized()
+}
+
+/// Starts a normal thread with its own [DEFAULT_STACK_SIZE] stack running `f`
+///
+/// Spawning allocates so it must not be done from interrupt handlers
+///
+/// # Panics
+///
+/// If [init] has not been called
+pub fn spawn<F, T>(name: &str, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    spawn_with_class(name, SchedClass::Normal(0), f)
+}
+
+/// Starts a thread in a scheduling class, see [spawn]
+pub fn spawn_with_class<F, T>(name: &str, class: SchedClass, f: F) -> JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    assert!(is_initialized(), "thread::spawn called before thread::init");
+
+    // Spawning is a good time to free the stacks of finished threads
+    reap();
+
+    let result = Arc::new(Spinlock::new(None));
+    let thread_result = result.clone();
+
+    let thread = Thread::new(
+        name,
+        class,
+        DEFAULT_STACK_SIZE,
+        Box::new(move || {
+            let output = f();
+            *thread_result.lock() = Some(output);
+        }),
+    );
+
+    scheduler::add(thread.clone());
+
+    JoinHandle { thread, result }
+}
+
+/// The thread that is running
+///
+/// # Panics
+///
+/// If [init] has not been called
+pub fn current() -> Arc<Thread> {
+    scheduler::current().expect("thread::current called before thread::init")
+}
+
+/// ID of the thread that is running
+pub fn current_id() -> ThreadId {
+    current().id
+}
+
+/// Lets other ready threads run, returns straight away if there are none
+pub fn yield_now() {
+    interrupts::without_interrupts(|| scheduler::switch(ThreadState::Ready));
+}
+
+/// Blocks the running thread until [wake] is called for it
+///
+/// If the thread was woken since it last blocked this returns straight away,
+/// so callers should check what they are waiting for in a loop around this
+pub fn block() {
+    interrupts::without_interrupts(|| {
+        let Some(thread) = scheduler::current() else {
+            return;
+        };
+
+        if thread.wake_pending.swap(false, Ordering::SeqCst) {
+            return;
+        }
+        drop(thread);
+
+        scheduler::switch(ThreadState::Blocked);
+    });
+}
+
+/// Makes a blocked thread ready, or makes its next [block] return straight away if it is not blocked
+///
+/// This does not allocate once the run queue has grown so it can be used from interrupt handlers.
+/// Returns false if there is no thread with this ID
+pub fn wake(id: ThreadId) -> bool {
+    scheduler::wake(id)
+}
+
+/// Changes the scheduling class of a thread, returns false if there is no thread with this ID
+pub fn set_class(id: ThreadId, class: SchedClass) -> bool {
+    scheduler::set_class(id, class)
+}
+
+/// Blocks the running thread for at least `duration`, other threads run in the meantime
+pub fn sleep(duration: Duration) {
+    let deadline = Instant::now() + duration;
+
+    let timer = timer::add(deadline, wake_sleeper, current_id().0 as usize);
+
+    while Instant::now() < deadline {
+        match timer {
+            Ok(_) => block(),
+            // Without a timer to wake us we have to keep checking the clock
+            Err(_) => yield_now(),
+        }
+    }
+
+    if let Ok(timer) = timer {
+        timer::cancel(timer);
+    }
+}
+
+/// Timer callback for [sleep], `data` is the ID of the sleeping thread
+fn wake_sleeper(data: usize) {
+    wake(ThreadId(data as u64));
+}
+
+/// Sets the page table the running thread uses and loads it, None goes back to the kernel's
+///
+/// # Safety
+///
+/// The page table must map the kernel the same way as the kernel's own table
+/// and it must stay alive until it is replaced or the thread finishes
+pub(crate) unsafe fn set_page_table(page_table: Option<PhysFrame>) {
+    interrupts::without_interrupts(|| {
+        let thread = current();
+        thread.page_table.store(
+            page_table.map_or(0, |frame| frame.start_address().as_u64()),
+            Ordering::SeqCst,
+        );
+        thread.activate();
+    });
+}
+
+/// Sets the FS base the running thread uses in user mode and loads it
+///
+/// # Panics
+///
+/// If `fs_base` is not a canonical address
+pub(crate) fn set_fs_base(fs_base: u64) {
+    let fs_base = VirtAddr::new(fs_base);
+
+    interrupts::without_interrupts(|| {
+        current().fs_base.store(fs_base.as_u64(), Ordering::SeqCst);
+        FsBase::write(fs_base);
+    });
+}
+
+/// Sets the GS base the running thread uses in user mode and loads it
+///
+/// # Panics
+///
+/// If `gs_base` is not a canonical address
+pub(crate) fn set_gs_base(gs_base: u64) {
+    let gs_base = VirtAddr::new(gs_base);
+
+    interrupts::without_interrupts(|| {
+        current().gs_base.store(gs_base.as_u64(), Ordering::SeqCst);
+        GsBase::write(gs_base);
+    });
+}
+
+/// The GS base the running thread has in user mode, the program may have changed it since it was last switched in
+pub fn gs_base() -> u64 {
+    GsBase::read().as_u64()
+}
+
+/// Records the user mode context of the running thread, 0 when it leaves user mode
+pub(crate) fn set_user_context(user_context: u64) {
+    current().user_context.store(user_context, Ordering::SeqCst);
+}
+
+/// The user mode context set with [set_user_context], 0 if the thread is not in user mode
+pub(crate) fn user_context() -> u64 {
+    scheduler::current().map_or(0, |thread| thread.user_context.load(Ordering::SeqCst))
+}
+
+/// Ends the running thread
+pub fn exit() -> ! {
+    interrupts::disable();
+
+    let thread = current();
+    thread.set_state(ThreadState::Dead);
+
+    let joiner = thread.joiner.swap(0, Ordering::SeqCst);
+    if joiner != 0 {
+        wake(ThreadId(joiner));
+    }
+
+    // Nothing on this stack is ever dropped once we switch away
+    drop(thread);
+
+    scheduler::switch(ThreadState::Dead);
+
+    unreachable!("a dead thread was switched back to");
+}
+
+/// Switches threads if the running thread's time slice has run out
+///
+/// Called at the end of the LAPIC timer interrupt handler after the end of interrupt has been sent
+pub(crate) fn preempt() {
+    scheduler::preempt();
+}
+
+/// Frees finished threads that nothing refers to any more
+///
+/// A thread cannot free its own stack and freeing needs the page table lock,
+/// so dead threads wait in a list until a thread outside an interrupt handler gets here
+fn reap() {
+    let dead = scheduler::take_dead();
+    drop(dead);
+}
+
+/// Every thread starts here the first time it is switched to
+extern "C" fn thread_start() -> ! {
+    // Switches always happen with interrupts disabled
+    let entry = current().entry.lock().take();
+
+    interrupts::enable();
+
+    if let Some(entry) = entry {
+        entry();
+    }
+
+    exit();
+}
+
+fn idle_loop() -> ! {
+    loop {
+        reap();
+
+        // Interrupts are disabled between the check and the hlt so a wake cannot be missed
+        interrupts::disable();
+        if scheduler::has_ready() {
+            interrupts::enable();
+            yield_now();
+        } else {
+            interrupts::enable_and_hlt();
+        }
+    }
+}
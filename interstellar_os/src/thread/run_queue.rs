@@ -0,0 +1,302 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Picking which ready thread runs next
+//!
+//! Threads are in one of three classes, a ready thread in a higher class always runs first:
+//!
+//! * [SchedClass::RealTime] threads run in priority order, threads with the same priority take turns
+//! * [SchedClass::Normal] threads share the CPU fairly using their virtual runtime,
+//!   the CPU time they have used scaled down by their weight, the thread that has had the least runs next
+//! * [SchedClass::Idle] threads only run when nothing else is ready
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::Arc;
+use core::time::Duration;
+
+use super::{Thread, ThreadId, TIME_SLICE};
+
+/// Number of real-time priorities, 0 is the lowest
+pub const REALTIME_PRIORITIES: usize = 8;
+
+pub const NICE_MIN: i8 = -20;
+pub const NICE_MAX: i8 = 19;
+
+/// Every ready normal thread should run once in this time
+const SCHED_LATENCY: u64 = 20_000_000;
+
+/// A normal thread runs for at least this long before it can be preempted by another normal thread
+const MIN_GRANULARITY: u64 = 1_000_000;
+
+/// A woken normal thread preempts the running one if it is this far behind in virtual runtime
+const WAKEUP_GRANULARITY: u64 = 1_000_000;
+
+/// Weight of a thread with a nice value of 0
+const NICE_0_WEIGHT: u64 = 1024;
+
+/// Weight for each nice value from -20 to 19, each step is about 10% more or less CPU time
+const NICE_TO_WEIGHT: [u64; 40] = [
+    88761, 71755, 56483, 46273, 36291, 29154, 23254, 18705, 14949, 11916, 9548, 7620, 6100, 4904,
+    3906, 3121, 2501, 1991, 1586, 1277, 1024, 820, 655, 526, 423, 335, 272, 215, 172, 137, 110, 87,
+    70, 56, 45, 36, 29, 23, 18, 15,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchedClass {
+    /// Runs before every normal and idle thread, the priority is below [REALTIME_PRIORITIES] and higher runs first
+    RealTime(u8),
+    /// Shares the CPU with other normal threads, the nice value is from [NICE_MIN] to [NICE_MAX] and lower gets more
+    Normal(i8),
+    /// Only runs when nothing else is ready
+    Idle,
+}
+
+impl SchedClass {
+    /// Clamps the priority or nice value into range
+    pub(super) fn clamped(self) -> SchedClass {
+        match self {
+            SchedClass::RealTime(priority) => {
+                SchedClass::RealTime(priority.min(REALTIME_PRIORITIES as u8 - 1))
+            }
+            SchedClass::Normal(nice) => SchedClass::Normal(nice.clamp(NICE_MIN, NICE_MAX)),
+            SchedClass::Idle => SchedClass::Idle,
+        }
+    }
+
+    /// Higher classes run first
+    fn rank(self) -> u16 {
+        match self {
+            SchedClass::RealTime(priority) => 2 + priority as u16,
+            SchedClass::Normal(_) => 1,
+            SchedClass::Idle => 0,
+        }
+    }
+
+    fn weight(self) -> u64 {
+        match self {
+            SchedClass::Normal(nice) => NICE_TO_WEIGHT[(nice - NICE_MIN) as usize],
+            _ => NICE_0_WEIGHT,
+        }
+    }
+}
+
+impl core::fmt::Display for SchedClass {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            SchedClass::RealTime(priority) => write!(f, "rt{priority}"),
+            SchedClass::Normal(nice) => write!(f, "{nice}"),
+            SchedClass::Idle => write!(f, "idle"),
+        }
+    }
+}
+
+/// Scheduling state of a thread, only used with the scheduler locked
+pub(super) struct SchedInfo {
+    pub(super) class: SchedClass,
+    /// CPU time scaled by the weight, only used by normal threads
+    pub(super) vruntime: u64,
+    /// Nanoseconds the thread has run for
+    pub(super) cpu_time: u64,
+    /// Times the thread has been switched to
+    pub(super) switches: u64,
+}
+
+impl SchedInfo {
+    pub(super) fn new(class: SchedClass) -> SchedInfo {
+        SchedInfo {
+            class: class.clamped(),
+            vruntime: 0,
+            cpu_time: 0,
+            switches: 0,
+        }
+    }
+
+    /// Adds `nanos` of running time
+    pub(super) fn charge(&mut self, nanos: u64) {
+        self.cpu_time += nanos;
+        self.vruntime += nanos * NICE_0_WEIGHT / self.class.weight();
+    }
+}
+
+/// # Safety
+///
+/// The scheduler must be locked
+#[allow(clippy::mut_from_ref)]
+pub(super) unsafe fn sched(thread: &Thread) -> &mut SchedInfo {
+    &mut *thread.sched.get()
+}
+
+pub(super) struct RunQueue {
+    realtime: [VecDeque<Arc<Thread>>; REALTIME_PRIORITIES],
+    /// Ordered by virtual runtime, the thread ID keeps keys unique
+    normal: BTreeMap<(u64, ThreadId), Arc<Thread>>,
+    idle: VecDeque<Arc<Thread>>,
+    /// Never goes backwards, woken and new normal threads start near it
+    min_vruntime: u64,
+    /// Sum of the weights of the ready normal threads
+    normal_weight: u64,
+    len: usize,
+}
+
+impl RunQueue {
+    pub(super) fn new() -> RunQueue {
+        RunQueue {
+            realtime: core::array::from_fn(|_| VecDeque::new()),
+            normal: BTreeMap::new(),
+            idle: VecDeque::new(),
+            min_vruntime: 0,
+            normal_weight: 0,
+            len: 0,
+        }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Adds a thread that was running or has just been made
+    pub(super) fn push(&mut self, thread: Arc<Thread>) {
+        let info = unsafe { sched(&thread) };
+
+        match info.class {
+            SchedClass::RealTime(priority) => self.realtime[priority as usize].push_back(thread),
+            SchedClass::Normal(_) => {
+                self.normal_weight += info.class.weight();
+                self.normal.insert((info.vruntime, thread.id), thread);
+            }
+            SchedClass::Idle => self.idle.push_back(thread),
+        }
+
+        self.len += 1;
+    }
+
+    /// Adds a thread that has just been made, it starts level with the threads that are already running
+    pub(super) fn push_new(&mut self, thread: Arc<Thread>) {
+        unsafe { sched(&thread) }.vruntime = self.min_vruntime;
+
+        self.push(thread);
+    }
+
+    /// Adds a thread that has been blocked
+    ///
+    /// A normal thread that slept for a long time would otherwise have a much lower virtual runtime than
+    /// the others and hog the CPU until it caught up, so it starts just behind the threads that kept running
+    pub(super) fn push_woken(&mut self, thread: Arc<Thread>) {
+        let info = unsafe { sched(&thread) };
+        info.vruntime = info
+            .vruntime
+            .max(self.min_vruntime.saturating_sub(SCHED_LATENCY / 2));
+
+        self.push(thread);
+    }
+
+    /// Moves the minimum virtual runtime up to the lowest of the running thread and the ready threads
+    pub(super) fn update_min_vruntime(&mut self, current: &Thread) {
+        let current = unsafe { sched(current) };
+
+        let leftmost = self
+            .normal
+            .first_key_value()
+            .map(|((vruntime, _), _)| *vruntime);
+        let lowest = match (current.class, leftmost) {
+            (SchedClass::Normal(_), Some(leftmost)) => current.vruntime.min(leftmost),
+            (SchedClass::Normal(_), None) => current.vruntime,
+            (_, Some(leftmost)) => leftmost,
+            (_, None) => return,
+        };
+
+        self.min_vruntime = self.min_vruntime.max(lowest);
+    }
+
+    /// Takes the thread that should run next
+    pub(super) fn pop(&mut self) -> Option<Arc<Thread>> {
+        let thread = if let Some(queue) = self.realtime.iter_mut().rev().find(|q| !q.is_empty()) {
+            queue.pop_front()
+        } else if let Some((_, thread)) = self.normal.pop_first() {
+            let info = unsafe { sched(&thread) };
+            self.normal_weight -= info.class.weight();
+            self.min_vruntime = self.min_vruntime.max(info.vruntime);
+            Some(thread)
+        } else {
+            self.idle.pop_front()
+        }?;
+
+        self.len -= 1;
+        Some(thread)
+    }
+
+    /// Takes a thread out of the queue, returns false if it was not in it
+    pub(super) fn remove(&mut self, thread: &Thread) -> bool {
+        let info = unsafe { sched(thread) };
+
+        let removed = match info.class {
+            SchedClass::RealTime(priority) => {
+                remove_from(&mut self.realtime[priority as usize], thread.id)
+            }
+            SchedClass::Normal(_) => {
+                let removed = self.normal.remove(&(info.vruntime, thread.id)).is_some();
+                if removed {
+                    self.normal_weight -= info.class.weight();
+                }
+                removed
+            }
+            SchedClass::Idle => remove_from(&mut self.idle, thread.id),
+        };
+
+        if removed {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// How long `thread` should run before it can be preempted
+    pub(super) fn time_slice(&self, thread: &Thread) -> Duration {
+        let info = unsafe { sched(thread) };
+
+        match info.class {
+            SchedClass::Normal(_) => {
+                // Every normal thread gets a share of the latency matching its weight
+                let weight = info.class.weight();
+                let slice = SCHED_LATENCY * weight / (self.normal_weight + weight);
+                Duration::from_nanos(slice.max(MIN_GRANULARITY))
+            }
+            _ => TIME_SLICE,
+        }
+    }
+
+    /// True if `woken` should preempt `current`, the running thread must have just been charged
+    pub(super) fn should_preempt(&self, woken: &Thread, current: &Thread) -> bool {
+        let woken = unsafe { sched(woken) };
+        let current = unsafe { sched(current) };
+
+        match (woken.class, current.class) {
+            (SchedClass::Normal(_), SchedClass::Normal(_)) => {
+                woken.vruntime + WAKEUP_GRANULARITY < current.vruntime
+            }
+            (woken, current) => woken.rank() > current.rank(),
+        }
+    }
+}
+
+fn remove_from(queue: &mut VecDeque<Arc<Thread>>, id: ThreadId) -> bool {
+    match queue.iter().position(|thread| thread.id == id) {
+        Some(index) => {
+            queue.remove(index);
+            true
+        }
+        None => false,
+    }
+}
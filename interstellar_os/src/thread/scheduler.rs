@@ -0,0 +1,309 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+use super::run_queue::{sched, RunQueue, SchedClass};
+use super::{context, Thread, ThreadId, ThreadState};
+use crate::time::timer_wheel::TimerId;
+use crate::time::{monotonic_nanos, timer, Instant};
+
+/// Only ever locked with interrupts disabled, so on one CPU it is never contended
+static SCHEDULER: OnceCell<Spinlock<Scheduler>> = OnceCell::uninit();
+
+/// Set by the time slice timer, the LAPIC timer handler switches threads when it sees this
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+
+struct Scheduler {
+    /// Every thread that has not finished, including the idle thread
+    threads: BTreeMap<ThreadId, Arc<Thread>>,
+    ready: RunQueue,
+    current: Arc<Thread>,
+    /// Run when nothing is ready, it is never put in the ready queue
+    idle: Arc<Thread>,
+    /// Finished threads waiting for [take_dead]
+    dead: Vec<Arc<Thread>>,
+    /// Timer ending the running thread's time slice, only set while other threads are ready
+    slice_timer: Option<TimerId>,
+    /// When the running thread was last charged for its CPU time
+    charged_at: u64,
+}
+
+impl Scheduler {
+    /// Starts a time slice for the running thread if other threads are waiting and none is running
+    fn arm_slice(&mut self) {
+        if self.slice_timer.is_none() && !self.ready.is_empty() {
+            let slice = self.ready.time_slice(&self.current);
+            self.slice_timer = timer::add(Instant::now() + slice, slice_expired, 0).ok();
+        }
+    }
+
+    /// Starts a fresh time slice for a thread that has just been switched to
+    fn restart_slice(&mut self) {
+        self.cancel_slice();
+        NEED_RESCHED.store(false, Ordering::SeqCst);
+
+        self.arm_slice();
+    }
+
+    fn cancel_slice(&mut self) {
+        if let Some(slice_timer) = self.slice_timer.take() {
+            timer::cancel(slice_timer);
+        }
+    }
+
+    /// Adds the time since the running thread was last charged to its CPU time
+    fn charge_current(&mut self) {
+        let now = monotonic_nanos();
+        let running = now.saturating_sub(self.charged_at);
+        self.charged_at = now;
+
+        unsafe { sched(&self.current) }.charge(running);
+        self.ready.update_min_vruntime(&self.current);
+    }
+}
+
+/// Runs `f` with the scheduler locked, returns None before [init]
+fn with<R>(f: impl FnOnce(&mut Scheduler) -> R) -> Option<R> {
+    let scheduler = SCHEDULER.get()?;
+    Some(interrupts::without_interrupts(|| f(&mut scheduler.lock())))
+}
+
+pub(super) fn init(kernel: Arc<Thread>, idle: Arc<Thread>) {
+    let mut threads = BTreeMap::new();
+    threads.insert(kernel.id, kernel.clone());
+    threads.insert(idle.id, idle.clone());
+
+    SCHEDULER.init_once(|| {
+        Spinlock::new(Scheduler {
+            threads,
+            ready: RunQueue::new(),
+            current: kernel,
+            idle,
+            dead: Vec::new(),
+            slice_timer: None,
+            charged_at: monotonic_nanos(),
+        })
+    });
+}
+
+pub(super) fn is_initialized() -> bool {
+    SCHEDULER.is_initialized()
+}
+
+pub(super) fn current() -> Option<Arc<Thread>> {
+    with(|scheduler| scheduler.current.clone())
+}
+
+pub(super) fn has_ready() -> bool {
+    with(|scheduler| !scheduler.ready.is_empty()).unwrap_or(false)
+}
+
+/// Puts a new thread in the ready queue
+pub(super) fn add(thread: Arc<Thread>) {
+    with(|scheduler| {
+        scheduler.threads.insert(thread.id, thread.clone());
+        make_ready(scheduler, thread, true);
+    });
+}
+
+pub(super) fn wake(id: ThreadId) -> bool {
+    with(|scheduler| {
+        let Some(thread) = scheduler.threads.get(&id) else {
+            return false;
+        };
+
+        if thread.state() == ThreadState::Blocked {
+            thread.set_state(ThreadState::Ready);
+            let thread = thread.clone();
+            make_ready(scheduler, thread, false);
+        } else {
+            thread.wake_pending.store(true, Ordering::SeqCst);
+        }
+
+        true
+    })
+    .unwrap_or(false)
+}
+
+/// Queues a new or woken thread, preempting the running thread as soon as possible if the new one should run first
+fn make_ready(scheduler: &mut Scheduler, thread: Arc<Thread>, new: bool) {
+    scheduler.charge_current();
+
+    if new {
+        scheduler.ready.push_new(thread.clone());
+    } else {
+        scheduler.ready.push_woken(thread.clone());
+    }
+
+    let preempt = scheduler.ready.should_preempt(&thread, &scheduler.current);
+
+    if preempt && !Arc::ptr_eq(&scheduler.current, &scheduler.idle) {
+        // A timer that has already expired fires straight away and ends the slice
+        scheduler.cancel_slice();
+        scheduler.slice_timer = timer::add(Instant::now(), slice_expired, 0).ok();
+    } else {
+        scheduler.arm_slice();
+    }
+}
+
+pub(super) fn set_class(id: ThreadId, class: SchedClass) -> bool {
+    with(|scheduler| {
+        let Some(thread) = scheduler.threads.get(&id).cloned() else {
+            return false;
+        };
+
+        // The run queue is ordered by class so the thread has to be taken out while it changes
+        let queued = scheduler.ready.remove(&thread);
+        unsafe { sched(&thread) }.class = class.clamped();
+        if queued {
+            scheduler.ready.push(thread);
+        }
+
+        // The running thread may no longer be the one that should run
+        scheduler.cancel_slice();
+        scheduler.arm_slice();
+
+        true
+    })
+    .unwrap_or(false)
+}
+
+pub(super) fn class(thread: &Thread) -> SchedClass {
+    with(|_| unsafe { sched(thread) }.class).unwrap_or(SchedClass::Normal(0))
+}
+
+pub(super) fn take_dead() -> Vec<Arc<Thread>> {
+    with(|scheduler| core::mem::take(&mut scheduler.dead)).unwrap_or_default()
+}
+
+/// CPU usage of a thread at the time [stats] was called
+#[derive(Debug, Clone)]
+pub struct ThreadStats {
+    pub id: ThreadId,
+    pub name: String,
+    pub state: ThreadState,
+    pub class: SchedClass,
+    /// Time spent running
+    pub cpu_time: Duration,
+    /// Number of times the thread has been switched to
+    pub switches: u64,
+}
+
+/// Statistics for every thread that has not finished, ordered by ID
+pub fn stats() -> Vec<ThreadStats> {
+    with(|scheduler| {
+        // Bring the running thread's time up to date
+        scheduler.charge_current();
+
+        scheduler
+            .threads
+            .values()
+            .map(|thread| {
+                let info = unsafe { sched(thread) };
+
+                ThreadStats {
+                    id: thread.id,
+                    name: thread.name.clone(),
+                    state: thread.state(),
+                    class: info.class,
+                    cpu_time: Duration::from_nanos(info.cpu_time),
+                    switches: info.switches,
+                }
+            })
+            .collect()
+    })
+    .unwrap_or_default()
+}
+
+/// Switches to the next ready thread leaving the running thread in `state`
+///
+/// If the running thread is only yielding and nothing else should run first this returns straight away,
+/// otherwise it returns once the running thread is switched back to.
+/// Interrupts must be disabled
+pub(super) fn switch(state: ThreadState) {
+    let Some(scheduler) = SCHEDULER.get() else {
+        return;
+    };
+
+    let (old, new) = {
+        let mut scheduler = scheduler.lock();
+
+        scheduler.charge_current();
+
+        let previous = scheduler.current.clone();
+        let previous_is_idle = Arc::ptr_eq(&previous, &scheduler.idle);
+
+        // A yielding thread goes back in the queue first so it keeps running if it is still the best choice
+        if state == ThreadState::Ready && !previous_is_idle {
+            scheduler.ready.push(previous.clone());
+        }
+
+        let next = match scheduler.ready.pop() {
+            Some(next) => next,
+            None if state == ThreadState::Ready => {
+                scheduler.restart_slice();
+                return;
+            }
+            None => scheduler.idle.clone(),
+        };
+
+        if Arc::ptr_eq(&next, &previous) {
+            scheduler.restart_slice();
+            return;
+        }
+
+        previous.set_state(state);
+        if state == ThreadState::Dead {
+            scheduler.threads.remove(&previous.id);
+            scheduler.dead.push(previous.clone());
+        }
+
+        next.set_state(ThreadState::Running);
+        unsafe { sched(&next) }.switches += 1;
+        scheduler.current = next.clone();
+        scheduler.restart_slice();
+
+        previous.deactivate();
+        next.activate();
+
+        // The threads map, ready queue or dead list keep both threads alive across the switch
+        let new = unsafe { *next.stack_pointer.get() };
+        (previous.stack_pointer.get(), new)
+    };
+
+    unsafe { context::switch(old, new) };
+}
+
+pub(super) fn preempt() {
+    if NEED_RESCHED.swap(false, Ordering::SeqCst) {
+        switch(ThreadState::Ready);
+    }
+}
+
+/// Timer callback ending a time slice
+fn slice_expired(_data: usize) {
+    with(|scheduler| scheduler.slice_timer = None);
+    NEED_RESCHED.store(true, Ordering::SeqCst);
+}
@@ -0,0 +1,103 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use alloc::alloc::{alloc, dealloc, handle_alloc_error, Layout};
+use x86_64::structures::paging::{Mapper, Page, PageSize, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: usize = Size4KiB::SIZE as usize;
+
+/// A kernel thread stack taken from the heap
+///
+/// The lowest page is unmapped while the stack is in use,
+/// so a thread that overflows its stack page faults instead of writing over other heap memory
+pub(super) struct Stack {
+    base: *mut u8,
+    layout: Layout,
+    /// The frame behind the guard page, mapped back before the memory is returned to the heap
+    guard_frame: PhysFrame,
+}
+
+// The stack is only used by the thread it belongs to
+unsafe impl Send for Stack {}
+unsafe impl Sync for Stack {}
+
+impl Stack {
+    /// Allocates a stack with at least `size` usable bytes
+    pub(super) fn new(size: usize) -> Stack {
+        let size = size.next_multiple_of(PAGE_SIZE) + PAGE_SIZE;
+        let layout = Layout::from_size_align(size, PAGE_SIZE).unwrap();
+
+        let base = unsafe { alloc(layout) };
+        if base.is_null() {
+            handle_alloc_error(layout);
+        }
+
+        let guard_page = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(base));
+
+        let mut mapper = crate::memory::MAPPER.lock();
+        let (guard_frame, flush) = mapper
+            .as_mut()
+            .unwrap()
+            .unmap(guard_page)
+            .expect("thread stack guard page is not mapped");
+        flush.flush();
+
+        Stack {
+            base,
+            layout,
+            guard_frame,
+        }
+    }
+
+    /// The address the stack grows down from
+    pub(super) fn top(&self) -> u64 {
+        self.base as u64 + self.layout.size() as u64
+    }
+
+    /// Usable size in bytes, not counting the guard page
+    pub(super) fn size(&self) -> usize {
+        self.layout.size() - PAGE_SIZE
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        let guard_page = Page::<Size4KiB>::containing_address(VirtAddr::from_ptr(self.base));
+
+        // The page fault handler takes the frame allocator for copy-on-write, so it is never held with interrupts enabled
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let mut mapper = crate::memory::MAPPER.lock();
+            let mut frame_allocator = crate::memory::FRAME_ALLOCATOR.lock();
+
+            unsafe {
+                mapper
+                    .as_mut()
+                    .unwrap()
+                    .map_to(
+                        guard_page,
+                        self.guard_frame,
+                        PageTableFlags::PRESENT | PageTableFlags::WRITABLE,
+                        frame_allocator.as_mut().unwrap(),
+                    )
+                    .expect("failed to map thread stack guard page back")
+                    .flush();
+            }
+        });
+
+        unsafe { dealloc(self.base, self.layout) };
+    }
+}
@@ -0,0 +1,140 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Queues of threads waiting for something to happen
+//!
+//! A thread waits on a [WaitQueue] until another thread wakes it through the same queue. The condition it waits for
+//! is checked with the queue locked, so a wake that comes between the check and the thread blocking is not lost as
+//! long as whatever changes the condition wakes the queue afterwards.
+
+use alloc::collections::VecDeque;
+use spinning_top::Spinlock;
+
+use super::{block, current_id, wake, wake_sleeper, yield_now, ThreadId};
+use crate::time::{timer, Instant};
+
+/// How [WaitQueue::wait_if] ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitResult {
+    /// The condition did not hold so the thread did not wait
+    NotWaited,
+    /// Woken through the queue
+    Woken,
+    /// The deadline passed first
+    TimedOut,
+    /// The thread was woken for something else and was told to stop waiting
+    Interrupted,
+}
+
+/// Threads waiting to be woken in the order they started waiting
+pub struct WaitQueue {
+    waiters: Spinlock<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: Spinlock::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks the running thread until it is woken through the queue, if `condition` holds with the queue locked
+    ///
+    /// When the thread is woken for anything else it stops waiting if `interrupted` returns true
+    /// or `deadline` has passed, otherwise it blocks again.
+    pub fn wait_if(
+        &self,
+        condition: impl FnOnce() -> bool,
+        deadline: Option<Instant>,
+        interrupted: impl Fn() -> bool,
+    ) -> WaitResult {
+        let id = current_id();
+
+        {
+            let mut waiters = self.waiters.lock();
+            if !condition() {
+                return WaitResult::NotWaited;
+            }
+            waiters.push_back(id);
+        }
+
+        let timer =
+            deadline.map(|deadline| timer::add(deadline, wake_sleeper, id.as_u64() as usize));
+
+        let result = loop {
+            match timer {
+                // Without a timer to wake us we have to keep checking the clock
+                Some(Err(_)) => yield_now(),
+                _ => block(),
+            }
+
+            if !self.waiters.lock().contains(&id) {
+                break WaitResult::Woken;
+            }
+
+            let stop = if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                WaitResult::TimedOut
+            } else if interrupted() {
+                WaitResult::Interrupted
+            } else {
+                continue;
+            };
+
+            // A wake may have taken the thread off the queue since it looked
+            let mut waiters = self.waiters.lock();
+            match waiters.iter().position(|&waiter| waiter == id) {
+                Some(index) => {
+                    waiters.remove(index);
+                    break stop;
+                }
+                None => break WaitResult::Woken,
+            }
+        };
+
+        if let Some(Ok(timer)) = timer {
+            timer::cancel(timer);
+        }
+
+        result
+    }
+
+    /// Wakes up to `count` threads, the ones that have waited longest first, and returns how many were woken
+    pub fn wake(&self, count: usize) -> usize {
+        let mut waiters = self.waiters.lock();
+        let count = count.min(waiters.len());
+
+        for id in waiters.drain(..count) {
+            wake(id);
+        }
+
+        count
+    }
+
+    /// Wakes every waiting thread and returns how many there were
+    pub fn wake_all(&self) -> usize {
+        self.wake(usize::MAX)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.lock().is_empty()
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
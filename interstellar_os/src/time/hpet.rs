@@ -14,88 +14,427 @@
 //You should have received a copy of the GNU General Public License
 //along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use x86_64::PhysAddr;
+use alloc::format;
+use conquer_once::spin::OnceCell;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use x86_64::{PhysAddr, VirtAddr};
 
 use crate::acpi::ACPI_INFO;
+use crate::other::log::LOGGER;
 
-struct HpetRegisters<'a> {
-    #[allow(dead_code)]
-    pub general_capabilities_and_id: volatile::VolatilePtr<'a, u64>,
-    #[allow(dead_code)]
-    pub general_configuration: volatile::VolatilePtr<'a, u64>,
-    #[allow(dead_code)]
-    pub general_interrupt_status: volatile::VolatilePtr<'a, u64>,
-    #[allow(dead_code)]
-    pub main_counter_value: volatile::VolatilePtr<'a, u64>,
+// General registers
+const GENERAL_CAPABILITIES_AND_ID: u64 = 0x000;
+const GENERAL_CONFIGURATION: u64 = 0x010;
+const GENERAL_INTERRUPT_STATUS: u64 = 0x020;
+const MAIN_COUNTER_VALUE: u64 = 0x0F0;
+
+// Timer N registers - each comparator has 0x20 bytes of registers starting at 0x100
+const TIMER_CONFIGURATION: u64 = 0x100;
+const TIMER_COMPARATOR_VALUE: u64 = 0x108;
+const TIMER_STRIDE: u64 = 0x20;
+
+// General capabilities bits
+const CAP_NUM_TIMERS_SHIFT: u64 = 8;
+const CAP_NUM_TIMERS_MASK: u64 = 0x1F;
+const CAP_COUNT_SIZE: u64 = 1 << 13;
+const CAP_LEGACY_ROUTE: u64 = 1 << 15;
+const CAP_PERIOD_SHIFT: u64 = 32;
+
+// General configuration bits
+const CONF_ENABLE: u64 = 1 << 0;
+const CONF_LEGACY_ROUTE: u64 = 1 << 1;
+
+// Timer configuration bits
+const TIMER_INT_TYPE_LEVEL: u64 = 1 << 1;
+const TIMER_INT_ENABLE: u64 = 1 << 2;
+const TIMER_TYPE_PERIODIC: u64 = 1 << 3;
+const TIMER_PERIODIC_CAP: u64 = 1 << 4;
+const TIMER_SIZE_CAP: u64 = 1 << 5;
+const TIMER_VAL_SET: u64 = 1 << 6;
+const TIMER_32BIT_MODE: u64 = 1 << 8;
+const TIMER_INT_ROUTE_SHIFT: u64 = 9;
+const TIMER_INT_ROUTE_MASK: u64 = 0x1F << TIMER_INT_ROUTE_SHIFT;
+const TIMER_FSB_ENABLE: u64 = 1 << 14;
+const TIMER_INT_ROUTE_CAP_SHIFT: u64 = 32;
+
+/// Femtoseconds in one nanosecond
+const FEMTOS_PER_NANO: u128 = 1_000_000;
+
+/// The largest counter period the HPET spec allows - 100ns
+const MAX_PERIOD_FEMTOS: u64 = 100_000_000;
+
+/// The HPET if one was found in the ACPI tables
+///
+/// This does not use a lock so it can be read from interrupt handlers,
+/// all registers are accessed through volatile reads and writes
+pub static HPET: OnceCell<Hpet> = OnceCell::uninit();
+
+/// Number of HPET comparator interrupts that have fired
+pub static HPET_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Function called from the HPET interrupt handler stored as a usize so it can be swapped atomically
+static HPET_CALLBACK: AtomicUsize = AtomicUsize::new(0);
+
+/// Detects and initializes the HPET
+///
+/// If the ACPI tables do not describe a HPET this does nothing and the rest of the kernel falls back to the PIT
+pub fn init() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Initializing HPET", file!(), line!());
+
+    let base_address = {
+        let acpi_info = ACPI_INFO.get().unwrap().lock();
+
+        match acpi_info.hpet_info.as_ref() {
+            Ok(hpet_info) => hpet_info.base_address as u64,
+            Err(e) => {
+                LOGGER
+                    .get()
+                    .unwrap()
+                    .lock()
+                    .info(&format!("HPET not available: {e:?}"));
+                return;
+            }
+        }
+    };
+
+    match unsafe { Hpet::new(PhysAddr::new(base_address)) } {
+        Some(hpet) => {
+            hpet.enable();
+
+            LOGGER.get().unwrap().lock().info(&format!(
+                "HPET initialized, period: {}fs, comparators: {}, 64 bit: {}",
+                hpet.period_femtos(),
+                hpet.num_comparators(),
+                hpet.is_64bit()
+            ));
+
+            HPET.init_once(|| hpet);
+        }
+        None => {
+            LOGGER
+                .get()
+                .unwrap()
+                .lock()
+                .warn("HPET reported an invalid counter period, ignoring it");
+        }
+    }
 }
 
-pub struct HPET<'a> {
-    #[allow(dead_code)]
-    registers: HpetRegisters<'a>,
+/// Returns the HPET if it is present and initialized
+pub fn get() -> Option<&'static Hpet> {
+    HPET.get()
 }
 
-impl Default for HPET<'_> {
-    fn default() -> Self {
-        Self::new()
+/// Sets the function called every time a HPET comparator interrupt fires
+///
+/// The function is called from an interrupt handler so it must not block or allocate
+pub fn set_callback(callback: fn()) {
+    HPET_CALLBACK.store(callback as usize, Ordering::SeqCst);
+}
+
+/// Called by the HPET interrupt handler
+pub(crate) fn handle_interrupt() {
+    HPET_COUNT.fetch_add(1, Ordering::SeqCst);
+
+    let callback = HPET_CALLBACK.load(Ordering::SeqCst);
+    if callback != 0 {
+        let callback: fn() = unsafe { core::mem::transmute(callback) };
+        callback();
     }
 }
 
-impl HPET<'_> {
-    /// This should only be called if the HPET info in the ACPI tables is present
-    pub fn new() -> Self {
-        let acpi_info = ACPI_INFO.get().unwrap().lock();
+/// How a comparator should fire
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ComparatorMode {
+    /// Fire once after the given time
+    OneShot,
+    /// Fire every time the given time passes
+    Periodic,
+}
 
-        let hpet_info = acpi_info
-            .hpet_info
-            .as_ref()
-            .expect("HPET info not available");
+/// Errors that can happen when programming a comparator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// The comparator does not exist
+    InvalidComparator,
+    /// The comparator cannot run in periodic mode
+    PeriodicNotSupported,
+    /// None of the IOAPIC inputs the comparator can be routed to are free
+    NoRoute,
+    /// The duration is too long to fit in a 32 bit comparator
+    DurationTooLong,
+}
 
-        let phys_mem_offet = crate::other::info::BOOT_INFO
-            .get()
-            .unwrap()
-            .lock()
-            .physical_memory_offset;
+/// High Precision Event Timer driver
+pub struct Hpet {
+    /// Virtual address of the register block
+    base: VirtAddr,
+    /// Counter period in femtoseconds
+    period: u64,
+    /// Number of comparators
+    comparators: u8,
+    /// The main counter is 64 bits wide
+    counter_64bit: bool,
+    /// Last value of the extended counter for 32 bit HPETs, see [Hpet::counter]
+    high: AtomicU64,
+}
 
-        let hpet_base_address = hpet_info.base_address as u64 + phys_mem_offet;
+impl Hpet {
+    /// Maps the HPET register block and reads its capabilities
+    ///
+    /// Returns [None] if the reported counter period is invalid
+    ///
+    /// # Safety
+    ///
+    /// `base_address` must be the physical address of a HPET register block
+    pub unsafe fn new(base_address: PhysAddr) -> Option<Hpet> {
+        let base = crate::memory::map_address(base_address, 1024);
 
-        let general_capabilities_and_id = unsafe {
-            volatile::VolatilePtr::new(
-                core::ptr::NonNull::new(PhysAddr::new(hpet_base_address).as_u64() as *mut u64)
-                    .unwrap(),
-            )
-        };
-        let general_configuration = unsafe {
-            volatile::VolatilePtr::new(
-                core::ptr::NonNull::new(
-                    PhysAddr::new(hpet_base_address + 0x010).as_u64() as *mut u64
-                )
-                .unwrap(),
-            )
+        let mut hpet = Hpet {
+            base,
+            period: 0,
+            comparators: 0,
+            counter_64bit: false,
+            high: AtomicU64::new(0),
         };
-        let general_interrupt_status = unsafe {
-            volatile::VolatilePtr::new(
-                core::ptr::NonNull::new(
-                    PhysAddr::new(hpet_base_address + 0x020).as_u64() as *mut u64
-                )
-                .unwrap(),
-            )
-        };
-        let main_counter_value = unsafe {
-            volatile::VolatilePtr::new(
-                core::ptr::NonNull::new(
-                    PhysAddr::new(hpet_base_address + 0x0F0).as_u64() as *mut u64
-                )
-                .unwrap(),
-            )
+
+        let capabilities = hpet.read(GENERAL_CAPABILITIES_AND_ID);
+
+        hpet.period = capabilities >> CAP_PERIOD_SHIFT;
+        hpet.comparators = ((capabilities >> CAP_NUM_TIMERS_SHIFT) & CAP_NUM_TIMERS_MASK) as u8 + 1;
+        hpet.counter_64bit = capabilities & CAP_COUNT_SIZE != 0;
+
+        if hpet.period == 0 || hpet.period > MAX_PERIOD_FEMTOS {
+            return None;
+        }
+
+        Some(hpet)
+    }
+
+    /// Starts the main counter from 0 with every comparator disabled
+    fn enable(&self) {
+        let config = self.read(GENERAL_CONFIGURATION);
+
+        // Stop the counter so it can be reset
+        self.write(
+            GENERAL_CONFIGURATION,
+            config & !(CONF_ENABLE | CONF_LEGACY_ROUTE),
+        );
+
+        for comparator in 0..self.comparators {
+            let timer_config = self.read(timer_register(TIMER_CONFIGURATION, comparator));
+            self.write(
+                timer_register(TIMER_CONFIGURATION, comparator),
+                timer_config & !(TIMER_INT_ENABLE | TIMER_TYPE_PERIODIC | TIMER_FSB_ENABLE),
+            );
+        }
+
+        self.write(MAIN_COUNTER_VALUE, 0);
+        self.write(GENERAL_INTERRUPT_STATUS, u64::MAX);
+
+        self.write(
+            GENERAL_CONFIGURATION,
+            (config & !CONF_LEGACY_ROUTE) | CONF_ENABLE,
+        );
+    }
+
+    /// Counter period in femtoseconds
+    pub fn period_femtos(&self) -> u64 {
+        self.period
+    }
+
+    /// Counter frequency in Hz
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period
+    }
+
+    /// Number of comparators
+    pub fn num_comparators(&self) -> u8 {
+        self.comparators
+    }
+
+    /// The main counter is 64 bits wide
+    pub fn is_64bit(&self) -> bool {
+        self.counter_64bit
+    }
+
+    /// The HPET can replace the PIT and RTC interrupts using legacy replacement routing
+    pub fn legacy_route_capable(&self) -> bool {
+        self.read(GENERAL_CAPABILITIES_AND_ID) & CAP_LEGACY_ROUTE != 0
+    }
+
+    /// Reads the main counter
+    ///
+    /// A 32 bit counter is extended to 64 bits by counting wrap arounds,
+    /// so it has to be read at least once every wrap which is around 42 seconds at QEMUs 100MHz
+    pub fn counter(&self) -> u64 {
+        if self.counter_64bit {
+            return self.read(MAIN_COUNTER_VALUE);
+        }
+
+        extend_counter(&self.high, || self.read(MAIN_COUNTER_VALUE) as u32)
+    }
+
+    /// Converts counter ticks to nanoseconds
+    pub fn ticks_to_nanos(&self, ticks: u64) -> u64 {
+        (ticks as u128 * self.period as u128 / FEMTOS_PER_NANO) as u64
+    }
+
+    /// Converts nanoseconds to counter ticks
+    pub fn nanos_to_ticks(&self, nanos: u64) -> u64 {
+        (nanos as u128 * FEMTOS_PER_NANO / self.period as u128) as u64
+    }
+
+    /// Monotonic nanoseconds since the HPET was initialized
+    pub fn nanos(&self) -> u64 {
+        self.ticks_to_nanos(self.counter())
+    }
+
+    /// Time since the HPET was initialized
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.nanos())
+    }
+
+    /// Spins until the given duration has passed using the main counter
+    pub fn busy_wait(&self, duration: Duration) {
+        let target = self.counter() + self.nanos_to_ticks(duration.as_nanos() as u64);
+        while self.counter() < target {
+            core::hint::spin_loop();
+        }
+    }
+
+    /// Programs a comparator to raise an interrupt on `vector` after `duration`, once or periodically
+    ///
+    /// The comparator is routed to the first IOAPIC input it supports that is not used by the ISA interrupts
+    pub fn set_comparator(
+        &self,
+        comparator: u8,
+        mode: ComparatorMode,
+        duration: Duration,
+        vector: u8,
+    ) -> Result<(), HpetError> {
+        if comparator >= self.comparators {
+            return Err(HpetError::InvalidComparator);
+        }
+
+        let config_register = timer_register(TIMER_CONFIGURATION, comparator);
+        let comparator_register = timer_register(TIMER_COMPARATOR_VALUE, comparator);
+
+        let mut config = self.read(config_register);
+
+        if mode == ComparatorMode::Periodic && config & TIMER_PERIODIC_CAP == 0 {
+            return Err(HpetError::PeriodicNotSupported);
+        }
+
+        let ticks = self.nanos_to_ticks(duration.as_nanos() as u64).max(1);
+
+        let comparator_64bit = config & TIMER_SIZE_CAP != 0;
+        if !comparator_64bit && ticks > u32::MAX as u64 {
+            return Err(HpetError::DurationTooLong);
+        }
+
+        // Pick an IOAPIC input above the ISA range that this comparator can use
+        let route_cap = (config >> TIMER_INT_ROUTE_CAP_SHIFT) as u32;
+        let gsi = (16..32)
+            .find(|gsi| route_cap & (1 << gsi) != 0)
+            .ok_or(HpetError::NoRoute)? as u8;
+
+        crate::interrupts::route_gsi(gsi, vector);
+
+        config &= !(TIMER_INT_ROUTE_MASK
+            | TIMER_TYPE_PERIODIC
+            | TIMER_INT_TYPE_LEVEL
+            | TIMER_FSB_ENABLE
+            | TIMER_32BIT_MODE);
+        config |= (gsi as u64) << TIMER_INT_ROUTE_SHIFT;
+
+        // Disable the comparator while changing it
+        self.write(config_register, config & !TIMER_INT_ENABLE);
+
+        let target = self.read(MAIN_COUNTER_VALUE).wrapping_add(ticks);
+
+        match mode {
+            ComparatorMode::OneShot => {
+                self.write(comparator_register, target);
+                self.write(config_register, config | TIMER_INT_ENABLE);
+            }
+            ComparatorMode::Periodic => {
+                // With VAL_SET the first write sets the comparator and the second sets the period
+                self.write(
+                    config_register,
+                    config | TIMER_TYPE_PERIODIC | TIMER_VAL_SET | TIMER_INT_ENABLE,
+                );
+                self.write(comparator_register, target);
+                self.write(comparator_register, ticks);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops a comparator from raising interrupts
+    pub fn disable_comparator(&self, comparator: u8) -> Result<(), HpetError> {
+        if comparator >= self.comparators {
+            return Err(HpetError::InvalidComparator);
+        }
+
+        let config_register = timer_register(TIMER_CONFIGURATION, comparator);
+        let config = self.read(config_register);
+        self.write(
+            config_register,
+            config & !(TIMER_INT_ENABLE | TIMER_TYPE_PERIODIC),
+        );
+
+        Ok(())
+    }
+
+    fn read(&self, offset: u64) -> u64 {
+        let ptr = unsafe {
+            volatile::VolatilePtr::new(NonNull::new_unchecked(
+                (self.base + offset).as_mut_ptr::<u64>(),
+            ))
         };
+        ptr.read()
+    }
 
-        let registers = HpetRegisters {
-            general_capabilities_and_id,
-            general_configuration,
-            general_interrupt_status,
-            main_counter_value,
+    fn write(&self, offset: u64, value: u64) {
+        let ptr = unsafe {
+            volatile::VolatilePtr::new(NonNull::new_unchecked(
+                (self.base + offset).as_mut_ptr::<u64>(),
+            ))
         };
+        ptr.write(value);
+    }
+}
+
+fn timer_register(register: u64, comparator: u8) -> u64 {
+    register + TIMER_STRIDE * comparator as u64
+}
+
+/// Extends the 32 bit counter read by `read_low` to 64 bits, `last` is the last extended value
+///
+/// The counter is read again on every attempt after loading `last`,
+/// a value read before another CPU stored a newer `last` would otherwise look like a wrap around
+pub fn extend_counter(last: &AtomicU64, mut read_low: impl FnMut() -> u32) -> u64 {
+    loop {
+        let previous = last.load(Ordering::SeqCst);
+        let mut new = (previous & !0xFFFF_FFFF) | read_low() as u64;
+
+        if new < previous {
+            new += 1 << 32;
+        }
 
-        HPET { registers }
+        if last
+            .compare_exchange(previous, new, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return new;
+        }
     }
 }
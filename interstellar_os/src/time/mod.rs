@@ -14,94 +14,206 @@
 //You should have received a copy of the GNU General Public License
 //along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use alloc::format;
 use conquer_once::spin::OnceCell;
-use core::{sync::atomic::AtomicU64, time::Duration};
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+
+pub use core::time::Duration;
+pub use instant::Instant;
+pub use interval::{interval, Interval};
+pub use sleep::{sleep, sleep_until, Sleep};
+pub use system_time::{SystemTime, UNIX_EPOCH};
+pub use timeout::{timeout, Elapsed, Timeout};
+
+use crate::other::log::LOGGER;
 
 pub mod hpet;
+pub mod instant;
+mod interval;
 pub mod pit;
+pub mod rtc;
+mod sleep;
+pub mod system_time;
+mod timeout;
+pub mod timer;
+pub mod timer_wheel;
+pub mod tsc;
 
 /// This is the timer count for the PIT timer this is incremented every tick
 ///
 /// # Fun Fact
 ///
 /// This will take approx 5.85 Billion years to overflow at a 10ms per second tick
-pub static mut PIT_COUNT: AtomicU64 = AtomicU64::new(0);
+pub static PIT_COUNT: AtomicU64 = AtomicU64::new(0);
 
-/// This is the timer count for the LAPIC timer this is incremented every tick
+/// This is the count of LAPIC timer interrupts
+///
+/// With the periodic tick on this is incremented every 10ms, in tickless mode only when a timer is due
 ///
 /// # Fun Fact
 ///
 /// This will take approx 5.85 Billion years to overflow at a 10ms per second tick
-pub static mut APIC_COUNT: AtomicU64 = AtomicU64::new(0);
+pub static APIC_COUNT: AtomicU64 = AtomicU64::new(0);
 
 /// This is the global timer set up at OS boot
 ///
 /// It can be used freely by most parts of the kernel
-pub static mut GLOBAL_TIMER: OnceCell<Spinlock<Timer>> = OnceCell::uninit();
+pub static GLOBAL_TIMER: OnceCell<Spinlock<Timer>> = OnceCell::uninit();
 
-/// initialize the global timer
+/// The clocksource [Instant] reads from, stored as a [ClockSource]
+static CLOCKSOURCE: AtomicU8 = AtomicU8::new(ClockSource::Pit as u8);
+
+/// Added to the clocksource so time does not jump backwards when switching from the PIT at boot
+static CLOCKSOURCE_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// The hardware counters the monotonic clock can be read from, best first
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ClockSource {
+    /// Invariant time stamp counter - nanosecond resolution and cheapest to read
+    Tsc,
+    /// High precision event timer - 100ns resolution or better but slower MMIO reads
+    Hpet,
+    /// Programmable interval timer tick count - 10ms resolution
+    Pit,
+}
+
+impl ClockSource {
+    fn from_u8(value: u8) -> ClockSource {
+        match value {
+            0 => ClockSource::Tsc,
+            1 => ClockSource::Hpet,
+            _ => ClockSource::Pit,
+        }
+    }
+
+    /// Nanoseconds read directly from this clocksource
+    fn nanos(self) -> u64 {
+        match self {
+            ClockSource::Tsc => tsc::nanos(),
+            ClockSource::Hpet => hpet::get().map(|hpet| hpet.nanos()).unwrap_or(0),
+            ClockSource::Pit => PIT_COUNT.load(Ordering::SeqCst) * 10_000_000,
+        }
+    }
+}
+
+/// initialize the HPET, calibrate the TSC, pick the best clocksource, read the RTC, start the tickless timers and initialize the global timer
 pub fn init() {
-    unsafe { GLOBAL_TIMER.init_once(|| Spinlock::new(Timer::new())) };
+    hpet::init();
+
+    let tsc_usable = tsc::init();
+
+    let clocksource = if tsc_usable {
+        ClockSource::Tsc
+    } else if hpet::get().is_some() {
+        ClockSource::Hpet
+    } else {
+        ClockSource::Pit
+    };
+
+    set_clocksource(clocksource);
+
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .info(&format!("Clocksource: {clocksource:?}"));
+
+    rtc::init();
+
+    timer::init();
+
+    // The PIT is only needed for time keeping if there is nothing better
+    if clocksource != ClockSource::Pit {
+        crate::interrupts::disable_pit_interrupt();
+    }
+
+    GLOBAL_TIMER.init_once(|| Spinlock::new(Timer::new()));
+}
+
+/// Changes the clocksource used by [Instant] keeping the time monotonic
+pub fn set_clocksource(clocksource: ClockSource) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let now = monotonic_nanos();
+        let new = clocksource.nanos();
+
+        CLOCKSOURCE_OFFSET.store(now.saturating_sub(new), Ordering::SeqCst);
+        CLOCKSOURCE.store(clocksource as u8, Ordering::SeqCst);
+    });
+}
+
+/// The clocksource currently used by [Instant]
+pub fn clocksource() -> ClockSource {
+    ClockSource::from_u8(CLOCKSOURCE.load(Ordering::SeqCst))
+}
+
+/// Nanoseconds since boot from the selected clocksource
+pub fn monotonic_nanos() -> u64 {
+    clocksource().nanos() + CLOCKSOURCE_OFFSET.load(Ordering::SeqCst)
+}
+
+/// Converts monotonic nanoseconds to the nanoseconds read from the selected clocksource
+pub(crate) fn clocksource_nanos(monotonic: u64) -> u64 {
+    monotonic.saturating_sub(CLOCKSOURCE_OFFSET.load(Ordering::SeqCst))
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct Timer {
-    /// This is the start timer count for the timer
-    ///
-    /// # Fun Fact
-    ///
-    /// This will take approx 5.85 Billion years to overflow at a 10ms per second tick
-    start_count: u64,
+    /// The time the timer was created
+    start: Instant,
 }
 
 impl Timer {
     #[allow(clippy::new_without_default)]
     /// Create a new Timer and records the start time
     pub fn new() -> Timer {
-        let start_count = unsafe { APIC_COUNT.load(core::sync::atomic::Ordering::SeqCst) };
-        Timer { start_count }
+        Timer {
+            start: Instant::now(),
+        }
     }
 
     /// Get the elapsed time
     ///
-    /// This is tested to be accurate within 0.3 seconds
+    /// This has the resolution of the selected [ClockSource]
     pub fn elapsed(&self) -> Duration {
-        // get the current count say its 10
-        let current_count = unsafe { APIC_COUNT.load(core::sync::atomic::Ordering::SeqCst) };
-        // then we get the elapsed ticks by taking away the start count say this is 0
-        let elapsed_ticks = current_count - self.start_count;
-        // then we return elasped: 10 * ms per tick: 10 which would be 100ms
-        Duration::from_millis(elapsed_ticks * 10) // Assuming that the timer increments every 10ms
+        self.start.elapsed()
     }
 
     /// Suspend the CPU for a [Duration]
     ///
-    /// This is tested to be accurate within 0.3 seconds
-    ///
-    /// The lowest time you can give is 10ms anything under this will finish after 1 loop cycle
+    /// The CPU is halted until a timer wakes it at the target time,
+    /// before the timers are initialized or with interrupts disabled this spins instead
     pub fn sleep(self, duration: Duration) {
-        // Calculate the number of timer ticks for the desired duration
-
-        // Assuming that the timer increments every 10ms
-        // Assuming duration.as_millis is 1000 then it = 100
-        let dur = duration.as_millis() / 10;
-
-        // Get the current timer count
-        // Assuming this is 0
-        let start_count = unsafe { APIC_COUNT.load(core::sync::atomic::Ordering::SeqCst) };
-
-        // Calculate the target count
-        // Assuming this is 100
-        let target_count = start_count + dur as u64;
-
-        // Wait until the timer count reaches the target
-        loop {
-            let current_count = unsafe { APIC_COUNT.load(core::sync::atomic::Ordering::SeqCst) };
-            // Then assuming this would be if 0 >= 100    - wait for 100 ticks which is 1000ms which is 1s
-            if current_count >= target_count {
-                break;
+        let target = Instant::now() + duration;
+
+        let woken = AtomicBool::new(false);
+
+        // The timer holds a pointer to `woken` so once it is added we have to wait for it to fire
+        if interrupts::are_enabled()
+            && timer::add(target, wake_sleeper, &woken as *const AtomicBool as usize).is_ok()
+        {
+            loop {
+                // Interrupts are disabled between the check and the hlt so the wake cannot be missed
+                interrupts::disable();
+                if woken.load(Ordering::SeqCst) {
+                    interrupts::enable();
+                    break;
+                }
+                interrupts::enable_and_hlt();
+            }
+        } else {
+            // Wait until the clock reaches the target
+            while Instant::now() < target {
+                core::hint::spin_loop();
             }
         }
     }
 }
+
+/// Timer callback for [Timer::sleep], `data` points to the sleepers flag
+fn wake_sleeper(data: usize) {
+    let woken = unsafe { &*(data as *const AtomicBool) };
+    woken.store(true, Ordering::SeqCst);
+}
@@ -0,0 +1,352 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use alloc::format;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::other::log::LOGGER;
+
+const CMOS_INDEX_PORT: u16 = 0x70;
+const CMOS_DATA_PORT: u16 = 0x71;
+
+/// Setting this bit in the index port disables NMIs while we talk to the CMOS, it is cleared again afterwards
+const NMI_DISABLE: u8 = 0x80;
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0A;
+const REGISTER_STATUS_B: u8 = 0x0B;
+const REGISTER_STATUS_C: u8 = 0x0C;
+
+/// Status A bit 7 - the RTC is updating its registers so they should not be read
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status A bits 0-3 - periodic interrupt rate
+const STATUS_A_RATE_MASK: u8 = 0x0F;
+
+/// Status B bit 1 - hours are in 24 hour format
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Status B bit 2 - values are binary instead of BCD
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Status B bit 6 - periodic interrupt enable
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+
+/// In 12 hour format this bit of the hours register is set for PM
+const HOUR_PM: u8 = 0x80;
+
+/// Used when the FADT does not give a century register
+const DEFAULT_CENTURY: u16 = 20;
+
+/// Number of RTC periodic interrupts that have fired
+pub static RTC_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// CMOS register holding the century, 0 if there is none - taken from the FADT
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+/// Unix time in nanoseconds when the monotonic clock read 0, see [super::SystemTime]
+pub(super) static BOOT_UNIX_NANOS: AtomicU64 = AtomicU64::new(0);
+
+/// Stops two CPUs or an interrupt handler from interleaving index and data port accesses
+static CMOS_LOCK: Mutex<()> = Mutex::new(());
+
+/// Reads the RTC once and records the wall clock time at boot
+pub fn init() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Initializing RTC", file!(), line!());
+
+    let century_register = crate::acpi::ACPI_INFO
+        .get()
+        .unwrap()
+        .lock()
+        .century_register;
+    CENTURY_REGISTER.store(century_register, Ordering::SeqCst);
+
+    let now = read();
+
+    // Line the RTC seconds up with the monotonic clock
+    let unix_nanos = now.unix_timestamp() * 1_000_000_000;
+    let boot_unix_nanos = unix_nanos.saturating_sub(super::monotonic_nanos());
+    BOOT_UNIX_NANOS.store(boot_unix_nanos, Ordering::SeqCst);
+
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .info(&format!("RTC time: {now}"));
+}
+
+/// A date and time read from the RTC
+///
+/// The RTC has no time zone, it is treated as UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since 1970-01-01 00:00:00 UTC
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(self.year as i64, self.month as i64, self.day as i64);
+
+        (days as u64) * 86400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    /// Converts seconds since 1970-01-01 00:00:00 UTC into a [DateTime]
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86400) as i64;
+        let seconds_of_day = timestamp % 86400;
+
+        let (year, month, day) = civil_from_days(days);
+
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds_of_day / 3600) as u8,
+            minute: ((seconds_of_day % 3600) / 60) as u8,
+            second: (seconds_of_day % 60) as u8,
+        }
+    }
+}
+
+impl core::fmt::Display for DateTime {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Reads the current date and time from the RTC
+///
+/// The RTC can update its registers while we read them, so we wait until no update is in progress
+/// and keep reading until two reads in a row give the same values
+pub fn read() -> DateTime {
+    interrupts::without_interrupts(|| {
+        let _lock = CMOS_LOCK.lock();
+
+        let mut last = read_raw();
+
+        loop {
+            let current = read_raw();
+            if current == last {
+                break;
+            }
+            last = current;
+        }
+
+        let status_b = unsafe { read_register(REGISTER_STATUS_B) };
+
+        decode(last, status_b)
+    })
+}
+
+/// Raw register values - `[seconds, minutes, hours, day, month, year, century]`
+type RawTime = [u8; 7];
+
+fn read_raw() -> RawTime {
+    while unsafe { read_register(REGISTER_STATUS_A) } & STATUS_A_UPDATE_IN_PROGRESS != 0 {
+        core::hint::spin_loop();
+    }
+
+    let century_register = CENTURY_REGISTER.load(Ordering::SeqCst);
+
+    unsafe {
+        [
+            read_register(REGISTER_SECONDS),
+            read_register(REGISTER_MINUTES),
+            read_register(REGISTER_HOURS),
+            read_register(REGISTER_DAY),
+            read_register(REGISTER_MONTH),
+            read_register(REGISTER_YEAR),
+            if century_register != 0 {
+                read_register(century_register)
+            } else {
+                0
+            },
+        ]
+    }
+}
+
+/// Converts raw register values into a [DateTime] using the format given in status register B
+fn decode(raw: RawTime, status_b: u8) -> DateTime {
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year, mut century] = raw;
+
+    let pm = hour & HOUR_PM != 0;
+    hour &= !HOUR_PM;
+
+    if status_b & STATUS_B_BINARY == 0 {
+        second = bcd_to_binary(second);
+        minute = bcd_to_binary(minute);
+        hour = bcd_to_binary(hour);
+        day = bcd_to_binary(day);
+        month = bcd_to_binary(month);
+        year = bcd_to_binary(year);
+        century = bcd_to_binary(century);
+    }
+
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12am is 0 and 12pm is 12
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    let century = if century != 0 {
+        century as u16
+    } else {
+        DEFAULT_CENTURY
+    };
+
+    DateTime {
+        year: century * 100 + year as u16,
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    }
+}
+
+/// Enables the RTC periodic interrupt
+///
+/// `rate` must be between 3 and 15, the interrupt fires at `32768 >> (rate - 1)` Hz so 3 is 8192Hz and 15 is 2Hz
+pub fn enable_periodic(rate: u8) {
+    let rate = rate.clamp(3, 15);
+
+    interrupts::without_interrupts(|| {
+        let _lock = CMOS_LOCK.lock();
+
+        unsafe {
+            let status_a = read_register(REGISTER_STATUS_A);
+            write_register(REGISTER_STATUS_A, (status_a & !STATUS_A_RATE_MASK) | rate);
+
+            let status_b = read_register(REGISTER_STATUS_B);
+            write_register(REGISTER_STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+
+            // Throw away any interrupt that is already pending so the next one is raised
+            read_register(REGISTER_STATUS_C);
+        }
+    });
+}
+
+/// Disables the RTC periodic interrupt
+pub fn disable_periodic() {
+    interrupts::without_interrupts(|| {
+        let _lock = CMOS_LOCK.lock();
+
+        unsafe {
+            let status_b = read_register(REGISTER_STATUS_B);
+            write_register(REGISTER_STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+        }
+    });
+}
+
+/// Frequency of the periodic interrupt for a rate
+pub fn periodic_frequency(rate: u8) -> u32 {
+    32768 >> (rate.clamp(3, 15) - 1)
+}
+
+/// Called by the RTC interrupt handler
+///
+/// Status register C has to be read or the RTC will not raise another interrupt
+pub(crate) fn handle_interrupt() {
+    // Everything else holds the lock with interrupts disabled so it cannot be interrupted mid access
+    unsafe { read_register(REGISTER_STATUS_C) };
+
+    RTC_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+/// # Safety
+///
+/// The caller must make sure nothing else is using the CMOS ports
+unsafe fn read_register(register: u8) -> u8 {
+    let mut index: Port<u8> = Port::new(CMOS_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+
+    index.write(NMI_DISABLE | register);
+    let value = data.read();
+    index.write(register);
+    value
+}
+
+/// # Safety
+///
+/// The caller must make sure nothing else is using the CMOS ports
+unsafe fn write_register(register: u8, value: u8) {
+    let mut index: Port<u8> = Port::new(CMOS_INDEX_PORT);
+    let mut data: Port<u8> = Port::new(CMOS_DATA_PORT);
+
+    index.write(NMI_DISABLE | register);
+    data.write(value);
+    index.write(register);
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0F) + (value >> 4) * 10
+}
+
+/// Days since 1970-01-01 for a date in the proleptic Gregorian calendar
+///
+/// From Howard Hinnant's date algorithms <http://howardhinnant.github.io/date_algorithms.html>
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+    era * 146097 + day_of_era - 719468
+}
+
+/// The date for a number of days since 1970-01-01, the inverse of [days_from_civil]
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_part = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_part + 2) / 5 + 1;
+    let month = if month_part < 10 {
+        month_part + 3
+    } else {
+        month_part - 9
+    };
+    let year = year_of_era + era * 400;
+
+    (if month <= 2 { year + 1 } else { year }, month, day)
+}
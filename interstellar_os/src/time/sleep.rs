@@ -0,0 +1,98 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use core::future::Future;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use super::timer::{self, TimerEvent};
+use super::timer_wheel::TimerId;
+use super::Instant;
+
+/// Waits until `duration` has passed without blocking the executor
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Waits until `deadline` without blocking the executor
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Sleep {
+        deadline,
+        timer: None,
+    }
+}
+
+/// Future returned by [sleep] and [sleep_until]
+///
+/// While pending a kernel timer holds the task's waker and the LAPIC timer interrupt wakes it at the deadline
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Sleep {
+    deadline: Instant,
+    timer: Option<TimerId>,
+}
+
+impl Sleep {
+    /// The time this future completes at
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// The deadline has passed
+    pub fn is_elapsed(&self) -> bool {
+        Instant::now() >= self.deadline
+    }
+
+    /// Changes the deadline, the future can be polled again after it has completed
+    pub fn reset(&mut self, deadline: Instant) {
+        self.cancel_timer();
+        self.deadline = deadline;
+    }
+
+    fn cancel_timer(&mut self) {
+        if let Some(id) = self.timer.take() {
+            timer::cancel(id);
+        }
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // The old timer may hold a waker for a different task so it is always replaced
+        self.cancel_timer();
+
+        if self.is_elapsed() {
+            return Poll::Ready(());
+        }
+
+        match timer::insert(self.deadline, 0, TimerEvent::Wake(cx.waker().clone())) {
+            Ok(id) => self.timer = Some(id),
+            // Without a timer the only option is to keep polling
+            Err(_) => cx.waker().wake_by_ref(),
+        }
+
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.cancel_timer();
+    }
+}
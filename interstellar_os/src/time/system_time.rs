@@ -0,0 +1,98 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use core::ops::{Add, Sub};
+use core::sync::atomic::Ordering;
+use core::time::Duration;
+
+use super::rtc::{DateTime, BOOT_UNIX_NANOS};
+
+/// 1970-01-01 00:00:00 UTC
+pub const UNIX_EPOCH: SystemTime = SystemTime(0);
+
+/// Wall clock time with nanosecond resolution
+///
+/// This works like `std::time::SystemTime`, the RTC is read once at boot and the monotonic clock is added to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SystemTime(u64);
+
+/// Returned by [SystemTime::duration_since] when the other time is later
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SystemTimeError(Duration);
+
+impl SystemTimeError {
+    /// How far the other time was ahead
+    pub fn duration(&self) -> Duration {
+        self.0
+    }
+}
+
+impl SystemTime {
+    /// Returns the current wall clock time
+    pub fn now() -> SystemTime {
+        SystemTime(BOOT_UNIX_NANOS.load(Ordering::SeqCst) + super::monotonic_nanos())
+    }
+
+    /// Creates a [SystemTime] from nanoseconds since [UNIX_EPOCH]
+    pub const fn from_unix_nanos(nanos: u64) -> SystemTime {
+        SystemTime(nanos)
+    }
+
+    /// Nanoseconds since [UNIX_EPOCH]
+    pub const fn unix_nanos(&self) -> u64 {
+        self.0
+    }
+
+    /// Whole seconds since [UNIX_EPOCH]
+    pub const fn unix_timestamp(&self) -> u64 {
+        self.0 / 1_000_000_000
+    }
+
+    /// Time passed from `earlier` to this [SystemTime]
+    pub fn duration_since(&self, earlier: SystemTime) -> Result<Duration, SystemTimeError> {
+        if self.0 >= earlier.0 {
+            Ok(Duration::from_nanos(self.0 - earlier.0))
+        } else {
+            Err(SystemTimeError(Duration::from_nanos(earlier.0 - self.0)))
+        }
+    }
+
+    /// Time passed since this [SystemTime]
+    pub fn elapsed(&self) -> Result<Duration, SystemTimeError> {
+        SystemTime::now().duration_since(*self)
+    }
+
+    /// Splits this [SystemTime] into a UTC date and time
+    pub fn date_time(&self) -> DateTime {
+        DateTime::from_unix_timestamp(self.unix_timestamp())
+    }
+}
+
+impl Add<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn add(self, other: Duration) -> SystemTime {
+        SystemTime(self.0 + other.as_nanos() as u64)
+    }
+}
+
+impl Sub<Duration> for SystemTime {
+    type Output = SystemTime;
+
+    fn sub(self, other: Duration) -> SystemTime {
+        SystemTime(self.0 - other.as_nanos() as u64)
+    }
+}
@@ -0,0 +1,307 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Kernel timers
+//!
+//! Pending deadlines are kept in a [TimerWheel] and the LAPIC timer is programmed to fire at the earliest one,
+//! in TSC-deadline mode when the CPU supports it and the TSC is the clocksource, otherwise in one-shot mode.
+//! When nothing is pending the LAPIC timer is stopped so an idle CPU is not woken up.
+//!
+//! The old fixed 10ms tick can be turned back on with [set_tick_mode], timers then expire on the next tick.
+//!
+//! Timer callbacks run in the LAPIC timer interrupt handler so they must be short and must not block,
+//! async tasks should use [super::sleep] and friends which wake the task from the interrupt instead.
+
+use alloc::format;
+use conquer_once::spin::OnceCell;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use core::task::Waker;
+use core::time::Duration;
+use spinning_top::Spinlock;
+use x2apic::lapic::{LocalApic, TimerMode};
+use x86_64::instructions::interrupts;
+use x86_64::registers::model_specific::Msr;
+
+use super::timer_wheel::{TimerId, TimerWheel};
+use super::{ClockSource, Instant};
+use crate::other::log::LOGGER;
+
+/// Maximum number of pending timers
+pub const MAX_TIMERS: usize = 4096;
+
+/// Time between interrupts when the periodic tick is on
+pub const TICK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// TSC value the LAPIC timer fires at in TSC-deadline mode
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
+static TIMERS: OnceCell<Spinlock<TimerWheel<TimerEvent>>> = OnceCell::uninit();
+
+/// Stored as a [TickMode]
+static TICK_MODE: AtomicU8 = AtomicU8::new(TickMode::Periodic as u8);
+
+/// Set when the LAPIC timer is used in TSC-deadline mode
+static TSC_DEADLINE: AtomicBool = AtomicBool::new(false);
+
+/// Number of timers that have expired
+pub static TIMERS_FIRED: AtomicU64 = AtomicU64::new(0);
+
+/// What happens when a timer expires
+#[derive(Debug, Clone)]
+pub enum TimerEvent {
+    /// Call a function with an argument
+    Call(fn(usize), usize),
+    /// Wake an async task, used by [super::sleep], [super::timeout] and [super::interval]
+    Wake(Waker),
+}
+
+/// How the LAPIC timer is driven
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TickMode {
+    /// Interrupt every [TICK_INTERVAL] whether or not a timer is due
+    Periodic,
+    /// Only interrupt when the next timer is due
+    Tickless,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    /// [MAX_TIMERS] timers are already pending
+    Full,
+    /// [init] has not been called yet
+    NotInitialized,
+}
+
+/// Sets up the timer wheel and switches the LAPIC timer to tickless mode
+///
+/// This has to be called after the clocksource has been selected
+pub fn init() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Initializing timers", file!(), line!());
+
+    TIMERS.init_once(|| Spinlock::new(TimerWheel::new(MAX_TIMERS, super::monotonic_nanos())));
+
+    let tsc_deadline = raw_cpuid::CpuId::new()
+        .get_feature_info()
+        .map(|info| info.has_tsc_deadline())
+        .unwrap_or(false)
+        && super::clocksource() == ClockSource::Tsc;
+
+    TSC_DEADLINE.store(tsc_deadline, Ordering::SeqCst);
+
+    set_tick_mode(TickMode::Tickless);
+
+    LOGGER.get().unwrap().lock().info(&format!(
+        "Timers initialized, TSC-deadline mode: {tsc_deadline}"
+    ));
+}
+
+/// Calls `function(data)` from the timer interrupt at `deadline`
+pub fn add(deadline: Instant, function: fn(usize), data: usize) -> Result<TimerId, TimerError> {
+    insert(deadline, 0, TimerEvent::Call(function, data))
+}
+
+/// Calls `function(data)` from the timer interrupt every `interval` starting one `interval` from now
+pub fn add_periodic(
+    interval: Duration,
+    function: fn(usize),
+    data: usize,
+) -> Result<TimerId, TimerError> {
+    // A zero interval would make the timer one-shot
+    let interval = (interval.as_nanos() as u64).max(1);
+
+    insert(
+        Instant::now() + Duration::from_nanos(interval),
+        interval,
+        TimerEvent::Call(function, data),
+    )
+}
+
+/// Adds a timer to the wheel and reprograms the LAPIC timer if it is now the earliest
+pub fn insert(deadline: Instant, interval: u64, event: TimerEvent) -> Result<TimerId, TimerError> {
+    let timers = TIMERS.get().ok_or(TimerError::NotInitialized)?;
+
+    interrupts::without_interrupts(|| {
+        let mut wheel = timers.lock();
+
+        let earliest = wheel.next_deadline();
+
+        let id = wheel
+            .insert(deadline.as_nanos(), interval, event)
+            .ok_or(TimerError::Full)?;
+
+        if earliest.is_none_or(|earliest| deadline.as_nanos() < earliest) {
+            drop(wheel);
+            program_next();
+        }
+
+        Ok(id)
+    })
+}
+
+/// Stops a timer that has not fired yet
+///
+/// Returns false if the timer already fired or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    let Some(timers) = TIMERS.get() else {
+        return false;
+    };
+
+    // The LAPIC timer is left alone, if this was the earliest timer the interrupt will find nothing to do
+    interrupts::without_interrupts(|| timers.lock().cancel(id).is_some())
+}
+
+/// The earliest pending deadline
+pub fn next_deadline() -> Option<Instant> {
+    let timers = TIMERS.get()?;
+
+    interrupts::without_interrupts(|| timers.lock().next_deadline().map(Instant::from_nanos))
+}
+
+/// Number of pending timers
+pub fn pending() -> usize {
+    TIMERS.get().map_or(0, |timers| {
+        interrupts::without_interrupts(|| timers.lock().len())
+    })
+}
+
+/// Timers have been initialized so [add] can be used
+pub fn is_initialized() -> bool {
+    TIMERS.is_initialized()
+}
+
+/// The current [TickMode]
+pub fn tick_mode() -> TickMode {
+    match TICK_MODE.load(Ordering::SeqCst) {
+        0 => TickMode::Periodic,
+        _ => TickMode::Tickless,
+    }
+}
+
+/// Turns the periodic tick on or off
+pub fn set_tick_mode(mode: TickMode) {
+    interrupts::without_interrupts(|| {
+        TICK_MODE.store(mode as u8, Ordering::SeqCst);
+
+        match mode {
+            TickMode::Periodic => with_lapic(|lapic| unsafe {
+                lapic.set_timer_mode(TimerMode::Periodic);
+                lapic.set_timer_initial(lapic_ticks(TICK_INTERVAL.as_nanos() as u64));
+                lapic.enable_timer();
+            }),
+            TickMode::Tickless => program_next(),
+        }
+    });
+}
+
+/// Called by the LAPIC timer interrupt handler
+///
+/// Runs every expired timer then programs the LAPIC timer for the next one
+pub(crate) fn handle_interrupt() {
+    let Some(timers) = TIMERS.get() else {
+        return;
+    };
+
+    let now = super::monotonic_nanos();
+
+    loop {
+        // The lock is dropped before the callback so it can add or cancel timers
+        let expired = {
+            let mut wheel = timers.lock();
+            wheel.advance(now);
+            wheel.pop_expired()
+        };
+
+        match expired {
+            Some((_, TimerEvent::Call(function, data))) => {
+                TIMERS_FIRED.fetch_add(1, Ordering::SeqCst);
+                function(data);
+            }
+            Some((_, TimerEvent::Wake(waker))) => {
+                TIMERS_FIRED.fetch_add(1, Ordering::SeqCst);
+                waker.wake();
+            }
+            None => break,
+        }
+    }
+
+    if tick_mode() == TickMode::Tickless {
+        program_next();
+    }
+}
+
+/// Programs the LAPIC timer to fire at the earliest deadline or stops it if there are no timers
+///
+/// Must be called with interrupts disabled
+fn program_next() {
+    if tick_mode() != TickMode::Tickless {
+        return;
+    }
+
+    let Some(timers) = TIMERS.get() else {
+        return;
+    };
+
+    let next = timers.lock().next_deadline();
+
+    match next {
+        Some(deadline) if TSC_DEADLINE.load(Ordering::SeqCst) => {
+            let tsc = super::tsc::nanos_to_tsc(super::clocksource_nanos(deadline));
+
+            with_lapic(|lapic| unsafe {
+                lapic.set_timer_mode(TimerMode::TscDeadline);
+                lapic.enable_timer();
+            });
+
+            // A deadline that has already passed fires straight away
+            unsafe { Msr::new(IA32_TSC_DEADLINE).write(tsc.max(1)) };
+        }
+        Some(deadline) => {
+            let delay = deadline.saturating_sub(super::monotonic_nanos());
+
+            with_lapic(|lapic| unsafe {
+                lapic.set_timer_mode(TimerMode::OneShot);
+                // If the deadline is too far away for the counter the interrupt finds nothing to do and programs it again
+                lapic.set_timer_initial(lapic_ticks(delay));
+                lapic.enable_timer();
+            });
+        }
+        None => with_lapic(|lapic| unsafe { lapic.disable_timer() }),
+    }
+}
+
+/// Converts nanoseconds to LAPIC timer counts, at least 1 so the timer fires
+fn lapic_ticks(nanos: u64) -> u32 {
+    let frequency = crate::interrupts::LAPIC_TIMER_FREQUENCY.load(Ordering::SeqCst);
+
+    (nanos as u128 * frequency as u128 / 1_000_000_000).clamp(1, u32::MAX as u128) as u32
+}
+
+/// Locks the LAPIC even if the interrupted code was holding the lock
+fn with_lapic<R>(f: impl FnOnce(&mut LocalApic) -> R) -> R {
+    let lapic = crate::interrupts::LAPIC.get().unwrap();
+
+    if let Some(mut lapic) = lapic.try_lock() {
+        f(&mut lapic)
+    } else {
+        unsafe { lapic.force_unlock() };
+        f(&mut lapic.lock())
+    }
+}
@@ -0,0 +1,386 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A hierarchical timer wheel holding pending deadlines
+//!
+//! Deadlines are nanoseconds on the monotonic clock. The wheel has [LEVELS] levels of [SLOTS] slots,
+//! each level covers 64 times the time of the one below it, level 0 slots are 1ns apart.
+//!
+//! A timer is put in the level of the highest 6 bit group where its deadline differs from the current time,
+//! in the slot given by that group of its deadline. So every slot in a level is later than the current time,
+//! lower slots are earlier than higher slots and every timer in a level is earlier than every timer in the levels above it.
+//! When time moves forward the slots that have been passed are emptied and their timers either expire
+//! or drop down to a lower level.
+//!
+//! Timers live in a fixed size table allocated up front and the slots are linked lists of indexes into that table,
+//! so adding, cancelling and expiring timers never allocates and is safe to do from an interrupt handler.
+
+use alloc::vec::Vec;
+
+/// Bits of the deadline covered by each level
+const LEVEL_BITS: u32 = 6;
+
+/// Slots per level
+pub const SLOTS: usize = 1 << LEVEL_BITS;
+
+/// Enough levels to cover every bit of a 64 bit deadline
+pub const LEVELS: usize = 11;
+
+const SLOT_MASK: u64 = SLOTS as u64 - 1;
+
+/// Marks the end of a list
+const NONE: u32 = u32::MAX;
+
+/// The list expired timers are moved to, after the lists for every slot
+const EXPIRED_LIST: usize = LEVELS * SLOTS;
+
+/// Identifies a timer in a [TimerWheel]
+///
+/// The generation changes every time a table entry is reused so an old [TimerId] cannot cancel a newer timer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId {
+    index: u32,
+    generation: u32,
+}
+
+/// Which list a table entry is linked into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+    Free,
+    List(usize),
+}
+
+struct Entry<T> {
+    /// When the timer expires in nanoseconds
+    deadline: u64,
+    /// Nanoseconds between expiries for periodic timers, 0 for one-shot timers
+    interval: u64,
+    generation: u32,
+    location: Location,
+    next: u32,
+    prev: u32,
+    data: Option<T>,
+}
+
+/// A hierarchical timer wheel with a fixed capacity
+pub struct TimerWheel<T> {
+    /// The time the wheel has been advanced to
+    current: u64,
+    entries: Vec<Entry<T>>,
+    /// The first entry of every slot list and then the expired list
+    heads: Vec<u32>,
+    /// One bit per slot that is set when the slot is not empty
+    occupied: [u64; LEVELS],
+    /// First unused entry, the free list is linked with `next`
+    free: u32,
+    len: usize,
+}
+
+impl<T: Clone> TimerWheel<T> {
+    /// Creates an empty wheel that can hold `capacity` timers starting at `now`
+    pub fn new(capacity: usize, now: u64) -> TimerWheel<T> {
+        let capacity = capacity.min(NONE as usize);
+
+        let mut entries = Vec::with_capacity(capacity);
+        for index in 0..capacity {
+            entries.push(Entry {
+                deadline: 0,
+                interval: 0,
+                generation: 0,
+                location: Location::Free,
+                next: if index + 1 < capacity {
+                    (index + 1) as u32
+                } else {
+                    NONE
+                },
+                prev: NONE,
+                data: None,
+            });
+        }
+
+        TimerWheel {
+            current: now,
+            entries,
+            heads: alloc::vec![NONE; EXPIRED_LIST + 1],
+            occupied: [0; LEVELS],
+            free: if capacity > 0 { 0 } else { NONE },
+            len: 0,
+        }
+    }
+
+    /// The time the wheel has been advanced to
+    pub fn current(&self) -> u64 {
+        self.current
+    }
+
+    /// Number of pending timers including ones that have expired but not been popped
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Maximum number of timers
+    pub fn capacity(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Adds a timer that expires at `deadline`
+    ///
+    /// If `interval` is not 0 the timer is added again `interval` nanoseconds later every time it expires
+    ///
+    /// Returns [None] if the wheel is full
+    pub fn insert(&mut self, deadline: u64, interval: u64, data: T) -> Option<TimerId> {
+        if self.free == NONE {
+            return None;
+        }
+
+        let index = self.free;
+        let entry = &mut self.entries[index as usize];
+        self.free = entry.next;
+
+        entry.deadline = deadline;
+        entry.interval = interval;
+        entry.data = Some(data);
+        let generation = entry.generation;
+
+        self.len += 1;
+        self.link(index);
+
+        Some(TimerId { index, generation })
+    }
+
+    /// Removes a timer that has not expired yet and returns its data
+    ///
+    /// Returns [None] if the timer has already expired or been cancelled
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        let entry = self.entries.get(id.index as usize)?;
+
+        if entry.generation != id.generation || entry.location == Location::Free {
+            return None;
+        }
+
+        self.unlink(id.index);
+        Some(self.release(id.index))
+    }
+
+    /// The deadline of a pending timer
+    pub fn deadline(&self, id: TimerId) -> Option<u64> {
+        let entry = self.entries.get(id.index as usize)?;
+
+        if entry.generation != id.generation || entry.location == Location::Free {
+            return None;
+        }
+
+        Some(entry.deadline)
+    }
+
+    /// The earliest deadline in the wheel
+    ///
+    /// Expired timers that have not been popped give the current time
+    pub fn next_deadline(&self) -> Option<u64> {
+        if self.heads[EXPIRED_LIST] != NONE {
+            return Some(self.current);
+        }
+
+        // The first occupied slot of the lowest occupied level holds the earliest timers
+        let level = self.occupied.iter().position(|&bits| bits != 0)?;
+        let slot = self.occupied[level].trailing_zeros() as usize;
+
+        let mut earliest = u64::MAX;
+        let mut index = self.heads[level * SLOTS + slot];
+        while index != NONE {
+            let entry = &self.entries[index as usize];
+            earliest = earliest.min(entry.deadline);
+            index = entry.next;
+        }
+
+        Some(earliest)
+    }
+
+    /// Moves the wheel forward to `now`
+    ///
+    /// Every timer with a deadline at or before `now` is moved to the expired list to be taken with [TimerWheel::pop_expired]
+    pub fn advance(&mut self, now: u64) {
+        if now <= self.current {
+            return;
+        }
+
+        let old = self.current;
+        self.current = now;
+
+        for level in 0..LEVELS {
+            let shift = LEVEL_BITS * level as u32;
+
+            let old_index = (old >> shift) & SLOT_MASK;
+            let new_index = (now >> shift) & SLOT_MASK;
+
+            let passed = if prefix(old, level) != prefix(now, level) {
+                // Time has moved past the whole level
+                u64::MAX
+            } else if old_index == new_index {
+                // Nothing at this level or above has been reached
+                break;
+            } else {
+                // The slots after the old time up to and including the new time
+                let up_to_new = if new_index == SLOT_MASK {
+                    u64::MAX
+                } else {
+                    (1 << (new_index + 1)) - 1
+                };
+                up_to_new & !((1 << (old_index + 1)) - 1)
+            };
+
+            let mut slots = self.occupied[level] & passed;
+            while slots != 0 {
+                let slot = slots.trailing_zeros() as usize;
+                slots &= slots - 1;
+
+                self.relink_list(level * SLOTS + slot);
+            }
+        }
+    }
+
+    /// Takes one expired timer
+    ///
+    /// Periodic timers are added again for their next deadline and a clone of their data is returned
+    pub fn pop_expired(&mut self) -> Option<(TimerId, T)> {
+        let index = self.heads[EXPIRED_LIST];
+        if index == NONE {
+            return None;
+        }
+
+        self.unlink(index);
+
+        let entry = &mut self.entries[index as usize];
+        let id = TimerId {
+            index,
+            generation: entry.generation,
+        };
+
+        if entry.interval != 0 {
+            let data = entry.data.clone().unwrap();
+
+            // Skip expiries that were missed instead of firing them all at once
+            entry.deadline = entry.deadline.saturating_add(entry.interval);
+            if entry.deadline <= self.current {
+                entry.deadline = self.current.saturating_add(entry.interval);
+            }
+
+            self.link(index);
+
+            Some((id, data))
+        } else {
+            Some((id, self.release(index)))
+        }
+    }
+
+    /// Puts an entry in the list for its deadline
+    fn link(&mut self, index: u32) {
+        let deadline = self.entries[index as usize].deadline;
+
+        let list = if deadline <= self.current {
+            EXPIRED_LIST
+        } else {
+            let highest_bit = 63 - (deadline ^ self.current).leading_zeros();
+            let level = (highest_bit / LEVEL_BITS) as usize;
+            let slot = ((deadline >> (LEVEL_BITS * level as u32)) & SLOT_MASK) as usize;
+
+            self.occupied[level] |= 1 << slot;
+
+            level * SLOTS + slot
+        };
+
+        let head = self.heads[list];
+        if head != NONE {
+            self.entries[head as usize].prev = index;
+        }
+
+        let entry = &mut self.entries[index as usize];
+        entry.location = Location::List(list);
+        entry.next = head;
+        entry.prev = NONE;
+
+        self.heads[list] = index;
+    }
+
+    /// Takes an entry out of its list
+    fn unlink(&mut self, index: u32) {
+        let Entry {
+            location,
+            next,
+            prev,
+            ..
+        } = self.entries[index as usize];
+
+        let Location::List(list) = location else {
+            return;
+        };
+
+        if prev != NONE {
+            self.entries[prev as usize].next = next;
+        } else {
+            self.heads[list] = next;
+        }
+
+        if next != NONE {
+            self.entries[next as usize].prev = prev;
+        }
+
+        if list != EXPIRED_LIST && self.heads[list] == NONE {
+            self.occupied[list / SLOTS] &= !(1 << (list % SLOTS));
+        }
+
+        self.entries[index as usize].location = Location::Free;
+    }
+
+    /// Empties a slot and links every entry in it again relative to the current time
+    fn relink_list(&mut self, list: usize) {
+        let mut index = self.heads[list];
+
+        self.heads[list] = NONE;
+        self.occupied[list / SLOTS] &= !(1 << (list % SLOTS));
+
+        while index != NONE {
+            let next = self.entries[index as usize].next;
+            self.link(index);
+            index = next;
+        }
+    }
+
+    /// Puts an unlinked entry back on the free list and returns its data
+    fn release(&mut self, index: u32) -> T {
+        let entry = &mut self.entries[index as usize];
+
+        entry.generation = entry.generation.wrapping_add(1);
+        entry.location = Location::Free;
+        entry.next = self.free;
+        entry.prev = NONE;
+
+        self.free = index;
+        self.len -= 1;
+
+        entry.data.take().unwrap()
+    }
+}
+
+/// The bits of `time` above `level`
+fn prefix(time: u64, level: usize) -> u64 {
+    time.checked_shr(LEVEL_BITS * (level as u32 + 1))
+        .unwrap_or(0)
+}
@@ -0,0 +1,173 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use alloc::format;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use crate::other::log::LOGGER;
+
+/// TSC frequency in Hz, 0 if it has not been calibrated
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// TSC value when it was calibrated, used as time 0
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+
+/// Set if the TSC runs at a constant rate in every power state
+static TSC_INVARIANT: AtomicBool = AtomicBool::new(false);
+
+/// How long to measure the TSC against the HPET or PIT when calibrating
+const CALIBRATION_TIME: Duration = Duration::from_millis(50);
+
+/// Reads the time stamp counter
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Checks CPUID for an invariant TSC
+pub fn has_invariant_tsc() -> bool {
+    raw_cpuid::CpuId::new()
+        .get_advanced_power_mgmt_info()
+        .map(|apm| apm.has_invariant_tsc())
+        .unwrap_or(false)
+}
+
+/// Detects and calibrates the TSC
+///
+/// The frequency is taken from CPUID if the CPU or hypervisor reports it,
+/// otherwise it is measured against the HPET or if there is no HPET the PIT
+///
+/// Returns true if the TSC is invariant and calibrated so it can be used as a clocksource
+pub fn init() -> bool {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Calibrating TSC", file!(), line!());
+
+    let invariant = has_invariant_tsc();
+    TSC_INVARIANT.store(invariant, Ordering::SeqCst);
+
+    let cpuid = raw_cpuid::CpuId::new();
+
+    let cpuid_frequency = cpuid
+        .get_tsc_info()
+        .and_then(|tsc_info| tsc_info.tsc_frequency())
+        .or_else(|| {
+            cpuid
+                .get_hypervisor_info()
+                .and_then(|hypervisor| hypervisor.tsc_frequency())
+                .map(|khz| khz as u64 * 1000)
+        });
+
+    let frequency = match cpuid_frequency {
+        Some(frequency) if frequency != 0 => frequency,
+        _ => {
+            if let Some(hpet) = super::hpet::get() {
+                calibrate_with_hpet(hpet)
+            } else {
+                calibrate_with_pit()
+            }
+        }
+    };
+
+    TSC_BASE.store(rdtsc(), Ordering::SeqCst);
+    TSC_FREQUENCY.store(frequency, Ordering::SeqCst);
+
+    LOGGER.get().unwrap().lock().info(&format!(
+        "TSC frequency: {frequency}Hz, invariant: {invariant}"
+    ));
+
+    invariant && frequency != 0
+}
+
+/// Measures the TSC while the HPET counts [CALIBRATION_TIME]
+fn calibrate_with_hpet(hpet: &super::hpet::Hpet) -> u64 {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let hpet_start = hpet.counter();
+        let tsc_start = rdtsc();
+
+        hpet.busy_wait(CALIBRATION_TIME);
+
+        let tsc_end = rdtsc();
+        let hpet_end = hpet.counter();
+
+        let nanos = hpet.ticks_to_nanos(hpet_end - hpet_start);
+
+        ((tsc_end - tsc_start) as u128 * 1_000_000_000 / nanos as u128) as u64
+    })
+}
+
+/// Measures the TSC across PIT ticks
+///
+/// The PIT only ticks every 10ms so the measurement is started on a tick edge to get a whole number of ticks
+fn calibrate_with_pit() -> u64 {
+    let ticks = CALIBRATION_TIME.as_millis() as u64 / 10;
+
+    let pit_count = || super::PIT_COUNT.load(Ordering::SeqCst);
+
+    // Wait for the start of a tick
+    let edge = pit_count();
+    while pit_count() == edge {
+        core::hint::spin_loop();
+    }
+
+    let start = pit_count();
+    let tsc_start = rdtsc();
+
+    while pit_count() < start + ticks {
+        core::hint::spin_loop();
+    }
+
+    let tsc_end = rdtsc();
+
+    (tsc_end - tsc_start) * 1000 / (ticks * 10)
+}
+
+/// TSC frequency in Hz or 0 if not calibrated
+pub fn frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::SeqCst)
+}
+
+/// The TSC runs at a constant rate
+pub fn is_invariant() -> bool {
+    TSC_INVARIANT.load(Ordering::SeqCst)
+}
+
+/// Converts TSC ticks to nanoseconds
+pub fn ticks_to_nanos(ticks: u64) -> u64 {
+    let frequency = frequency();
+    if frequency == 0 {
+        return 0;
+    }
+    (ticks as u128 * 1_000_000_000 / frequency as u128) as u64
+}
+
+/// Converts nanoseconds to TSC ticks
+pub fn nanos_to_ticks(nanos: u64) -> u64 {
+    (nanos as u128 * frequency() as u128 / 1_000_000_000) as u64
+}
+
+/// The TSC value `nanos` nanoseconds after the TSC was calibrated
+pub fn nanos_to_tsc(nanos: u64) -> u64 {
+    TSC_BASE.load(Ordering::SeqCst) + nanos_to_ticks(nanos)
+}
+
+/// Nanoseconds since the TSC was calibrated
+pub fn nanos() -> u64 {
+    ticks_to_nanos(rdtsc().saturating_sub(TSC_BASE.load(Ordering::SeqCst)))
+}
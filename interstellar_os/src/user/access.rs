@@ -0,0 +1,299 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Checked access to user memory from system calls
+//!
+//! Pointers from user programs are checked against the running thread's address space before they are used,
+//! then copied with [copy_user_bytes] which the page fault handler can stop at the faulting byte.
+//! A page that goes away between the check and the copy gives [BadAddress] instead of a kernel panic.
+//! With SMAP on, the copy is the only place the kernel touches user pages, inside a [UserAccess] section.
+
+use alloc::vec;
+use alloc::vec::Vec;
+use core::arch::{asm, global_asm};
+use core::marker::PhantomData;
+use core::mem::{size_of, MaybeUninit};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::VirtAddr;
+
+use super::{is_user_range, USER_END};
+use crate::cpu;
+
+/// A user pointer or range that is not mapped for the program, with the address it starts at
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BadAddress(pub u64);
+
+global_asm!(
+    // rdi: destination, rsi: source, rdx: length. Returns the number of bytes not copied in rax
+    ".global copy_user_bytes",
+    "copy_user_bytes:",
+    "mov rcx, rdx",
+    ".global copy_user_bytes_copy",
+    "copy_user_bytes_copy:",
+    "rep movsb",
+    "xor eax, eax",
+    "ret",
+    // The page fault handler jumps here with rcx holding the bytes left
+    ".global copy_user_bytes_fault",
+    "copy_user_bytes_fault:",
+    "mov rax, rcx",
+    "ret",
+);
+
+extern "C" {
+    fn copy_user_bytes(destination: *mut u8, source: *const u8, len: usize) -> usize;
+    fn copy_user_bytes_copy();
+    fn copy_user_bytes_fault();
+}
+
+/// Lets the kernel access user pages until it is dropped, SMAP faults on them otherwise
+struct UserAccess;
+
+impl UserAccess {
+    fn begin() -> Self {
+        if cpu::protections().smap {
+            unsafe { asm!("stac", options(nostack)) };
+        }
+        UserAccess
+    }
+}
+
+impl Drop for UserAccess {
+    fn drop(&mut self) {
+        if cpu::protections().smap {
+            unsafe { asm!("clac", options(nostack)) };
+        }
+    }
+}
+
+/// Moves a page fault on a user address inside [copy_user_bytes] to its error return
+///
+/// Returns false if the fault did not come from a user copy and has to be handled some other way
+pub(crate) fn fixup(stack_frame: &mut InterruptStackFrame, address: VirtAddr) -> bool {
+    if stack_frame.instruction_pointer.as_u64() != copy_user_bytes_copy as usize as u64
+        || address.as_u64() >= USER_END
+    {
+        return false;
+    }
+
+    unsafe {
+        stack_frame.as_mut().update(|frame| {
+            frame.instruction_pointer = VirtAddr::new(copy_user_bytes_fault as usize as u64);
+        });
+    }
+
+    true
+}
+
+/// Copies `destination.len()` bytes from user address `source`
+pub fn copy_from_user(destination: &mut [u8], source: u64) -> Result<(), BadAddress> {
+    if !is_user_range(source, destination.len(), false) {
+        return Err(BadAddress(source));
+    }
+
+    let _access = UserAccess::begin();
+    let left = unsafe {
+        copy_user_bytes(
+            destination.as_mut_ptr(),
+            source as *const u8,
+            destination.len(),
+        )
+    };
+
+    match left {
+        0 => Ok(()),
+        _ => Err(BadAddress(source)),
+    }
+}
+
+/// Copies `source` to user address `destination`, which has to be writable
+pub fn copy_to_user(destination: u64, source: &[u8]) -> Result<(), BadAddress> {
+    if !is_user_range(destination, source.len(), true) {
+        return Err(BadAddress(destination));
+    }
+
+    let _access = UserAccess::begin();
+    let left = unsafe { copy_user_bytes(destination as *mut u8, source.as_ptr(), source.len()) };
+
+    match left {
+        0 => Ok(()),
+        _ => Err(BadAddress(destination)),
+    }
+}
+
+/// Types that can be copied to and from user memory, any bit pattern has to be a valid value
+///
+/// # Safety
+///
+/// The type must have no padding and no invalid bit patterns
+pub unsafe trait Plain: Copy {}
+
+unsafe impl Plain for u8 {}
+unsafe impl Plain for u16 {}
+unsafe impl Plain for u32 {}
+unsafe impl Plain for u64 {}
+unsafe impl Plain for usize {}
+unsafe impl Plain for i8 {}
+unsafe impl Plain for i16 {}
+unsafe impl Plain for i32 {}
+unsafe impl Plain for i64 {}
+unsafe impl Plain for isize {}
+unsafe impl<T: Plain, const N: usize> Plain for [T; N] {}
+
+/// A pointer to a `T` in user memory, it is only checked when it is read or written
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct UserPtr<T> {
+    addr: u64,
+    _type: PhantomData<*mut T>,
+}
+
+impl<T> Clone for UserPtr<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for UserPtr<T> {}
+
+// Only the address is held, it is checked again by whichever thread uses it
+unsafe impl<T> Send for UserPtr<T> {}
+unsafe impl<T> Sync for UserPtr<T> {}
+
+impl<T: Plain> UserPtr<T> {
+    pub fn new(addr: u64) -> Self {
+        Self {
+            addr,
+            _type: PhantomData,
+        }
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.addr == 0
+    }
+
+    /// The pointer `count` values further on
+    pub fn add(&self, count: usize) -> Result<Self, BadAddress> {
+        (count as u64)
+            .checked_mul(size_of::<T>() as u64)
+            .and_then(|offset| self.addr.checked_add(offset))
+            .map(Self::new)
+            .ok_or(BadAddress(self.addr))
+    }
+
+    pub fn read(&self) -> Result<T, BadAddress> {
+        let mut value = MaybeUninit::<T>::uninit();
+
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>())
+        };
+        copy_from_user(bytes, self.addr)?;
+
+        // Every byte was written and T is Plain
+        Ok(unsafe { value.assume_init() })
+    }
+
+    pub fn write(&self, value: T) -> Result<(), BadAddress> {
+        let bytes =
+            unsafe { core::slice::from_raw_parts(&value as *const T as *const u8, size_of::<T>()) };
+        copy_to_user(self.addr, bytes)
+    }
+}
+
+/// `len` bytes of user memory from `addr`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UserSlice {
+    addr: u64,
+    len: usize,
+}
+
+impl UserSlice {
+    pub fn new(addr: u64, len: usize) -> Self {
+        Self { addr, len }
+    }
+
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Copies the whole slice into the kernel
+    pub fn read_to_vec(&self) -> Result<Vec<u8>, BadAddress> {
+        // Checked before allocating so a huge length fails instead of running out of memory
+        if !is_user_range(self.addr, self.len, false) {
+            return Err(BadAddress(self.addr));
+        }
+
+        let mut buffer = vec![0; self.len];
+        copy_from_user(&mut buffer, self.addr)?;
+        Ok(buffer)
+    }
+
+    /// Fills `buffer` from the start of the slice, which has to be at least as long
+    pub fn read(&self, buffer: &mut [u8]) -> Result<(), BadAddress> {
+        if buffer.len() > self.len {
+            return Err(BadAddress(self.addr));
+        }
+        copy_from_user(buffer, self.addr)
+    }
+
+    /// Writes `data` to the start of the slice, which has to be at least as long
+    pub fn write(&self, data: &[u8]) -> Result<(), BadAddress> {
+        if data.len() > self.len {
+            return Err(BadAddress(self.addr));
+        }
+        copy_to_user(self.addr, data)
+    }
+}
+
+/// Reads a NUL terminated string from user address `addr` without the NUL,
+/// None if there is no NUL in the first `max` bytes
+///
+/// It is copied a page at a time so a string that ends just before an unmapped page can be read
+pub fn read_c_string(addr: u64, max: usize) -> Result<Option<Vec<u8>>, BadAddress> {
+    let mut string = Vec::new();
+    let mut chunk = [0u8; 4096];
+
+    while string.len() < max {
+        let at = addr
+            .checked_add(string.len() as u64)
+            .ok_or(BadAddress(addr))?;
+        let len = (4096 - (at % 4096) as usize).min(max - string.len());
+
+        copy_from_user(&mut chunk[..len], at)?;
+
+        match chunk[..len].iter().position(|&byte| byte == 0) {
+            Some(end) => {
+                string.extend_from_slice(&chunk[..end]);
+                return Ok(Some(string));
+            }
+            None => string.extend_from_slice(&chunk[..len]),
+        }
+    }
+
+    Ok(None)
+}
@@ -0,0 +1,681 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! A page table for user programs
+//!
+//! The kernel's level 4 entries are shared with every address space so the kernel stays mapped after a switch,
+//! user pages only go between [USER_START] and [USER_END] where the kernel has no level 4 entries of its own.
+//!
+//! [AddressSpace::fork] shares every user page between two address spaces. Writable pages become read-only
+//! with [COPY_ON_WRITE] set, the first write to one faults and [handle_cow_fault] gives the writer its own copy.
+//! Shared frames are counted so a frame goes back to the frame allocator when the last address space mapping it
+//! unmaps it or is dropped.
+//!
+//! Debuggers change a program's memory with [AddressSpace::write_private], which gives the address space its own copy
+//! of any page it shares first.
+//!
+//! Memory the kernel places for a program, like thread local storage, is mapped by [AddressSpace::map_anywhere]
+//! upwards from [MAP_START]. The program's heap grows up from the end of its image with [AddressSpace::set_break].
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::tlb;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::mapper::TranslateResult;
+use x86_64::structures::paging::page_table::PageTableEntry;
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags,
+    PhysFrame, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+use super::{MAP_START, SIGNAL_TRAMPOLINE, STACK_SIZE, STACK_TOP, USER_END, USER_START};
+use crate::memory::{self, FRAME_ALLOCATOR};
+use crate::process::signal;
+
+/// Marks a read-only page that was writable before [AddressSpace::fork] shared it
+pub const COPY_ON_WRITE: PageTableFlags = PageTableFlags::BIT_9;
+
+/// How many address spaces map each frame shared by [AddressSpace::fork],
+/// frames that are not in here have one owner. Only locked with interrupts disabled as the page fault handler uses it
+static SHARED_FRAMES: Spinlock<BTreeMap<u64, usize>> = Spinlock::new(BTreeMap::new());
+
+/// Returned when pages cannot be mapped or written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The address is outside [USER_START]..[USER_END]
+    OutOfRange,
+    /// Part of the range is already mapped
+    AlreadyMapped,
+    /// Part of the range is not mapped
+    NotMapped,
+    /// There are no free frames
+    OutOfMemory,
+}
+
+/// A level 4 page table with the kernel mapped in its upper entries
+///
+/// Dropping it gives its user pages and page tables back to the frame allocator, it must not be loaded then
+pub struct AddressSpace {
+    level_4: PhysFrame,
+    /// Where [AddressSpace::map_anywhere] maps next
+    next_free: u64,
+    /// Where the heap starts, the page after the program image
+    break_start: u64,
+    /// The end of the heap, the pages up to it are mapped
+    break_end: u64,
+}
+
+impl AddressSpace {
+    /// Makes an address space with no user pages
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let level_4 = memory::allocate_frame().ok_or(MapError::OutOfMemory)?;
+
+        let kernel = unsafe { &*table(memory::kernel_page_table()) };
+        let table = unsafe { &mut *table(level_4) };
+        table.zero();
+
+        let user_entries = level_4_index(USER_START)..level_4_index(USER_END);
+
+        for (index, entry) in kernel.iter().enumerate() {
+            if !user_entries.contains(&index) {
+                table[index] = entry.clone();
+            }
+        }
+
+        let mut space = AddressSpace {
+            level_4,
+            next_free: MAP_START,
+            break_start: 0,
+            break_end: 0,
+        };
+        space.map_signal_trampoline()?;
+
+        Ok(space)
+    }
+
+    /// The level 4 table to load into CR3
+    pub fn page_table(&self) -> PhysFrame {
+        self.level_4
+    }
+
+    /// Maps zeroed pages covering `size` bytes from `start`, they are always present and user accessible
+    pub fn map(
+        &mut self,
+        start: VirtAddr,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<(), MapError> {
+        let pages = user_pages(start, size)?;
+
+        let mut mapper = unsafe { mapper(self.level_4) };
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+
+        // The page fault handler takes the frame allocator for copy-on-write
+        interrupts::without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let allocator = allocator.as_mut().ok_or(MapError::OutOfMemory)?;
+
+            for page in pages {
+                if mapper.translate_page(page).is_ok() {
+                    return Err(MapError::AlreadyMapped);
+                }
+
+                let frame = allocator.allocate_frame().ok_or(MapError::OutOfMemory)?;
+                unsafe { (*table(frame)).zero() };
+
+                unsafe { mapper.map_to(page, frame, flags, allocator) }
+                    .map_err(|_| MapError::OutOfMemory)?
+                    .flush();
+            }
+
+            Ok(())
+        })
+    }
+
+    /// Maps zeroed pages covering `size` bytes where nothing is mapped yet and returns where they start
+    ///
+    /// Each mapping is followed by an unmapped guard page.
+    pub fn map_anywhere(
+        &mut self,
+        size: usize,
+        flags: PageTableFlags,
+    ) -> Result<VirtAddr, MapError> {
+        let start = self.next_free;
+        let size = (size as u64)
+            .checked_add(4095)
+            .ok_or(MapError::OutOfRange)?
+            & !4095;
+
+        // The area runs up to the stack
+        if start.saturating_add(size) > STACK_TOP - STACK_SIZE as u64 {
+            return Err(MapError::OutOfRange);
+        }
+
+        self.map(VirtAddr::new(start), size as usize, flags)?;
+        self.next_free = start + size + 4096;
+
+        Ok(VirtAddr::new(start))
+    }
+
+    /// Unmaps the pages covering `size` bytes from `start`, pages in the range that are not mapped are skipped
+    ///
+    /// The signal trampoline cannot be unmapped. Frames no other address space maps go back to the frame allocator,
+    /// the page tables stay until the address space is dropped
+    pub fn unmap(&mut self, start: VirtAddr, size: usize) -> Result<(), MapError> {
+        let pages = user_pages(start, size)?;
+        let mut mapper = unsafe { mapper(self.level_4) };
+
+        interrupts::without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let mut shared = SHARED_FRAMES.lock();
+
+            for page in pages.filter(|page| page.start_address().as_u64() != SIGNAL_TRAMPOLINE) {
+                let Ok((frame, flush)) = mapper.unmap(page) else {
+                    continue;
+                };
+                flush.flush();
+
+                if release(&mut shared, frame) {
+                    if let Some(allocator) = allocator.as_mut() {
+                        unsafe { allocator.deallocate_frame(frame) };
+                    }
+                }
+            }
+        });
+
+        Ok(())
+    }
+
+    /// Starts the heap at `addr` rounded up to a page, with nothing mapped in it
+    pub fn set_break_start(&mut self, addr: VirtAddr) {
+        self.break_start = addr.align_up(4096u64).as_u64();
+        self.break_end = self.break_start;
+    }
+
+    /// The end of the heap
+    pub fn program_break(&self) -> VirtAddr {
+        VirtAddr::new(self.break_end)
+    }
+
+    /// Moves the end of the heap to `end`, mapping zeroed writable pages when it grows and unmapping them when it shrinks
+    ///
+    /// The heap cannot go below where it starts or into [MAP_START]
+    pub fn set_break(&mut self, end: VirtAddr) -> Result<(), MapError> {
+        if self.break_start == 0 || end.as_u64() < self.break_start || end.as_u64() > MAP_START {
+            return Err(MapError::OutOfRange);
+        }
+
+        let mapped = VirtAddr::new(self.break_end).align_up(4096u64);
+        let wanted = end.align_up(4096u64);
+
+        if wanted > mapped {
+            self.map(
+                mapped,
+                (wanted - mapped) as usize,
+                PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            )?;
+        } else if wanted < mapped {
+            self.unmap(wanted, (mapped - wanted) as usize)?;
+        }
+
+        self.break_end = end.as_u64();
+        Ok(())
+    }
+
+    /// Maps the shared signal trampoline page, it can be run but not written
+    fn map_signal_trampoline(&mut self) -> Result<(), MapError> {
+        let frame = signal::trampoline_frame().ok_or(MapError::OutOfMemory)?;
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(SIGNAL_TRAMPOLINE));
+
+        let mut mapper = unsafe { mapper(self.level_4) };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let table_flags = flags | PageTableFlags::WRITABLE;
+
+        interrupts::without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let allocator = allocator.as_mut().ok_or(MapError::OutOfMemory)?;
+
+            unsafe { mapper.map_to_with_table_flags(page, frame, flags, table_flags, allocator) }
+                .map_err(|_| MapError::OutOfMemory)?
+                .ignore();
+
+            Ok(())
+        })
+    }
+
+    /// Makes a copy of the address space that shares every user page with this one until either writes to it
+    pub fn fork(&mut self) -> Result<AddressSpace, MapError> {
+        let mut child = AddressSpace::new()?;
+        child.next_free = self.next_free;
+        child.break_start = self.break_start;
+        child.break_end = self.break_end;
+
+        let mut parent_mapper = unsafe { mapper(self.level_4) };
+        let mut child_mapper = unsafe { mapper(child.level_4) };
+        let table_flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+
+        interrupts::without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let allocator = allocator.as_mut().ok_or(MapError::OutOfMemory)?;
+            let mut shared = SHARED_FRAMES.lock();
+
+            for (page, frame, mut flags) in user_mappings(self.level_4) {
+                // The child has the trampoline already
+                if page.start_address().as_u64() == SIGNAL_TRAMPOLINE {
+                    continue;
+                }
+
+                if flags.contains(PageTableFlags::WRITABLE) {
+                    flags.remove(PageTableFlags::WRITABLE);
+                    flags.insert(COPY_ON_WRITE);
+
+                    unsafe { parent_mapper.update_flags(page, flags) }
+                        .map_err(|_| MapError::NotMapped)?
+                        .ignore();
+                }
+
+                unsafe {
+                    child_mapper.map_to_with_table_flags(page, frame, flags, table_flags, allocator)
+                }
+                .map_err(|_| MapError::OutOfMemory)?
+                .ignore();
+
+                *shared.entry(frame.start_address().as_u64()).or_insert(1) += 1;
+            }
+
+            Ok::<(), MapError>(())
+        })?;
+
+        // The parent's pages are read-only now
+        if Cr3::read().0 == self.level_4 {
+            tlb::flush_all();
+        }
+
+        Ok(child)
+    }
+
+    /// Copies `data` into the address space at `start`, the pages must already be mapped
+    ///
+    /// This works whether or not the address space is loaded and ignores write protection,
+    /// so it must not be used after [AddressSpace::fork] as the pages may be shared
+    pub fn write(&mut self, start: VirtAddr, data: &[u8]) -> Result<(), MapError> {
+        // Only checked to be in user space, the copy below goes page by page from the address
+        let _ = user_pages(start, data.len())?;
+
+        let mapper = unsafe { mapper(self.level_4) };
+        let mut written = 0;
+
+        while written < data.len() {
+            let addr = start + written as u64;
+            let phys = mapper.translate_addr(addr).ok_or(MapError::NotMapped)?;
+
+            // Copy up to the end of the page
+            let len = (4096 - (addr.as_u64() % 4096) as usize).min(data.len() - written);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[written..].as_ptr(),
+                    memory::phys_to_virt(phys).as_mut_ptr::<u8>(),
+                    len,
+                );
+            }
+
+            written += len;
+        }
+
+        Ok(())
+    }
+
+    /// Copies `data` into the address space at `start` like [AddressSpace::write], after giving this address space
+    /// its own copy of every page in the range that another one may map
+    ///
+    /// Debuggers use this to change a program's code and data, the pages keep their protection
+    pub fn write_private(&mut self, start: VirtAddr, data: &[u8]) -> Result<(), MapError> {
+        let pages = user_pages(start, data.len())?;
+        let mut mapper = unsafe { mapper(self.level_4) };
+
+        interrupts::without_interrupts(|| {
+            let mut shared = SHARED_FRAMES.lock();
+
+            for page in pages {
+                // Every address space maps the same trampoline frame
+                if page.start_address().as_u64() == SIGNAL_TRAMPOLINE {
+                    return Err(MapError::OutOfRange);
+                }
+
+                let (frame, mut flags) = match mapper.translate(page.start_address()) {
+                    TranslateResult::Mapped { frame, flags, .. } => {
+                        (PhysFrame::containing_address(frame.start_address()), flags)
+                    }
+                    _ => return Err(MapError::NotMapped),
+                };
+
+                if flags.contains(COPY_ON_WRITE) {
+                    flags.remove(COPY_ON_WRITE);
+                    flags.insert(PageTableFlags::WRITABLE);
+                    unshare(&mut mapper, &mut shared, page, frame, flags)?;
+                } else if !flags.contains(PageTableFlags::WRITABLE) {
+                    // Read-only pages keep their protection
+                    unshare(&mut mapper, &mut shared, page, frame, flags)?;
+                }
+            }
+
+            Ok(())
+        })?;
+
+        self.write(start, data)
+    }
+
+    /// Copies `buffer.len()` bytes from the address space at `start` into `buffer`, the pages must be mapped
+    ///
+    /// This works whether or not the address space is loaded
+    pub fn read(&self, start: VirtAddr, buffer: &mut [u8]) -> Result<(), MapError> {
+        let _ = user_pages(start, buffer.len())?;
+
+        let mapper = unsafe { mapper(self.level_4) };
+        let mut done = 0;
+
+        while done < buffer.len() {
+            let addr = start + done as u64;
+            let phys = mapper.translate_addr(addr).ok_or(MapError::NotMapped)?;
+
+            // Copy up to the end of the page
+            let len = (4096 - (addr.as_u64() % 4096) as usize).min(buffer.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    memory::phys_to_virt(phys).as_ptr::<u8>(),
+                    buffer[done..].as_mut_ptr(),
+                    len,
+                );
+            }
+
+            done += len;
+        }
+
+        Ok(())
+    }
+
+    /// The flags `addr` is mapped with, None if it is not mapped
+    pub fn flags(&self, addr: VirtAddr) -> Option<PageTableFlags> {
+        flags(self.level_4, addr)
+    }
+}
+
+/// The flags `addr` is mapped with in the page table `level_4`, None if it is not mapped
+///
+/// This only reads the tables so it can be used on the active page table
+pub fn flags(level_4: PhysFrame, addr: VirtAddr) -> Option<PageTableFlags> {
+    match unsafe { mapper(level_4) }.translate(addr) {
+        TranslateResult::Mapped { flags, .. } => Some(flags),
+        _ => None,
+    }
+}
+
+/// The physical address `addr` is mapped to in the page table `level_4`, None if it is not mapped
+///
+/// This only reads the tables so it can be used on the active page table
+pub fn translate(level_4: PhysFrame, addr: VirtAddr) -> Option<PhysAddr> {
+    unsafe { mapper(level_4) }.translate_addr(addr)
+}
+
+impl Drop for AddressSpace {
+    /// Gives the frames no other address space maps back to the frame allocator, with the user page tables
+    fn drop(&mut self) {
+        assert_ne!(
+            Cr3::read().0,
+            self.level_4,
+            "dropped the address space that is loaded"
+        );
+
+        let mappings = user_mappings(self.level_4);
+        let tables = user_tables(self.level_4);
+
+        interrupts::without_interrupts(|| {
+            let mut allocator = FRAME_ALLOCATOR.lock();
+            let Some(allocator) = allocator.as_mut() else {
+                return;
+            };
+            let mut shared = SHARED_FRAMES.lock();
+
+            for (page, frame, _) in mappings {
+                // Every address space maps the same trampoline frame
+                if page.start_address().as_u64() != SIGNAL_TRAMPOLINE && release(&mut shared, frame)
+                {
+                    unsafe { allocator.deallocate_frame(frame) };
+                }
+            }
+
+            for table in tables {
+                unsafe { allocator.deallocate_frame(table) };
+            }
+        });
+    }
+}
+
+/// Gives up one address space's share of `frame`, returns true if nobody maps it anymore
+fn release(shared: &mut BTreeMap<u64, usize>, frame: PhysFrame) -> bool {
+    let addr = frame.start_address().as_u64();
+
+    match shared.get(&addr).copied() {
+        // The other owner has the frame to itself now
+        Some(2) => {
+            shared.remove(&addr);
+            false
+        }
+        Some(owners) => {
+            shared.insert(addr, owners - 1);
+            false
+        }
+        None => true,
+    }
+}
+
+/// Gives the writer of a copy-on-write page its own writable copy, returns false if `addr` is not a copy-on-write page
+///
+/// Called by the page fault handler for write faults on user pages in the active address space, with interrupts disabled
+pub(crate) fn handle_cow_fault(addr: VirtAddr) -> bool {
+    if addr.as_u64() < USER_START || addr.as_u64() >= USER_END {
+        return false;
+    }
+
+    let level_4 = Cr3::read().0;
+    let mut mapper = unsafe { mapper(level_4) };
+    let page = Page::<Size4KiB>::containing_address(addr);
+
+    // Threads of one process can fault on the same page at once, only the first copies it
+    let mut shared = SHARED_FRAMES.lock();
+
+    let (frame, mut flags) = match mapper.translate(addr) {
+        TranslateResult::Mapped { frame, flags, .. } if flags.contains(COPY_ON_WRITE) => (
+            PhysFrame::<Size4KiB>::containing_address(frame.start_address()),
+            flags,
+        ),
+        // Another thread got its copy first, this CPU still had the read-only entry cached
+        TranslateResult::Mapped { flags, .. }
+            if flags.contains(PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE) =>
+        {
+            tlb::flush(addr);
+            return true;
+        }
+        _ => return false,
+    };
+
+    flags.remove(COPY_ON_WRITE);
+    flags.insert(PageTableFlags::WRITABLE);
+
+    unshare(&mut mapper, &mut shared, page, frame, flags).is_ok()
+}
+
+/// Maps the copy-on-write `frame` at `page` with `flags` for one of its owners, copying it if someone else still maps it
+fn unshare(
+    mapper: &mut OffsetPageTable,
+    shared: &mut BTreeMap<u64, usize>,
+    page: Page<Size4KiB>,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapError> {
+    let addr = frame.start_address().as_u64();
+
+    match shared.get(&addr).copied() {
+        Some(owners) if owners > 1 => {
+            remap_copy(mapper, page, frame, flags)?;
+            release(shared, frame);
+        }
+        // The last owner just gets write access back
+        _ => {
+            shared.remove(&addr);
+            unsafe { mapper.update_flags(page, flags) }
+                .map_err(|_| MapError::NotMapped)?
+                .flush();
+        }
+    }
+
+    Ok(())
+}
+
+/// Maps a new copy of `frame` at `page` in its place with `flags`
+fn remap_copy(
+    mapper: &mut OffsetPageTable,
+    page: Page<Size4KiB>,
+    frame: PhysFrame,
+    flags: PageTableFlags,
+) -> Result<(), MapError> {
+    let copy = memory::allocate_frame().ok_or(MapError::OutOfMemory)?;
+
+    unsafe {
+        core::ptr::copy_nonoverlapping(
+            memory::phys_to_virt(frame.start_address()).as_ptr::<u8>(),
+            memory::phys_to_virt(copy.start_address()).as_mut_ptr::<u8>(),
+            4096,
+        );
+    }
+
+    let (_, flush) = mapper.unmap(page).map_err(|_| MapError::NotMapped)?;
+    flush.ignore();
+
+    // The tables above the page already exist so no frames are needed
+    unsafe { mapper.map_to(page, copy, flags, &mut NoFrames) }
+        .map_err(|_| MapError::OutOfMemory)?
+        .flush();
+
+    Ok(())
+}
+
+/// Used where every page table is known to exist already
+struct NoFrames;
+
+unsafe impl FrameAllocator<Size4KiB> for NoFrames {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        None
+    }
+}
+
+/// Every user page mapped in `level_4` with its frame and flags
+fn user_mappings(level_4: PhysFrame) -> Vec<(Page<Size4KiB>, PhysFrame, PageTableFlags)> {
+    let mut mappings = Vec::new();
+    let level_4_table = unsafe { &*table(level_4) };
+
+    for l4 in level_4_index(USER_START)..level_4_index(USER_END) {
+        for (l3, l3_entry) in next_level(&level_4_table[l4]) {
+            for (l2, l2_entry) in next_level(l3_entry) {
+                for (l1, l1_entry) in next_level(l2_entry) {
+                    let Ok(frame) = l1_entry.frame() else {
+                        continue;
+                    };
+                    let addr = (l4 << 39 | l3 << 30 | l2 << 21 | l1 << 12) as u64;
+                    mappings.push((
+                        Page::containing_address(VirtAddr::new(addr)),
+                        frame,
+                        l1_entry.flags(),
+                    ));
+                }
+            }
+        }
+    }
+
+    mappings
+}
+
+/// The page tables of the user half of `level_4` below the level 4 table, followed by the level 4 table itself
+fn user_tables(level_4: PhysFrame) -> Vec<PhysFrame> {
+    let mut tables = Vec::new();
+    let level_4_table = unsafe { &*table(level_4) };
+
+    for l4 in level_4_index(USER_START)..level_4_index(USER_END) {
+        let entry = &level_4_table[l4];
+        for (_, l3_entry) in next_level(entry) {
+            for (_, l2_entry) in next_level(l3_entry) {
+                tables.extend(l2_entry.frame().ok());
+            }
+            tables.extend(l3_entry.frame().ok());
+        }
+        tables.extend(entry.frame().ok());
+    }
+
+    tables.push(level_4);
+    tables
+}
+
+/// The present entries of the table `entry` points to, with their indexes
+fn next_level(entry: &PageTableEntry) -> impl Iterator<Item = (usize, &'static PageTableEntry)> {
+    // User space is only mapped with 4 KiB pages
+    let next = entry.frame().ok().map(|frame| unsafe { &*table(frame) });
+
+    next.into_iter()
+        .flat_map(|next| next.iter().enumerate())
+        .filter(|(_, entry)| entry.flags().contains(PageTableFlags::PRESENT))
+}
+
+/// A mapper for the page table `level_4`
+///
+/// # Safety
+///
+/// Only one mapper that changes the tables may exist at a time
+unsafe fn mapper(level_4: PhysFrame) -> OffsetPageTable<'static> {
+    OffsetPageTable::new(&mut *table(level_4), memory::phys_to_virt(PhysAddr::new(0)))
+}
+
+/// The page table in `frame` through the physical memory mapping
+fn table(frame: PhysFrame) -> *mut PageTable {
+    memory::phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+fn level_4_index(addr: u64) -> usize {
+    (addr >> 39) as usize & 0x1FF
+}
+
+/// The pages covering `size` bytes from `start` if they are all in user space
+fn user_pages(
+    start: VirtAddr,
+    size: usize,
+) -> Result<impl Iterator<Item = Page<Size4KiB>>, MapError> {
+    let end = start
+        .as_u64()
+        .checked_add(size as u64)
+        .ok_or(MapError::OutOfRange)?;
+
+    if start.as_u64() < USER_START || end > USER_END {
+        return Err(MapError::OutOfRange);
+    }
+
+    let first = Page::<Size4KiB>::containing_address(start);
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end.max(start.as_u64() + 1) - 1));
+
+    Ok(Page::range_inclusive(first, last).take(if size == 0 { 0 } else { usize::MAX }))
+}
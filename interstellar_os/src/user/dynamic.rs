@@ -0,0 +1,497 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Dynamic linking
+//!
+//! A program with a PT_INTERP header names the dynamic linker that loads it. When that file is in the initrd, found
+//! by its path or its file name, it is loaded next to the program and started instead of it, with AT_BASE telling it
+//! where it is loaded like Linux does for `ld.so`.
+//!
+//! Otherwise the kernel links the program itself. Each shared object named by a DT_NEEDED entry, of the program or of
+//! another shared object, is found in the initrd the same way and loaded once. Then the RELA relocations of every
+//! object are applied, with symbols looked up in the program first and then in the shared objects in the order they
+//! were loaded. Calls through the PLT are bound when the program is loaded, there is no lazy binding.
+//! The kernel does not run the initialisation functions of shared objects and they cannot have thread local storage.
+//!
+//! Shared objects and dynamic linkers are loaded at a random page from [LIBRARY_START] with a random gap between them,
+//! so their addresses differ every time a program is loaded.
+
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::VirtAddr;
+
+use super::elf::{map_segments, read_u16, read_u32, read_u64, Elf, ElfError};
+use super::tls::Tls;
+use super::{find_program, program_name, AddressSpace, LIBRARY_START};
+use crate::drivers::random::RandomNumberGenerator;
+use crate::other::log::LOGGER;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_PLTRELSZ: u64 = 2;
+const DT_HASH: u64 = 4;
+const DT_STRTAB: u64 = 5;
+const DT_SYMTAB: u64 = 6;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_STRSZ: u64 = 10;
+const DT_SYMENT: u64 = 11;
+const DT_REL: u64 = 17;
+const DT_PLTREL: u64 = 20;
+const DT_JMPREL: u64 = 23;
+const DT_GNU_HASH: u64 = 0x6FFF_FEF5;
+
+const R_X86_64_NONE: u32 = 0;
+const R_X86_64_64: u32 = 1;
+const R_X86_64_COPY: u32 = 5;
+const R_X86_64_GLOB_DAT: u32 = 6;
+const R_X86_64_JUMP_SLOT: u32 = 7;
+const R_X86_64_RELATIVE: u32 = 8;
+const R_X86_64_TPOFF64: u32 = 18;
+
+const STB_LOCAL: u8 = 0;
+const STB_WEAK: u8 = 2;
+const STT_TLS: u8 = 6;
+const SHN_UNDEF: u16 = 0;
+
+const DYNAMIC_ENTRY_SIZE: usize = 16;
+const SYMBOL_SIZE: u64 = 24;
+const RELA_SIZE: u64 = 24;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// The first shared object is loaded at one of this many pages from [LIBRARY_START]
+const ASLR_PAGES: u128 = 1 << 30;
+
+/// Up to this many unmapped pages are left after each shared object
+const GAP_PAGES: u128 = 256;
+
+/// Set once [random_pages] has warned that there are no random numbers
+static ASLR_WARNED: AtomicBool = AtomicBool::new(false);
+
+/// How a dynamically linked program is loaded
+pub(super) enum Linker<'a> {
+    /// The dynamic linker from PT_INTERP loads the shared objects itself
+    Interpreter(Elf<'static>),
+    /// The kernel links the program with its shared objects, in the order symbols are looked up in
+    Kernel {
+        program: Dynamic<'a>,
+        libraries: Vec<(Elf<'static>, Dynamic<'static>)>,
+    },
+}
+
+impl<'a> Linker<'a> {
+    /// Finds and checks the dynamic linker at `interpreter`, or the shared objects `program` needs if it is not in
+    /// the initrd, nothing is mapped yet
+    pub fn new(program: &Elf<'a>, interpreter: &str) -> Result<Linker<'a>, ElfError> {
+        let mut rng = RandomNumberGenerator::new();
+        let mut base = LIBRARY_START + random_pages(&mut rng, ASLR_PAGES) * PAGE_SIZE;
+
+        if let Some(image) = find_object(interpreter) {
+            return Ok(Linker::Interpreter(Elf::parse(image, Some(base))?));
+        }
+
+        let dynamic = Dynamic::parse(program)?;
+        let mut needed: VecDeque<&str> = dynamic.needed.iter().copied().collect();
+        let mut loaded: Vec<&str> = Vec::new();
+        let mut libraries = Vec::new();
+
+        while let Some(name) = needed.pop_front() {
+            if loaded.contains(&name) {
+                continue;
+            }
+
+            let image = find_object(name).ok_or(ElfError::MissingLibrary)?;
+            let elf = Elf::parse(image, Some(base))?;
+            if elf.tls.is_some() {
+                return Err(ElfError::LibraryTls);
+            }
+            let library = Dynamic::parse(&elf)?;
+
+            let end = VirtAddr::new(elf.end()).align_up(PAGE_SIZE).as_u64();
+            base = end + (1 + random_pages(&mut rng, GAP_PAGES)) * PAGE_SIZE;
+
+            needed.extend(library.needed.iter().copied());
+            loaded.push(name);
+            libraries.push((elf, library));
+        }
+
+        Ok(Linker::Kernel {
+            program: dynamic,
+            libraries,
+        })
+    }
+
+    /// Maps the dynamic linker or the shared objects in `space`, where `program` is already mapped, and relocates them
+    ///
+    /// Returns the dynamic linker if there is one, the program starts at its entry point
+    pub fn link(
+        &self,
+        space: &mut AddressSpace,
+        program: &Elf<'a>,
+    ) -> Result<Option<&Elf<'static>>, ElfError> {
+        match self {
+            Linker::Interpreter(interpreter) => {
+                map_segments(space, interpreter)?;
+                Ok(Some(interpreter))
+            }
+            Linker::Kernel {
+                program: dynamic,
+                libraries,
+            } => {
+                for (elf, _) in libraries {
+                    map_segments(space, elf)?;
+                }
+
+                let mut scope: Vec<(&Elf, &Dynamic)> = Vec::with_capacity(libraries.len() + 1);
+                scope.push((program, dynamic));
+                scope.extend(libraries.iter().map(|(elf, dynamic)| (elf, dynamic)));
+
+                // Copy relocations in the program read data the shared objects have already relocated
+                for &object in scope.iter().rev() {
+                    relocate(space, object, &scope, program.tls)?;
+                }
+
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// The tables the dynamic section of an object points at
+pub(super) struct Dynamic<'a> {
+    /// The shared objects it needs
+    needed: Vec<&'a str>,
+    symbols: &'a [u8],
+    strings: &'a [u8],
+    /// From DT_RELA and DT_JMPREL
+    relocations: [&'a [u8]; 2],
+}
+
+impl<'a> Dynamic<'a> {
+    fn parse(elf: &Elf<'a>) -> Result<Dynamic<'a>, ElfError> {
+        let section = elf.dynamic.ok_or(ElfError::BadDynamic)?;
+
+        let mut needed = Vec::new();
+        let (mut strings, mut strings_size) = (None, 0);
+        let (mut symbols, mut hash, mut gnu_hash) = (None, None, None);
+        let (mut rela, mut rela_size) = (None, 0);
+        let (mut jmprel, mut jmprel_size) = (None, 0);
+
+        for entry in section.chunks_exact(DYNAMIC_ENTRY_SIZE) {
+            let value = read_u64(entry, 8)?;
+
+            match read_u64(entry, 0)? {
+                DT_NULL => break,
+                DT_NEEDED => needed.push(value),
+                DT_STRTAB => strings = Some(value),
+                DT_STRSZ => strings_size = value,
+                DT_SYMTAB => symbols = Some(value),
+                DT_HASH => hash = Some(value),
+                DT_GNU_HASH => gnu_hash = Some(value),
+                DT_RELA => rela = Some(value),
+                DT_RELASZ => rela_size = value,
+                DT_JMPREL => jmprel = Some(value),
+                DT_PLTRELSZ => jmprel_size = value,
+                DT_SYMENT if value != SYMBOL_SIZE => return Err(ElfError::BadDynamic),
+                DT_RELAENT if value != RELA_SIZE => return Err(ElfError::BadDynamic),
+                // x86_64 only uses RELA relocations
+                DT_REL => return Err(ElfError::BadDynamic),
+                DT_PLTREL if value != DT_RELA => return Err(ElfError::BadDynamic),
+                _ => {}
+            }
+        }
+
+        // The addresses in the section are where the object is linked
+        let table = |addr: Option<u64>, len: u64| -> Result<&'a [u8], ElfError> {
+            match addr {
+                Some(addr) => {
+                    elf.bytes_at(addr.checked_add(elf.bias).ok_or(ElfError::BadDynamic)?, len)
+                }
+                None if len == 0 => Ok(&[]),
+                None => Err(ElfError::BadDynamic),
+            }
+        };
+
+        let symbol_count = match (hash, gnu_hash) {
+            // The number of chains in a DT_HASH table is the number of symbols
+            (Some(hash), _) => read_u32(table(Some(hash), 8)?, 4)? as u64,
+            (None, Some(gnu_hash)) => gnu_hash_symbol_count(
+                elf,
+                gnu_hash.checked_add(elf.bias).ok_or(ElfError::BadDynamic)?,
+            )?,
+            (None, None) => 0,
+        };
+
+        let strings = table(strings, strings_size)?;
+        let needed = needed
+            .into_iter()
+            .map(|offset| {
+                core::str::from_utf8(string_at(strings, offset)?).map_err(|_| ElfError::BadDynamic)
+            })
+            .collect::<Result<Vec<&str>, ElfError>>()?;
+
+        let relocations = [table(rela, rela_size)?, table(jmprel, jmprel_size)?];
+        if relocations
+            .iter()
+            .any(|table| !(table.len() as u64).is_multiple_of(RELA_SIZE))
+        {
+            return Err(ElfError::BadDynamic);
+        }
+
+        Ok(Dynamic {
+            needed,
+            symbols: table(symbols, symbol_count * SYMBOL_SIZE)?,
+            strings,
+            relocations,
+        })
+    }
+
+    fn symbol(&self, index: u64) -> Result<Symbol<'a>, ElfError> {
+        let start = index.checked_mul(SYMBOL_SIZE).ok_or(ElfError::BadDynamic)? as usize;
+        let entry = self
+            .symbols
+            .get(start..start + SYMBOL_SIZE as usize)
+            .ok_or(ElfError::BadDynamic)?;
+
+        Ok(Symbol {
+            name: string_at(self.strings, read_u32(entry, 0)? as u64)?,
+            info: entry[4],
+            section: read_u16(entry, 6)?,
+            value: read_u64(entry, 8)?,
+            size: read_u64(entry, 16)?,
+        })
+    }
+
+    /// The global or weak symbol called `name` this object defines
+    fn find(&self, name: &[u8]) -> Option<Symbol<'a>> {
+        (1..self.symbols.len() as u64 / SYMBOL_SIZE)
+            .filter_map(|index| self.symbol(index).ok())
+            .find(|symbol| {
+                symbol.section != SHN_UNDEF && symbol.binding() != STB_LOCAL && symbol.name == name
+            })
+    }
+}
+
+/// An entry of a symbol table
+#[derive(Debug, Clone, Copy)]
+struct Symbol<'a> {
+    name: &'a [u8],
+    info: u8,
+    section: u16,
+    value: u64,
+    size: u64,
+}
+
+impl Symbol<'_> {
+    fn binding(&self) -> u8 {
+        self.info >> 4
+    }
+
+    fn is_tls(&self) -> bool {
+        self.info & 0xF == STT_TLS
+    }
+}
+
+/// A symbol and the object that defines it
+struct Definition<'s, 'a> {
+    elf: &'s Elf<'a>,
+    symbol: Symbol<'a>,
+}
+
+impl Definition<'_, '_> {
+    /// The symbol's address, or its offset in the TLS template for a thread local variable
+    fn address(&self) -> u64 {
+        if self.symbol.is_tls() {
+            self.symbol.value
+        } else {
+            self.symbol.value.wrapping_add(self.elf.bias)
+        }
+    }
+}
+
+/// Applies the relocations of `object`
+fn relocate(
+    space: &mut AddressSpace,
+    (elf, dynamic): (&Elf, &Dynamic),
+    scope: &[(&Elf, &Dynamic)],
+    tls: Option<Tls>,
+) -> Result<(), ElfError> {
+    for entry in dynamic
+        .relocations
+        .iter()
+        .flat_map(|table| table.chunks_exact(RELA_SIZE as usize))
+    {
+        let place = read_u64(entry, 0)?.wrapping_add(elf.bias);
+        let info = read_u64(entry, 8)?;
+        let addend = read_u64(entry, 16)?;
+        let kind = info as u32;
+        let index = info >> 32;
+
+        let value = match kind {
+            R_X86_64_NONE => continue,
+            R_X86_64_RELATIVE => elf.bias.wrapping_add(addend),
+            R_X86_64_64 => resolve((elf, dynamic), index, scope, false)?
+                .map_or(0, |definition| definition.address())
+                .wrapping_add(addend),
+            R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT => resolve((elf, dynamic), index, scope, false)?
+                .map_or(0, |definition| definition.address()),
+            // Only the program has thread local storage, its variables are below the thread pointer
+            R_X86_64_TPOFF64 => {
+                let definition = resolve((elf, dynamic), index, scope, false)?
+                    .ok_or(ElfError::UndefinedSymbol)?;
+                let tls = tls.ok_or(ElfError::BadDynamic)?;
+                if !definition.symbol.is_tls() {
+                    return Err(ElfError::BadDynamic);
+                }
+
+                definition
+                    .address()
+                    .wrapping_add(addend)
+                    .wrapping_sub(tls.offset())
+            }
+            // The program gets its own copy of a variable from a shared object
+            R_X86_64_COPY => {
+                let definition = resolve((elf, dynamic), index, scope, true)?
+                    .ok_or(ElfError::UndefinedSymbol)?;
+                let (source, size) = (definition.address(), definition.symbol.size);
+                if !definition.elf.contains(source, size) || !elf.contains(place, size) {
+                    return Err(ElfError::BadDynamic);
+                }
+
+                let mut data = alloc::vec![0; size as usize];
+                space.read(VirtAddr::new(source), &mut data)?;
+                space.write(VirtAddr::new(place), &data)?;
+                continue;
+            }
+            _ => return Err(ElfError::UnsupportedRelocation(kind)),
+        };
+
+        // A relocation may only change the object it belongs to
+        if !elf.contains(place, 8) {
+            return Err(ElfError::BadDynamic);
+        }
+        space.write(VirtAddr::new(place), &value.to_le_bytes())?;
+    }
+
+    Ok(())
+}
+
+/// Finds where symbol `index` of `object` is defined, None for an undefined weak symbol
+///
+/// Copy relocations skip the object itself as its definition is the copy
+fn resolve<'s, 'a>(
+    object: (&'s Elf<'a>, &'s Dynamic<'a>),
+    index: u64,
+    scope: &[(&'s Elf<'a>, &'s Dynamic<'a>)],
+    copy: bool,
+) -> Result<Option<Definition<'s, 'a>>, ElfError> {
+    let (elf, dynamic) = object;
+    let symbol = dynamic.symbol(index)?;
+
+    if symbol.binding() == STB_LOCAL {
+        return match symbol.section {
+            SHN_UNDEF => Err(ElfError::BadDynamic),
+            _ => Ok(Some(Definition { elf, symbol })),
+        };
+    }
+
+    let definition = scope
+        .iter()
+        .filter(|(_, other)| !(copy && core::ptr::eq(*other, dynamic)))
+        .find_map(|&(elf, other)| {
+            other
+                .find(symbol.name)
+                .map(|symbol| Definition { elf, symbol })
+        });
+
+    match definition {
+        Some(definition) => Ok(Some(definition)),
+        None if symbol.binding() == STB_WEAK => Ok(None),
+        None => Err(ElfError::UndefinedSymbol),
+    }
+}
+
+/// The number of symbols in the symbol table a DT_GNU_HASH table at `addr` covers
+///
+/// The table only has the symbols that are exported, from the symbol offset on. Each bucket is the first symbol of a
+/// chain that ends with a hash with the lowest bit set, so the chain of the highest bucket ends the table.
+fn gnu_hash_symbol_count(elf: &Elf, addr: u64) -> Result<u64, ElfError> {
+    let header = elf.bytes_at(addr, 16)?;
+    let bucket_count = read_u32(header, 0)? as u64;
+    let symbol_offset = read_u32(header, 4)? as u64;
+    let bloom_size = read_u32(header, 8)? as u64;
+
+    let buckets_addr = addr + 16 + bloom_size * 8;
+    let buckets = elf.bytes_at(buckets_addr, bucket_count * 4)?;
+    let chains_addr = buckets_addr + bucket_count * 4;
+
+    let mut last = 0;
+    for bucket in buckets.chunks_exact(4) {
+        last = last.max(read_u32(bucket, 0)? as u64);
+    }
+
+    if last < symbol_offset {
+        return Ok(symbol_offset);
+    }
+
+    loop {
+        let hash = read_u32(
+            elf.bytes_at(chains_addr + (last - symbol_offset) * 4, 4)?,
+            0,
+        )?;
+        last += 1;
+
+        if hash & 1 != 0 {
+            return Ok(last);
+        }
+    }
+}
+
+/// The null terminated string at `offset` in a string table
+fn string_at(strings: &[u8], offset: u64) -> Result<&[u8], ElfError> {
+    let rest = strings.get(offset as usize..).ok_or(ElfError::BadDynamic)?;
+    let end = rest
+        .iter()
+        .position(|&byte| byte == 0)
+        .ok_or(ElfError::BadDynamic)?;
+
+    Ok(&rest[..end])
+}
+
+/// A shared object or dynamic linker from the initrd, found by its path or just its file name
+fn find_object(path: &str) -> Option<&'static [u8]> {
+    find_program(path).or_else(|| find_program(program_name(path)))
+}
+
+/// A random number of pages below `max`
+///
+/// Without a random number generator this is 0 and libraries load at fixed addresses, which is logged once
+fn random_pages(rng: &mut RandomNumberGenerator, max: u128) -> u64 {
+    match rng.generate_number(Some(0), Some(max - 1)) {
+        Some(pages) => pages as u64,
+        None => {
+            if !ASLR_WARNED.swap(true, Ordering::SeqCst) {
+                LOGGER.get().unwrap().lock().warn(
+                    "No random numbers for ASLR, shared objects are loaded at fixed addresses",
+                );
+            }
+            0
+        }
+    }
+}
@@ -0,0 +1,636 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Loads ELF64 executables into an [AddressSpace]
+//!
+//! x86_64 executables are loaded at the address they are linked at, position independent ones (PIE) at
+//! [IMAGE_BASE]. Every header is checked before anything is mapped so a malformed file gives an [ElfError]
+//! instead of a panic.
+//!
+//! Programs with a PT_INTERP header are dynamically linked, their shared objects are loaded by [super::dynamic].
+//!
+//! Interstellar programs are marked by a note owned by "Interstellar", which `interstellar_user` and the programs
+//! in `user-programs` add. Programs without it, like static musl binaries, are Linux programs and run with the
+//! Linux system calls from [crate::syscall::linux], see [Abi]. Those are rejected if their OS ABI byte names
+//! another system, the byte is left as none by most Linux toolchains so it cannot mark Linux programs itself.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+use super::dynamic::Linker;
+use super::tls::{Tls, TlsTemplate};
+use super::{AddressSpace, MapError, IMAGE_BASE, STACK_SIZE, STACK_TOP, USER_END, USER_START};
+use crate::drivers::random::RandomNumberGenerator;
+
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ELFOSABI_NONE: u8 = 0;
+const ELFOSABI_LINUX: u8 = 3;
+const ET_EXEC: u16 = 2;
+const ET_DYN: u16 = 3;
+const EM_X86_64: u16 = 62;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+const PT_NOTE: u32 = 4;
+const PT_PHDR: u32 = 6;
+const PT_TLS: u32 = 7;
+
+/// The owner of the note that marks interstellar programs, its description is [ABI_VERSION]
+const NOTE_NAME: &[u8] = b"Interstellar\0";
+const NT_INTERSTELLAR_ABI: u32 = 1;
+const ABI_VERSION: u32 = 1;
+
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Auxiliary vector entry types
+pub const AT_NULL: u64 = 0;
+pub const AT_PHDR: u64 = 3;
+pub const AT_PHENT: u64 = 4;
+pub const AT_PHNUM: u64 = 5;
+pub const AT_PAGESZ: u64 = 6;
+pub const AT_BASE: u64 = 7;
+pub const AT_ENTRY: u64 = 9;
+pub const AT_RANDOM: u64 = 25;
+
+/// Why an executable could not be loaded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// The file ends before a header it says is there
+    Truncated,
+    /// The file does not start with the ELF magic number
+    BadMagic,
+    /// The file is not a 64 bit little endian ELF file of the current version
+    UnsupportedFormat,
+    /// The file is not for x86_64
+    WrongMachine,
+    /// The file has no Interstellar note and its OS ABI is not Linux
+    WrongOs,
+    /// The file is not an executable
+    NotExecutable,
+    /// A program header has a size or alignment that makes no sense
+    BadSegment,
+    /// A segment or the entry point is outside user space
+    OutOfRange,
+    /// Two loadable segments cover the same memory
+    OverlappingSegments,
+    /// There are no loadable segments
+    NoSegments,
+    /// The arguments and environment do not fit on the stack
+    ArgumentsTooLong,
+    /// Mapping the program failed
+    Map(MapError),
+    /// A shared object the program needs is not in the initrd
+    MissingLibrary,
+    /// The dynamic section or a table it points to is malformed
+    BadDynamic,
+    /// A symbol a relocation needs is not defined by the program or its shared objects
+    UndefinedSymbol,
+    /// A relocation type the loader cannot apply, with the type
+    UnsupportedRelocation(u32),
+    /// A shared object has thread local storage, only the program may have it
+    LibraryTls,
+}
+
+impl From<MapError> for ElfError {
+    fn from(err: MapError) -> Self {
+        ElfError::Map(err)
+    }
+}
+
+/// The system calls a program was built for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Abi {
+    /// The system calls in [crate::syscall::SYSCALLS]
+    #[default]
+    Interstellar,
+    /// Linux x86_64 system call numbers and error numbers, see [crate::syscall::linux]
+    Linux,
+}
+
+/// Where a loaded program starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadedProgram {
+    pub entry: VirtAddr,
+    /// Points at argc on the new stack
+    pub stack_pointer: VirtAddr,
+    /// The TLS template from the PT_TLS header, see [super::tls]
+    pub tls: Option<Tls>,
+    pub abi: Abi,
+}
+
+/// A loadable segment from a program header
+#[derive(Debug, Clone, Copy)]
+struct Segment {
+    flags: u32,
+    offset: u64,
+    vaddr: u64,
+    file_size: u64,
+    memory_size: u64,
+}
+
+impl Segment {
+    fn end(&self) -> u64 {
+        self.vaddr + self.memory_size
+    }
+}
+
+/// The parts of an ELF file the loader needs, all checked against the file size
+///
+/// Addresses already have the bias added, the difference between where the file is loaded and where it is linked
+pub(super) struct Elf<'a> {
+    data: &'a [u8],
+    pub bias: u64,
+    entry: u64,
+    program_header_offset: u64,
+    program_header_count: u16,
+    /// Sorted by address
+    segments: Vec<Segment>,
+    /// Address of the program headers given by a PT_PHDR header
+    phdr: Option<u64>,
+    pub tls: Option<Tls>,
+    abi: Abi,
+    /// The dynamic linker named by PT_INTERP
+    interpreter: Option<&'a str>,
+    /// The contents of the PT_DYNAMIC segment
+    pub dynamic: Option<&'a [u8]>,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the headers of an executable, or of a shared object loaded at `base` if it is given
+    pub fn parse(data: &'a [u8], base: Option<u64>) -> Result<Elf<'a>, ElfError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        // Position independent executables are moved up to the image base
+        let bias = match (read_u16(data, 16)?, base) {
+            (ET_EXEC, None) => 0,
+            (ET_DYN, None) => IMAGE_BASE,
+            (ET_DYN, Some(base)) => base,
+            _ => return Err(ElfError::NotExecutable),
+        };
+        if read_u16(data, 18)? != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let linked_entry = read_u64(data, 24)?;
+        let entry = linked_entry.wrapping_add(bias);
+        let program_header_offset = read_u64(data, 32)?;
+        let program_header_size = read_u16(data, 54)? as usize;
+        let program_header_count = read_u16(data, 56)?;
+
+        if program_header_count != 0 && program_header_size != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::UnsupportedFormat);
+        }
+
+        let mut segments = Vec::new();
+        let mut phdr = None;
+        let mut tls = None;
+        let mut interpreter = None;
+        let mut dynamic = None;
+        let mut abi = Abi::Linux;
+
+        for index in 0..program_header_count as u64 {
+            let header = program_header_offset
+                .checked_add(index * PROGRAM_HEADER_SIZE as u64)
+                .ok_or(ElfError::Truncated)? as usize;
+
+            if header.saturating_add(PROGRAM_HEADER_SIZE) > data.len() {
+                return Err(ElfError::Truncated);
+            }
+
+            let kind = read_u32(data, header)?;
+            let flags = read_u32(data, header + 4)?;
+            let offset = read_u64(data, header + 8)?;
+            let vaddr = read_u64(data, header + 16)?
+                .checked_add(bias)
+                .ok_or(ElfError::OutOfRange)?;
+            let file_size = read_u64(data, header + 32)?;
+            let memory_size = read_u64(data, header + 40)?;
+            let align = read_u64(data, header + 48)?;
+
+            match kind {
+                PT_LOAD => {
+                    if file_size > memory_size
+                        || (align > 1
+                            && (!align.is_power_of_two() || vaddr % align != offset % align))
+                    {
+                        return Err(ElfError::BadSegment);
+                    }
+
+                    let file_end = offset.checked_add(file_size).ok_or(ElfError::Truncated)?;
+                    if file_end > data.len() as u64 {
+                        return Err(ElfError::Truncated);
+                    }
+
+                    let end = vaddr.checked_add(memory_size).ok_or(ElfError::OutOfRange)?;
+                    if vaddr < USER_START || end > USER_END {
+                        return Err(ElfError::OutOfRange);
+                    }
+
+                    segments.push(Segment {
+                        flags,
+                        offset,
+                        vaddr,
+                        file_size,
+                        memory_size,
+                    });
+                }
+                PT_PHDR => phdr = Some(vaddr),
+                PT_INTERP => {
+                    // A path ending with a null
+                    let path = file_range(data, offset, file_size)?;
+                    interpreter = match path.split_last() {
+                        Some((0, path)) => {
+                            Some(core::str::from_utf8(path).map_err(|_| ElfError::BadSegment)?)
+                        }
+                        _ => return Err(ElfError::BadSegment),
+                    };
+                }
+                PT_DYNAMIC => dynamic = Some(file_range(data, offset, file_size)?),
+                PT_NOTE if has_interstellar_note(file_range(data, offset, file_size)?) => {
+                    abi = Abi::Interstellar
+                }
+                PT_TLS => {
+                    if file_size > memory_size
+                        || (align > 1 && (!align.is_power_of_two() || align > PAGE_SIZE))
+                    {
+                        return Err(ElfError::BadSegment);
+                    }
+
+                    tls = Some(Tls {
+                        template: TlsTemplate {
+                            start_addr: vaddr,
+                            file_size,
+                            mem_size: memory_size,
+                        },
+                        align: align.max(1),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        // Linker output for Linux leaves the OS ABI as none, anything else is for another system
+        if abi == Abi::Linux && !matches!(data[7], ELFOSABI_NONE | ELFOSABI_LINUX) {
+            return Err(ElfError::WrongOs);
+        }
+
+        if segments.is_empty() {
+            return Err(ElfError::NoSegments);
+        }
+
+        segments.sort_by_key(|segment| segment.vaddr);
+        if segments
+            .windows(2)
+            .any(|pair| pair[0].end() > pair[1].vaddr)
+        {
+            return Err(ElfError::OverlappingSegments);
+        }
+
+        // Shared objects do not need an entry point
+        if (base.is_none() || linked_entry != 0)
+            && !segments.iter().any(|segment| {
+                segment.flags & PF_X != 0 && (segment.vaddr..segment.end()).contains(&entry)
+            })
+        {
+            return Err(ElfError::OutOfRange);
+        }
+
+        // Each thread's copy of the template is read from memory so a loadable segment has to hold it
+        if let Some(tls) = tls {
+            let template = tls.template;
+            if !segments.iter().any(|segment| {
+                segment.vaddr <= template.start_addr
+                    && template
+                        .start_addr
+                        .checked_add(template.file_size)
+                        .is_some_and(|end| end <= segment.vaddr + segment.file_size)
+            }) {
+                return Err(ElfError::BadSegment);
+            }
+        }
+
+        Ok(Elf {
+            data,
+            bias,
+            entry,
+            program_header_offset,
+            program_header_count,
+            segments,
+            phdr,
+            tls,
+            abi,
+            interpreter,
+            dynamic,
+        })
+    }
+
+    /// Where the last segment ends
+    pub fn end(&self) -> u64 {
+        self.segments.iter().map(Segment::end).max().unwrap_or(0)
+    }
+
+    /// The `len` bytes of the file loaded at `addr`, they must be in one segment and not in its BSS
+    pub fn bytes_at(&self, addr: u64, len: u64) -> Result<&'a [u8], ElfError> {
+        let segment = self
+            .segments
+            .iter()
+            .find(|segment| {
+                segment.vaddr <= addr
+                    && addr
+                        .checked_add(len)
+                        .is_some_and(|end| end <= segment.vaddr + segment.file_size)
+            })
+            .ok_or(ElfError::BadDynamic)?;
+
+        file_range(self.data, segment.offset + (addr - segment.vaddr), len)
+    }
+
+    /// Whether `len` bytes from `addr` are inside one of the segments
+    pub fn contains(&self, addr: u64, len: u64) -> bool {
+        self.segments.iter().any(|segment| {
+            segment.vaddr <= addr
+                && addr
+                    .checked_add(len)
+                    .is_some_and(|end| end <= segment.end())
+        })
+    }
+
+    /// Where the program headers are in memory, for AT_PHDR
+    fn program_headers_address(&self) -> u64 {
+        self.phdr.unwrap_or_else(|| {
+            // The first segment usually maps the start of the file with the headers in it
+            self.segments
+                .iter()
+                .find(|segment| {
+                    (segment.offset..segment.offset + segment.file_size)
+                        .contains(&self.program_header_offset)
+                })
+                .map_or(0, |segment| {
+                    segment.vaddr + (self.program_header_offset - segment.offset)
+                })
+        })
+    }
+}
+
+/// Checks `image`, maps its segments and a stack in `space` and puts `args` and `env` on the stack
+///
+/// The heap of `space` starts after the last segment. A dynamically linked program is linked with its shared objects
+/// or started in its dynamic linker, see [super::dynamic].
+///
+/// Nothing is mapped if the headers, or those of the shared objects, are malformed
+pub fn load(
+    space: &mut AddressSpace,
+    image: &[u8],
+    args: &[&str],
+    env: &[&str],
+) -> Result<LoadedProgram, ElfError> {
+    let elf = Elf::parse(image, None)?;
+    let linker = match elf.interpreter {
+        Some(interpreter) => Some(Linker::new(&elf, interpreter)?),
+        None => None,
+    };
+
+    map_segments(space, &elf)?;
+    space.set_break_start(VirtAddr::new(elf.end()));
+
+    space.map(
+        VirtAddr::new(STACK_TOP - STACK_SIZE as u64),
+        STACK_SIZE,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+
+    let mut auxv = Vec::from([
+        (AT_PHDR, elf.program_headers_address()),
+        (AT_PHENT, PROGRAM_HEADER_SIZE as u64),
+        (AT_PHNUM, elf.program_header_count as u64),
+        (AT_PAGESZ, PAGE_SIZE),
+        (AT_ENTRY, elf.entry),
+    ]);
+
+    // The program starts in the dynamic linker when there is one
+    let mut entry = elf.entry;
+    if let Some(linker) = linker {
+        if let Some(interpreter) = linker.link(space, &elf)? {
+            entry = interpreter.entry;
+            auxv.push((AT_BASE, interpreter.bias));
+        }
+    }
+
+    let stack_pointer = build_stack(space, args, env, &auxv)?;
+
+    Ok(LoadedProgram {
+        entry: VirtAddr::new(entry),
+        stack_pointer,
+        tls: elf.tls,
+        abi: elf.abi,
+    })
+}
+
+/// Maps the segments of `elf` in `space` and copies their data in
+pub(super) fn map_segments(space: &mut AddressSpace, elf: &Elf) -> Result<(), ElfError> {
+    // Segments that are not page aligned can share a page, it gets the permissions of both
+    let mut pages: BTreeMap<u64, PageTableFlags> = BTreeMap::new();
+
+    for segment in elf
+        .segments
+        .iter()
+        .filter(|segment| segment.memory_size != 0)
+    {
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.vaddr));
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.end() - 1));
+
+        for page in Page::range_inclusive(first, last) {
+            let flags = pages
+                .entry(page.start_address().as_u64())
+                .or_insert(PageTableFlags::NO_EXECUTE);
+
+            if segment.flags & PF_W != 0 {
+                flags.insert(PageTableFlags::WRITABLE);
+            }
+            if segment.flags & PF_X != 0 {
+                flags.remove(PageTableFlags::NO_EXECUTE);
+            }
+        }
+    }
+
+    for (&page, &flags) in pages.iter() {
+        space.map(VirtAddr::new(page), PAGE_SIZE as usize, flags)?;
+    }
+
+    // The pages are zeroed when they are mapped so the BSS past the file data is already zero
+    for segment in elf.segments.iter() {
+        let data =
+            &elf.data[segment.offset as usize..(segment.offset + segment.file_size) as usize];
+        space.write(VirtAddr::new(segment.vaddr), data)?;
+    }
+
+    Ok(())
+}
+
+/// Lays out the System V entry stack below [STACK_TOP] and returns the stack pointer
+///
+/// From the stack pointer up: argc, the argv pointers, null, the envp pointers, null,
+/// the auxiliary vector ending with AT_NULL, then the strings and 16 random bytes for AT_RANDOM
+pub fn build_stack(
+    space: &mut AddressSpace,
+    args: &[&str],
+    env: &[&str],
+    auxv: &[(u64, u64)],
+) -> Result<VirtAddr, ElfError> {
+    let mut strings: Vec<u8> = Vec::new();
+    let mut string_offsets = Vec::new();
+
+    for string in args.iter().chain(env.iter()) {
+        string_offsets.push(strings.len() as u64);
+        strings.extend_from_slice(string.as_bytes());
+        strings.push(0);
+    }
+
+    let mut random = [0u8; 16];
+    let mut rng = RandomNumberGenerator::new();
+    for byte in random.iter_mut() {
+        *byte = rng.generate_number(Some(0), Some(255)).unwrap_or(0) as u8;
+    }
+    let random_offset = strings.len() as u64;
+    strings.extend_from_slice(&random);
+
+    let strings_start = (STACK_TOP - strings.len() as u64) & !0xF;
+
+    // argc, argv and null, envp and null, auxv pairs and AT_NULL
+    let words = 1 + args.len() + 1 + env.len() + 1 + (auxv.len() + 2) * 2;
+    let mut stack_pointer = strings_start - (words as u64 * 8);
+    stack_pointer &= !0xF;
+
+    // Leave at least half of the stack for the program
+    if STACK_TOP - stack_pointer > STACK_SIZE as u64 / 2 {
+        return Err(ElfError::ArgumentsTooLong);
+    }
+
+    let mut vector: Vec<u64> = Vec::with_capacity(words);
+    vector.push(args.len() as u64);
+    vector.extend(
+        string_offsets[..args.len()]
+            .iter()
+            .map(|offset| strings_start + offset),
+    );
+    vector.push(0);
+    vector.extend(
+        string_offsets[args.len()..]
+            .iter()
+            .map(|offset| strings_start + offset),
+    );
+    vector.push(0);
+    for &(key, value) in auxv {
+        vector.push(key);
+        vector.push(value);
+    }
+    vector.push(AT_RANDOM);
+    vector.push(strings_start + random_offset);
+    vector.push(AT_NULL);
+    vector.push(0);
+
+    let bytes: Vec<u8> = vector.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+    space.write(VirtAddr::new(stack_pointer), &bytes)?;
+    space.write(VirtAddr::new(strings_start), &strings)?;
+
+    Ok(VirtAddr::new(stack_pointer))
+}
+
+/// Whether a PT_NOTE segment has the interstellar ABI note, notes that run past the end are ignored
+fn has_interstellar_note(notes: &[u8]) -> bool {
+    let mut offset = 0;
+
+    // Each note is a name size, description size and type, then the name and description padded to 4 bytes
+    while let (Ok(name_size), Ok(desc_size)) =
+        (read_u32(notes, offset), read_u32(notes, offset + 4))
+    {
+        let name_start = offset + 12;
+        let name_end = name_start.saturating_add(name_size as usize);
+        if name_end > notes.len() {
+            return false;
+        }
+
+        let desc_start = (name_end + 3) & !3;
+        if &notes[name_start..name_end] == NOTE_NAME
+            && read_u32(notes, offset + 8) == Ok(NT_INTERSTELLAR_ABI)
+            && desc_size == 4
+            && read_u32(notes, desc_start) == Ok(ABI_VERSION)
+        {
+            return true;
+        }
+
+        offset = desc_start
+            .saturating_add(desc_size as usize)
+            .saturating_add(3)
+            & !3;
+    }
+
+    false
+}
+
+/// `len` bytes of `data` from `offset`
+fn file_range(data: &[u8], offset: u64, len: u64) -> Result<&[u8], ElfError> {
+    let end = offset.checked_add(len).ok_or(ElfError::Truncated)?;
+    if end > data.len() as u64 {
+        return Err(ElfError::Truncated);
+    }
+
+    Ok(&data[offset as usize..end as usize])
+}
+
+pub(super) fn read_u16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    let bytes = offset
+        .checked_add(2)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub(super) fn read_u32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    let bytes = offset
+        .checked_add(4)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+pub(super) fn read_u64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    let bytes = offset
+        .checked_add(8)
+        .and_then(|end| data.get(offset..end))
+        .ok_or(ElfError::Truncated)?;
+    Ok(u64::from_le_bytes(bytes.try_into().unwrap()))
+}
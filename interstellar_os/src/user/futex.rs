@@ -0,0 +1,146 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Futexes, the kernel half of user space locks
+//!
+//! A futex is an aligned 32 bit word in user memory. [wait] blocks while the word holds the value the program expects
+//! and [wake] wakes threads blocked on it, what the word means is up to the program. The waiters of each word are kept
+//! in a [WaitQueue] keyed by the word's physical address, so threads sharing the memory share the futex.
+
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use core::time::Duration;
+use spinning_top::Spinlock;
+use x86_64::instructions::interrupts;
+use x86_64::VirtAddr;
+
+use super::{address_space, is_user_range, UserPtr};
+use crate::process;
+use crate::thread::{self, WaitQueue, WaitResult};
+use crate::time::Instant;
+
+/// Why a futex operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexError {
+    /// The word is not in writable user memory
+    BadAddress,
+    /// The word is not 4 byte aligned
+    Unaligned,
+    /// The word did not hold the expected value
+    WouldBlock,
+    /// The timeout passed before the thread was woken
+    TimedOut,
+    /// A signal arrived or the process was killed while waiting
+    Interrupted,
+}
+
+/// Wait queues of the futexes that have waiters, by physical address
+static FUTEXES: Spinlock<BTreeMap<u64, Arc<WaitQueue>>> = Spinlock::new(BTreeMap::new());
+
+/// Blocks the running thread until the futex at `addr` is woken if it holds `expected`,
+/// for at most `timeout` if there is one
+pub fn wait(addr: u64, expected: u32, timeout: Option<Duration>) -> Result<(), FutexError> {
+    let key = key(addr)?;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+    let interrupted = || {
+        process::current()
+            .is_some_and(|process| process.is_killed() || process.has_deliverable_signal())
+    };
+    if interrupted() {
+        return Err(FutexError::Interrupted);
+    }
+
+    let queue = FUTEXES
+        .lock()
+        .entry(key)
+        .or_insert_with(|| Arc::new(WaitQueue::new()))
+        .clone();
+
+    let mut faulted = false;
+    let result = queue.wait_if(
+        || match UserPtr::<u32>::new(addr).read() {
+            Ok(value) => value == expected,
+            Err(_) => {
+                faulted = true;
+                false
+            }
+        },
+        deadline,
+        interrupted,
+    );
+
+    release(key, queue);
+
+    match result {
+        WaitResult::Woken => Ok(()),
+        WaitResult::NotWaited if faulted => Err(FutexError::BadAddress),
+        WaitResult::NotWaited => Err(FutexError::WouldBlock),
+        WaitResult::TimedOut => Err(FutexError::TimedOut),
+        WaitResult::Interrupted => Err(FutexError::Interrupted),
+    }
+}
+
+/// Wakes up to `count` threads waiting on the futex at `addr` and returns how many were woken
+pub fn wake(addr: u64, count: usize) -> Result<usize, FutexError> {
+    let key = key(addr)?;
+
+    let mut futexes = FUTEXES.lock();
+    let Some(queue) = futexes.get(&key) else {
+        return Ok(0);
+    };
+
+    let woken = queue.wake(count);
+
+    // Threads that were woken still hold the queue until they are back from waiting
+    if Arc::strong_count(queue) == 1 && queue.is_empty() {
+        futexes.remove(&key);
+    }
+
+    Ok(woken)
+}
+
+/// Drops a waiter's hold on a queue, the queue goes once nothing waits on it or holds it
+fn release(key: u64, queue: Arc<WaitQueue>) {
+    let mut futexes = FUTEXES.lock();
+    drop(queue);
+
+    let unused = futexes
+        .get(&key)
+        .is_some_and(|queue| Arc::strong_count(queue) == 1 && queue.is_empty());
+    if unused {
+        futexes.remove(&key);
+    }
+}
+
+/// The physical address of the futex at `addr` in the running thread's address space
+fn key(addr: u64) -> Result<u64, FutexError> {
+    if addr % 4 != 0 {
+        return Err(FutexError::Unaligned);
+    }
+    if !is_user_range(addr, 4, true) {
+        return Err(FutexError::BadAddress);
+    }
+
+    let addr = VirtAddr::new(addr);
+
+    // A copy-on-write page would move to a new frame when it is written, taking the futex with it
+    interrupts::without_interrupts(|| address_space::handle_cow_fault(addr));
+
+    address_space::translate(thread::current().page_table(), addr)
+        .map(|phys| phys.as_u64())
+        .ok_or(FutexError::BadAddress)
+}
@@ -0,0 +1,575 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Running programs in ring 3
+//!
+//! A user program is an ELF executable loaded by [elf::load].
+//! It runs on a kernel thread with its own [AddressSpace]. [enter_frame] loads every register from a [SyscallFrame]
+//! and switches to ring 3 with `iretq`, it returns once the program exits with the exit system call or is killed
+//! by an exception. While the program runs, interrupts and system calls from it use the thread's kernel stack below
+//! the frame of [enter_frame].
+//!
+//! Programs are found by path in the initrd with [find_program]. A new process is started from one with [spawn],
+//! [fork] copies the running process and [exec] replaces its program. [clone] starts another thread in the running
+//! process, every thread has its own copy of the program's thread local storage, see [tls], and they can wait
+//! for each other with a [futex].
+//!
+//! Dynamically linked programs get their shared objects from the initrd too, see [dynamic].
+//!
+//! Each thread has its own FS and GS base, the kernel uses neither so programs may load them however they like.
+
+use alloc::format;
+use alloc::sync::Arc;
+use core::arch::global_asm;
+use x86_64::instructions::interrupts;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use crate::drivers::fs::initrd;
+use crate::gdt;
+use crate::other::log::LOGGER;
+use crate::process::signal::Signal;
+use crate::process::trace::{self, Tracer};
+use crate::process::{self, Pid, Process};
+use crate::syscall::{SyscallFrame, USER_FLAGS};
+use crate::thread::{self, JoinHandle, ThreadId};
+
+pub mod access;
+pub mod address_space;
+pub mod dynamic;
+pub mod elf;
+pub mod futex;
+pub mod tls;
+
+pub use access::{copy_from_user, copy_to_user, read_c_string, BadAddress, UserPtr, UserSlice};
+pub use address_space::{AddressSpace, MapError};
+pub use elf::{Abi, ElfError, LoadedProgram};
+
+/// Lowest address user pages can be mapped at, the first level 4 entry is left to the kernel's identity mappings
+pub const USER_START: u64 = 0x0000_0080_0000_0000;
+
+/// User pages end here, below the kernel heap
+pub const USER_END: u64 = 0x0000_4000_0000_0000;
+
+/// Where the signal trampoline is mapped in every address space, see [process::signal]
+pub const SIGNAL_TRAMPOLINE: u64 = USER_START;
+
+/// The address user programs are linked at
+pub const IMAGE_BASE: u64 = USER_START + 0x40_0000;
+
+/// Shared objects are loaded at a random address above this, see [dynamic]
+pub const LIBRARY_START: u64 = 0x0000_1000_0000_0000;
+
+/// Where [AddressSpace::map_anywhere] starts mapping memory for programs, like thread local storage
+pub const MAP_START: u64 = 0x0000_2000_0000_0000;
+
+/// The user stack grows down from here
+pub const STACK_TOP: u64 = USER_END;
+
+/// Size of the user stack
+pub const STACK_SIZE: usize = 64 * 1024;
+
+/// The exception that killed a user program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserFault {
+    DivideError,
+    Overflow,
+    BoundRange,
+    InvalidOpcode,
+    StackSegment,
+    GeneralProtection,
+    /// With the address that was accessed
+    PageFault(u64),
+    AlignmentCheck,
+    /// An `int3` that is not a tracer's breakpoint
+    Breakpoint,
+    /// A single step trap the program asked for itself with the trap flag
+    SingleStep,
+}
+
+impl UserFault {
+    /// The POSIX signal a program gets for the fault
+    pub fn signal(&self) -> Signal {
+        match self {
+            UserFault::DivideError => Signal::SIGFPE,
+            UserFault::InvalidOpcode => Signal::SIGILL,
+            UserFault::StackSegment | UserFault::AlignmentCheck => Signal::SIGBUS,
+            UserFault::Breakpoint | UserFault::SingleStep => Signal::SIGTRAP,
+            _ => Signal::SIGSEGV,
+        }
+    }
+}
+
+/// How a user program ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// With the code passed to the exit or thread exit system call
+    Code(i64),
+    /// By a fault the program did not handle
+    Fault(UserFault),
+    /// Ended by [process::kill]
+    Killed,
+    /// By a signal whose default action ends the program
+    Signal(Signal),
+}
+
+impl Exit {
+    /// The status `waitpid` reports, encoded like POSIX: the low byte of the code shifted left by 8
+    /// after a normal exit, the signal number otherwise
+    pub fn wait_status(&self) -> u64 {
+        match self {
+            Exit::Code(code) => ((*code as u64) & 0xFF) << 8,
+            Exit::Fault(fault) => fault.signal().number(),
+            Exit::Killed => Signal::SIGKILL.number(),
+            Exit::Signal(signal) => signal.number(),
+        }
+    }
+}
+
+/// Kept on the kernel stack by [enter] while the thread is in user mode
+#[repr(C)]
+struct UserContext {
+    /// Stack pointer [user_leave] goes back to, interrupts from user mode start here.
+    /// This has to be the first field, the scheduler reads it to set the TSS
+    kernel_stack: u64,
+    exit: Option<Exit>,
+}
+
+global_asm!(
+    ".global user_enter",
+    "user_enter:",
+    // Saved for user_leave, the same way the thread context switch saves them
+    "push rbp",
+    "push rbx",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov [rsi], rsp",
+    // Every register comes from the frame, it ends with the interrupt return frame for ring 3
+    "mov rsp, rdx",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rbp",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rbx",
+    "pop rax",
+    "iretq",
+    "",
+    ".global user_leave",
+    "user_leave:",
+    "mov rsp, rdi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbx",
+    "pop rbp",
+    "ret",
+);
+
+extern "C" {
+    /// Saves the kernel stack pointer in `kernel_stack` and the TSS and returns to ring 3 with the registers in `frame`,
+    /// returns when [user_leave] is called with the saved stack pointer
+    fn user_enter(kernel_stack: *mut u64, tss_kernel_stack: *mut u64, frame: *const SyscallFrame);
+
+    fn user_leave(kernel_stack: u64) -> !;
+}
+
+/// Runs the running thread in ring 3 from `entry` with the stack pointer at `stack` until the program exits
+///
+/// Every other register starts at zero.
+///
+/// # Safety
+///
+/// The running thread's address space must map `entry` and `stack` for user mode
+pub unsafe fn enter(entry: VirtAddr, stack: VirtAddr) -> Exit {
+    enter_frame(initial_registers(entry, stack))
+}
+
+/// Runs the running thread in ring 3 with the registers in `frame` until the program exits
+///
+/// The selectors are always the user ones and only the flags a program may change are kept, with interrupts enabled.
+///
+/// # Safety
+///
+/// The running thread's address space must map `frame.rip` and `frame.rsp` for user mode
+pub unsafe fn enter_frame(mut frame: SyscallFrame) -> Exit {
+    let mut context = UserContext {
+        kernel_stack: 0,
+        exit: None,
+    };
+
+    let selectors = gdt::selectors();
+    frame.cs = selectors.user_code.0 as u64;
+    frame.ss = selectors.user_data.0 as u64;
+    frame.rflags = (frame.rflags & USER_FLAGS) | RFlags::INTERRUPT_FLAG.bits();
+
+    // Interrupts from the program start on this thread's stack so nothing may switch threads until the TSS is set
+    interrupts::disable();
+    thread::set_user_context(&mut context as *mut UserContext as u64);
+
+    user_enter(&mut context.kernel_stack, gdt::kernel_stack_slot(), &frame);
+
+    // Back from user_leave with interrupts disabled
+    thread::set_user_context(0);
+    interrupts::enable();
+
+    core::ptr::read_volatile(&context.exit).expect("left user mode without an exit reason")
+}
+
+/// Ends the user program running on this thread, [enter] returns `exit`
+///
+/// This is called from system calls and exception handlers, everything on the kernel stack since [enter] is thrown away
+///
+/// # Panics
+///
+/// If the thread is not running a user program
+pub fn exit_current(exit: Exit) -> ! {
+    interrupts::disable();
+
+    let context = thread::user_context() as *mut UserContext;
+    assert!(!context.is_null(), "exit_current called outside user mode");
+
+    unsafe {
+        (*context).exit = Some(exit);
+        user_leave((*context).kernel_stack)
+    }
+}
+
+/// True if the running thread's page table maps `len` bytes from `addr` for user mode, and for writing if `write` is set
+pub fn is_user_range(addr: u64, len: usize, write: bool) -> bool {
+    let Some(end) = addr.checked_add(len as u64) else {
+        return false;
+    };
+    if addr < USER_START || end > USER_END {
+        return false;
+    }
+
+    let page_table = thread::current().page_table();
+
+    let mut page = addr & !0xFFF;
+    while page < end {
+        match address_space::flags(page_table, VirtAddr::new(page)) {
+            // Copy-on-write pages become writable when they are written to
+            Some(flags)
+                if flags.contains(PageTableFlags::USER_ACCESSIBLE)
+                    && (!write
+                        || flags.intersects(
+                            PageTableFlags::WRITABLE | address_space::COPY_ON_WRITE,
+                        )) => {}
+            _ => return false,
+        }
+        page += 4096;
+    }
+
+    true
+}
+
+/// A process started by [spawn] or [fork]
+pub struct Child {
+    pid: Pid,
+    thread: JoinHandle<Exit>,
+}
+
+impl Child {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    /// Blocks until every thread of the program has ended and returns how the process ended
+    pub fn join(self) -> Exit {
+        self.thread.join()
+    }
+}
+
+/// The executable at `path` in the initrd, a leading `/` is ignored
+pub fn find_program(path: &str) -> Option<&'static [u8]> {
+    let path = path.trim_start_matches('/');
+    if path.is_empty() {
+        return None;
+    }
+
+    initrd::get_file_bytes(path)
+}
+
+/// The last part of `path`, used to name processes
+pub fn program_name(path: &str) -> &str {
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+/// Loads an ELF executable into a new process with `args` and `env` and runs it on a new thread
+///
+/// The process is a child of the running process, if there is one
+pub fn spawn(name: &str, image: &[u8], args: &[&str], env: &[&str]) -> Result<Child, ElfError> {
+    spawn_inner(name, image, args, env, None)
+}
+
+/// Starts a program like [spawn] with `tracer` tracing it, it stops before its first instruction
+pub fn spawn_traced(
+    name: &str,
+    image: &[u8],
+    args: &[&str],
+    env: &[&str],
+    tracer: Tracer,
+) -> Result<Child, ElfError> {
+    spawn_inner(name, image, args, env, Some(tracer))
+}
+
+fn spawn_inner(
+    name: &str,
+    image: &[u8],
+    args: &[&str],
+    env: &[&str],
+    tracer: Option<Tracer>,
+) -> Result<Child, ElfError> {
+    let mut space = AddressSpace::new()?;
+    let program = elf::load(&mut space, image, args, env)?;
+    let thread_pointer = thread_pointer(&mut space, &program)?;
+
+    let parent = process::current().map(|parent| parent.pid());
+    let process = process::create(name, parent, space);
+    process.set_program(&program);
+
+    if let Some(tracer) = tracer {
+        trace::attach_new(&process, tracer);
+    }
+
+    Ok(start(
+        process,
+        initial_registers(program.entry, program.stack_pointer),
+        thread_pointer,
+        0,
+    ))
+}
+
+/// Copies the running process into a new child that carries on from the registers in `frame`, with `rax` set to 0
+///
+/// The child has one thread, a copy of the running one.
+///
+/// # Panics
+///
+/// If the running thread is not a process
+pub fn fork(frame: &SyscallFrame) -> Result<Child, MapError> {
+    let parent = process::current().expect("fork called outside a process");
+    let process = process::fork(&parent)?;
+
+    let mut registers = *frame;
+    registers.rax = 0;
+
+    Ok(start(
+        process,
+        registers,
+        thread::current().fs_base(),
+        thread::gs_base(),
+    ))
+}
+
+/// Starts a thread in the running process that carries on from the registers in `frame`, with `rax` set to 0
+/// and the stack pointer at `stack`
+///
+/// The thread pointer is `thread_pointer`, or a new copy of the program's TLS template if it is None.
+/// When the thread ends 0 is written to `clear_tid` and one waiter on it as a futex is woken, unless it is null.
+///
+/// # Panics
+///
+/// If the running thread is not a process
+pub fn clone(
+    frame: &SyscallFrame,
+    stack: VirtAddr,
+    thread_pointer: Option<VirtAddr>,
+    clear_tid: UserPtr<u32>,
+) -> Result<ThreadId, MapError> {
+    let process = process::current().expect("clone called outside a process");
+
+    let fs_base = match thread_pointer {
+        Some(thread_pointer) => thread_pointer.as_u64(),
+        None => process.new_thread_pointer()?,
+    };
+
+    let gs_base = thread::gs_base();
+
+    let mut registers = *frame;
+    registers.rax = 0;
+    registers.rsp = stack.as_u64();
+
+    // A process that is already ending gets no new threads, the caller ends with it
+    if !process.add_thread() {
+        drop(process);
+        exit_current(Exit::Killed);
+    }
+
+    let name = process.name();
+    let thread = thread::spawn(&name, move || {
+        run(&process, registers, fs_base, gs_base, clear_tid);
+    });
+
+    Ok(thread.thread().id())
+}
+
+/// Replaces the running process's program with the executable `image` from `path`, the program starts when the
+/// system call returns with the registers left in `frame`
+///
+/// Nothing changes if the executable cannot be loaded.
+///
+/// # Panics
+///
+/// If the running thread is not a process
+pub fn exec(
+    frame: &mut SyscallFrame,
+    path: &str,
+    image: &[u8],
+    args: &[&str],
+    env: &[&str],
+) -> Result<(), ElfError> {
+    let process = process::current().expect("exec called outside a process");
+
+    let mut space = AddressSpace::new()?;
+    let program = elf::load(&mut space, image, args, env)?;
+    let thread_pointer = thread_pointer(&mut space, &program)?;
+    let page_table = space.page_table();
+
+    let old = process.replace_image(program_name(path), space, &program);
+    unsafe { thread::set_page_table(Some(page_table)) };
+    thread::set_fs_base(thread_pointer);
+    thread::set_gs_base(0);
+    drop(old);
+
+    *frame = initial_registers(program.entry, program.stack_pointer);
+
+    Ok(())
+}
+
+/// Starts `init` from the initrd, does nothing if there is no init program
+pub fn start_init() {
+    let Some(image) = find_program("init") else {
+        LOGGER
+            .get()
+            .unwrap()
+            .lock()
+            .warn("No init program in the initrd");
+        return;
+    };
+
+    LOGGER.get().unwrap().lock().info("Starting init");
+
+    if let Err(err) = spawn("init", image, &["init"], &[]) {
+        LOGGER
+            .get()
+            .unwrap()
+            .lock()
+            .error(&format!("Could not start init: {err:?}"));
+    }
+}
+
+/// Registers for a program's first instruction, the rest are zero
+fn initial_registers(entry: VirtAddr, stack: VirtAddr) -> SyscallFrame {
+    let selectors = gdt::selectors();
+
+    SyscallFrame {
+        r15: 0,
+        r14: 0,
+        r13: 0,
+        r12: 0,
+        r11: 0,
+        r10: 0,
+        r9: 0,
+        r8: 0,
+        rbp: 0,
+        rdi: 0,
+        rsi: 0,
+        rdx: 0,
+        rcx: 0,
+        rbx: 0,
+        rax: 0,
+        rip: entry.as_u64(),
+        cs: selectors.user_code.0 as u64,
+        rflags: RFlags::INTERRUPT_FLAG.bits(),
+        rsp: stack.as_u64(),
+        ss: selectors.user_data.0 as u64,
+    }
+}
+
+/// The thread pointer of the first thread of a program loaded into `space`, 0 if it has no TLS template
+fn thread_pointer(space: &mut AddressSpace, program: &LoadedProgram) -> Result<u64, MapError> {
+    match program.tls {
+        Some(tls) => Ok(tls::allocate(space, &tls)?.as_u64()),
+        None => Ok(0),
+    }
+}
+
+/// Runs a new process's program on a new thread
+fn start(process: Arc<Process>, registers: SyscallFrame, fs_base: u64, gs_base: u64) -> Child {
+    let pid = process.pid();
+    let name = process.name();
+
+    // Nothing else knows about the process yet so it cannot be ending
+    process.add_thread();
+
+    let thread = thread::spawn(&name, move || {
+        run(&process, registers, fs_base, gs_base, UserPtr::new(0));
+
+        // Child::join waits for this thread, so it waits for the rest of the threads too
+        process.wait_for_exit()
+    });
+
+    Child { pid, thread }
+}
+
+/// Runs a process's program on this thread until the thread ends, the process ends with its last thread
+fn run(
+    process: &Process,
+    mut registers: SyscallFrame,
+    fs_base: u64,
+    gs_base: u64,
+    clear_tid: UserPtr<u32>,
+) {
+    process.attach_current_thread();
+    trace::thread_start(process, &mut registers);
+
+    let exit = match process.page_table() {
+        Some(page_table) if !process.is_killed() => unsafe {
+            thread::set_page_table(Some(page_table));
+            thread::set_fs_base(fs_base);
+            thread::set_gs_base(gs_base);
+            let exit = enter_frame(registers);
+
+            // Lets another thread join this one, the address space is still loaded
+            if !clear_tid.is_null() && clear_tid.write(0).is_ok() {
+                let _ = futex::wake(clear_tid.addr(), 1);
+            }
+
+            thread::set_fs_base(0);
+            thread::set_gs_base(0);
+            thread::set_page_table(None);
+            exit
+        },
+        _ => Exit::Killed,
+    };
+
+    process::exit_thread(process, exit);
+}
@@ -0,0 +1,74 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Thread local storage for user programs
+//!
+//! A program's PT_TLS header describes its TLS template in the same form as the kernel's own [TlsTemplate]
+//! from the bootloader: the initial data at `start_addr`, `file_size` bytes of it followed by zeroes up to `mem_size`.
+//!
+//! Every thread gets its own copy of the template laid out like the x86_64 System V ABI's variant II. The TLS data
+//! ends at the thread pointer, which points at the thread control block whose first word is the thread pointer
+//! itself. The thread pointer is kept in the FS base, so `mov rax, fs:0` loads it and variables are at negative
+//! offsets from it.
+
+pub use bootloader_api::info::TlsTemplate;
+
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+use super::{AddressSpace, MapError};
+
+/// Size of the thread control block after the TLS data, only the first word is used
+pub const TCB_SIZE: u64 = 64;
+
+/// A program's TLS template and the alignment it needs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tls {
+    pub template: TlsTemplate,
+    /// A power of two no larger than a page
+    pub align: u64,
+}
+
+impl Tls {
+    /// Bytes from the start of the TLS data to the thread pointer, the linker works out the same
+    pub fn offset(&self) -> u64 {
+        let align = self.align.max(1);
+        (self.template.mem_size + align - 1) & !(align - 1)
+    }
+}
+
+/// Maps a new block for `tls` in `space`, copies the template into it and returns the thread pointer
+///
+/// The template is read from the address space so the pages it is in must be mapped
+pub fn allocate(space: &mut AddressSpace, tls: &Tls) -> Result<VirtAddr, MapError> {
+    let offset = tls.offset();
+
+    // Blocks are page aligned which covers any alignment the template asks for
+    let block = space.map_anywhere(
+        (offset + TCB_SIZE) as usize,
+        PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+    )?;
+    let thread_pointer = block + offset;
+
+    let mut initial = alloc::vec![0; tls.template.file_size as usize];
+    space.read(VirtAddr::new(tls.template.start_addr), &mut initial)?;
+    space.write(block, &initial)?;
+
+    // The rest of the data is still zero from mapping
+    space.write(thread_pointer, &thread_pointer.as_u64().to_le_bytes())?;
+
+    Ok(thread_pointer)
+}
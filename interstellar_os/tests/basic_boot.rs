@@ -23,6 +23,7 @@
 use interstellar_os as lib;
 
 use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
+use core::hint::black_box;
 use lib::{other::log::LOGGER, serial_print};
 
 pub static BOOTLOADER_CONFIG: BootloaderConfig = {
@@ -68,7 +69,7 @@ fn simple_addition() {
         .unwrap()
         .lock()
         .trace("Running simple addition", file!(), line!());
-    assert_eq!(1 + 1, 1 + 1);
+    assert_eq!(black_box(1) + 1, 2);
 }
 
 #[test_case]
@@ -78,7 +79,7 @@ fn simple_subtraction() {
         .unwrap()
         .lock()
         .trace("Running simple subtraction", file!(), line!());
-    assert_eq!(5 - 2, 3);
+    assert_eq!(black_box(5) - 2, 3);
 }
 
 #[test_case]
@@ -88,7 +89,7 @@ fn simple_multiplication() {
         .unwrap()
         .lock()
         .trace("Running simple multiplication", file!(), line!());
-    assert_eq!(5 * 5, 5 * 5);
+    assert_eq!(black_box(5) * 5, 25);
 }
 
 #[test_case]
@@ -98,5 +99,5 @@ fn simple_division() {
         .unwrap()
         .lock()
         .trace("Running simple division", file!(), line!());
-    assert_eq!(50 / 5, 50 / 5);
+    assert_eq!(black_box(50) / 5, 10);
 }
@@ -0,0 +1,297 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)] // Allows Us To Run Custom Tests
+#![test_runner(interstellar_os::test_runner)] // Defines The Test Runner Function
+#![reexport_test_harness_main = "test_main"]
+
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use interstellar_os as lib;
+
+use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
+use lib::task::executor::{self, Executor, Spawner};
+use lib::task::sync::Notify;
+use lib::task::{spawn, spawn_named, JoinError};
+use lib::time::Instant;
+use lib::{other::log::LOGGER, serial_print};
+
+extern crate alloc;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    use bootloader_api::config::*;
+
+    let mut mappings = Mappings::new_default();
+    mappings.kernel_stack = Mapping::Dynamic;
+    mappings.boot_info = Mapping::Dynamic;
+    mappings.framebuffer = Mapping::Dynamic;
+    mappings.physical_memory = Some(Mapping::Dynamic);
+    mappings.page_table_recursive = None;
+    mappings.aslr = true;
+    mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
+    mappings.dynamic_range_end = Some(0xFFFF_FFFF_FFFF_FFFF);
+
+    let mut config = BootloaderConfig::new_default();
+    config.mappings = mappings;
+    config.kernel_stack_size = 48 * 1024; // 48 Kib   decreasing this will cause undefined behavior
+    config
+};
+
+entry_point!(executor, config = &BOOTLOADER_CONFIG);
+
+/// Shared by every test so [spawn] always adds tasks to it
+static mut EXECUTOR: Option<Executor> = None;
+
+fn executor(boot_info: &'static mut BootInfo) -> ! {
+    serial_print!("\nexecutor::executor...\t");
+    lib::init(boot_info); // Start Interrupt Descriptor table ect.
+
+    unsafe { EXECUTOR = Some(Executor::new(Spawner::new())) };
+
+    serial_print!("[Ok]\n");
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+/// Runs tasks until none are ready and returns how many are left waiting
+fn run_until_idle() -> usize {
+    unsafe { (*addr_of_mut!(EXECUTOR)).as_mut().unwrap().run_until_idle() }
+}
+
+/// Returns pending once so the task goes back through the run queue
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+//########################################
+// Test Cases
+//########################################
+
+static JOIN_RESULT: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn join_handle_output() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running join handle output test", file!(), line!());
+
+    let handle = spawn(async {
+        YieldNow(false).await;
+        40 + 2
+    });
+
+    spawn(async move {
+        JOIN_RESULT.store(handle.await.unwrap(), Ordering::SeqCst);
+    })
+    .detach();
+
+    assert_eq!(run_until_idle(), 0);
+    assert_eq!(JOIN_RESULT.load(Ordering::SeqCst), 42);
+}
+
+static NESTED_SUM: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn spawn_from_task() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running spawn from task test", file!(), line!());
+
+    spawn(async {
+        let mut handles = alloc::vec::Vec::new();
+        for i in 1..=10u64 {
+            handles.push(spawn(async move {
+                YieldNow(false).await;
+                i
+            }));
+        }
+
+        let mut sum = 0;
+        for handle in handles {
+            sum += handle.await.unwrap();
+        }
+        NESTED_SUM.store(sum, Ordering::SeqCst);
+    })
+    .detach();
+
+    assert_eq!(run_until_idle(), 0);
+    assert_eq!(NESTED_SUM.load(Ordering::SeqCst), 55);
+}
+
+static ABORT_RESULT: AtomicBool = AtomicBool::new(false);
+
+#[test_case]
+fn abort_task() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running abort task test", file!(), line!());
+
+    let handle = spawn(core::future::pending::<()>());
+    let id = handle.id();
+
+    assert_eq!(run_until_idle(), 1);
+    assert!(!handle.is_finished());
+
+    handle.abort();
+    assert!(handle.is_finished());
+
+    spawn(async move {
+        let result = handle.await;
+        ABORT_RESULT.store(result == Err(JoinError::Cancelled), Ordering::SeqCst);
+    })
+    .detach();
+
+    assert_eq!(run_until_idle(), 0, "task {id:?} was not dropped");
+    assert!(ABORT_RESULT.load(Ordering::SeqCst));
+}
+
+static FINISHED_TASKS: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn many_tasks() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running many tasks test", file!(), line!());
+
+    // Well past the old fixed limit of 100 tasks
+    for _ in 0..1000 {
+        spawn(async {
+            YieldNow(false).await;
+            FINISHED_TASKS.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+    }
+
+    assert_eq!(run_until_idle(), 0);
+    assert_eq!(FINISHED_TASKS.load(Ordering::SeqCst), 1000);
+}
+
+static SLEPT: AtomicBool = AtomicBool::new(false);
+
+#[test_case]
+fn task_woken_by_timer() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running task woken by timer test", file!(), line!());
+
+    spawn(async {
+        lib::time::sleep(Duration::from_millis(10)).await;
+        SLEPT.store(true, Ordering::SeqCst);
+    })
+    .detach();
+
+    while run_until_idle() != 0 {
+        core::hint::spin_loop();
+    }
+
+    assert!(SLEPT.load(Ordering::SeqCst));
+}
+
+static STATS_NOTIFY: Notify = Notify::new();
+
+#[test_case]
+fn task_stats() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running task stats test", file!(), line!());
+
+    let handle = spawn_named("stats", async {
+        YieldNow(false).await;
+        STATS_NOTIFY.notified().await;
+    });
+
+    assert_eq!(run_until_idle(), 1);
+
+    let stats = executor::stats();
+    let task = stats.iter().find(|task| task.id == handle.id()).unwrap();
+
+    assert_eq!(task.name.as_deref(), Some("stats"));
+    assert_eq!(task.polls, 2);
+    assert_eq!(task.wakes, 1);
+    assert!(task.last_wake.is_some());
+
+    STATS_NOTIFY.notify_one();
+    assert_eq!(run_until_idle(), 0);
+
+    // Finished tasks are forgotten
+    assert!(executor::stats().iter().all(|task| task.id != handle.id()));
+}
+
+static SLOW_NOTIFY: Notify = Notify::new();
+
+#[test_case]
+fn slow_poll_counted() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running slow poll counted test", file!(), line!());
+
+    let threshold = executor::slow_poll_warning();
+    executor::set_slow_poll_warning(Some(Duration::from_millis(1)));
+
+    let handle = spawn(async {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_millis(5) {
+            core::hint::spin_loop();
+        }
+        SLOW_NOTIFY.notified().await;
+    });
+
+    assert_eq!(run_until_idle(), 1);
+
+    let stats = executor::stats();
+    let task = stats.iter().find(|task| task.id == handle.id()).unwrap();
+    assert_eq!(task.slow_polls, 1);
+    assert!(task.poll_time >= Duration::from_millis(5));
+
+    executor::set_slow_poll_warning(threshold);
+
+    SLOW_NOTIFY.notify_one();
+    assert_eq!(run_until_idle(), 0);
+}
@@ -0,0 +1,221 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)] // Allows Us To Run Custom Tests
+#![test_runner(interstellar_os::test_runner)] // Defines The Test Runner Function
+#![reexport_test_harness_main = "test_main"]
+
+use interstellar_os as lib;
+
+use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
+use core::time::Duration;
+use lib::time::Instant;
+use lib::{interrupts::machine_check, other::log::LOGGER, serial_print};
+use x86_64::registers::control::{Cr4, Cr4Flags};
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    use bootloader_api::config::*;
+
+    let mut mappings = Mappings::new_default();
+    mappings.kernel_stack = Mapping::Dynamic;
+    mappings.boot_info = Mapping::Dynamic;
+    mappings.framebuffer = Mapping::Dynamic;
+    mappings.physical_memory = Some(Mapping::Dynamic);
+    mappings.page_table_recursive = None;
+    mappings.aslr = true;
+    mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
+    mappings.dynamic_range_end = Some(0xFFFF_FFFF_FFFF_FFFF);
+
+    let mut config = BootloaderConfig::new_default();
+    config.mappings = mappings;
+    config.kernel_stack_size = 48 * 1024; // 48 Kib   decreasing this will cause undefined behavior
+    config
+};
+
+entry_point!(machine_check, config = &BOOTLOADER_CONFIG);
+
+fn machine_check(boot_info: &'static mut BootInfo) -> ! {
+    serial_print!("\nmachine_check::machine_check...\t");
+    lib::init(boot_info); // Start Interrupt Descriptor table ect.
+
+    serial_print!("[Ok]\n");
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+//########################################
+// Test Cases
+//########################################
+
+#[test_case]
+fn cr4_mce_enabled() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running cr4 mce enabled test", file!(), line!());
+
+    // QEMU is started with -cpu max which always supports MCE and MCA
+    assert!(machine_check::enabled());
+    assert!(machine_check::bank_count() > 0);
+    assert!(Cr4::read().contains(Cr4Flags::MACHINE_CHECK_EXCEPTION));
+}
+
+#[test_case]
+fn poll_without_errors() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running poll without errors test", file!(), line!());
+
+    // The banks were cleared on init so polling should find nothing and not fault
+    machine_check::poll();
+
+    for bank in 0..machine_check::bank_count() {
+        let record = machine_check::MachineCheckRecord::read(bank, 0);
+        assert!(!record.is_valid());
+    }
+}
+
+#[test_case]
+fn decode_error_codes() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running decode error codes test", file!(), line!());
+
+    assert_eq!(machine_check::decode_error_code(0x0000), "no error");
+    assert_eq!(
+        machine_check::decode_error_code(0x0400),
+        "internal timer error"
+    );
+    assert_eq!(machine_check::decode_error_code(0x0011), "TLB error");
+    assert_eq!(
+        machine_check::decode_error_code(0x0134),
+        "cache hierarchy error"
+    );
+    assert_eq!(
+        machine_check::decode_error_code(0x009F),
+        "memory controller error"
+    );
+    assert_eq!(
+        machine_check::decode_error_code(0x0E0F),
+        "bus or interconnect error"
+    );
+    // The filtering bit should not change the class
+    assert_eq!(
+        machine_check::decode_error_code(0x1134),
+        "cache hierarchy error"
+    );
+}
+
+#[test_case]
+fn decode_record() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running decode record test", file!(), line!());
+
+    let record = machine_check::MachineCheckRecord {
+        bank: 1,
+        status: 0xBC00_0000_0000_0134,
+        addr: Some(0x1000),
+        misc: None,
+        mcg_status: 0b101,
+    };
+
+    assert!(record.is_valid());
+    assert!(!record.overflow());
+    assert!(record.uncorrected());
+    assert!(record.enabled());
+    assert!(!record.processor_context_corrupt());
+    assert!(record.restartable());
+    assert_eq!(record.error_code(), 0x0134);
+
+    // Without software recovery nothing says an uncorrected error is safe
+    assert!(!record.recoverable(false));
+    // No S or AR bit makes it UCNA which needs no action
+    assert!(record.recoverable(true));
+}
+
+#[test_case]
+fn classify_uncorrected_errors() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running classify uncorrected errors test",
+        file!(),
+        line!(),
+    );
+
+    let record = |status: u64, mcg_status: u64| machine_check::MachineCheckRecord {
+        bank: 1,
+        status,
+        addr: None,
+        misc: None,
+        mcg_status,
+    };
+
+    // Corrected
+    assert!(record(0x9000_0000_0000_0000, 0).recoverable(false));
+    // SRAO - S set and AR clear
+    let srao = record(0xBD00_0000_0000_017A, 0b101);
+    assert!(srao.signaled());
+    assert!(!srao.action_required());
+    assert!(srao.recoverable(true));
+    assert!(!srao.recoverable(false));
+    // SRAR - S and AR set, the kernel cannot act on it
+    let srar = record(0xBD80_0000_0000_0134, 0b111);
+    assert!(srar.action_required());
+    assert!(!srar.recoverable(true));
+    // RIPV clear
+    assert!(!record(0xBD00_0000_0000_017A, 0b100).recoverable(true));
+    // PCC set
+    assert!(!record(0xBE00_0000_0000_0000, 0b101).recoverable(true));
+}
+
+#[test_case]
+fn injected_corrected_error_is_polled() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running injected corrected error is polled test",
+        file!(),
+        line!(),
+    );
+
+    let before = machine_check::corrected_errors();
+
+    // A corrected error in bank 1 - VAL and EN
+    lib::qemu_monitor("mce 0 1 0x9000000000000000 0 0 0");
+
+    // The poll timer may find it first, either way it is counted once
+    let start = Instant::now();
+    while machine_check::corrected_errors() == before {
+        assert!(
+            start.elapsed() < Duration::from_secs(10),
+            "injected machine check was not polled"
+        );
+        machine_check::poll();
+        core::hint::spin_loop();
+    }
+
+    assert_eq!(machine_check::corrected_errors(), before + 1);
+    assert!(!machine_check::MachineCheckRecord::read(1, 0).is_valid());
+}
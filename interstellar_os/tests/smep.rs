@@ -0,0 +1,135 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)] // Allows Us To Run Custom Tests
+#![test_runner(interstellar_os::test_runner)] // Defines The Test Runner Function
+#![reexport_test_harness_main = "test_main"]
+#![feature(abi_x86_interrupt)]
+
+use interstellar_os as lib;
+
+use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
+use core::sync::atomic::{AtomicU64, Ordering};
+use lazy_static::lazy_static;
+use lib::other::log::LOGGER;
+use lib::{exit_qemu, memory, serial_print, serial_println, QemuExitCode};
+use x86_64::registers::control::Cr2;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
+use x86_64::structures::paging::PageTableFlags;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    use bootloader_api::config::*;
+
+    let mut mappings = Mappings::new_default();
+    mappings.kernel_stack = Mapping::Dynamic;
+    mappings.boot_info = Mapping::Dynamic;
+    mappings.framebuffer = Mapping::Dynamic;
+    mappings.physical_memory = Some(Mapping::Dynamic);
+    mappings.page_table_recursive = None;
+    mappings.aslr = true;
+    mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
+    mappings.dynamic_range_end = Some(0xFFFF_FFFF_FFFF_FFFF);
+
+    let mut config = BootloaderConfig::new_default();
+    config.mappings = mappings;
+    config.kernel_stack_size = 48 * 1024; // 48 Kib   decreasing this will cause undefined behavior
+    config
+};
+
+entry_point!(smep, config = &BOOTLOADER_CONFIG);
+
+/// Address of the user accessible page the kernel tries to run
+static USER_PAGE: AtomicU64 = AtomicU64::new(0);
+
+fn smep(boot_info: &'static mut BootInfo) -> ! {
+    serial_print!("\nsmep::smep...\t");
+    lib::init(boot_info);
+
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running smep test", file!(), line!());
+
+    if !lib::cpu::protections().smep {
+        serial_println!("[Ok] (the CPU does not have SMEP)");
+        exit_qemu(QemuExitCode::Success);
+    }
+
+    // A page with `ret` in it that user mode can run, mapped in the kernel's page table
+    let frame = memory::allocate_frame().expect("out of memory");
+    unsafe { *memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>() = 0xC3 };
+    memory::identity_map(
+        frame,
+        Some(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE),
+    )
+    .expect("could not map the test page");
+
+    let address = frame.start_address().as_u64();
+    USER_PAGE.store(address, Ordering::SeqCst);
+
+    x86_64::instructions::interrupts::disable();
+    TEST_IDT.load();
+
+    let function: extern "C" fn() = unsafe { core::mem::transmute(address as usize) };
+    function();
+
+    serial_println!("[failed]\nThe kernel ran code from a user page");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+extern "x86-interrupt" fn test_page_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    let expected = PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::INSTRUCTION_FETCH;
+
+    if error_code.contains(expected)
+        && !error_code.contains(PageFaultErrorCode::USER_MODE)
+        && Cr2::read().as_u64() == USER_PAGE.load(Ordering::SeqCst)
+    {
+        serial_print!("[Ok]");
+        exit_qemu(QemuExitCode::Success);
+    }
+
+    serial_println!("[failed]\nUnexpected page fault: {:?}", error_code);
+    exit_qemu(QemuExitCode::Failed);
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(
+    _stack_frame: InterruptStackFrame,
+    _error_code: u64,
+) -> ! {
+    serial_println!("[failed]\nDouble fault");
+    exit_qemu(QemuExitCode::Failed);
+}
+
+lazy_static! {
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(test_page_fault_handler)
+                .set_stack_index(lib::gdt::PAGE_FAULT_IST_INDEX);
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(lib::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
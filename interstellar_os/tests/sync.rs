@@ -0,0 +1,365 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)] // Allows Us To Run Custom Tests
+#![test_runner(interstellar_os::test_runner)] // Defines The Test Runner Function
+#![reexport_test_harness_main = "test_main"]
+
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll};
+use core::time::Duration;
+
+use interstellar_os as lib;
+
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
+use lib::task::executor::{Executor, Spawner};
+use lib::task::spawn;
+use lib::task::sync::{mpsc, oneshot, Mutex, Notify, RwLock, Semaphore};
+use lib::time::{timer, Instant};
+use lib::{other::log::LOGGER, serial_print};
+
+extern crate alloc;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    use bootloader_api::config::*;
+
+    let mut mappings = Mappings::new_default();
+    mappings.kernel_stack = Mapping::Dynamic;
+    mappings.boot_info = Mapping::Dynamic;
+    mappings.framebuffer = Mapping::Dynamic;
+    mappings.physical_memory = Some(Mapping::Dynamic);
+    mappings.page_table_recursive = None;
+    mappings.aslr = true;
+    mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
+    mappings.dynamic_range_end = Some(0xFFFF_FFFF_FFFF_FFFF);
+
+    let mut config = BootloaderConfig::new_default();
+    config.mappings = mappings;
+    config.kernel_stack_size = 48 * 1024; // 48 Kib   decreasing this will cause undefined behavior
+    config
+};
+
+entry_point!(sync, config = &BOOTLOADER_CONFIG);
+
+/// Shared by every test so [spawn] always adds tasks to it
+static mut EXECUTOR: Option<Executor> = None;
+
+fn sync(boot_info: &'static mut BootInfo) -> ! {
+    serial_print!("\nsync::sync...\t");
+    lib::init(boot_info); // Start Interrupt Descriptor table ect.
+
+    unsafe { EXECUTOR = Some(Executor::new(Spawner::new())) };
+
+    serial_print!("[Ok]\n");
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+/// Runs tasks until none are ready and returns how many are left waiting
+fn run_until_idle() -> usize {
+    unsafe { (*addr_of_mut!(EXECUTOR)).as_mut().unwrap().run_until_idle() }
+}
+
+/// Returns pending once so the task goes back through the run queue
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+//########################################
+// Test Cases
+//########################################
+
+static COUNTER: Mutex<u64> = Mutex::new(0);
+
+#[test_case]
+fn mutex_contention() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running mutex contention test", file!(), line!());
+
+    for _ in 0..10 {
+        spawn(async {
+            for _ in 0..10 {
+                let mut counter = COUNTER.lock().await;
+                let value = *counter;
+                // Other tasks run while we hold the lock and must not get it
+                YieldNow(false).await;
+                *counter = value + 1;
+            }
+        })
+        .detach();
+    }
+
+    assert_eq!(run_until_idle(), 0);
+    assert_eq!(*COUNTER.try_lock().unwrap(), 100);
+}
+
+static RWLOCK: RwLock<u64> = RwLock::new(0);
+static READERS_SAW: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn rwlock_readers_and_writer() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running rwlock test", file!(), line!());
+
+    let first = RWLOCK.try_read().unwrap();
+    let second = RWLOCK.try_read().unwrap();
+    assert!(RWLOCK.try_write().is_none());
+
+    spawn(async {
+        *RWLOCK.write().await = 7;
+    })
+    .detach();
+    assert_eq!(run_until_idle(), 1);
+
+    // The waiting writer stops new readers
+    assert!(RWLOCK.try_read().is_none());
+
+    spawn(async {
+        READERS_SAW.store(*RWLOCK.read().await, Ordering::SeqCst);
+    })
+    .detach();
+    assert_eq!(run_until_idle(), 2);
+
+    drop(first);
+    drop(second);
+
+    assert_eq!(run_until_idle(), 0);
+    assert_eq!(READERS_SAW.load(Ordering::SeqCst), 7);
+}
+
+static SEMAPHORE: Semaphore = Semaphore::new(0);
+static ORDER: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+#[test_case]
+fn semaphore_fifo() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running semaphore fifo test", file!(), line!());
+
+    for i in 0..5u64 {
+        spawn(async move {
+            SEMAPHORE.acquire().await.forget();
+            ORDER.lock().await.push(i);
+        })
+        .detach();
+        // Each task has to start waiting before the next is spawned
+        run_until_idle();
+    }
+
+    assert_eq!(run_until_idle(), 5);
+
+    for _ in 0..5 {
+        SEMAPHORE.add_permits(1);
+        run_until_idle();
+    }
+
+    assert_eq!(run_until_idle(), 0);
+    assert_eq!(*ORDER.try_lock().unwrap(), [0, 1, 2, 3, 4]);
+    assert_eq!(SEMAPHORE.available_permits(), 0);
+}
+
+static NOTIFY: Notify = Notify::new();
+static NOTIFIED: AtomicBool = AtomicBool::new(false);
+
+fn notify_from_interrupt(_data: usize) {
+    NOTIFY.notify_one();
+}
+
+#[test_case]
+fn notify_from_timer_interrupt() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running notify from timer interrupt test",
+        file!(),
+        line!(),
+    );
+
+    spawn(async {
+        NOTIFY.notified().await;
+        NOTIFIED.store(true, Ordering::SeqCst);
+    })
+    .detach();
+    assert_eq!(run_until_idle(), 1);
+
+    timer::add(
+        Instant::now() + Duration::from_millis(10),
+        notify_from_interrupt,
+        0,
+    )
+    .unwrap();
+
+    while run_until_idle() != 0 {
+        core::hint::spin_loop();
+    }
+
+    assert!(NOTIFIED.load(Ordering::SeqCst));
+}
+
+#[test_case]
+fn notify_stores_permit() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running notify permit test", file!(), line!());
+
+    let notify = Notify::new();
+    notify.notify_one();
+
+    let mut notified = core::pin::pin!(notify.notified());
+    let waker = futures_util::task::noop_waker();
+    let mut cx = Context::from_waker(&waker);
+
+    assert_eq!(notified.as_mut().poll(&mut cx), Poll::Ready(()));
+}
+
+static RECEIVED: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+static SENT: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn mpsc_backpressure() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running mpsc backpressure test", file!(), line!());
+
+    let (tx, mut rx) = mpsc::channel::<u64>(2);
+
+    for producer in 0..2u64 {
+        let tx = tx.clone();
+        spawn(async move {
+            for i in 0..5 {
+                tx.send(producer * 100 + i).await.unwrap();
+                SENT.fetch_add(1, Ordering::SeqCst);
+            }
+        })
+        .detach();
+    }
+    drop(tx);
+
+    // Nobody is receiving so only the capacity can be sent
+    assert_eq!(run_until_idle(), 2);
+    assert_eq!(SENT.load(Ordering::SeqCst), 2);
+
+    spawn(async move {
+        while let Some(value) = rx.recv().await {
+            RECEIVED.lock().await.push(value);
+        }
+    })
+    .detach();
+
+    assert_eq!(run_until_idle(), 0);
+    assert_eq!(SENT.load(Ordering::SeqCst), 10);
+
+    let received = RECEIVED.try_lock().unwrap();
+    assert_eq!(received.len(), 10);
+    for producer in 0..2u64 {
+        let values: Vec<u64> = received
+            .iter()
+            .copied()
+            .filter(|value| value / 100 == producer)
+            .collect();
+        assert_eq!(values, [0, 1, 2, 3, 4].map(|i| producer * 100 + i));
+    }
+}
+
+#[test_case]
+fn mpsc_try_send_and_close() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running mpsc try send test", file!(), line!());
+
+    let (tx, mut rx) = mpsc::channel(1);
+
+    assert_eq!(tx.try_send(1), Ok(()));
+    assert_eq!(tx.try_send(2), Err(mpsc::TrySendError::Full(2)));
+    assert_eq!(rx.try_recv(), Ok(1));
+    assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Empty));
+
+    rx.close();
+    assert_eq!(tx.try_send(3), Err(mpsc::TrySendError::Closed(3)));
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    for i in 0..100 {
+        tx.send(i).unwrap();
+    }
+    drop(tx);
+    for i in 0..100 {
+        assert_eq!(rx.try_recv(), Ok(i));
+    }
+    assert_eq!(rx.try_recv(), Err(mpsc::TryRecvError::Disconnected));
+}
+
+static ONESHOT_VALUE: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn oneshot_channel() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running oneshot test", file!(), line!());
+
+    let (tx, rx) = oneshot::channel();
+
+    spawn(async move {
+        ONESHOT_VALUE.store(rx.await.unwrap(), Ordering::SeqCst);
+    })
+    .detach();
+    assert_eq!(run_until_idle(), 1);
+
+    tx.send(42).unwrap();
+
+    assert_eq!(run_until_idle(), 0);
+    assert_eq!(ONESHOT_VALUE.load(Ordering::SeqCst), 42);
+
+    let (tx, rx) = oneshot::channel::<u64>();
+    drop(rx);
+    assert_eq!(tx.send(1), Err(1));
+
+    let (tx, mut rx) = oneshot::channel::<u64>();
+    drop(tx);
+    assert_eq!(rx.try_recv(), Err(oneshot::TryRecvError::Closed));
+}
@@ -0,0 +1,334 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)] // Allows Us To Run Custom Tests
+#![test_runner(interstellar_os::test_runner)] // Defines The Test Runner Function
+#![reexport_test_harness_main = "test_main"]
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+
+use interstellar_os as lib;
+
+use alloc::vec;
+use alloc::vec::Vec;
+use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
+use lib::task::executor::{Executor, Spawner};
+use lib::task::spawn;
+use lib::task::sync::Notify;
+use lib::thread::{self, SchedClass, ThreadState};
+use lib::time::Instant;
+use lib::{other::log::LOGGER, serial_print};
+use spinning_top::Spinlock;
+
+extern crate alloc;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    use bootloader_api::config::*;
+
+    let mut mappings = Mappings::new_default();
+    mappings.kernel_stack = Mapping::Dynamic;
+    mappings.boot_info = Mapping::Dynamic;
+    mappings.framebuffer = Mapping::Dynamic;
+    mappings.physical_memory = Some(Mapping::Dynamic);
+    mappings.page_table_recursive = None;
+    mappings.aslr = true;
+    mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
+    mappings.dynamic_range_end = Some(0xFFFF_FFFF_FFFF_FFFF);
+
+    let mut config = BootloaderConfig::new_default();
+    config.mappings = mappings;
+    config.kernel_stack_size = 48 * 1024; // 48 Kib   decreasing this will cause undefined behavior
+    config
+};
+
+entry_point!(threads, config = &BOOTLOADER_CONFIG);
+
+fn threads(boot_info: &'static mut BootInfo) -> ! {
+    serial_print!("\nthread::threads...\t");
+    lib::init(boot_info); // Start Interrupt Descriptor table ect.
+
+    serial_print!("[Ok]\n");
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+//########################################
+// Test Cases
+//########################################
+
+#[test_case]
+fn join_returns_result() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running join returns result test", file!(), line!());
+
+    let handle = thread::spawn("adder", || (1..=10u64).sum::<u64>());
+
+    assert_eq!(handle.join(), 55);
+}
+
+static ORDER: Spinlock<Vec<u64>> = Spinlock::new(Vec::new());
+
+#[test_case]
+fn yield_interleaves() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running yield interleaves test", file!(), line!());
+
+    let handles: Vec<_> = (0..2u64)
+        .map(|i| {
+            thread::spawn("yielder", move || {
+                for step in 0..3 {
+                    ORDER.lock().push(step * 2 + i);
+                    thread::yield_now();
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join();
+    }
+
+    assert_eq!(*ORDER.lock(), [0, 1, 2, 3, 4, 5]);
+}
+
+static SPINNER_RAN: AtomicBool = AtomicBool::new(false);
+
+#[test_case]
+fn timer_preempts_busy_thread() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running preemption test", file!(), line!());
+
+    let handle = thread::spawn("spinner", || SPINNER_RAN.store(true, Ordering::SeqCst));
+
+    // This thread never yields, the spinner can only run if the LAPIC timer preempts us
+    while !SPINNER_RAN.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+
+    handle.join();
+}
+
+static UNBLOCKED: AtomicBool = AtomicBool::new(false);
+
+#[test_case]
+fn block_and_wake() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running block and wake test", file!(), line!());
+
+    let handle = thread::spawn("blocker", || {
+        while !UNBLOCKED.load(Ordering::SeqCst) {
+            thread::block();
+        }
+    });
+
+    while handle.thread().state() != ThreadState::Blocked {
+        thread::yield_now();
+    }
+
+    UNBLOCKED.store(true, Ordering::SeqCst);
+    assert!(thread::wake(handle.thread().id()));
+
+    handle.join();
+}
+
+#[test_case]
+fn thread_sleep() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running thread sleep test", file!(), line!());
+
+    let handle = thread::spawn("sleeper", || {
+        let start = Instant::now();
+        thread::sleep(Duration::from_millis(20));
+        start.elapsed()
+    });
+
+    assert!(handle.join() >= Duration::from_millis(20));
+}
+
+static NOTIFY: Notify = Notify::new();
+static TASK_NOTIFIED: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn thread_wakes_async_task() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running thread wakes async task test", file!(), line!());
+
+    let mut executor = Executor::new(Spawner::new());
+
+    spawn(async {
+        NOTIFY.notified().await;
+        TASK_NOTIFIED.store(1, Ordering::SeqCst);
+    })
+    .detach();
+    assert_eq!(executor.run_until_idle(), 1);
+
+    let handle = thread::spawn("notifier", || {
+        thread::sleep(Duration::from_millis(10));
+        NOTIFY.notify_one();
+    });
+
+    while executor.run_until_idle() != 0 {
+        thread::yield_now();
+    }
+
+    handle.join();
+    assert_eq!(TASK_NOTIFIED.load(Ordering::SeqCst), 1);
+}
+
+static CLASS_ORDER: Spinlock<Vec<&str>> = Spinlock::new(Vec::new());
+
+#[test_case]
+fn realtime_runs_first() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running realtime runs first test", file!(), line!());
+
+    let normal = thread::spawn("normal", || CLASS_ORDER.lock().push("normal"));
+    let realtime = thread::spawn_with_class("realtime", SchedClass::RealTime(3), || {
+        CLASS_ORDER.lock().push("realtime")
+    });
+
+    normal.join();
+    realtime.join();
+
+    assert_eq!(*CLASS_ORDER.lock(), ["realtime", "normal"]);
+}
+
+static STOP_SPINNING: AtomicBool = AtomicBool::new(false);
+
+#[test_case]
+fn nice_threads_share_by_weight() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running nice weight test", file!(), line!());
+
+    let spin = || {
+        while !STOP_SPINNING.load(Ordering::SeqCst) {
+            core::hint::spin_loop();
+        }
+    };
+
+    let favoured = thread::spawn_with_class("favoured", SchedClass::Normal(-5), spin);
+    let background = thread::spawn_with_class("background", SchedClass::Normal(5), spin);
+
+    thread::sleep(Duration::from_millis(200));
+
+    let stats = thread::stats();
+    let cpu_time = |id| {
+        stats
+            .iter()
+            .find(|thread| thread.id == id)
+            .unwrap()
+            .cpu_time
+    };
+    let favoured_time = cpu_time(favoured.thread().id());
+    let background_time = cpu_time(background.thread().id());
+
+    STOP_SPINNING.store(true, Ordering::SeqCst);
+    favoured.join();
+    background.join();
+
+    // The weights are 3121 and 335 so the favoured thread should get about 9 times as much
+    assert!(
+        favoured_time > background_time * 3,
+        "favoured {favoured_time:?} background {background_time:?}"
+    );
+}
+
+#[test_case]
+fn stats_include_every_thread() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running thread stats test", file!(), line!());
+
+    let stats = thread::stats();
+
+    let kernel = stats.iter().find(|thread| thread.name == "kernel").unwrap();
+    assert_eq!(kernel.state, ThreadState::Running);
+    assert!(kernel.cpu_time > Duration::ZERO);
+
+    let idle = stats.iter().find(|thread| thread.name == "idle").unwrap();
+    assert_eq!(idle.class, SchedClass::Idle);
+}
+
+#[test_case]
+fn wait_queue_wakes_in_order() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running wait queue wakes in order test", file!(), line!());
+
+    static QUEUE: thread::WaitQueue = thread::WaitQueue::new();
+    static WOKEN: Spinlock<Vec<u64>> = Spinlock::new(Vec::new());
+
+    let waiters: Vec<_> = (0..2)
+        .map(|index| {
+            let waiter = thread::spawn("waiter", move || {
+                let result = QUEUE.wait_if(|| true, None, || false);
+                WOKEN.lock().push(index);
+                result
+            });
+            // Let it start waiting before the next one
+            thread::sleep(Duration::from_millis(10));
+            waiter
+        })
+        .collect();
+
+    assert_eq!(
+        QUEUE.wait_if(|| false, None, || false),
+        thread::WaitResult::NotWaited
+    );
+
+    assert_eq!(QUEUE.wake(1), 1);
+    thread::sleep(Duration::from_millis(10));
+    assert_eq!(*WOKEN.lock(), vec![0]);
+
+    assert_eq!(QUEUE.wake_all(), 1);
+    for waiter in waiters {
+        assert_eq!(waiter.join(), thread::WaitResult::Woken);
+    }
+    assert!(QUEUE.is_empty());
+}
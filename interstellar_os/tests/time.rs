@@ -20,6 +20,10 @@
 #![test_runner(interstellar_os::test_runner)] // Defines The Test Runner Function
 #![reexport_test_harness_main = "test_main"]
 
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
 use core::time::Duration;
 
 use interstellar_os as lib;
@@ -73,20 +77,23 @@ fn sleep() {
 
     let timer = lib::time::Timer::new();
 
-    // The LAPIC timer ticks every 10ms
+    // With the periodic tick on the LAPIC timer ticks every 10ms
     // So every 100 ticks is 1 second
+    lib::time::timer::set_tick_mode(lib::time::timer::TickMode::Periodic);
 
-    let start_count = unsafe { lib::time::APIC_COUNT.load(core::sync::atomic::Ordering::SeqCst) };
+    let start_count = lib::time::APIC_COUNT.load(core::sync::atomic::Ordering::SeqCst);
 
     timer.sleep(Duration::from_secs(1));
 
-    let end_count = unsafe { lib::time::APIC_COUNT.load(core::sync::atomic::Ordering::SeqCst) };
+    let end_count = lib::time::APIC_COUNT.load(core::sync::atomic::Ordering::SeqCst);
+
+    lib::time::timer::set_tick_mode(lib::time::timer::TickMode::Tickless);
 
     // So if this is close to 100 we know the timings are semi correct
 
     let slept_for = end_count - start_count;
 
-    if slept_for > 130 || slept_for < 70 {
+    if !(70..=130).contains(&slept_for) {
         panic!("Timing should be close to 100 but it is {}", slept_for)
     }
 }
@@ -105,7 +112,466 @@ fn elapsed() {
 
     let elapsed = timer.elapsed().as_secs_f64();
 
-    if elapsed > 1.3 || elapsed < 0.7 {
+    if !(0.7..=1.3).contains(&elapsed) {
         panic!("Timing should be close to 1 but was {}", elapsed)
     }
 }
+
+#[test_case]
+fn hpet_monotonic() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running hpet monotonic test", file!(), line!());
+
+    // QEMU always provides a HPET but real hardware may not
+    if let Some(hpet) = lib::time::hpet::get() {
+        let mut last = hpet.nanos();
+        for _ in 0..1000 {
+            let now = hpet.nanos();
+            assert!(now >= last, "HPET went backwards {last} -> {now}");
+            last = now;
+        }
+    }
+}
+
+#[test_case]
+fn hpet_counter_extension() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running hpet counter extension test", file!(), line!());
+
+    use lib::time::hpet::extend_counter;
+
+    // Wrapping around adds 2^32
+    let last = AtomicU64::new(0);
+    assert_eq!(extend_counter(&last, || 0xFFFF_FFF0), 0xFFFF_FFF0);
+    assert_eq!(extend_counter(&last, || 0x10), 0x1_0000_0010);
+    assert_eq!(extend_counter(&last, || 0x20), 0x1_0000_0020);
+
+    // Another CPU stores a newer value after this read, the counter is read again instead of wrapping
+    let last = AtomicU64::new(0xFFFF_FFF0);
+    let mut reads = [0xFFFF_FFF8, 0x8].into_iter();
+    let value = extend_counter(&last, || {
+        let low = reads.next().unwrap();
+        if low == 0xFFFF_FFF8 {
+            last.store(0x1_0000_0005, Ordering::SeqCst);
+        }
+        low
+    });
+    assert_eq!(value, 0x1_0000_0008);
+    assert_eq!(last.load(Ordering::SeqCst), 0x1_0000_0008);
+}
+
+#[test_case]
+fn hpet_elapsed() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running hpet elapsed test", file!(), line!());
+
+    if let Some(hpet) = lib::time::hpet::get() {
+        let start = hpet.nanos();
+
+        lib::time::Timer::new().sleep(Duration::from_millis(500));
+
+        let elapsed = (hpet.nanos() - start) as f64 / 1_000_000_000.0;
+
+        if !(0.2..=0.8).contains(&elapsed) {
+            panic!("HPET measured {} but should be close to 0.5", elapsed)
+        }
+    }
+}
+
+#[test_case]
+fn hpet_oneshot() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running hpet oneshot test", file!(), line!());
+
+    if let Some(hpet) = lib::time::hpet::get() {
+        let start_count = lib::time::hpet::HPET_COUNT.load(core::sync::atomic::Ordering::SeqCst);
+
+        hpet.set_comparator(
+            0,
+            lib::time::hpet::ComparatorMode::OneShot,
+            Duration::from_millis(10),
+            lib::interrupts::InterruptIndex::Hpet.as_u8(),
+        )
+        .expect("could not program HPET comparator");
+
+        hpet.busy_wait(Duration::from_millis(100));
+
+        let end_count = lib::time::hpet::HPET_COUNT.load(core::sync::atomic::Ordering::SeqCst);
+
+        hpet.disable_comparator(0).unwrap();
+
+        assert_eq!(end_count - start_count, 1);
+    }
+}
+
+#[test_case]
+fn clocksource_selected() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running clocksource selected test", file!(), line!());
+
+    // QEMU always has a HPET so we should never fall back to the 10ms PIT
+    assert_ne!(lib::time::clocksource(), lib::time::ClockSource::Pit);
+}
+
+#[test_case]
+fn instant_resolution() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running instant resolution test", file!(), line!());
+
+    let start = lib::time::Instant::now();
+
+    // Spin until the clock moves, with the TSC or HPET this should take well under 1ms
+    let mut now = lib::time::Instant::now();
+    while now == start {
+        now = lib::time::Instant::now();
+    }
+
+    assert!(now > start);
+    assert!(now - start < Duration::from_millis(1));
+}
+
+#[test_case]
+fn instant_elapsed() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running instant elapsed test", file!(), line!());
+
+    let start = lib::time::Instant::now();
+
+    lib::time::Timer::new().sleep(Duration::from_millis(200));
+
+    let elapsed = start.elapsed().as_secs_f64();
+
+    if !(0.2..=0.3).contains(&elapsed) {
+        panic!("Timing should be close to 0.2 but was {}", elapsed)
+    }
+
+    assert_eq!(
+        start + Duration::from_millis(5) - start,
+        Duration::from_millis(5)
+    );
+}
+
+#[test_case]
+fn rtc_read() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running RTC read test", file!(), line!());
+
+    let now = lib::time::rtc::read();
+
+    assert!(now.year >= 2023);
+    assert!((1..=12).contains(&now.month));
+    assert!((1..=31).contains(&now.day));
+    assert!(now.hour < 24 && now.minute < 60 && now.second < 60);
+}
+
+#[test_case]
+fn unix_timestamp_round_trip() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running unix timestamp round trip test", file!(), line!());
+
+    let date = lib::time::rtc::DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 13,
+        minute: 37,
+        second: 42,
+    };
+
+    assert_eq!(date.unix_timestamp(), 1_709_213_862);
+    assert_eq!(
+        lib::time::rtc::DateTime::from_unix_timestamp(1_709_213_862),
+        date
+    );
+    assert_eq!(lib::time::UNIX_EPOCH.date_time().year, 1970);
+}
+
+#[test_case]
+fn system_time_monotonic() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running system time test", file!(), line!());
+
+    let start = lib::time::SystemTime::now();
+
+    lib::time::Timer::new().sleep(Duration::from_millis(50));
+
+    let elapsed = lib::time::SystemTime::now().duration_since(start).unwrap();
+
+    assert!(elapsed >= Duration::from_millis(50));
+    assert!(
+        start
+            .duration_since(lib::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            > 1_672_531_200
+    );
+}
+
+#[test_case]
+fn rtc_periodic_interrupt() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running RTC periodic interrupt test", file!(), line!());
+
+    let start = lib::time::rtc::RTC_COUNT.load(Ordering::SeqCst);
+
+    // 1024Hz
+    lib::time::rtc::enable_periodic(6);
+    lib::time::Timer::new().sleep(Duration::from_millis(100));
+    lib::time::rtc::disable_periodic();
+
+    let fired = lib::time::rtc::RTC_COUNT.load(Ordering::SeqCst) - start;
+
+    assert!(fired > 50, "RTC only fired {fired} times in 100ms");
+}
+
+#[test_case]
+fn timer_wheel_order() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running timer wheel order test", file!(), line!());
+
+    let mut wheel = lib::time::timer_wheel::TimerWheel::new(16, 1000);
+
+    // Spread over several levels of the wheel
+    wheel.insert(1_000_000_000, 0, 3).unwrap();
+    wheel.insert(1005, 0, 1).unwrap();
+    let cancelled = wheel.insert(50_000, 0, 9).unwrap();
+    wheel.insert(70_000, 0, 2).unwrap();
+
+    assert_eq!(wheel.next_deadline(), Some(1005));
+    assert_eq!(wheel.cancel(cancelled), Some(9));
+    assert_eq!(wheel.cancel(cancelled), None);
+
+    let mut expired = [0; 3];
+    let mut count = 0;
+    for now in [1004, 1005, 69_999, 70_000, 999_999_999, 1_000_000_000] {
+        wheel.advance(now);
+        while let Some((_, data)) = wheel.pop_expired() {
+            expired[count] = data;
+            count += 1;
+        }
+    }
+
+    assert_eq!(expired, [1, 2, 3]);
+    assert!(wheel.is_empty());
+    assert_eq!(wheel.next_deadline(), None);
+}
+
+#[test_case]
+fn timer_wheel_periodic() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running timer wheel periodic test", file!(), line!());
+
+    let mut wheel = lib::time::timer_wheel::TimerWheel::new(1, 0);
+
+    let id = wheel.insert(100, 100, ()).unwrap();
+
+    assert!(wheel.insert(5, 0, ()).is_none(), "wheel should be full");
+
+    wheel.advance(250);
+    assert!(wheel.pop_expired().is_some());
+    assert!(wheel.pop_expired().is_none());
+
+    // Missed expiries are skipped
+    assert_eq!(wheel.deadline(id), Some(350));
+    assert!(wheel.cancel(id).is_some());
+}
+
+static TIMER_FIRED: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn timer_fires() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running timer fires test", file!(), line!());
+
+    fn fired(data: usize) {
+        TIMER_FIRED.store(data as u64, Ordering::SeqCst);
+    }
+
+    let start = lib::time::Instant::now();
+    let deadline = start + Duration::from_millis(20);
+
+    lib::time::timer::add(deadline, fired, 42).unwrap();
+
+    let cancelled = lib::time::timer::add(start + Duration::from_millis(10), fired, 7).unwrap();
+    assert!(lib::time::timer::cancel(cancelled));
+
+    while TIMER_FIRED.load(Ordering::SeqCst) == 0 {
+        x86_64::instructions::hlt();
+    }
+
+    assert_eq!(TIMER_FIRED.load(Ordering::SeqCst), 42);
+    assert!(lib::time::Instant::now() >= deadline);
+}
+
+#[test_case]
+fn tickless_idle() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running tickless idle test", file!(), line!());
+
+    assert_eq!(
+        lib::time::timer::tick_mode(),
+        lib::time::timer::TickMode::Tickless
+    );
+
+    let start_count = lib::time::APIC_COUNT.load(Ordering::SeqCst);
+
+    lib::time::Timer::new().sleep(Duration::from_millis(500));
+
+    let end_count = lib::time::APIC_COUNT.load(Ordering::SeqCst);
+
+    // A periodic tick would have fired 50 times
+    assert!(
+        end_count - start_count < 10,
+        "LAPIC timer fired {} times while sleeping",
+        end_count - start_count
+    );
+}
+
+static WOKEN: AtomicBool = AtomicBool::new(false);
+
+static WAKER_VTABLE: RawWakerVTable =
+    RawWakerVTable::new(clone_waker, wake_waker, wake_waker, drop_waker);
+
+fn clone_waker(_: *const ()) -> RawWaker {
+    RawWaker::new(core::ptr::null(), &WAKER_VTABLE)
+}
+
+fn wake_waker(_: *const ()) {
+    WOKEN.store(true, Ordering::SeqCst);
+}
+
+fn drop_waker(_: *const ()) {}
+
+/// Polls a future until it completes halting the CPU until it is woken
+fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = unsafe { Waker::from_raw(clone_waker(core::ptr::null())) };
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+
+        loop {
+            x86_64::instructions::interrupts::disable();
+            if WOKEN.swap(false, Ordering::SeqCst) {
+                x86_64::instructions::interrupts::enable();
+                break;
+            }
+            x86_64::instructions::interrupts::enable_and_hlt();
+        }
+    }
+}
+
+#[test_case]
+fn async_sleep() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running async sleep test", file!(), line!());
+
+    let pending = lib::time::timer::pending();
+    let start = lib::time::Instant::now();
+
+    block_on(lib::time::sleep(Duration::from_millis(30)));
+
+    assert!(start.elapsed() >= Duration::from_millis(30));
+    assert_eq!(lib::time::timer::pending(), pending);
+}
+
+#[test_case]
+fn async_timeout() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running async timeout test", file!(), line!());
+
+    let pending = lib::time::timer::pending();
+    let start = lib::time::Instant::now();
+
+    let result = block_on(lib::time::timeout(
+        core::future::pending::<()>(),
+        Duration::from_millis(20),
+    ));
+
+    assert_eq!(result, Err(lib::time::Elapsed));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+
+    let result = block_on(lib::time::timeout(
+        async {
+            lib::time::sleep(Duration::from_millis(5)).await;
+            7
+        },
+        Duration::from_secs(1),
+    ));
+
+    assert_eq!(result, Ok(7));
+    assert_eq!(lib::time::timer::pending(), pending);
+}
+
+#[test_case]
+fn async_interval() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running async interval test", file!(), line!());
+
+    let mut interval = lib::time::interval(Duration::from_millis(10));
+
+    let first = block_on(interval.tick());
+    let second = block_on(interval.tick());
+    let third = block_on(interval.tick());
+
+    assert_eq!(second - first, Duration::from_millis(10));
+    assert_eq!(third - second, Duration::from_millis(10));
+    assert!(lib::time::Instant::now() >= third);
+}
@@ -0,0 +1,1907 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)] // Allows Us To Run Custom Tests
+#![test_runner(interstellar_os::test_runner)] // Defines The Test Runner Function
+#![reexport_test_harness_main = "test_main"]
+
+use interstellar_os as lib;
+
+use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
+use core::time::Duration;
+use lib::allocator::HEAP_START;
+use lib::drivers::fs::initrd::{InitrdData, InitrdFileEntry, InitrdMetadata, INITRDDATA};
+use lib::drivers::random::RandomNumberGenerator;
+use lib::memory;
+use lib::process::signal::{self, DefaultAction, Signal};
+use lib::process::trace::{self, Resume, StopReason, TraceError, Tracer};
+use lib::process::{self, ProcessError, ProcessState};
+use lib::syscall;
+use lib::thread;
+use lib::user::{
+    self, address_space, elf, Abi, AddressSpace, ElfError, Exit, UserFault, IMAGE_BASE,
+    LIBRARY_START, MAP_START, SIGNAL_TRAMPOLINE,
+};
+use lib::{other::log::LOGGER, serial_print};
+
+extern crate alloc;
+
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use spinning_top::Spinlock;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::VirtAddr;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    use bootloader_api::config::*;
+
+    let mut mappings = Mappings::new_default();
+    mappings.kernel_stack = Mapping::Dynamic;
+    mappings.boot_info = Mapping::Dynamic;
+    mappings.framebuffer = Mapping::Dynamic;
+    mappings.physical_memory = Some(Mapping::Dynamic);
+    mappings.page_table_recursive = None;
+    mappings.aslr = true;
+    mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
+    mappings.dynamic_range_end = Some(0xFFFF_FFFF_FFFF_FFFF);
+
+    let mut config = BootloaderConfig::new_default();
+    config.mappings = mappings;
+    config.kernel_stack_size = 48 * 1024; // 48 Kib   decreasing this will cause undefined behavior
+    config
+};
+
+entry_point!(user_mode, config = &BOOTLOADER_CONFIG);
+
+fn user_mode(boot_info: &'static mut BootInfo) -> ! {
+    serial_print!("\nuser_mode::user_mode...\t");
+    lib::init(boot_info); // Start Interrupt Descriptor table ect.
+    serial_print!("[Ok]\n");
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+/// Where [executable] puts the code in the image, after the headers and [INTERSTELLAR_NOTE]
+const CODE_OFFSET: u64 = 64 + 2 * 56 + 32;
+
+/// The note that marks interstellar programs, programs without it get the Linux system calls
+const INTERSTELLAR_NOTE: [u8; 32] =
+    *b"\x0d\0\0\0\x04\0\0\0\x01\0\0\0Interstellar\0\0\0\0\x01\0\0\0";
+
+/// Adds a PT_NOTE header for [INTERSTELLAR_NOTE] at `offset` in the file
+fn note_header(image: &mut Vec<u8>, offset: u64) {
+    image.extend_from_slice(&4u32.to_le_bytes());
+    image.extend_from_slice(&4u32.to_le_bytes()); // PF_R
+    image.extend_from_slice(&offset.to_le_bytes());
+    image.extend_from_slice(&(IMAGE_BASE + offset).to_le_bytes());
+    image.extend_from_slice(&(IMAGE_BASE + offset).to_le_bytes());
+    image.extend_from_slice(&(INTERSTELLAR_NOTE.len() as u64).to_le_bytes());
+    image.extend_from_slice(&(INTERSTELLAR_NOTE.len() as u64).to_le_bytes());
+    image.extend_from_slice(&4u64.to_le_bytes());
+}
+
+/// Wraps `code` in an interstellar ELF executable with one segment loaded at [IMAGE_BASE]
+///
+/// The segment is `memory_size` bytes long, anything past the code is BSS
+fn executable(code: &[u8], flags: u32, memory_size: u64) -> Vec<u8> {
+    let file_size = CODE_OFFSET + code.len() as u64;
+    let mut image = Vec::new();
+
+    // ELF header
+    image.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+    image.extend_from_slice(&[0; 8]);
+    image.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    image.extend_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&(IMAGE_BASE + CODE_OFFSET).to_le_bytes());
+    image.extend_from_slice(&64u64.to_le_bytes()); // Program headers
+    image.extend_from_slice(&0u64.to_le_bytes()); // Section headers
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&64u16.to_le_bytes());
+    image.extend_from_slice(&56u16.to_le_bytes());
+    image.extend_from_slice(&2u16.to_le_bytes());
+    image.extend_from_slice(&[0; 6]);
+
+    // PT_LOAD covering the whole file
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&flags.to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&IMAGE_BASE.to_le_bytes());
+    image.extend_from_slice(&IMAGE_BASE.to_le_bytes());
+    image.extend_from_slice(&file_size.to_le_bytes());
+    image.extend_from_slice(&memory_size.max(file_size).to_le_bytes());
+    image.extend_from_slice(&0x1000u64.to_le_bytes());
+
+    note_header(&mut image, CODE_OFFSET - INTERSTELLAR_NOTE.len() as u64);
+
+    image.extend_from_slice(&INTERSTELLAR_NOTE);
+    image.extend_from_slice(code);
+    image
+}
+
+/// PF_R | PF_X
+const READ_EXECUTE: u32 = 0b101;
+
+fn run(name: &str, code: &[u8]) -> Exit {
+    run_with_args(name, code, &[name])
+}
+
+fn run_with_args(name: &str, code: &[u8], args: &[&str]) -> Exit {
+    let image = executable(code, READ_EXECUTE, 0);
+    user::spawn(name, &image, args, &[]).unwrap().join()
+}
+
+fn run_image(name: &str, image: &[u8]) -> Exit {
+    user::spawn(name, image, &[name], &[]).unwrap().join()
+}
+
+fn load(image: &[u8]) -> Result<elf::LoadedProgram, ElfError> {
+    let mut space = AddressSpace::new().unwrap();
+    elf::load(&mut space, image, &["test"], &[])
+}
+
+//########################################
+// Test Cases
+//########################################
+
+/// mov edi, 42; xor eax, eax; int 0x80
+const EXIT_42: [u8; 9] = [0xbf, 0x2a, 0x00, 0x00, 0x00, 0x31, 0xc0, 0xcd, 0x80];
+
+#[test_case]
+fn exit_code() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running exit code test", file!(), line!());
+
+    assert_eq!(run("exit_code", &EXIT_42), Exit::Code(42));
+}
+
+/// Writes "user!\n" then exits with what write returned
+const WRITE_AND_EXIT: [u8; 37] = [
+    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+    0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+    0x48, 0x8d, 0x35, 0x0e, 0x00, 0x00, 0x00, // lea rsi, [rip + message]
+    0xba, 0x06, 0x00, 0x00, 0x00, // mov edx, 6
+    0xcd, 0x80, // int 0x80
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x31, 0xc0, // xor eax, eax
+    0xcd, 0x80, // int 0x80
+    b'u', b's', b'e', b'r', b'!', b'\n',
+];
+
+#[test_case]
+fn write_syscall() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running write syscall test", file!(), line!());
+
+    assert_eq!(run("write", &WRITE_AND_EXIT), Exit::Code(6));
+}
+
+/// Writes from a kernel address then exits with what write returned
+const WRITE_KERNEL_POINTER: [u8; 34] = [
+    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+    0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+    0x48, 0xbe, 0x00, 0x00, 0x00, 0x00, 0x00, 0x80, 0xff,
+    0xff, // movabs rsi, 0xffff800000000000
+    0xba, 0x06, 0x00, 0x00, 0x00, // mov edx, 6
+    0xcd, 0x80, // int 0x80
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x31, 0xc0, // xor eax, eax
+    0xcd, 0x80, // int 0x80
+];
+
+#[test_case]
+fn write_rejects_kernel_pointer() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running write rejects kernel pointer test",
+        file!(),
+        line!(),
+    );
+
+    // -BadAddress
+    assert_eq!(run("bad_pointer", &WRITE_KERNEL_POINTER), Exit::Code(-2));
+}
+
+#[test_case]
+fn invalid_opcode_kills_program() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running invalid opcode kills program test",
+        file!(),
+        line!(),
+    );
+
+    // ud2
+    assert_eq!(
+        run("ud2", &[0x0f, 0x0b]),
+        Exit::Fault(UserFault::InvalidOpcode)
+    );
+}
+
+/// movabs rax, HEAP_START; mov rax, [rax]
+const READ_KERNEL_HEAP: [u8; 13] = [
+    0x48, 0xb8, 0x00, 0x00, 0x44, 0x44, 0x44, 0x44, 0x00, 0x00, 0x48, 0x8b, 0x00,
+];
+
+#[test_case]
+fn kernel_memory_is_not_user_accessible() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running kernel memory is not user accessible test",
+        file!(),
+        line!(),
+    );
+
+    assert_eq!(
+        run("read_kernel", &READ_KERNEL_HEAP),
+        Exit::Fault(UserFault::PageFault(HEAP_START as u64))
+    );
+}
+
+/// lea rax, [rip - 7]; mov byte ptr [rax], 0
+const WRITE_OWN_CODE: [u8; 10] = [0x48, 0x8d, 0x05, 0xf9, 0xff, 0xff, 0xff, 0xc6, 0x00, 0x00];
+
+#[test_case]
+fn program_image_is_read_only() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running program image is read only test", file!(), line!());
+
+    assert_eq!(
+        run("write_code", &WRITE_OWN_CODE),
+        Exit::Fault(UserFault::PageFault(IMAGE_BASE + CODE_OFFSET))
+    );
+}
+
+/// mov rdi, [rsp]; xor eax, eax; int 0x80
+const EXIT_ARGC: [u8; 8] = [0x48, 0x8b, 0x3c, 0x24, 0x31, 0xc0, 0xcd, 0x80];
+
+#[test_case]
+fn argc_is_on_the_stack() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running argc is on the stack test", file!(), line!());
+
+    assert_eq!(
+        run_with_args("argc", &EXIT_ARGC, &["argc", "one", "two"]),
+        Exit::Code(3)
+    );
+}
+
+/// mov rax, [rsp + 16]; movzx edi, byte ptr [rax]; xor eax, eax; int 0x80
+const EXIT_FIRST_ARGUMENT: [u8; 12] = [
+    0x48, 0x8b, 0x44, 0x24, 0x10, 0x0f, 0xb6, 0x38, 0x31, 0xc0, 0xcd, 0x80,
+];
+
+#[test_case]
+fn argv_points_at_arguments() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running argv points at arguments test", file!(), line!());
+
+    assert_eq!(
+        run_with_args("argv", &EXIT_FIRST_ARGUMENT, &["argv", "x"]),
+        Exit::Code(b'x' as i64)
+    );
+}
+
+/// Exits with the BSS qword at IMAGE_BASE + 0x800 after writing to it
+const READ_BSS: [u8; 24] = [
+    // movabs rax, IMAGE_BASE + 0x800
+    0x48, 0xb8, 0x00, 0x08, 0x40, 0x00, 0x80, 0x00, 0x00, 0x00, 0x48, 0x8b,
+    0x38, // mov rdi, [rax]
+    0x48, 0xc7, 0x00, 0x01, 0x00, 0x00, 0x00, // mov qword ptr [rax], 1
+    0x31, 0xc0, // xor eax, eax
+    0xcd, 0x80, // int 0x80
+];
+
+#[test_case]
+fn bss_is_zeroed_and_writable() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running bss is zeroed and writable test", file!(), line!());
+
+    // PF_R | PF_W | PF_X
+    let image = executable(&READ_BSS, 0b111, 0x1000);
+
+    assert_eq!(
+        user::spawn("bss", &image, &["bss"], &[]).map(|child| child.join()),
+        Ok(Exit::Code(0))
+    );
+}
+
+#[test_case]
+fn malformed_headers_are_rejected() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running malformed headers are rejected test",
+        file!(),
+        line!(),
+    );
+
+    let valid = executable(&EXIT_42, READ_EXECUTE, 0);
+    assert!(load(&valid).is_ok());
+
+    let mut bad_magic = valid.clone();
+    bad_magic[1] = b'X';
+    assert_eq!(load(&bad_magic).err(), Some(ElfError::BadMagic));
+
+    let mut wrong_machine = valid.clone();
+    wrong_machine[18] = 3; // EM_386
+    assert_eq!(load(&wrong_machine).err(), Some(ElfError::WrongMachine));
+
+    let mut core_dump = valid.clone();
+    core_dump[16] = 4; // ET_CORE
+    assert_eq!(load(&core_dump).err(), Some(ElfError::NotExecutable));
+
+    let mut misaligned = valid.clone();
+    misaligned[64 + 48] = 3; // p_align
+    assert_eq!(load(&misaligned).err(), Some(ElfError::BadSegment));
+
+    let mut kernel_segment = valid.clone();
+    kernel_segment[64 + 16 + 7] = 0xFF; // p_vaddr
+    assert_eq!(load(&kernel_segment).err(), Some(ElfError::OutOfRange));
+
+    let mut outside_entry = valid.clone();
+    outside_entry[24 + 3] = 0x10; // e_entry
+    assert_eq!(load(&outside_entry).err(), Some(ElfError::OutOfRange));
+}
+
+#[test_case]
+fn truncated_files_are_rejected() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running truncated files are rejected test",
+        file!(),
+        line!(),
+    );
+
+    let valid = executable(&EXIT_42, READ_EXECUTE, 0);
+
+    for length in 0..valid.len() {
+        assert_eq!(load(&valid[..length]).err(), Some(ElfError::Truncated));
+    }
+}
+
+/// mov edi, 42; xor eax, eax; syscall
+const SYSCALL_EXIT_42: [u8; 9] = [0xbf, 0x2a, 0x00, 0x00, 0x00, 0x31, 0xc0, 0x0f, 0x05];
+
+#[test_case]
+fn syscall_instruction() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running syscall instruction test", file!(), line!());
+
+    assert_eq!(run("syscall", &SYSCALL_EXIT_42), Exit::Code(42));
+}
+
+/// Makes system call 99 then exits with what it returned
+const UNKNOWN_SYSCALL: [u8; 14] = [
+    0xb8, 0x63, 0x00, 0x00, 0x00, // mov eax, 99
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn unknown_syscall_fails() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running unknown syscall fails test", file!(), line!());
+
+    // -NoSuchSyscall
+    assert_eq!(run("unknown", &UNKNOWN_SYSCALL), Exit::Code(-1));
+}
+
+/// Sets rbx and r12, makes a system call then exits with their sum
+const PRESERVES_REGISTERS: [u8; 26] = [
+    0xbb, 0x07, 0x00, 0x00, 0x00, // mov ebx, 7
+    0x41, 0xbc, 0x05, 0x00, 0x00, 0x00, // mov r12d, 5
+    0xb8, 0x63, 0x00, 0x00, 0x00, // mov eax, 99
+    0x0f, 0x05, // syscall
+    0x4a, 0x8d, 0x3c, 0x23, // lea rdi, [rbx + r12]
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn syscall_preserves_registers() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running syscall preserves registers test",
+        file!(),
+        line!(),
+    );
+
+    assert_eq!(run("registers", &PRESERVES_REGISTERS), Exit::Code(12));
+}
+
+#[test_case]
+fn syscall_table_names() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running syscall table names test", file!(), line!());
+
+    assert_eq!(syscall::name(syscall::SYS_EXIT), Some("exit"));
+    assert_eq!(syscall::name(syscall::SYS_WRITE), Some("write"));
+    assert_eq!(syscall::name(syscall::SYS_FORK), Some("fork"));
+    assert_eq!(syscall::name(syscall::SYS_SPAWN), Some("spawn"));
+    assert_eq!(syscall::name(syscall::SYS_SIGRETURN), Some("sigreturn"));
+    assert_eq!(syscall::name(syscall::SYS_CLONE), Some("clone"));
+    assert_eq!(syscall::name(syscall::SYS_FUTEX), Some("futex"));
+    assert_eq!(syscall::name(99), None);
+}
+
+/// Writes 64K from a one page program then exits with what write returned
+const WRITE_PAST_IMAGE: [u8; 31] = [
+    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+    0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+    0x48, 0x8d, 0x35, 0x0e, 0x00, 0x00, 0x00, // lea rsi, [rip + 14]
+    0xba, 0x00, 0x00, 0x01, 0x00, // mov edx, 0x10000
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn write_rejects_partly_unmapped_buffer() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running write rejects partly unmapped buffer test",
+        file!(),
+        line!(),
+    );
+
+    // -BadAddress
+    assert_eq!(run("past_image", &WRITE_PAST_IMAGE), Exit::Code(-2));
+}
+
+#[test_case]
+fn user_access_from_kernel_thread() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running user access from kernel thread test",
+        file!(),
+        line!(),
+    );
+
+    // The kernel's own page table has nothing mapped for user mode
+    let mut buffer = [0u8; 8];
+    assert_eq!(
+        user::copy_from_user(&mut buffer, IMAGE_BASE),
+        Err(user::BadAddress(IMAGE_BASE))
+    );
+    assert_eq!(
+        user::copy_to_user(IMAGE_BASE, &buffer),
+        Err(user::BadAddress(IMAGE_BASE))
+    );
+
+    let kernel = HEAP_START as u64;
+    assert!(user::UserPtr::<u64>::new(kernel).read().is_err());
+    assert!(user::UserPtr::<u64>::new(kernel).write(1).is_err());
+    assert!(user::UserSlice::new(kernel, 16).read_to_vec().is_err());
+
+    // Lengths that wrap around the address space
+    assert!(user::UserSlice::new(IMAGE_BASE, usize::MAX)
+        .read_to_vec()
+        .is_err());
+    assert!(user::UserPtr::<u64>::new(u64::MAX - 3).read().is_err());
+}
+
+/// sgdt [rsp - 16]; xor edi, edi; xor eax, eax; syscall
+const STORE_GDT: [u8; 11] = [
+    0x0f, 0x01, 0x44, 0x24, 0xf0, 0x31, 0xff, 0x31, 0xc0, 0x0f, 0x05,
+];
+
+#[test_case]
+fn umip_stops_descriptor_table_reads() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running umip stops descriptor table reads test",
+        file!(),
+        line!(),
+    );
+
+    let expected = if lib::cpu::protections().umip {
+        Exit::Fault(UserFault::GeneralProtection)
+    } else {
+        Exit::Code(0)
+    };
+
+    assert_eq!(run("sgdt", &STORE_GDT), expected);
+}
+
+/// mov ax, 0x1b; mov gs, ax; jmp $
+const ZERO_GS_AND_SPIN: [u8; 8] = [0x66, 0xb8, 0x1b, 0x00, 0x8e, 0xe8, 0xeb, 0xfe];
+
+/// mov ax, 0x1b; mov gs, ax; mov edi, 42; xor eax, eax; syscall
+const ZERO_GS_AND_EXIT_42: [u8; 15] = [
+    0x66, 0xb8, 0x1b, 0x00, 0x8e, 0xe8, 0xbf, 0x2a, 0x00, 0x00, 0x00, 0x31, 0xc0, 0x0f, 0x05,
+];
+
+#[test_case]
+fn user_gs_does_not_reach_the_kernel() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running user gs does not reach the kernel test",
+        file!(),
+        line!(),
+    );
+
+    // Loading a GS selector sets the GS base to 0, the program is then preempted many times
+    let image = executable(&ZERO_GS_AND_SPIN, READ_EXECUTE, 0);
+    let child = user::spawn("zero_gs", &image, &["zero_gs"], &[]).unwrap();
+
+    thread::sleep(Duration::from_millis(50));
+
+    assert_eq!(lib::cpu::id(), 0);
+    assert_eq!(lib::cpu::current().index(), 0);
+
+    process::kill(child.pid()).unwrap();
+    assert_eq!(child.join(), Exit::Killed);
+
+    // The syscall entry gets the per-CPU data from the kernel GS base whatever GS holds
+    assert_eq!(run("zero_gs_exit", &ZERO_GS_AND_EXIT_42), Exit::Code(42));
+}
+
+//########################################
+// Processes
+//########################################
+
+/// Exits with the PID getpid returned
+const EXIT_PID: [u8; 14] = [
+    0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, 2
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn getpid_returns_process_id() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running getpid returns process id test", file!(), line!());
+
+    let image = executable(&EXIT_PID, READ_EXECUTE, 0);
+    let child = user::spawn("getpid", &image, &["getpid"], &[]).unwrap();
+    let pid = child.pid();
+
+    assert_eq!(child.join(), Exit::Code(pid.as_u64() as i64));
+}
+
+/// Waits for any child then exits with what waitpid returned
+const WAIT_ANY: [u8; 25] = [
+    0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3
+    0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff, // mov rdi, -1
+    0x31, 0xf6, // xor esi, esi
+    0x31, 0xd2, // xor edx, edx
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn waitpid_without_children_fails() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running waitpid without children fails test",
+        file!(),
+        line!(),
+    );
+
+    // -NoChildren
+    assert_eq!(run("wait", &WAIT_ANY), Exit::Code(-6));
+}
+
+/// Kills itself with signal 9 then exits with 0 if that returned
+const KILL_SELF: [u8; 28] = [
+    0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, 2
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0xbe, 0x09, 0x00, 0x00, 0x00, // mov esi, 9
+    0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, 4
+    0x0f, 0x05, // syscall
+    0x31, 0xff, // xor edi, edi
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn kill_syscall_ends_process() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running kill syscall ends process test", file!(), line!());
+
+    assert_eq!(run("kill_self", &KILL_SELF), Exit::Killed);
+}
+
+#[test_case]
+fn kill_running_process() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running kill running process test", file!(), line!());
+
+    // jmp $
+    let image = executable(&[0xeb, 0xfe], READ_EXECUTE, 0);
+    let child = user::spawn("spin", &image, &["spin"], &[]).unwrap();
+    let pid = child.pid();
+
+    thread::sleep(Duration::from_millis(30));
+
+    let running = process::list()
+        .into_iter()
+        .find(|process| process.pid == pid)
+        .expect("the process is not in the process table");
+    assert_eq!(running.state, ProcessState::Running);
+    assert_eq!(running.parent, None);
+
+    process::kill(pid).unwrap();
+    assert_eq!(child.join(), Exit::Killed);
+
+    // Nobody waits for processes the kernel started so they are removed when they end
+    assert!(process::get(pid).is_none());
+    assert_eq!(process::kill(pid), Err(ProcessError::NoSuchProcess));
+}
+
+#[test_case]
+fn wait_statuses() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running wait statuses test", file!(), line!());
+
+    assert_eq!(Exit::Code(3).wait_status(), 0x300);
+    assert_eq!(Exit::Killed.wait_status(), 9);
+    assert_eq!(
+        Exit::Fault(UserFault::PageFault(0)).wait_status(),
+        11 // SIGSEGV
+    );
+}
+
+//########################################
+// Fork, Exec And Spawn
+//########################################
+
+/// Forks, the child exits with 7. The parent waits for it then exits with the status,
+/// or -1 if waitpid returned the wrong PID
+const FORK_WAIT: [u8; 66] = [
+    0xb8, 0x05, 0x00, 0x00, 0x00, // mov eax, 5
+    0x0f, 0x05, // syscall
+    0x48, 0x85, 0xc0, // test rax, rax
+    0x74, 0x2d, // jz +45
+    0x48, 0x89, 0xc3, // mov rbx, rax
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x48, 0x8d, 0x74, 0x24, 0xf0, // lea rsi, [rsp - 16]
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3
+    0x0f, 0x05, // syscall
+    0x48, 0x39, 0xd8, // cmp rax, rbx
+    0x75, 0x09, // jne +9
+    0x48, 0x8b, 0x7c, 0x24, 0xf0, // mov rdi, qword ptr [rsp - 16]
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+    0x48, 0xc7, 0xc7, 0xff, 0xff, 0xff, 0xff, // mov rdi, -1
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+    0xbf, 0x07, 0x00, 0x00, 0x00, // mov edi, 7
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn fork_returns_child_pid() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running fork returns child pid test", file!(), line!());
+
+    assert_eq!(run("fork", &FORK_WAIT), Exit::Code(0x700));
+}
+
+/// Writes 1 to the stack then forks, the child writes 2 to the same place and exits with it.
+/// The parent waits then exits with what it reads plus the child's status
+const COPY_ON_WRITE: [u8; 70] = [
+    0x48, 0xc7, 0x44, 0x24, 0xf8, 0x01, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 8], 1
+    0xb8, 0x05, 0x00, 0x00, 0x00, // mov eax, 5
+    0x0f, 0x05, // syscall
+    0x48, 0x85, 0xc0, // test rax, rax
+    0x74, 0x1f, // jz +31
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x48, 0x8d, 0x74, 0x24, 0xf0, // lea rsi, [rsp - 16]
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3
+    0x0f, 0x05, // syscall
+    0x48, 0x8b, 0x7c, 0x24, 0xf8, // mov rdi, qword ptr [rsp - 8]
+    0x48, 0x03, 0x7c, 0x24, 0xf0, // add rdi, qword ptr [rsp - 16]
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+    0x48, 0xc7, 0x44, 0x24, 0xf8, 0x02, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 8], 2
+    0x48, 0x8b, 0x7c, 0x24, 0xf8, // mov rdi, qword ptr [rsp - 8]
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn fork_copies_on_write() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running fork copies on write test", file!(), line!());
+
+    // The parent still reads 1, the child exited with 2
+    assert_eq!(run("cow", &COPY_ON_WRITE), Exit::Code(0x201));
+}
+
+/// Puts `/argc` in the initrd, the test kernel boots without one
+fn install_programs() {
+    let _ = INITRDDATA.try_init_once(|| {
+        let files = [
+            ("argc", executable(&EXIT_ARGC, READ_EXECUTE, 0)),
+            ("libgreet.so", LIBGREET.to_vec()),
+            ("ld-interstellar.so.2", pie_executable(&EXIT_42)),
+        ];
+
+        let mut contents = Vec::new();
+        let mut entries = Vec::new();
+        for (name, image) in files {
+            entries.push(InitrdFileEntry {
+                name: name.to_string(),
+                size: image.len(),
+                offset: contents.len(),
+            });
+            contents.extend_from_slice(&image);
+        }
+
+        let mut data = b"Data:".to_vec();
+        data.extend_from_slice(&contents);
+        data.extend_from_slice(b"Data End:");
+
+        Spinlock::new(InitrdData::new(
+            InitrdMetadata {
+                num_files: entries.len(),
+                total_files_size: contents.len(),
+            },
+            entries,
+            data.leak(),
+        ))
+    });
+}
+
+/// Execs a path that is not in the initrd then exits with what exec returned
+const EXEC_MISSING: [u8; 34] = [
+    0x48, 0x8d, 0x3d, 0x12, 0x00, 0x00, 0x00, // lea rdi, [rip + 18]
+    0x31, 0xf6, // xor esi, esi
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x06, 0x00, 0x00, 0x00, // mov eax, 6
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+    b'/', b'm', b'i', b's', b's', b'i', b'n', b'g', 0, // "/missing"
+];
+
+#[test_case]
+fn exec_missing_file_fails() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running exec missing file fails test", file!(), line!());
+
+    // -NoSuchFile
+    assert_eq!(run("exec_missing", &EXEC_MISSING), Exit::Code(-7));
+}
+
+/// Execs `/argc` with two arguments, exits with what exec returned if it fails
+const EXEC_ARGC: [u8; 74] = [
+    0x48, 0x8d, 0x05, 0x3c, 0x00, 0x00, 0x00, // lea rax, [rip + 60]
+    0x48, 0x89, 0x44, 0x24, 0xe0, // mov qword ptr [rsp - 32], rax
+    0x48, 0x8d, 0x05, 0x35, 0x00, 0x00, 0x00, // lea rax, [rip + 53]
+    0x48, 0x89, 0x44, 0x24, 0xe8, // mov qword ptr [rsp - 24], rax
+    0x48, 0xc7, 0x44, 0x24, 0xf0, 0x00, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 16], 0
+    0x48, 0x8d, 0x3d, 0x15, 0x00, 0x00, 0x00, // lea rdi, [rip + 21]
+    0x48, 0x8d, 0x74, 0x24, 0xe0, // lea rsi, [rsp - 32]
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x06, 0x00, 0x00, 0x00, // mov eax, 6
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+    b'/', b'a', b'r', b'g', b'c', 0, // "/argc"
+    b'a', b'r', b'g', b'c', 0, // "argc"
+    b'x', 0, // "x"
+];
+
+#[test_case]
+fn exec_replaces_program() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running exec replaces program test", file!(), line!());
+
+    install_programs();
+
+    let image = executable(&EXEC_ARGC, READ_EXECUTE, 0);
+    let child = user::spawn("exec", &image, &["exec"], &[]).unwrap();
+    let pid = child.pid();
+
+    assert_eq!(child.join(), Exit::Code(2));
+    assert!(process::get(pid).is_none());
+}
+
+/// Spawns `/argc` with two arguments and waits for it,
+/// then exits with the status or the error from spawn
+const SPAWN_ARGC: [u8; 101] = [
+    0x48, 0x8d, 0x05, 0x57, 0x00, 0x00, 0x00, // lea rax, [rip + 87]
+    0x48, 0x89, 0x44, 0x24, 0xe0, // mov qword ptr [rsp - 32], rax
+    0x48, 0x8d, 0x05, 0x50, 0x00, 0x00, 0x00, // lea rax, [rip + 80]
+    0x48, 0x89, 0x44, 0x24, 0xe8, // mov qword ptr [rsp - 24], rax
+    0x48, 0xc7, 0x44, 0x24, 0xf0, 0x00, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 16], 0
+    0x48, 0x8d, 0x3d, 0x30, 0x00, 0x00, 0x00, // lea rdi, [rip + 48]
+    0x48, 0x8d, 0x74, 0x24, 0xe0, // lea rsi, [rsp - 32]
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x07, 0x00, 0x00, 0x00, // mov eax, 7
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0x48, 0x85, 0xc0, // test rax, rax
+    0x78, 0x16, // js +22
+    0x48, 0x89, 0xc3, // mov rbx, rax
+    0x48, 0x8d, 0x74, 0x24, 0xf8, // lea rsi, [rsp - 8]
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x03, 0x00, 0x00, 0x00, // mov eax, 3
+    0x0f, 0x05, // syscall
+    0x48, 0x8b, 0x7c, 0x24, 0xf8, // mov rdi, qword ptr [rsp - 8]
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+    b'/', b'a', b'r', b'g', b'c', 0, // "/argc"
+    b'a', b'r', b'g', b'c', 0, // "argc"
+    b'x', 0, // "x"
+];
+
+#[test_case]
+fn spawn_syscall_starts_child() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running spawn syscall starts child test", file!(), line!());
+
+    install_programs();
+
+    assert_eq!(run("spawn", &SPAWN_ARGC), Exit::Code(0x200));
+}
+
+#[test_case]
+fn programs_are_found_by_path() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running programs are found by path test", file!(), line!());
+
+    install_programs();
+
+    assert!(user::find_program("/argc").is_some());
+    assert!(user::find_program("argc").is_some());
+    assert!(user::find_program("/").is_none());
+    assert!(user::find_program("/missing").is_none());
+    assert_eq!(user::program_name("/bin/argc"), "argc");
+}
+
+//########################################
+// Signals
+//########################################
+
+/// Handles SIGSEGV then writes to an unmapped page, the handler exits with the fault address from the SigInfo
+const SEGV_HANDLER: [u8; 81] = [
+    0x48, 0x8d, 0x05, 0x42, 0x00, 0x00, 0x00, // lea rax, [rip + 66]
+    0x48, 0x89, 0x44, 0x24, 0xe0, // mov qword ptr [rsp - 32], rax
+    0x48, 0xc7, 0x44, 0x24, 0xe8, 0x00, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 24], 0
+    0x48, 0xc7, 0x44, 0x24, 0xf0, 0x00, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 16], 0
+    0x48, 0xc7, 0x44, 0x24, 0xf8, 0x00, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 8], 0
+    0xbf, 0x0b, 0x00, 0x00, 0x00, // mov edi, 11
+    0x48, 0x8d, 0x74, 0x24, 0xe0, // lea rsi, [rsp - 32]
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x08, 0x00, 0x00, 0x00, // mov eax, 8
+    0x0f, 0x05, // syscall
+    0x48, 0xb8, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0x00, // mov rax, 0x8000100000
+    0xc6, 0x00, 0x01, // mov byte ptr [rax], 1
+    0x0f, 0x0b, // ud2
+    0x48, 0x8b, 0x7e, 0x10, // mov rdi, qword ptr [rsi + 16]
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn fault_runs_signal_handler() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running fault runs signal handler test", file!(), line!());
+
+    assert_eq!(
+        run("segv_handler", &SEGV_HANDLER),
+        Exit::Code(0x80_0010_0000)
+    );
+}
+
+/// Handles SIGUSR1 and sends it to itself with 0x1234 in rbx. The handler saves the signal number below the
+/// interrupted stack pointer and clobbers rbx and rax, then the program exits with rbx plus the saved number plus
+/// what kill returned
+const USR1_HANDLER: [u8; 123] = [
+    0xbb, 0x34, 0x12, 0x00, 0x00, // mov ebx, 0x1234
+    0x48, 0xc7, 0x44, 0x24, 0xc0, 0x00, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 64], 0
+    0x48, 0x8d, 0x05, 0x58, 0x00, 0x00, 0x00, // lea rax, [rip + 88]
+    0x48, 0x89, 0x44, 0x24, 0xe0, // mov qword ptr [rsp - 32], rax
+    0x48, 0xc7, 0x44, 0x24, 0xe8, 0x00, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 24], 0
+    0x48, 0xc7, 0x44, 0x24, 0xf0, 0x00, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 16], 0
+    0x48, 0xc7, 0x44, 0x24, 0xf8, 0x00, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 8], 0
+    0xbf, 0x0a, 0x00, 0x00, 0x00, // mov edi, 10
+    0x48, 0x8d, 0x74, 0x24, 0xe0, // lea rsi, [rsp - 32]
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x08, 0x00, 0x00, 0x00, // mov eax, 8
+    0x0f, 0x05, // syscall
+    0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, 2
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0xbe, 0x0a, 0x00, 0x00, 0x00, // mov esi, 10
+    0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, 4
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xdf, // mov rdi, rbx
+    0x48, 0x03, 0x7c, 0x24, 0xc0, // add rdi, qword ptr [rsp - 64]
+    0x48, 0x01, 0xc7, // add rdi, rax
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+    0x48, 0x8b, 0x82, 0x90, 0x00, 0x00, 0x00, // mov rax, qword ptr [rdx + 144]
+    0x48, 0x89, 0x78, 0xc0, // mov qword ptr [rax - 64], rdi
+    0x31, 0xdb, // xor ebx, ebx
+    0xc3, // ret
+];
+
+#[test_case]
+fn sigreturn_restores_registers() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running sigreturn restores registers test",
+        file!(),
+        line!(),
+    );
+
+    // SIGUSR1 is 10 and kill returns 0
+    assert_eq!(run("usr1_handler", &USR1_HANDLER), Exit::Code(0x1234 + 10));
+}
+
+/// Sends itself SIGUSR1 then exits with 0
+const RAISE_USR1: [u8; 28] = [
+    0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, 2
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0xbe, 0x0a, 0x00, 0x00, 0x00, // mov esi, 10
+    0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, 4
+    0x0f, 0x05, // syscall
+    0x31, 0xff, // xor edi, edi
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn default_action_ends_process() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running default action ends process test",
+        file!(),
+        line!(),
+    );
+
+    let exit = run("raise", &RAISE_USR1);
+    assert_eq!(exit, Exit::Signal(Signal::SIGUSR1));
+    assert_eq!(exit.wait_status(), 10);
+}
+
+/// Blocks SIGUSR1, sends it to itself, then exits with 0
+const BLOCKED_USR1: [u8; 56] = [
+    0x48, 0xc7, 0x44, 0x24, 0xf8, 0x00, 0x02, 0x00, 0x00, // mov qword ptr [rsp - 8], 1 << 9
+    0xbf, 0x00, 0x00, 0x00, 0x00, // mov edi, 0
+    0x48, 0x8d, 0x74, 0x24, 0xf8, // lea rsi, [rsp - 8]
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x09, 0x00, 0x00, 0x00, // mov eax, 9
+    0x0f, 0x05, // syscall
+    0xb8, 0x02, 0x00, 0x00, 0x00, // mov eax, 2
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc7, // mov rdi, rax
+    0xbe, 0x0a, 0x00, 0x00, 0x00, // mov esi, 10
+    0xb8, 0x04, 0x00, 0x00, 0x00, // mov eax, 4
+    0x0f, 0x05, // syscall
+    0x31, 0xff, // xor edi, edi
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn blocked_signal_stays_pending() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running blocked signal stays pending test",
+        file!(),
+        line!(),
+    );
+
+    assert_eq!(run("blocked", &BLOCKED_USR1), Exit::Code(0));
+}
+
+#[test_case]
+fn stop_and_continue() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running stop and continue test", file!(), line!());
+
+    // jmp $
+    let image = executable(&[0xeb, 0xfe], READ_EXECUTE, 0);
+    let child = user::spawn("spin", &image, &["spin"], &[]).unwrap();
+    let pid = child.pid();
+
+    let state = || {
+        process::list()
+            .into_iter()
+            .find(|process| process.pid == pid)
+            .map(|process| process.state)
+    };
+
+    // The program never makes a system call so the signal arrives with a timer interrupt
+    signal::send(pid, Signal::SIGSTOP).unwrap();
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(state(), Some(ProcessState::Stopped));
+
+    signal::send(pid, Signal::SIGCONT).unwrap();
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(state(), Some(ProcessState::Running));
+
+    signal::send(pid, Signal::SIGTERM).unwrap();
+    assert_eq!(child.join(), Exit::Signal(Signal::SIGTERM));
+}
+
+#[test_case]
+fn signal_defaults() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running signal defaults test", file!(), line!());
+
+    assert_eq!(Signal::new(0), None);
+    assert_eq!(Signal::new(65), None);
+    assert_eq!(Signal::new(11), Some(Signal::SIGSEGV));
+    assert_eq!(Signal::SIGUSR1.bit(), 1 << 9);
+
+    assert_eq!(Signal::SIGSEGV.default_action(), DefaultAction::Core);
+    assert_eq!(Signal::SIGCHLD.default_action(), DefaultAction::Ignore);
+    assert_eq!(Signal::SIGTSTP.default_action(), DefaultAction::Stop);
+    assert_eq!(Signal::SIGCONT.default_action(), DefaultAction::Continue);
+    assert_eq!(Signal::SIGTERM.default_action(), DefaultAction::Terminate);
+
+    assert!(!Signal::SIGKILL.can_be_caught());
+    assert!(!Signal::SIGSTOP.can_be_caught());
+    assert_eq!(UserFault::DivideError.signal(), Signal::SIGFPE);
+    assert_eq!(UserFault::InvalidOpcode.signal(), Signal::SIGILL);
+}
+
+//########################################
+// Threads And Futexes
+//########################################
+
+/// Like [executable] with a PT_TLS header, the template is `tdata` followed by `tbss` zeroed bytes
+///
+/// The template is put in front of the code, both in the one loadable segment
+fn tls_executable(code: &[u8], tdata: &[u8], tbss: u64, align: u64) -> Vec<u8> {
+    let note_offset = 64 + 3 * 56;
+    let tdata_offset = note_offset + INTERSTELLAR_NOTE.len() as u64;
+    let code_offset = tdata_offset + tdata.len() as u64;
+    let file_size = code_offset + code.len() as u64;
+    let mut image = Vec::new();
+
+    // ELF header
+    image.extend_from_slice(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+    image.extend_from_slice(&[0; 8]);
+    image.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    image.extend_from_slice(&62u16.to_le_bytes()); // EM_X86_64
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&(IMAGE_BASE + code_offset).to_le_bytes());
+    image.extend_from_slice(&64u64.to_le_bytes()); // Program headers
+    image.extend_from_slice(&0u64.to_le_bytes()); // Section headers
+    image.extend_from_slice(&0u32.to_le_bytes());
+    image.extend_from_slice(&64u16.to_le_bytes());
+    image.extend_from_slice(&56u16.to_le_bytes());
+    image.extend_from_slice(&3u16.to_le_bytes());
+    image.extend_from_slice(&[0; 6]);
+
+    // PT_LOAD covering the whole file
+    image.extend_from_slice(&1u32.to_le_bytes());
+    image.extend_from_slice(&READ_EXECUTE.to_le_bytes());
+    image.extend_from_slice(&0u64.to_le_bytes());
+    image.extend_from_slice(&IMAGE_BASE.to_le_bytes());
+    image.extend_from_slice(&IMAGE_BASE.to_le_bytes());
+    image.extend_from_slice(&file_size.to_le_bytes());
+    image.extend_from_slice(&file_size.to_le_bytes());
+    image.extend_from_slice(&0x1000u64.to_le_bytes());
+
+    // PT_TLS
+    image.extend_from_slice(&7u32.to_le_bytes());
+    image.extend_from_slice(&4u32.to_le_bytes());
+    image.extend_from_slice(&tdata_offset.to_le_bytes());
+    image.extend_from_slice(&(IMAGE_BASE + tdata_offset).to_le_bytes());
+    image.extend_from_slice(&(IMAGE_BASE + tdata_offset).to_le_bytes());
+    image.extend_from_slice(&(tdata.len() as u64).to_le_bytes());
+    image.extend_from_slice(&(tdata.len() as u64 + tbss).to_le_bytes());
+    image.extend_from_slice(&align.to_le_bytes());
+
+    note_header(&mut image, note_offset);
+
+    image.extend_from_slice(&INTERSTELLAR_NOTE);
+    image.extend_from_slice(tdata);
+    image.extend_from_slice(code);
+    image
+}
+
+/// A TLS variable that starts at 42, 16 bytes below the thread pointer with [tls_executable]
+const TDATA: [u8; 8] = [42, 0, 0, 0, 0, 0, 0, 0];
+
+#[test_case]
+fn tls_template_is_loaded() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running tls template is loaded test", file!(), line!());
+
+    let tls = load(&tls_executable(&EXIT_42, &TDATA, 8, 8))
+        .unwrap()
+        .tls
+        .unwrap();
+    assert_eq!(tls.template.start_addr, IMAGE_BASE + 64 + 2 * 56);
+    assert_eq!(tls.template.file_size, 8);
+    assert_eq!(tls.template.mem_size, 16);
+    assert_eq!(tls.offset(), 16);
+
+    assert_eq!(
+        load(&executable(&EXIT_42, READ_EXECUTE, 0)).unwrap().tls,
+        None
+    );
+    assert_eq!(
+        load(&tls_executable(&EXIT_42, &TDATA, 8, 3)),
+        Err(ElfError::BadSegment)
+    );
+}
+
+/// Sets its TLS variable to 5 and starts a thread that stores its own copy of the variable for the first thread
+/// and exits. The first thread waits on the futex the kernel clears when the thread ends, then exits with the
+/// stored value plus its own variable
+const TLS_THREAD: [u8; 108] = [
+    0x64, 0xc7, 0x04, 0x25, 0xf0, 0xff, 0xff, 0xff, 0x05, 0x00, 0x00,
+    0x00, // mov dword ptr fs:[-16], 5
+    0x48, 0x8d, 0x5c, 0x24, 0xc0, // lea rbx, [rsp - 64]
+    0xc7, 0x43, 0x04, 0x01, 0x00, 0x00, 0x00, // mov dword ptr [rbx + 4], 1
+    0x48, 0x8d, 0xbc, 0x24, 0x00, 0xf8, 0xff, 0xff, // lea rdi, [rsp - 2048]
+    0x31, 0xf6, // xor esi, esi
+    0x48, 0x8d, 0x53, 0x04, // lea rdx, [rbx + 4]
+    0xb8, 0x0b, 0x00, 0x00, 0x00, // mov eax, 11
+    0x0f, 0x05, // syscall
+    0x48, 0x85, 0xc0, // test rax, rax
+    0x75, 0x13, // jnz +19
+    0x64, 0x8b, 0x04, 0x25, 0xf0, 0xff, 0xff, 0xff, // mov eax, dword ptr fs:[-16]
+    0x89, 0x03, // mov dword ptr [rbx], eax
+    0x31, 0xff, // xor edi, edi
+    0xb8, 0x0d, 0x00, 0x00, 0x00, // mov eax, 13
+    0x0f, 0x05, // syscall
+    0x8b, 0x53, 0x04, // mov edx, dword ptr [rbx + 4]
+    0x85, 0xd2, // test edx, edx
+    0x74, 0x12, // jz +18
+    0x48, 0x8d, 0x7b, 0x04, // lea rdi, [rbx + 4]
+    0x31, 0xf6, // xor esi, esi
+    0x45, 0x31, 0xd2, // xor r10d, r10d
+    0xb8, 0x0e, 0x00, 0x00, 0x00, // mov eax, 14
+    0x0f, 0x05, // syscall
+    0xeb, 0xe7, // jmp -25
+    0x8b, 0x3b, // mov edi, dword ptr [rbx]
+    0x64, 0x03, 0x3c, 0x25, 0xf0, 0xff, 0xff, 0xff, // add edi, dword ptr fs:[-16]
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn threads_get_their_own_tls() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running threads get their own tls test", file!(), line!());
+
+    let image = tls_executable(&TLS_THREAD, &TDATA, 8, 8);
+    let exit = user::spawn("tls_thread", &image, &["tls_thread"], &[])
+        .unwrap()
+        .join();
+
+    // The new thread sees the template's 42, the first thread its own 5
+    assert_eq!(exit, Exit::Code(47));
+}
+
+/// Starts a thread that exits the process with 3 while the first thread waits on a futex that is never woken
+const THREAD_EXIT_GROUP: [u8; 70] = [
+    0x48, 0x8d, 0x5c, 0x24, 0xc0, // lea rbx, [rsp - 64]
+    0xc7, 0x03, 0x00, 0x00, 0x00, 0x00, // mov dword ptr [rbx], 0
+    0x48, 0x8d, 0xbc, 0x24, 0x00, 0xf8, 0xff, 0xff, // lea rdi, [rsp - 2048]
+    0x31, 0xf6, // xor esi, esi
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x0b, 0x00, 0x00, 0x00, // mov eax, 11
+    0x0f, 0x05, // syscall
+    0x48, 0x85, 0xc0, // test rax, rax
+    0x75, 0x09, // jnz +9
+    0xbf, 0x03, 0x00, 0x00, 0x00, // mov edi, 3
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xdf, // mov rdi, rbx
+    0x31, 0xf6, // xor esi, esi
+    0x31, 0xd2, // xor edx, edx
+    0x45, 0x31, 0xd2, // xor r10d, r10d
+    0xb8, 0x0e, 0x00, 0x00, 0x00, // mov eax, 14
+    0x0f, 0x05, // syscall
+    0xbf, 0x63, 0x00, 0x00, 0x00, // mov edi, 99
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn exit_ends_every_thread() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running exit ends every thread test", file!(), line!());
+
+    assert_eq!(run("exit_group", &THREAD_EXIT_GROUP), Exit::Code(3));
+}
+
+/// Waits on a futex holding 1 expecting 2, then expecting 1 with a 1ms timeout, and exits with the sum of the results
+const FUTEX_ERRORS: [u8; 66] = [
+    0xc7, 0x44, 0x24, 0xf8, 0x01, 0x00, 0x00, 0x00, // mov dword ptr [rsp - 8], 1
+    0x48, 0x8d, 0x7c, 0x24, 0xf8, // lea rdi, [rsp - 8]
+    0x31, 0xf6, // xor esi, esi
+    0xba, 0x02, 0x00, 0x00, 0x00, // mov edx, 2
+    0x45, 0x31, 0xd2, // xor r10d, r10d
+    0xb8, 0x0e, 0x00, 0x00, 0x00, // mov eax, 14
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc3, // mov rbx, rax
+    0x48, 0x8d, 0x7c, 0x24, 0xf8, // lea rdi, [rsp - 8]
+    0x31, 0xf6, // xor esi, esi
+    0xba, 0x01, 0x00, 0x00, 0x00, // mov edx, 1
+    0x41, 0xba, 0x40, 0x42, 0x0f, 0x00, // mov r10d, 1000000
+    0xb8, 0x0e, 0x00, 0x00, 0x00, // mov eax, 14
+    0x0f, 0x05, // syscall
+    0x48, 0x8d, 0x3c, 0x03, // lea rdi, [rbx + rax]
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn futex_wait_checks_value_and_timeout() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running futex wait checks value and timeout test",
+        file!(),
+        line!(),
+    );
+
+    // WouldBlock is 11 and TimedOut is 12
+    assert_eq!(run("futex_errors", &FUTEX_ERRORS), Exit::Code(-23));
+}
+
+//########################################
+// Program Memory
+//########################################
+
+/// Grows the heap by two pages with brk and maps a page with mmap, writes 5 and 7 to them and exits with the sum
+const HEAP_AND_MMAP: [u8; 71] = [
+    0x31, 0xff, // xor edi, edi
+    0xb8, 0x10, 0x00, 0x00, 0x00, // mov eax, 16
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc3, // mov rbx, rax
+    0x48, 0x8d, 0xb8, 0x00, 0x20, 0x00, 0x00, // lea rdi, [rax + 8192]
+    0xb8, 0x10, 0x00, 0x00, 0x00, // mov eax, 16
+    0x0f, 0x05, // syscall
+    0xc7, 0x83, 0xfc, 0x1f, 0x00, 0x00, 0x05, 0x00, 0x00,
+    0x00, // mov dword ptr [rbx + 8188], 5
+    0xbf, 0x00, 0x10, 0x00, 0x00, // mov edi, 4096
+    0xbe, 0x02, 0x00, 0x00, 0x00, // mov esi, 2
+    0xb8, 0x11, 0x00, 0x00, 0x00, // mov eax, 17
+    0x0f, 0x05, // syscall
+    0xc7, 0x00, 0x07, 0x00, 0x00, 0x00, // mov dword ptr [rax], 7
+    0x8b, 0xbb, 0xfc, 0x1f, 0x00, 0x00, // mov edi, dword ptr [rbx + 8188]
+    0x03, 0x38, // add edi, dword ptr [rax]
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+];
+
+#[test_case]
+fn brk_and_mmap_map_memory() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running brk and mmap map memory test", file!(), line!());
+
+    assert_eq!(syscall::name(syscall::SYS_BRK), Some("brk"));
+    assert_eq!(syscall::name(syscall::SYS_MMAP), Some("mmap"));
+    assert_eq!(run("heap", &HEAP_AND_MMAP), Exit::Code(12));
+}
+
+#[test_case]
+fn heap_starts_after_the_image() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running heap starts after the image test",
+        file!(),
+        line!(),
+    );
+
+    let image = executable(&EXIT_42, READ_EXECUTE, 0x1800);
+    let mut space = AddressSpace::new().unwrap();
+    elf::load(&mut space, &image, &["heap"], &[]).unwrap();
+
+    let start = space.program_break();
+    assert_eq!(start.as_u64(), IMAGE_BASE + 0x2000);
+
+    assert_eq!(
+        space.set_break(start - 1u64),
+        Err(user::MapError::OutOfRange)
+    );
+
+    space.set_break(start + 0x1800u64).unwrap();
+    assert!(space.flags(start + 0x1000u64).is_some());
+
+    // Shrinking unmaps the pages past the new end
+    space.set_break(start + 0x10u64).unwrap();
+    assert!(space.flags(start).is_some());
+    assert!(space.flags(start + 0x1000u64).is_none());
+    assert_eq!(space.program_break(), start + 0x10u64);
+}
+
+//########################################
+// Linux System Calls
+//########################################
+
+/// Like [executable] but position independent, linked at 0
+fn pie_executable(code: &[u8]) -> Vec<u8> {
+    let mut image = executable(code, READ_EXECUTE, 0);
+    image[16] = 3; // ET_DYN
+    image[24..32].copy_from_slice(&CODE_OFFSET.to_le_bytes());
+    image[64 + 16..64 + 32].fill(0); // p_vaddr and p_paddr
+    image
+}
+
+/// Like [executable] without [INTERSTELLAR_NOTE]'s header, so it runs as a Linux program
+fn linux_executable(code: &[u8]) -> Vec<u8> {
+    let mut image = executable(code, READ_EXECUTE, 0);
+    image[56..58].copy_from_slice(&1u16.to_le_bytes()); // e_phnum
+    image
+}
+
+#[test_case]
+fn linux_programs_are_detected() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running linux programs are detected test",
+        file!(),
+        line!(),
+    );
+
+    let native = executable(&EXIT_42, READ_EXECUTE, 0);
+    assert_eq!(load(&native).unwrap().abi, Abi::Interstellar);
+
+    assert_eq!(load(&linux_executable(&EXIT_42)).unwrap().abi, Abi::Linux);
+
+    // The OS ABI byte and notes owned by others don't matter
+    let mut linux_abi = native.clone();
+    linux_abi[7] = 3; // ELFOSABI_LINUX
+    assert_eq!(load(&linux_abi).unwrap().abi, Abi::Interstellar);
+
+    let mut gnu_note = native.clone();
+    let note = (CODE_OFFSET - INTERSTELLAR_NOTE.len() as u64) as usize;
+    gnu_note[note + 12..note + 24].copy_from_slice(b"GNU\0\0\0\0\0\0\0\0\0");
+    assert_eq!(load(&gnu_note).unwrap().abi, Abi::Linux);
+
+    let mut future_version = native.clone();
+    future_version[note + 28] = 2;
+    assert_eq!(load(&future_version).unwrap().abi, Abi::Linux);
+
+    // Without the note a program for another system is not run as a Linux program
+    let mut freebsd = linux_executable(&EXIT_42);
+    freebsd[7] = 9; // ELFOSABI_FREEBSD
+    assert_eq!(load(&freebsd).err(), Some(ElfError::WrongOs));
+
+    let mut freebsd_abi = native;
+    freebsd_abi[7] = 9;
+    assert_eq!(load(&freebsd_abi).unwrap().abi, Abi::Interstellar);
+}
+
+#[test_case]
+fn static_pie_is_loaded_at_image_base() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running static pie is loaded at image base test",
+        file!(),
+        line!(),
+    );
+
+    let image = pie_executable(&EXIT_42);
+    let program = load(&image).unwrap();
+    assert_eq!(program.entry.as_u64(), IMAGE_BASE + CODE_OFFSET);
+
+    assert_eq!(run_image("pie", &image), Exit::Code(42));
+}
+
+/// Sets and reads back the FS base with arch_prctl, gets the window size, reads 4 bytes of `/argc`, writes "linux\n"
+/// with writev and makes an unknown system call, then exits with the sum of the results and the second byte read
+const LINUX_SYSCALLS: [u8; 223] = [
+    0xbf, 0x02, 0x10, 0x00, 0x00, // mov edi, 0x1002
+    0x48, 0x89, 0xe6, // mov rsi, rsp
+    0xb8, 0x9e, 0x00, 0x00, 0x00, // mov eax, 158
+    0x0f, 0x05, // syscall
+    0xbf, 0x03, 0x10, 0x00, 0x00, // mov edi, 0x1003
+    0x48, 0x8d, 0x74, 0x24, 0xf8, // lea rsi, [rsp - 8]
+    0xb8, 0x9e, 0x00, 0x00, 0x00, // mov eax, 158
+    0x0f, 0x05, // syscall
+    0x48, 0x39, 0x64, 0x24, 0xf8, // cmp qword ptr [rsp - 8], rsp
+    0x0f, 0x85, 0x9c, 0x00, 0x00, 0x00, // jnz +156
+    0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+    0xbe, 0x13, 0x54, 0x00, 0x00, // mov esi, 0x5413
+    0x48, 0x8d, 0x54, 0x24, 0xf0, // lea rdx, [rsp - 16]
+    0xb8, 0x10, 0x00, 0x00, 0x00, // mov eax, 16
+    0x0f, 0x05, // syscall
+    0x48, 0x89, 0xc3, // mov rbx, rax
+    0x48, 0xc7, 0xc7, 0x9c, 0xff, 0xff, 0xff, // mov rdi, -100
+    0x48, 0x8d, 0x35, 0x81, 0x00, 0x00, 0x00, // lea rsi, [rip + 129]
+    0x31, 0xd2, // xor edx, edx
+    0xb8, 0x01, 0x01, 0x00, 0x00, // mov eax, 257
+    0x0f, 0x05, // syscall
+    0x89, 0xc7, // mov edi, eax
+    0x48, 0x8d, 0x74, 0x24, 0xe0, // lea rsi, [rsp - 32]
+    0xba, 0x04, 0x00, 0x00, 0x00, // mov edx, 4
+    0x31, 0xc0, // xor eax, eax
+    0x0f, 0x05, // syscall
+    0x48, 0x01, 0xc3, // add rbx, rax
+    0x48, 0x8d, 0x05, 0x63, 0x00, 0x00, 0x00, // lea rax, [rip + 99]
+    0x48, 0x89, 0x44, 0x24, 0xc0, // mov qword ptr [rsp - 64], rax
+    0x48, 0xc7, 0x44, 0x24, 0xc8, 0x03, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 56], 3
+    0x48, 0x83, 0xc0, 0x03, // add rax, 3
+    0x48, 0x89, 0x44, 0x24, 0xd0, // mov qword ptr [rsp - 48], rax
+    0x48, 0xc7, 0x44, 0x24, 0xd8, 0x03, 0x00, 0x00, 0x00, // mov qword ptr [rsp - 40], 3
+    0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+    0x48, 0x8d, 0x74, 0x24, 0xc0, // lea rsi, [rsp - 64]
+    0xba, 0x02, 0x00, 0x00, 0x00, // mov edx, 2
+    0xb8, 0x14, 0x00, 0x00, 0x00, // mov eax, 20
+    0x0f, 0x05, // syscall
+    0x48, 0x01, 0xc3, // add rbx, rax
+    0xb8, 0xe8, 0x03, 0x00, 0x00, // mov eax, 1000
+    0x0f, 0x05, // syscall
+    0x48, 0x01, 0xc3, // add rbx, rax
+    0x0f, 0xb6, 0x7c, 0x24, 0xe1, // movzx edi, byte ptr [rsp - 31]
+    0x48, 0x01, 0xdf, // add rdi, rbx
+    0xb8, 0xe7, 0x00, 0x00, 0x00, // mov eax, 231
+    0x0f, 0x05, // syscall
+    0xbf, 0x01, 0x00, 0x00, 0x00, // mov edi, 1
+    0xb8, 0xe7, 0x00, 0x00, 0x00, // mov eax, 231
+    0x0f, 0x05, // syscall
+    b'a', b'r', b'g', b'c', 0, // "argc"
+    b'l', b'i', b'n', b'u', b'x', b'\n', 0, // "linux\n"
+];
+
+#[test_case]
+fn linux_syscalls() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running linux syscalls test", file!(), line!());
+
+    assert_eq!(
+        syscall::linux::name(syscall::linux::SYS_WRITEV),
+        Some("writev")
+    );
+    assert_eq!(syscall::linux::name(1000), None);
+
+    install_programs();
+
+    let image = linux_executable(&LINUX_SYSCALLS);
+
+    // ioctl 0, read 4, writev 6, ENOSYS -38 and 'E' 69
+    assert_eq!(run_image("linux", &image), Exit::Code(41));
+}
+
+#[test_case]
+fn musl_hello_runs() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running musl hello runs test", file!(), line!());
+
+    // user-programs/musl_hello.c, musl's start up code and printf run on the Linux system calls
+    let image = include_bytes!(concat!(env!("OUT_DIR"), "/musl_hello"));
+    assert_eq!(load(image).unwrap().abi, Abi::Linux);
+
+    syscall::capture_console();
+    let exit = run_image("musl_hello", image);
+    let output = syscall::take_console_capture();
+
+    assert_eq!(exit, Exit::Code(0));
+    assert_eq!(output, b"Hello from musl, musl_hello!\n");
+}
+
+//########################################
+// Dynamic Linking
+//########################################
+
+/// A shared library from user-programs/libgreet.s, [install_programs] puts it in the initrd
+const LIBGREET: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/libgreet.so"));
+
+/// A program from user-programs/dynamic_hello.s that needs libgreet.so and names `/lib/ld-interstellar.so.1`
+/// as its dynamic linker
+const DYNAMIC_HELLO: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/dynamic_hello"));
+
+/// Changes a string in `image` to another of the same length
+fn replace_string(image: &[u8], from: &[u8], to: &[u8]) -> Vec<u8> {
+    let mut image = image.to_vec();
+    let start = image
+        .windows(from.len())
+        .position(|window| window == from)
+        .unwrap();
+    image[start..start + to.len()].copy_from_slice(to);
+    image
+}
+
+#[test_case]
+fn dynamically_linked_program_runs() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running dynamically linked program runs test",
+        file!(),
+        line!(),
+    );
+
+    install_programs();
+
+    // The dynamic linker is not in the initrd so the kernel loads libgreet.so and relocates both.
+    // The calls through the PLT return exit_code from the program twice, 20 each, and library_value is 3
+    let program = load(DYNAMIC_HELLO).unwrap();
+    assert_eq!(program.entry.as_u64(), IMAGE_BASE + 0x300);
+
+    assert_eq!(run_image("dynamic_hello", DYNAMIC_HELLO), Exit::Code(43));
+}
+
+#[test_case]
+fn missing_shared_object_is_an_error() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running missing shared object is an error test",
+        file!(),
+        line!(),
+    );
+
+    install_programs();
+
+    let image = replace_string(DYNAMIC_HELLO, b"libgreet.so", b"libgrxxx.so");
+    assert_eq!(load(&image).err(), Some(ElfError::MissingLibrary));
+}
+
+#[test_case]
+fn dynamic_linker_from_initrd_is_started() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running dynamic linker from initrd is started test",
+        file!(),
+        line!(),
+    );
+
+    install_programs();
+
+    // `ld-interstellar.so.2` in the initrd exits with 42 without loading the program
+    let image = replace_string(
+        DYNAMIC_HELLO,
+        b"ld-interstellar.so.1",
+        b"ld-interstellar.so.2",
+    );
+
+    // Loaded at a different random address each time, or at LIBRARY_START without RDSEED
+    let first = load(&image).unwrap().entry.as_u64();
+    let second = load(&image).unwrap().entry.as_u64();
+    if RandomNumberGenerator::new()
+        .generate_number(None, None)
+        .is_some()
+    {
+        assert_ne!(first, second);
+    }
+    for entry in [first, second] {
+        assert!((LIBRARY_START..MAP_START).contains(&entry));
+        assert_eq!(entry % 4096, CODE_OFFSET);
+    }
+
+    assert_eq!(run_image("interpreter", &image), Exit::Code(42));
+}
+
+//########################################
+// Debugging
+//########################################
+
+/// How long the tests wait for a traced program to stop
+const STOP_WAIT: Duration = Duration::from_secs(1);
+
+#[test_case]
+fn traced_program_steps_and_changes_registers() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running traced program steps and changes registers test",
+        file!(),
+        line!(),
+    );
+
+    assert_eq!(syscall::name(syscall::SYS_PTRACE), Some("ptrace"));
+
+    let image = executable(&EXIT_42, READ_EXECUTE, 0);
+    let child = user::spawn_traced("traced", &image, &["traced"], &[], Tracer::Kernel).unwrap();
+    let pid = child.pid();
+    let entry = IMAGE_BASE + CODE_OFFSET;
+
+    // Held before the first instruction
+    let stop = trace::wait(pid, Tracer::Kernel, Some(STOP_WAIT)).unwrap();
+    assert_eq!(stop.reason, StopReason::Start);
+    assert_eq!(stop.registers.rip, entry);
+    assert_eq!(
+        process::get(pid).map(|process| process.state()),
+        Some(ProcessState::Stopped)
+    );
+
+    let mut code = [0; 9];
+    trace::read_memory(pid, Tracer::Kernel, entry, &mut code).unwrap();
+    assert_eq!(code, EXIT_42);
+
+    // mov edi, 42
+    trace::resume(pid, Tracer::Kernel, Resume::Step).unwrap();
+    let stop = trace::wait(pid, Tracer::Kernel, Some(STOP_WAIT)).unwrap();
+    assert_eq!(stop.reason, StopReason::Step);
+    assert_eq!(stop.registers.rip, entry + 5);
+    assert_eq!(stop.registers.rdi, 42);
+
+    let mut registers = stop.registers;
+    registers.rdi = 7;
+    trace::set_registers(pid, Tracer::Kernel, registers).unwrap();
+
+    // Nothing may send the program back into the kernel
+    registers.rip = u64::MAX;
+    assert_eq!(
+        trace::set_registers(pid, Tracer::Kernel, registers),
+        Err(TraceError::BadAddress)
+    );
+
+    trace::resume(pid, Tracer::Kernel, Resume::Continue).unwrap();
+    assert_eq!(child.join(), Exit::Code(7));
+}
+
+#[test_case]
+fn breakpoint_stops_traced_program() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running breakpoint stops traced program test",
+        file!(),
+        line!(),
+    );
+
+    let image = executable(&EXIT_42, READ_EXECUTE, 0);
+    let child = user::spawn_traced("traced", &image, &["traced"], &[], Tracer::Kernel).unwrap();
+    let pid = child.pid();
+    let entry = IMAGE_BASE + CODE_OFFSET;
+
+    trace::wait(pid, Tracer::Kernel, Some(STOP_WAIT)).unwrap();
+
+    // mov edi, 9 and a breakpoint on int 0x80, the code is read-only for the program
+    trace::write_memory(pid, Tracer::Kernel, entry + 1, &[9]).unwrap();
+    trace::set_breakpoint(pid, Tracer::Kernel, entry + 7).unwrap();
+    assert_eq!(trace::breakpoints(pid, Tracer::Kernel), Ok(vec![entry + 7]));
+
+    // The tracer reads the instruction the int3 replaced
+    let mut code = [0; 2];
+    trace::read_memory(pid, Tracer::Kernel, entry + 7, &mut code).unwrap();
+    assert_eq!(code, [0xcd, 0x80]);
+
+    trace::resume(pid, Tracer::Kernel, Resume::Continue).unwrap();
+    let stop = trace::wait(pid, Tracer::Kernel, Some(STOP_WAIT)).unwrap();
+    assert_eq!(stop.reason, StopReason::Breakpoint(entry + 7));
+    assert_eq!(stop.registers.rip, entry + 7);
+    assert_eq!(stop.registers.rdi, 9);
+
+    // Carrying on runs the original instruction
+    trace::resume(pid, Tracer::Kernel, Resume::Continue).unwrap();
+    assert_eq!(child.join(), Exit::Code(9));
+}
+
+#[test_case]
+fn attach_stops_running_program() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running attach stops running program test",
+        file!(),
+        line!(),
+    );
+
+    // jmp $
+    let image = executable(&[0xeb, 0xfe], READ_EXECUTE, 0);
+    let child = user::spawn("spin", &image, &["spin"], &[]).unwrap();
+    let pid = child.pid();
+
+    assert_eq!(
+        trace::registers(pid, Tracer::Kernel),
+        Err(TraceError::NotTraced)
+    );
+
+    // The program never makes a system call so it stops after a timer interrupt
+    trace::attach(pid, Tracer::Kernel).unwrap();
+    assert_eq!(
+        trace::attach(pid, Tracer::Kernel),
+        Err(TraceError::AlreadyTraced)
+    );
+
+    let stop = trace::wait(pid, Tracer::Kernel, Some(STOP_WAIT)).unwrap();
+    assert_eq!(stop.reason, StopReason::Interrupted);
+    assert_eq!(stop.registers.rip, IMAGE_BASE + CODE_OFFSET);
+
+    trace::detach(pid, Tracer::Kernel).unwrap();
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(
+        process::get(pid).map(|process| process.state()),
+        Some(ProcessState::Running)
+    );
+
+    process::kill(pid).unwrap();
+    assert_eq!(child.join(), Exit::Killed);
+}
+
+#[test_case]
+fn untraced_breakpoint_sends_sigtrap() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running untraced breakpoint sends sigtrap test",
+        file!(),
+        line!(),
+    );
+
+    assert_eq!(UserFault::Breakpoint.signal(), Signal::SIGTRAP);
+
+    // int3
+    assert_eq!(run("int3", &[0xcc]), Exit::Fault(UserFault::Breakpoint));
+}
+
+#[test_case]
+fn debugger_writes_do_not_reach_forked_pages() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running debugger writes do not reach forked pages test",
+        file!(),
+        line!(),
+    );
+
+    let code = VirtAddr::new(IMAGE_BASE);
+    let data = VirtAddr::new(IMAGE_BASE + 0x1000);
+
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(code, 4096, PageTableFlags::empty()).unwrap();
+    parent.map(data, 4096, PageTableFlags::WRITABLE).unwrap();
+    parent.write(code, &[1]).unwrap();
+    parent.write(data, &[2]).unwrap();
+
+    let mut child = parent.fork().unwrap();
+    child.write_private(code, &[3]).unwrap();
+    child.write_private(data, &[4]).unwrap();
+
+    let mut byte = [0];
+    parent.read(code, &mut byte).unwrap();
+    assert_eq!(byte, [1]);
+    parent.read(data, &mut byte).unwrap();
+    assert_eq!(byte, [2]);
+    child.read(code, &mut byte).unwrap();
+    assert_eq!(byte, [3]);
+    child.read(data, &mut byte).unwrap();
+    assert_eq!(byte, [4]);
+
+    // Code stays read-only for the program
+    let flags = child.flags(code).unwrap();
+    assert!(!flags.contains(PageTableFlags::WRITABLE));
+
+    assert_eq!(
+        child.write_private(VirtAddr::new(SIGNAL_TRAMPOLINE), &[0]),
+        Err(user::MapError::OutOfRange)
+    );
+}
+
+#[test_case]
+fn dropped_address_space_frees_frames() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running dropped address space frees frames test",
+        file!(),
+        line!(),
+    );
+
+    let data = VirtAddr::new(IMAGE_BASE);
+
+    let mut space = AddressSpace::new().unwrap();
+    space.map(data, 4096, PageTableFlags::WRITABLE).unwrap();
+    let page = address_space::translate(space.page_table(), data).unwrap();
+    let level_4 = space.page_table();
+    drop(space);
+
+    // Frames given back are handed out again first
+    let frames: Vec<_> = (0..8).map(|_| memory::allocate_frame().unwrap()).collect();
+    assert!(frames.iter().any(|frame| frame.start_address() == page));
+    assert!(frames.contains(&level_4));
+
+    for frame in frames {
+        unsafe { memory::deallocate_frame(frame) };
+    }
+}
+
+#[test_case]
+fn forked_frames_are_freed_by_last_owner() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running forked frames are freed by last owner test",
+        file!(),
+        line!(),
+    );
+
+    let data = VirtAddr::new(IMAGE_BASE);
+
+    let mut parent = AddressSpace::new().unwrap();
+    parent.map(data, 4096, PageTableFlags::WRITABLE).unwrap();
+    parent.write(data, &[7]).unwrap();
+    let child = parent.fork().unwrap();
+    let page = address_space::translate(child.page_table(), data).unwrap();
+
+    // The frame allocator writes into frames given back to it, which would change the child's byte
+    drop(parent);
+    let mut byte = [0];
+    child.read(data, &mut byte).unwrap();
+    assert_eq!(byte, [7]);
+
+    drop(child);
+    let frames: Vec<_> = (0..8).map(|_| memory::allocate_frame().unwrap()).collect();
+    assert!(frames.iter().any(|frame| frame.start_address() == page));
+
+    for frame in frames {
+        unsafe { memory::deallocate_frame(frame) };
+    }
+}
@@ -0,0 +1,199 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Work stealing between executors
+//!
+//! Only the boot CPU is started, so both executors here run on it and take turns.
+//! This covers moving tasks between workers but not two CPUs polling at once.
+
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)] // Allows Us To Run Custom Tests
+#![test_runner(interstellar_os::test_runner)] // Defines The Test Runner Function
+#![reexport_test_harness_main = "test_main"]
+
+use core::future::Future;
+use core::pin::Pin;
+use core::ptr::addr_of_mut;
+use core::sync::atomic::{AtomicU64, Ordering};
+use core::task::{Context, Poll};
+
+use interstellar_os as lib;
+
+use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
+use lib::task::executor::{Executor, Spawner};
+use lib::task::sync::Notify;
+use lib::task::{spawn, spawn_local, spawn_on};
+use lib::{other::log::LOGGER, serial_print};
+
+extern crate alloc;
+
+pub static BOOTLOADER_CONFIG: BootloaderConfig = {
+    use bootloader_api::config::*;
+
+    let mut mappings = Mappings::new_default();
+    mappings.kernel_stack = Mapping::Dynamic;
+    mappings.boot_info = Mapping::Dynamic;
+    mappings.framebuffer = Mapping::Dynamic;
+    mappings.physical_memory = Some(Mapping::Dynamic);
+    mappings.page_table_recursive = None;
+    mappings.aslr = true;
+    mappings.dynamic_range_start = Some(0xFFFF_8000_0000_0000);
+    mappings.dynamic_range_end = Some(0xFFFF_FFFF_FFFF_FFFF);
+
+    let mut config = BootloaderConfig::new_default();
+    config.mappings = mappings;
+    config.kernel_stack_size = 48 * 1024; // 48 Kib   decreasing this will cause undefined behavior
+    config
+};
+
+entry_point!(work_stealing, config = &BOOTLOADER_CONFIG);
+
+/// Made first so [spawn] and [spawn_on] add tasks to it
+static mut HOME: Option<Executor> = None;
+/// Only gets tasks by stealing them from [HOME]
+static mut THIEF: Option<Executor> = None;
+
+fn work_stealing(boot_info: &'static mut BootInfo) -> ! {
+    serial_print!("\nwork_stealing::work_stealing...\t");
+    lib::init(boot_info); // Start Interrupt Descriptor table ect.
+
+    unsafe {
+        HOME = Some(Executor::new(Spawner::new()));
+        THIEF = Some(Executor::new(Spawner::new()));
+    }
+
+    serial_print!("[Ok]\n");
+
+    test_main();
+
+    lib::exit_qemu(lib::QemuExitCode::Success);
+}
+
+fn home() -> &'static mut Executor {
+    unsafe { (*addr_of_mut!(HOME)).as_mut().unwrap() }
+}
+
+fn thief() -> &'static mut Executor {
+    unsafe { (*addr_of_mut!(THIEF)).as_mut().unwrap() }
+}
+
+/// Returns pending once so the task goes back through the run queue
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+//########################################
+// Test Cases
+//########################################
+
+static STOLEN: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn idle_executor_steals() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running idle executor steals test", file!(), line!());
+
+    for _ in 0..100 {
+        spawn(async {
+            YieldNow(false).await;
+            STOLEN.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+    }
+
+    assert_eq!(home().task_count(), 100);
+
+    // The thief keeps taking half of what is left until the home queue is empty
+    assert_eq!(thief().run_until_idle(), 0);
+    assert_eq!(home().task_count(), 0);
+    assert_eq!(STOLEN.load(Ordering::SeqCst), 100);
+}
+
+static PINNED: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn pinned_tasks_are_not_stolen() {
+    LOGGER.get().unwrap().lock().trace(
+        "Running pinned tasks are not stolen test",
+        file!(),
+        line!(),
+    );
+
+    for _ in 0..10 {
+        spawn_local(async {
+            YieldNow(false).await;
+            PINNED.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+        spawn_on(0, async {
+            YieldNow(false).await;
+            PINNED.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+    }
+
+    assert_eq!(thief().run_until_idle(), 0);
+    assert_eq!(PINNED.load(Ordering::SeqCst), 0);
+    assert_eq!(home().task_count(), 20);
+
+    assert_eq!(home().run_until_idle(), 0);
+    assert_eq!(PINNED.load(Ordering::SeqCst), 20);
+}
+
+static WAKE: Notify = Notify::new();
+static INNER_RAN: AtomicU64 = AtomicU64::new(0);
+
+#[test_case]
+fn stolen_task_moves_to_thief() {
+    LOGGER
+        .get()
+        .unwrap()
+        .lock()
+        .trace("Running stolen task moves to thief test", file!(), line!());
+
+    spawn(async {
+        // Spawned on the executor running this task
+        spawn(async {
+            INNER_RAN.fetch_add(1, Ordering::SeqCst);
+        })
+        .detach();
+        WAKE.notified().await;
+    })
+    .detach();
+
+    assert_eq!(thief().run_until_idle(), 1);
+    assert_eq!(home().task_count(), 0);
+    assert_eq!(INNER_RAN.load(Ordering::SeqCst), 1);
+
+    // The wake puts the task back on the thief's queue
+    WAKE.notify_one();
+    assert_eq!(thief().run_until_idle(), 0);
+}
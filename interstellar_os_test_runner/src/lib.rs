@@ -24,9 +24,11 @@ use std::{
     env::{self},
     ffi::OsString,
     fs,
-    io::{self, BufRead},
+    io::{self, BufRead, Read, Write},
+    net::{Ipv4Addr, TcpListener, TcpStream},
     path::{Path, PathBuf},
     process::{Command, Stdio},
+    time::Duration,
 };
 
 use bootloader::{BiosBoot, BootConfig, UefiBoot};
@@ -44,12 +46,12 @@ impl Logger {
     }
     /// Log to stdout
     pub fn log(&self, message: &str) {
-        println!("{}", message);
+        println!("{message}");
     }
     /// Log to stdout if verbose flag set
     pub fn verbose_log(&self, message: &str) {
         if self.verbose {
-            println!("{}", message);
+            println!("{message}");
         }
     }
 }
@@ -57,6 +59,14 @@ impl Logger {
 /// Static logger for use by test runner
 pub static LOGGER: OnceCell<Logger> = OnceCell::uninit();
 
+/// A kernel test prints this followed by a command to have it run in the QEMU monitor
+///
+/// This has to match `QEMU_MONITOR_PREFIX` in the kernel
+const MONITOR_PREFIX: &str = "qemu-monitor: ";
+
+/// The prompt the QEMU monitor prints when it is ready for the next command
+const MONITOR_PROMPT: &[u8] = b"(qemu) ";
+
 pub trait Testable {
     fn run(&self);
 }
@@ -81,6 +91,8 @@ pub fn test_runner(other_tests: &[&dyn Testable]) {
     let mut next_arg_test = false;
     let mut uefi = false;
     let mut bios = false;
+    let mut monitor_port: Option<u16> = None;
+    let mut next_arg_monitor = false;
 
     for arg in args.clone() {
         if next_arg_test {
@@ -92,6 +104,9 @@ pub fn test_runner(other_tests: &[&dyn Testable]) {
                 custom_tests = Some(vec![arg]);
             }
             next_arg_test = false;
+        } else if next_arg_monitor {
+            monitor_port = Some(arg.parse().expect("--monitor takes a port number"));
+            next_arg_monitor = false;
         } else if arg == *"--test" {
             println!("found test arg");
             next_arg_test = true;
@@ -101,6 +116,8 @@ pub fn test_runner(other_tests: &[&dyn Testable]) {
             uefi = true;
         } else if arg == *"--bios" {
             bios = true;
+        } else if arg == *"--monitor" {
+            next_arg_monitor = true;
         }
     }
 
@@ -116,7 +133,7 @@ pub fn test_runner(other_tests: &[&dyn Testable]) {
     LOGGER
         .get()
         .unwrap()
-        .verbose_log(&format!("Args passed to test runner: {:#?}", args));
+        .verbose_log(&format!("Args passed to test runner: {args:#?}"));
 
     LOGGER.get().unwrap().verbose_log(&format!(
         "Custom test args passed: {:#?}",
@@ -152,11 +169,11 @@ pub fn test_runner(other_tests: &[&dyn Testable]) {
         let mut failed_os_tests_names: Vec<String> = vec![];
 
         println!("Using UEFI");
-        println!("Running {} OS test/s...", total_os_tests);
+        println!("Running {total_os_tests} OS test/s...");
 
         for test in &os_tests {
             let disk = build_test_disk(&target_dir, &tests_dir.join(test), true);
-            match run_in_qemu(&disk, true) {
+            match run_in_qemu(&disk, true, monitor_port) {
                 Ok(_) => succeeded_os_tests += 1,
                 Err(_) => {
                     failed_os_tests += 1;
@@ -166,8 +183,7 @@ pub fn test_runner(other_tests: &[&dyn Testable]) {
         }
 
         println!(
-            "UEFI test results: {} total, {} succeeded, {} failed",
-            total_os_tests, succeeded_os_tests, failed_os_tests
+            "UEFI test results: {total_os_tests} total, {succeeded_os_tests} succeeded, {failed_os_tests} failed"
         );
 
         LOGGER.get().unwrap().verbose_log("Cleaning test/s");
@@ -176,7 +192,7 @@ pub fn test_runner(other_tests: &[&dyn Testable]) {
 
         if failed_os_tests > 0 {
             for name in failed_os_tests_names {
-                println!("{} Failed", name);
+                println!("{name} Failed");
             }
             panic!("Error: Some Tests Failed!");
         }
@@ -192,19 +208,18 @@ pub fn test_runner(other_tests: &[&dyn Testable]) {
         let mut failed_os_tests = 0;
 
         println!("Using BIOS");
-        println!("Running {} OS test/s...", total_os_tests);
+        println!("Running {total_os_tests} OS test/s...");
 
         for test in &os_tests {
             let disk = build_test_disk(&target_dir, &tests_dir.join(test), false);
-            match run_in_qemu(&disk, false) {
+            match run_in_qemu(&disk, false, monitor_port) {
                 Ok(_) => succeeded_os_tests += 1,
                 Err(_) => failed_os_tests += 1,
             }
         }
 
         println!(
-            "BIOS test results: {} total, {} succeeded, {} failed",
-            total_os_tests, succeeded_os_tests, failed_os_tests
+            "BIOS test results: {total_os_tests} total, {succeeded_os_tests} succeeded, {failed_os_tests} failed"
         );
 
         LOGGER.get().unwrap().verbose_log("Cleaning test/s");
@@ -264,8 +279,7 @@ fn find_kernel_tests(
     let files = get_files(Path::new(tests_dir))?;
     Ok(files
         .iter()
-        .cloned()
-        .filter(|file| {
+        .filter(|&file| {
             let test_pattern = Regex::new(r"\-[0-9a-z]{16}$").unwrap(); // exactly 16 lowercase alphanumeric characters on the end for some reason
 
             if test_args.is_some() {
@@ -289,6 +303,7 @@ fn find_kernel_tests(
                     .unwrap_or(false)
             }
         })
+        .cloned()
         .collect())
 }
 
@@ -316,7 +331,16 @@ fn build_test_disk(disks_path: &Path, test_path: &Path, uefi: bool) -> PathBuf {
 }
 
 /// runs the tests in QEMU
-fn run_in_qemu(disk_path: &Path, uefi: bool) -> Result<(), ()> {
+///
+/// The QEMU monitor listens on `monitor_port` or a free port so tests can ask for monitor commands
+fn run_in_qemu(disk_path: &Path, uefi: bool, monitor_port: Option<u16>) -> Result<(), ()> {
+    let monitor_port = monitor_port.unwrap_or_else(free_port);
+
+    LOGGER
+        .get()
+        .unwrap()
+        .verbose_log(&format!("QEMU monitor port: {monitor_port}"));
+
     let mut cmd = Command::new("qemu-system-x86_64");
     cmd.arg("-device")
         .arg("isa-debug-exit,iobase=0xf4,iosize=0x04")
@@ -331,6 +355,8 @@ fn run_in_qemu(disk_path: &Path, uefi: bool) -> Result<(), ()> {
         .arg("order=c")
         .arg("-cpu")
         .arg("max") // Enables all features supported by the accelerator in the current host; Needed for RDSEED
+        .arg("-monitor")
+        .arg(format!("tcp:127.0.0.1:{monitor_port},server,nowait")) // Used by tests to inject machine checks
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .stdin(Stdio::piped());
@@ -347,7 +373,15 @@ fn run_in_qemu(disk_path: &Path, uefi: bool) -> Result<(), ()> {
     let stdout_pipe = std::thread::spawn(move || {
         let reader = std::io::BufReader::new(stdout);
         for line in reader.lines() {
-            println!("{}", line.unwrap());
+            let line = line.unwrap();
+            println!("{line}");
+
+            if let Some(index) = line.find(MONITOR_PREFIX) {
+                let command = line[index + MONITOR_PREFIX.len()..].trim();
+                if let Err(error) = monitor_command(monitor_port, command) {
+                    println!("QEMU monitor command {command:?} failed: {error}");
+                }
+            }
         }
     });
 
@@ -369,6 +403,48 @@ fn run_in_qemu(disk_path: &Path, uefi: bool) -> Result<(), ()> {
     }
 }
 
+/// Returns a TCP port that nothing is listening on
+fn free_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .and_then(|listener| listener.local_addr())
+        .map(|addr| addr.port())
+        .expect("Failed to find a free port for the QEMU monitor")
+}
+
+/// Runs a command in the QEMU monitor and waits for it to finish
+fn monitor_command(port: u16, command: &str) -> io::Result<()> {
+    LOGGER
+        .get()
+        .unwrap()
+        .verbose_log(&format!("Running QEMU monitor command: {command}"));
+
+    let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    // The monitor greets each connection with its prompt
+    read_until_prompt(&mut stream)?;
+    stream.write_all(format!("{command}\n").as_bytes())?;
+    read_until_prompt(&mut stream)?;
+
+    Ok(())
+}
+
+/// Reads from the QEMU monitor until it prints its prompt
+fn read_until_prompt(stream: &mut TcpStream) -> io::Result<()> {
+    let mut output = Vec::new();
+    let mut buffer = [0; 256];
+
+    while !output.ends_with(MONITOR_PROMPT) {
+        let read = stream.read(&mut buffer)?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        output.extend_from_slice(&buffer[..read]);
+    }
+
+    Ok(())
+}
+
 /// Cleans the tests directory
 ///
 /// This is important because rebuilding for other features produces new binaries  
@@ -0,0 +1,37 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Prints the number of arguments, each argument and the environment
+
+#![no_std]
+#![no_main]
+
+use interstellar_user::{entry, env, println};
+
+entry!(main);
+
+fn main() -> i32 {
+    println!("{} arguments", env::arg_count());
+    for (index, arg) in env::args().enumerate() {
+        println!("{}: {}", index, arg);
+    }
+
+    for (name, value) in env::vars() {
+        println!("{}={}", name, value);
+    }
+
+    0
+}
@@ -0,0 +1,41 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Says hello and shows the heap works
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use interstellar_user::{entry, println, syscall};
+
+entry!(main);
+
+fn main() -> i32 {
+    println!("Hello from a Rust user program!");
+
+    let mut greeting = String::from("Running as");
+    greeting.push_str(" process ");
+    println!("{} {}", greeting, syscall::getpid());
+
+    let squares: Vec<u64> = (1..=10).map(|n| n * n).collect();
+    println!("Squares: {:?}", squares);
+
+    0
+}
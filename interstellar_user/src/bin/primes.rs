@@ -0,0 +1,53 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Prints the primes up to the number given as its first argument, 100 by default
+
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec;
+use interstellar_user::{entry, env, eprintln, println};
+
+entry!(main);
+
+fn main() -> i32 {
+    let limit = match env::args().nth(1).map(str::parse::<usize>) {
+        None => 100,
+        Some(Ok(limit)) => limit,
+        Some(Err(_)) => {
+            eprintln!("usage: primes [limit]");
+            return 1;
+        }
+    };
+
+    // Sieve of Eratosthenes
+    let mut is_prime = vec![true; limit + 1];
+    for number in 2..=limit {
+        if !is_prime[number] {
+            continue;
+        }
+
+        println!("{}", number);
+        for multiple in (number * number..=limit).step_by(number) {
+            is_prime[multiple] = false;
+        }
+    }
+
+    0
+}
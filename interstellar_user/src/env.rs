@@ -0,0 +1,95 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The arguments and environment the program was started with
+//!
+//! They are read from the entry stack the kernel builds: argc, the argument pointers and a null pointer,
+//! then the environment pointers and a null pointer. Strings that are not UTF-8 are skipped.
+
+use core::ffi::{c_char, CStr};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+static ARGC: AtomicUsize = AtomicUsize::new(0);
+static ARGV: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+static ENVP: AtomicPtr<*const c_char> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Remembers where the arguments and environment are
+///
+/// # Safety
+///
+/// `stack` must be the stack pointer the program was started with
+pub(crate) unsafe fn init(stack: *const u64) {
+    let argc = *stack as usize;
+    let argv = stack.add(1) as *mut *const c_char;
+
+    ARGC.store(argc, Ordering::Relaxed);
+    ARGV.store(argv, Ordering::Relaxed);
+    ENVP.store(argv.add(argc + 1), Ordering::Relaxed);
+}
+
+/// Iterates over the strings of a null terminated pointer array
+#[derive(Debug, Clone)]
+pub struct Strings {
+    next: *const *const c_char,
+}
+
+impl Iterator for Strings {
+    type Item = &'static str;
+
+    fn next(&mut self) -> Option<&'static str> {
+        loop {
+            if self.next.is_null() {
+                return None;
+            }
+
+            // The kernel put the strings on the stack, they live as long as the program
+            let pointer = unsafe { *self.next };
+            if pointer.is_null() {
+                return None;
+            }
+            self.next = unsafe { self.next.add(1) };
+
+            if let Ok(string) = unsafe { CStr::from_ptr(pointer) }.to_str() {
+                return Some(string);
+            }
+        }
+    }
+}
+
+/// The arguments, the first is usually the program name
+pub fn args() -> Strings {
+    Strings {
+        next: ARGV.load(Ordering::Relaxed),
+    }
+}
+
+/// How many arguments there are, counting ones that are not UTF-8
+pub fn arg_count() -> usize {
+    ARGC.load(Ordering::Relaxed)
+}
+
+/// The environment strings, each one is `NAME=value`
+pub fn vars() -> impl Iterator<Item = (&'static str, &'static str)> {
+    Strings {
+        next: ENVP.load(Ordering::Relaxed),
+    }
+    .map(|var| var.split_once('=').unwrap_or((var, "")))
+}
+
+/// The value of the environment variable `name`
+pub fn var(name: &str) -> Option<&'static str> {
+    vars().find(|(key, _)| *key == name).map(|(_, value)| value)
+}
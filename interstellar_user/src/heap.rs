@@ -0,0 +1,174 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The program's heap, it backs [alloc] once the program starts
+//!
+//! Small allocations are rounded up to a power of two size class and cut out of memory the heap gets from `brk`,
+//! freed blocks go on a list for their class to be used again. Larger ones get their own pages from `mmap`
+//! which are given back with `munmap` when they are freed.
+
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::hint;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::syscall::{self, PROT_READ, PROT_WRITE};
+
+/// The smallest size class
+const MIN_BLOCK: usize = 16;
+
+/// The largest size class, anything bigger is mapped with `mmap`
+const MAX_BLOCK: usize = 4096;
+
+/// One size class for each power of two from [MIN_BLOCK] to [MAX_BLOCK]
+const CLASSES: usize = (MAX_BLOCK.trailing_zeros() - MIN_BLOCK.trailing_zeros()) as usize + 1;
+
+/// The heap grows by at least this much at a time to keep `brk` calls down
+const GROW_SIZE: u64 = 64 * 1024;
+
+const PAGE_SIZE: usize = 4096;
+
+#[global_allocator]
+static HEAP: Heap = Heap::new();
+
+/// A freed block, kept in the block itself
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+struct Inner {
+    /// The first free block of each size class
+    free: [*mut FreeBlock; CLASSES],
+    /// Where the next new block is cut from, 0 until the heap is first used
+    next: u64,
+    /// The end of the heap from `brk`
+    end: u64,
+}
+
+/// The allocator, threads take turns with a spin lock
+struct Heap {
+    locked: AtomicBool,
+    inner: UnsafeCell<Inner>,
+}
+
+// The inner state is only used with the lock held
+unsafe impl Sync for Heap {}
+
+impl Heap {
+    const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            inner: UnsafeCell::new(Inner {
+                free: [ptr::null_mut(); CLASSES],
+                next: 0,
+                end: 0,
+            }),
+        }
+    }
+
+    /// Runs `f` with the lock held
+    fn with_inner<R>(&self, f: impl FnOnce(&mut Inner) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            hint::spin_loop();
+        }
+
+        let result = f(unsafe { &mut *self.inner.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// The size class index of a small layout, None if it is mapped with `mmap`
+fn size_class(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(MIN_BLOCK);
+    if size > MAX_BLOCK {
+        return None;
+    }
+
+    let size = size.next_power_of_two();
+    Some((size.trailing_zeros() - MIN_BLOCK.trailing_zeros()) as usize)
+}
+
+/// The bytes `mmap` maps for a large layout
+fn mapped_size(layout: &Layout) -> usize {
+    (layout.size() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+impl Inner {
+    /// Cuts a new block of `size` bytes aligned to its size out of the heap, growing it if needed
+    fn new_block(&mut self, size: u64) -> *mut u8 {
+        if self.next == 0 {
+            self.next = syscall::brk(0);
+            self.end = self.next;
+        }
+
+        let start = (self.next + size - 1) & !(size - 1);
+        let end = start + size;
+
+        if end > self.end {
+            let wanted = end.max(self.end + GROW_SIZE);
+            self.end = syscall::brk(wanted);
+
+            if self.end < end {
+                return ptr::null_mut();
+            }
+        }
+
+        self.next = end;
+        start as *mut u8
+    }
+}
+
+unsafe impl GlobalAlloc for Heap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let Some(class) = size_class(&layout) else {
+            // Pages from mmap are only page aligned
+            if layout.align() > PAGE_SIZE {
+                return ptr::null_mut();
+            }
+            return syscall::mmap(mapped_size(&layout), PROT_READ | PROT_WRITE)
+                .unwrap_or(ptr::null_mut());
+        };
+
+        self.with_inner(|inner| {
+            let block = inner.free[class];
+            if block.is_null() {
+                return inner.new_block((MIN_BLOCK << class) as u64);
+            }
+
+            inner.free[class] = (*block).next;
+            block as *mut u8
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let Some(class) = size_class(&layout) else {
+            let _ = syscall::munmap(ptr, mapped_size(&layout));
+            return;
+        };
+
+        self.with_inner(|inner| {
+            let block = ptr as *mut FreeBlock;
+            (*block).next = inner.free[class];
+            inner.free[class] = block;
+        })
+    }
+}
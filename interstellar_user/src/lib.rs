@@ -0,0 +1,49 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Runtime for interstellar OS user programs
+//!
+//! A program is a `#![no_std]` and `#![no_main]` binary that names its main function with [entry!],
+//! this crate gives it the `_start` entry point, a heap for [alloc], [print!] and [println!] over the
+//! write system call, its arguments and environment from [env] and wrappers for the system calls in [syscall].
+//!
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! use interstellar_user::{entry, println};
+//!
+//! entry!(main);
+//!
+//! fn main() -> i32 {
+//!     println!("Hello from user mode!");
+//!     0
+//! }
+//! ```
+//!
+//! Programs are linked at the user image base by this crate's build script, see `src/bin` for examples.
+
+#![no_std]
+
+extern crate alloc;
+
+pub mod env;
+mod heap;
+pub mod io;
+mod start;
+pub mod syscall;
+
+pub use start::exit;
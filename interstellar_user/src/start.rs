@@ -0,0 +1,89 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! The `_start` entry point and panic handler every program gets
+
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+
+use crate::{env, eprintln, syscall};
+
+global_asm!(
+    // The kernel starts the program with rsp pointing at argc, with argv and the environment above it
+    ".global _start",
+    "_start:",
+    "xor ebp, ebp",
+    "mov rdi, rsp",
+    "and rsp, -16",
+    "call {start}",
+    "ud2",
+    start = sym start,
+);
+
+// The note the kernel looks for to run the program with interstellar system calls instead of Linux ones
+global_asm!(
+    ".pushsection .note.interstellar, \"a\", @note",
+    ".balign 4",
+    ".long .Lname_end - .Lname",
+    ".long .Ldesc_end - .Ldesc",
+    ".long 1", // NT_INTERSTELLAR_ABI
+    ".Lname:",
+    ".asciz \"Interstellar\"",
+    ".Lname_end:",
+    ".balign 4",
+    ".Ldesc:",
+    ".long 1", // ABI version
+    ".Ldesc_end:",
+    ".popsection",
+);
+
+extern "Rust" {
+    /// Made by [crate::entry!]
+    fn __interstellar_main() -> i32;
+}
+
+/// Names the main function of the program, it takes no arguments and returns the exit code
+///
+/// The arguments and environment are in [crate::env].
+#[macro_export]
+macro_rules! entry {
+    ($main:path) => {
+        #[export_name = "__interstellar_main"]
+        fn __interstellar_main() -> i32 {
+            let main: fn() -> i32 = $main;
+            main()
+        }
+    };
+}
+
+/// Called by `_start` with the stack the kernel made
+extern "C" fn start(stack: *const u64) -> ! {
+    unsafe { env::init(stack) };
+
+    let code = unsafe { __interstellar_main() };
+    exit(code as i64)
+}
+
+/// Ends the program on every thread with `code`
+pub fn exit(code: i64) -> ! {
+    syscall::exit(code)
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    eprintln!("{}", info);
+    exit(101)
+}
@@ -0,0 +1,345 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! System calls, see the kernel's `syscall` module for what each one does
+//!
+//! The raw `syscallN` functions return what the kernel put in `rax`, the wrappers turn a negative value into an [Error].
+
+use alloc::vec::Vec;
+use core::arch::asm;
+use core::fmt;
+
+pub const SYS_EXIT: u64 = 0;
+pub const SYS_WRITE: u64 = 1;
+pub const SYS_GETPID: u64 = 2;
+pub const SYS_WAITPID: u64 = 3;
+pub const SYS_KILL: u64 = 4;
+pub const SYS_FORK: u64 = 5;
+pub const SYS_EXEC: u64 = 6;
+pub const SYS_SPAWN: u64 = 7;
+pub const SYS_SIGACTION: u64 = 8;
+pub const SYS_SIGPROCMASK: u64 = 9;
+pub const SYS_SIGRETURN: u64 = 10;
+pub const SYS_CLONE: u64 = 11;
+pub const SYS_GETTID: u64 = 12;
+pub const SYS_THREAD_EXIT: u64 = 13;
+pub const SYS_FUTEX: u64 = 14;
+pub const SYS_SET_THREAD_POINTER: u64 = 15;
+pub const SYS_BRK: u64 = 16;
+pub const SYS_MMAP: u64 = 17;
+pub const SYS_MUNMAP: u64 = 18;
+
+/// `waitpid` option to return straight away when no child has ended
+pub const WNOHANG: u64 = 1;
+
+pub const FUTEX_WAIT: u64 = 0;
+pub const FUTEX_WAKE: u64 = 1;
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+
+/// File descriptors every program starts with
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+/// A failed system call, the kernel's `SyscallError` number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error(pub u64);
+
+impl Error {
+    pub const NO_SUCH_SYSCALL: Error = Error(1);
+    pub const BAD_ADDRESS: Error = Error(2);
+    pub const BAD_FILE_DESCRIPTOR: Error = Error(3);
+    pub const INVALID_ARGUMENT: Error = Error(4);
+    pub const NO_SUCH_PROCESS: Error = Error(5);
+    pub const NO_CHILDREN: Error = Error(6);
+    pub const NO_SUCH_FILE: Error = Error(7);
+    pub const NOT_EXECUTABLE: Error = Error(8);
+    pub const OUT_OF_MEMORY: Error = Error(9);
+    pub const INTERRUPTED: Error = Error(10);
+    pub const WOULD_BLOCK: Error = Error(11);
+    pub const TIMED_OUT: Error = Error(12);
+
+    /// Splits a raw return value into a result
+    pub fn check(value: u64) -> Result<u64> {
+        match value as i64 {
+            error if error < 0 => Err(Error(error.unsigned_abs())),
+            _ => Ok(value),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match *self {
+            Error::NO_SUCH_SYSCALL => "no such system call",
+            Error::BAD_ADDRESS => "bad address",
+            Error::BAD_FILE_DESCRIPTOR => "bad file descriptor",
+            Error::INVALID_ARGUMENT => "invalid argument",
+            Error::NO_SUCH_PROCESS => "no such process",
+            Error::NO_CHILDREN => "no children",
+            Error::NO_SUCH_FILE => "no such file",
+            Error::NOT_EXECUTABLE => "not executable",
+            Error::OUT_OF_MEMORY => "out of memory",
+            Error::INTERRUPTED => "interrupted",
+            Error::WOULD_BLOCK => "would block",
+            Error::TIMED_OUT => "timed out",
+            Error(number) => return write!(f, "error {number}"),
+        };
+        f.write_str(message)
+    }
+}
+
+pub type Result<T> = core::result::Result<T, Error>;
+
+//########################################
+// Raw System Calls
+//########################################
+
+/// # Safety
+///
+/// The system call must not break memory the program is using
+#[inline]
+pub unsafe fn syscall0(number: u64) -> u64 {
+    let result;
+    asm!("syscall", inlateout("rax") number => result, out("rcx") _, out("r11") _, options(nostack));
+    result
+}
+
+/// # Safety
+///
+/// The system call must not break memory the program is using
+#[inline]
+pub unsafe fn syscall1(number: u64, arg1: u64) -> u64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") arg1,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+/// # Safety
+///
+/// The system call must not break memory the program is using
+#[inline]
+pub unsafe fn syscall2(number: u64, arg1: u64, arg2: u64) -> u64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+/// # Safety
+///
+/// The system call must not break memory the program is using
+#[inline]
+pub unsafe fn syscall3(number: u64, arg1: u64, arg2: u64, arg3: u64) -> u64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+/// # Safety
+///
+/// The system call must not break memory the program is using
+#[inline]
+pub unsafe fn syscall4(number: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> u64 {
+    let result;
+    asm!(
+        "syscall",
+        inlateout("rax") number => result,
+        in("rdi") arg1,
+        in("rsi") arg2,
+        in("rdx") arg3,
+        in("r10") arg4,
+        out("rcx") _,
+        out("r11") _,
+        options(nostack),
+    );
+    result
+}
+
+//########################################
+// Wrappers
+//########################################
+
+/// Ends the program on every thread with `code`
+pub fn exit(code: i64) -> ! {
+    unsafe { syscall1(SYS_EXIT, code as u64) };
+    unreachable!("exit returned")
+}
+
+/// Writes `buffer` to file descriptor `fd`, returns how many bytes were written
+pub fn write(fd: u64, buffer: &[u8]) -> Result<usize> {
+    let result = unsafe { syscall3(SYS_WRITE, fd, buffer.as_ptr() as u64, buffer.len() as u64) };
+    Error::check(result).map(|written| written as usize)
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall0(SYS_GETPID) }
+}
+
+pub fn gettid() -> u64 {
+    unsafe { syscall0(SYS_GETTID) }
+}
+
+/// Collects an ended child, any child if `pid` is None. Returns its PID and `waitpid` status,
+/// None if `options` has [WNOHANG] and no child has ended yet
+pub fn waitpid(pid: Option<u64>, options: u64) -> Result<Option<(u64, u64)>> {
+    let mut status = 0u64;
+    let pid = pid.unwrap_or(u64::MAX);
+    let result = unsafe { syscall3(SYS_WAITPID, pid, &mut status as *mut u64 as u64, options) };
+
+    match Error::check(result)? {
+        0 => Ok(None),
+        child => Ok(Some((child, status))),
+    }
+}
+
+/// Sends `signal` to the process `pid`
+pub fn kill(pid: u64, signal: u64) -> Result<()> {
+    Error::check(unsafe { syscall2(SYS_KILL, pid, signal) }).map(|_| ())
+}
+
+/// Copies the calling process, returns the child's PID in the parent and 0 in the child
+pub fn fork() -> Result<u64> {
+    Error::check(unsafe { syscall0(SYS_FORK) })
+}
+
+/// Replaces the program of the calling process, only returns if it fails
+pub fn exec(path: &str, args: &[&str], env: &[&str]) -> Error {
+    let strings = CStrings::new(path, args, env);
+    let (path, args, env) = strings.pointers();
+    let result = unsafe { syscall3(SYS_EXEC, path, args, env) };
+
+    Error::check(result)
+        .err()
+        .unwrap_or(Error::INVALID_ARGUMENT)
+}
+
+/// Starts a program in a new child process, returns its PID
+pub fn spawn(path: &str, args: &[&str], env: &[&str]) -> Result<u64> {
+    let strings = CStrings::new(path, args, env);
+    let (path, args, env) = strings.pointers();
+    Error::check(unsafe { syscall3(SYS_SPAWN, path, args, env) })
+}
+
+/// Ends the calling thread, `code` is the exit code of the process if it was the last thread
+pub fn thread_exit(code: i64) -> ! {
+    unsafe { syscall1(SYS_THREAD_EXIT, code as u64) };
+    unreachable!("thread_exit returned")
+}
+
+/// Blocks while `word` holds `value`, for at most `timeout_ns` nanoseconds unless it is 0
+pub fn futex_wait(word: &core::sync::atomic::AtomicU32, value: u32, timeout_ns: u64) -> Result<()> {
+    let result = unsafe {
+        syscall4(
+            SYS_FUTEX,
+            word.as_ptr() as u64,
+            FUTEX_WAIT,
+            value as u64,
+            timeout_ns,
+        )
+    };
+    Error::check(result).map(|_| ())
+}
+
+/// Wakes up to `count` threads waiting on `word`, returns how many were woken
+pub fn futex_wake(word: &core::sync::atomic::AtomicU32, count: u32) -> Result<usize> {
+    let result = unsafe { syscall4(SYS_FUTEX, word.as_ptr() as u64, FUTEX_WAKE, count as u64, 0) };
+    Error::check(result).map(|woken| woken as usize)
+}
+
+/// Moves the end of the heap to `end`, or only asks where it is with 0. Returns the end of the heap
+pub fn brk(end: u64) -> u64 {
+    unsafe { syscall1(SYS_BRK, end) }
+}
+
+/// Maps `len` bytes of zeroed pages with the [PROT_READ], [PROT_WRITE] and [PROT_EXEC] flags in `prot`
+pub fn mmap(len: usize, prot: u64) -> Result<*mut u8> {
+    Error::check(unsafe { syscall2(SYS_MMAP, len as u64, prot) }).map(|addr| addr as *mut u8)
+}
+
+/// Unmaps the pages covering `len` bytes from `addr`
+///
+/// # Safety
+///
+/// Nothing may use the memory afterwards
+pub unsafe fn munmap(addr: *mut u8, len: usize) -> Result<()> {
+    Error::check(syscall2(SYS_MUNMAP, addr as u64, len as u64)).map(|_| ())
+}
+
+/// NUL terminated copies of a path, arguments and environment with the null terminated pointer arrays
+/// [exec] and [spawn] take
+struct CStrings {
+    strings: Vec<Vec<u8>>,
+    args: Vec<u64>,
+    env: Vec<u64>,
+}
+
+impl CStrings {
+    fn new(path: &str, args: &[&str], env: &[&str]) -> Self {
+        let strings: Vec<Vec<u8>> = core::iter::once(&path)
+            .chain(args)
+            .chain(env)
+            .map(|string| {
+                let mut bytes = Vec::with_capacity(string.len() + 1);
+                bytes.extend_from_slice(string.as_bytes());
+                bytes.push(0);
+                bytes
+            })
+            .collect();
+
+        let pointer = |string: &Vec<u8>| string.as_ptr() as u64;
+        let (args, env) = strings[1..].split_at(args.len());
+
+        let args = args.iter().map(pointer).chain(Some(0)).collect();
+        let env = env.iter().map(pointer).chain(Some(0)).collect();
+
+        Self { strings, args, env }
+    }
+
+    fn pointers(&self) -> (u64, u64, u64) {
+        (
+            self.strings[0].as_ptr() as u64,
+            self.args.as_ptr() as u64,
+            self.env.as_ptr() as u64,
+        )
+    }
+}
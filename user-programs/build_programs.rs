@@ -0,0 +1,130 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// Builds the programs in user-programs, the build scripts of the builder and of interstellar_os include this file
+// so the initrd and the tests get the same binaries. It needs `as` and `ld` from binutils, a C compiler as `cc` and
+// the x86_64-unknown-linux-musl Rust target for its copy of musl
+
+// The programs built from user-programs, they are packed into the initrd under these names
+#[allow(dead_code)] // interstellar_os does not make an initrd
+const BUILT_PROGRAMS: [&str; 4] = ["init", "libgreet.so", "dynamic_hello", "musl_hello"];
+
+// Assembles and links the programs in `source` into `out_dir`
+fn build_user_programs(source: &std::path::Path, out_dir: &std::path::Path) {
+    println!("cargo:rerun-if-changed={}", source.display());
+
+    let linker_flags = ["-z", "max-page-size=4096", "-z", "noseparate-code", "-s"];
+    let assemble = |name: &str| {
+        let object = out_dir.join(format!("{name}.o"));
+        run_tool(
+            std::process::Command::new("as")
+                .arg(source.join(format!("{name}.s")))
+                .arg("-o")
+                .arg(&object),
+        );
+        object
+    };
+
+    // The first user program, a static executable at the user image base
+    let init = assemble("init");
+    run_tool(
+        std::process::Command::new("ld")
+            .args([
+                "-static",
+                "-nostdlib",
+                "-Ttext-segment=0x8000400000",
+                "-e",
+                "_start",
+            ])
+            .args(linker_flags)
+            .arg("-o")
+            .arg(out_dir.join("init"))
+            .arg(init),
+    );
+
+    // A shared library and a program linked against it, the library must be linked first
+    let libgreet = assemble("libgreet");
+    run_tool(
+        std::process::Command::new("ld")
+            .args(["-shared", "-soname", "libgreet.so", "--hash-style=sysv"])
+            .args(linker_flags)
+            .arg("-o")
+            .arg(out_dir.join("libgreet.so"))
+            .arg(libgreet),
+    );
+    let dynamic_hello = assemble("dynamic_hello");
+    run_tool(
+        std::process::Command::new("ld")
+            .args([
+                "-pie",
+                "-dynamic-linker",
+                "/lib/ld-interstellar.so.1",
+                "--hash-style=gnu",
+            ])
+            .args(linker_flags)
+            .arg("-o")
+            .arg(out_dir.join("dynamic_hello"))
+            .arg(dynamic_hello)
+            .arg(out_dir.join("libgreet.so")),
+    );
+
+    // A C program linked against musl as a static PIE, like `musl-gcc -static-pie` makes
+    let musl = musl_directory();
+    let musl_hello = out_dir.join("musl_hello.o");
+    run_tool(
+        std::process::Command::new("cc")
+            .args(["-c", "-O2", "-fPIE", "-nostdinc", "-o"])
+            .arg(&musl_hello)
+            .arg(source.join("musl_hello.c")),
+    );
+    run_tool(
+        std::process::Command::new("ld")
+            .args(["-static", "-pie", "--no-dynamic-linker", "-z", "text"])
+            .args(linker_flags)
+            .arg("-o")
+            .arg(out_dir.join("musl_hello"))
+            .args(["rcrt1.o", "crti.o", "crtbeginS.o"].map(|object| musl.join(object)))
+            .arg(musl_hello)
+            .args(["libc.a", "crtendS.o", "crtn.o"].map(|object| musl.join(object))),
+    );
+}
+
+// Where the x86_64-unknown-linux-musl Rust target keeps musl's start files and libc.a
+fn musl_directory() -> std::path::PathBuf {
+    // set by cargo for build scripts
+    let rustc = std::env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = std::process::Command::new(&rustc)
+        .args(["--print", "sysroot"])
+        .output()
+        .unwrap_or_else(|err| panic!("could not run {rustc:?}: {err}"));
+    let sysroot = String::from_utf8(output.stdout).expect("sysroot is not UTF-8");
+
+    let musl = std::path::Path::new(sysroot.trim())
+        .join("lib/rustlib/x86_64-unknown-linux-musl/lib/self-contained");
+    assert!(
+        musl.join("libc.a").exists(),
+        "musl was not found in {}, install it with `rustup target add x86_64-unknown-linux-musl`",
+        musl.display()
+    );
+    musl
+}
+
+fn run_tool(command: &mut std::process::Command) {
+    let status = command
+        .status()
+        .unwrap_or_else(|err| panic!("could not run {command:?}: {err}"));
+    assert!(status.success(), "{command:?} failed with {status}");
+}
@@ -0,0 +1,61 @@
+#This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+#Copyright (C) 2023  contributors of the interstellar OS project
+#
+#This program is free software: you can redistribute it and/or modify
+#it under the terms of the GNU General Public License as published by
+#the Free Software Foundation, either version 3 of the License, or
+#(at your option) any later version.
+#
+#This program is distributed in the hope that it will be useful,
+#but WITHOUT ANY WARRANTY; without even the implied warranty of
+#MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#GNU General Public License for more details.
+#
+#You should have received a copy of the GNU General Public License
+#along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+# A dynamically linked program, the kernel loads libgreet.so for it and binds the calls through the PLT
+#
+# Build with:
+#   This is done by build_programs.rs when the initrd is made
+#
+# It exits with 43, exit_code from say_hello and answer and library_value from the library
+
+.intel_syntax noprefix
+.text
+.global _start
+_start:
+    call say_hello@PLT
+    mov rbx, rax
+    call answer@PLT
+    add rbx, rax
+
+    mov rax, [rip + library_value@GOTPCREL]
+    add rbx, [rax]
+
+    # exit(rbx)
+    mov rdi, rbx
+    xor eax, eax
+    syscall
+
+.data
+.balign 8
+.global exit_code
+.type exit_code, @object
+.size exit_code, 8
+exit_code:
+    .quad 20
+
+# Marks this as an interstellar program, programs without the note run with the Linux system calls
+.section .note.interstellar, "a", @note
+.balign 4
+    .long name_end - name
+    .long desc_end - desc
+    .long 1 # NT_INTERSTELLAR_ABI
+name:
+    .asciz "Interstellar"
+name_end:
+.balign 4
+desc:
+    .long 1 # ABI version
+desc_end:
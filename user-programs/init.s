@@ -0,0 +1,54 @@
+#This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+#Copyright (C) 2023  contributors of the interstellar OS project
+#
+#This program is free software: you can redistribute it and/or modify
+#it under the terms of the GNU General Public License as published by
+#the Free Software Foundation, either version 3 of the License, or
+#(at your option) any later version.
+#
+#This program is distributed in the hope that it will be useful,
+#but WITHOUT ANY WARRANTY; without even the implied warranty of
+#MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#GNU General Public License for more details.
+#
+#You should have received a copy of the GNU General Public License
+#along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+# The first user program, a static ELF executable linked at the user image base
+#
+# Build with:
+#   This is done by build_programs.rs when the initrd is made
+
+.intel_syntax noprefix
+.text
+.global _start
+_start:
+    # write(1, message, length)
+    mov eax, 1
+    mov edi, 1
+    lea rsi, [rip + message]
+    mov edx, message_end - message
+    syscall
+
+    # exit(0)
+    xor eax, eax
+    xor edi, edi
+    syscall
+
+message:
+    .ascii "Hello from user mode!\n"
+message_end:
+
+# Marks this as an interstellar program, programs without the note run with the Linux system calls
+.section .note.interstellar, "a", @note
+.balign 4
+    .long name_end - name
+    .long desc_end - desc
+    .long 1 # NT_INTERSTELLAR_ABI
+name:
+    .asciz "Interstellar"
+name_end:
+.balign 4
+desc:
+    .long 1 # ABI version
+desc_end:
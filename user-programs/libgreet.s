@@ -0,0 +1,65 @@
+#This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+#Copyright (C) 2023  contributors of the interstellar OS project
+#
+#This program is free software: you can redistribute it and/or modify
+#it under the terms of the GNU General Public License as published by
+#the Free Software Foundation, either version 3 of the License, or
+#(at your option) any later version.
+#
+#This program is distributed in the hope that it will be useful,
+#but WITHOUT ANY WARRANTY; without even the implied warranty of
+#MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+#GNU General Public License for more details.
+#
+#You should have received a copy of the GNU General Public License
+#along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+# A shared library for dynamic_hello, it is found in the initrd by its name when the program is loaded
+#
+# Build with:
+#   This is done by build_programs.rs when the initrd is made
+
+.intel_syntax noprefix
+.text
+
+# Writes the greeting and returns the program's exit_code, read through the GOT
+.global say_hello
+.type say_hello, @function
+say_hello:
+    mov eax, 1
+    mov edi, 1
+    mov rsi, [rip + message_pointer]
+    mov edx, message_end - message
+    syscall
+
+    mov rax, [rip + exit_code@GOTPCREL]
+    mov rax, [rax]
+    ret
+
+# Returns the program's exit_code again, read through an absolute pointer
+.global answer
+.type answer, @function
+answer:
+    mov rax, [rip + exit_code_pointer]
+    mov rax, [rax]
+    ret
+
+.section .rodata
+message:
+    .ascii "Hello from a shared library!\n"
+message_end:
+
+.data
+.balign 8
+# Relocated by the loader, R_X86_64_RELATIVE
+message_pointer:
+    .quad message
+# R_X86_64_64 against a symbol in the program
+exit_code_pointer:
+    .quad exit_code
+
+.global library_value
+.type library_value, @object
+.size library_value, 8
+library_value:
+    .quad 3
@@ -0,0 +1,29 @@
+//This file contains code for interstellar OS - https://github.com/interstellarfrog/interstellar
+//Copyright (C) 2023  contributors of the interstellar OS project
+//
+//This program is free software: you can redistribute it and/or modify
+//it under the terms of the GNU General Public License as published by
+//the Free Software Foundation, either version 3 of the License, or
+//(at your option) any later version.
+//
+//This program is distributed in the hope that it will be useful,
+//but WITHOUT ANY WARRANTY; without even the implied warranty of
+//MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+//GNU General Public License for more details.
+//
+//You should have received a copy of the GNU General Public License
+//along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+// A C program built against musl, so the C library's own start up code and stdio run on interstellar's Linux system
+// calls. Static musl binaries have no interstellar note so they run as Linux programs.
+//
+// Build with:
+//   This is done by build_programs.rs when the initrd is made, it links musl from the x86_64-unknown-linux-musl
+//   Rust target which comes without C headers so printf is declared here
+
+int printf(const char *format, ...);
+
+int main(int argc, char **argv) {
+    printf("Hello from musl, %s!\n", argc > 0 ? argv[0] : "nobody");
+    return 0;
+}